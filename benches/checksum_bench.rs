@@ -0,0 +1,41 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use futures::Future;
+
+use dl::checksum::{self, HashChecker};
+use dl::transport::FakeFile;
+
+static PATH: &str = "data/foo_checksum.bin";
+
+fn checksumming_files(c: &mut Criterion) {
+    c.bench(
+        "checksum file",
+        ParameterizedBenchmark::new(
+            "md5sum",
+            |b, size| {
+                std::fs::write(PATH, &*FakeFile::generated(*size).content).unwrap();
+                b.iter(|| checksum::md5sum(PATH.as_ref()).unwrap());
+                std::fs::remove_file(PATH).unwrap();
+            },
+            vec![50 * 1000, 25 * 1000 * 1000, 256 * 1000 * 1000],
+        )
+        .with_function("verify against etag", |b, size| {
+            let file = FakeFile::generated(*size);
+            std::fs::write(PATH, &*file.content).unwrap();
+            let checker = HashChecker {
+                path: PATH.into(),
+                etag: Some(file.md5()),
+                pieces: vec![],
+            };
+            b.iter(|| checker.verify().wait().unwrap());
+            std::fs::remove_file(PATH).unwrap();
+        })
+        .throughput(|size| Throughput::Bytes(*size as u32))
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, checksumming_files);
+criterion_main!(benches);
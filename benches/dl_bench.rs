@@ -1,56 +1,100 @@
 #[macro_use]
 extern crate criterion;
-#[allow(unused_imports)]
-#[macro_use]
-extern crate lazy_static;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use criterion::{Criterion, ParameterizedBenchmark};
-use hyper::Uri;
+use hyper::{HeaderMap, Uri};
 use tokio::runtime::Runtime;
 
-use dl::{file, https};
+use dl::download::Destination;
+use dl::https::{self, HttpVersion, Network};
+use dl::output::{OutputFormat, Reporter};
+use dl::test_server::TestServer;
+use dl::timeout::Timeouts;
+use dl::transport::{FakeFile, SharedTransport};
+use dl::{disk, file};
 use file::FileDownloader;
 
-static PATH: &'static str = "data/foo.pdf";
+static PATH: &str = "data/foo.pdf";
+
+static SMALL_FILE_SIZE: u64 = 50 * 1000;
+static MEDIUM_FILE_SIZE: u64 = 25 * 1000 * 1000;
+static LARGE_FILE_SIZE: u64 = 600 * 1000 * 1000;
+
+// every connection to the server is shaped like one to a (fairly quick) server across the internet, so that
+// parallelism pays off the way it would in the wild. override with `DL_BENCH_BANDWIDTH` (bytes/sec per
+// connection, or 0 for no cap) and `DL_BENCH_LATENCY_MS`
+static DEFAULT_BANDWIDTH: u64 = 16 * 1024 * 1024;
+static DEFAULT_LATENCY_MS: u64 = 20;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
 
-static SMALL_FILE_URL: &'static str =
-    "https://recurse-uploads-production.s3.amazonaws.com/b9349b0c-359a-473a-9441-c1bc54a96ca6/austin_guest_resume.pdf";
-static SMALL_FILE_SIZE: u64 = 53_143;
+/// a local https server, shaped as configured, serving a generated file of `size` bytes at `/file`
+fn server_for(size: u64) -> TestServer {
+    let server = TestServer::builder()
+        .file("/file", FakeFile::generated(size))
+        .https()
+        .latency(Duration::from_millis(env_or(
+            "DL_BENCH_LATENCY_MS",
+            DEFAULT_LATENCY_MS,
+        )));
+    match env_or("DL_BENCH_BANDWIDTH", DEFAULT_BANDWIDTH) {
+        0 => server.start(),
+        bandwidth => server.bandwidth(bandwidth).start(),
+    }
+}
 
-static MEDIUM_FILE_URL: &'static str = "https://recurse-uploads-production.s3.amazonaws.com/dc12e4d0-3c82-45b8-9cb7-6c64a8f50cfb/austin_guest_resume.pdf";
-static MEDIUM_FILE_SIZE: u64 = 24_975_901;
+fn download(server: &TestServer, file_size: u64, parallelism: usize) {
+    download_over(server, file_size, parallelism, HttpVersion::Auto)
+}
 
-static LARGE_FILE_URL: &'static str =
-        "https://recurse-uploads-production.s3.amazonaws.com/cb60706d-3a65-42cc-bfb4-effc9e81f1f8/austin_guest_resume.pdf";
-static LARGE_FILE_SIZE: u64 = 637_828_873;
+fn download_over(server: &TestServer, file_size: u64, parallelism: usize, version: HttpVersion) {
+    let network = Network {
+        ca_bundle: Some(server.ca_bundle()),
+        http_version: version,
+        ..Network::default()
+    };
+    let res = FileDownloader {
+        transport: SharedTransport::new(
+            https::build_client(parallelism, &Timeouts::default(), &network, false).unwrap(),
+        ),
+        uri: server.url("/file").parse::<Uri>().unwrap(),
+        destination: Destination::File(PathBuf::from(PATH)),
+        file_size,
+        etag: None,
+        validator: None,
+        mirrors: vec![],
+        addresses: vec![],
+        bound: vec![],
+        headers: HeaderMap::new(),
+        parallelism,
+        piece_size: file::piece_size_for(file_size, parallelism, None),
+        retry: file::RetryPolicy::default(),
+        rate_limit: None,
+        timeouts: Timeouts::default(),
+        reporter: Reporter::new(OutputFormat::Text),
+    }
+    .fetch();
 
-static VERY_LARGE_FILE_URL: &'static str =
-        "https://gensho.ftp.acc.umu.se/debian-cd/current-live/amd64/iso-hybrid/debian-live-9.9.0-amd64-xfce.iso";
-static VERY_LARGE_FILE_SIZE: u64 = 1_951_432_704;
+    Runtime::new().unwrap().block_on(res).unwrap();
+    std::fs::remove_file(disk::part_path(Path::new(PATH))).unwrap();
+}
 
 fn small_file_varying_parallelism(c: &mut Criterion) {
+    let server = server_for(SMALL_FILE_SIZE);
     c.bench(
         "download small file",
         ParameterizedBenchmark::new(
             "with varying degrees of parallelism",
-            move |b, i| {
-                b.iter(move || {
-                    let res = FileDownloader {
-                        client: https::get_client(*i),
-                        uri: SMALL_FILE_URL.parse::<Uri>().unwrap(),
-                        path: PathBuf::from(PATH),
-                        file_size: SMALL_FILE_SIZE,
-                        etag: None,
-                        parallelism: *i,
-                    }
-                    .fetch();
-
-                    Runtime::new().unwrap().block_on(res).unwrap();
-                    std::fs::remove_file(&PATH).unwrap();
-                })
-            },
+            move |b, i| b.iter(|| download(&server, SMALL_FILE_SIZE, *i)),
             vec![1, 6, 12, 24, 48],
         )
         .sample_size(20),
@@ -58,26 +102,12 @@ fn small_file_varying_parallelism(c: &mut Criterion) {
 }
 
 fn medium_file_varying_parallelism(c: &mut Criterion) {
+    let server = server_for(MEDIUM_FILE_SIZE);
     c.bench(
         "download medium file",
         ParameterizedBenchmark::new(
             "with varying levels of parallelism",
-            move |b, i| {
-                b.iter(move || {
-                    let res = FileDownloader {
-                        client: https::get_client(*i),
-                        uri: MEDIUM_FILE_URL.parse::<Uri>().unwrap(),
-                        path: PathBuf::from(PATH),
-                        file_size: MEDIUM_FILE_SIZE,
-                        etag: None,
-                        parallelism: *i,
-                    }
-                    .fetch();
-
-                    Runtime::new().unwrap().block_on(res).unwrap();
-                    std::fs::remove_file(&PATH).unwrap();
-                })
-            },
+            move |b, i| b.iter(|| download(&server, MEDIUM_FILE_SIZE, *i)),
             vec![1, 6, 12, 24, 48],
         )
         .sample_size(20),
@@ -85,28 +115,33 @@ fn medium_file_varying_parallelism(c: &mut Criterion) {
 }
 
 fn large_file_varying_parallelism(c: &mut Criterion) {
+    let server = server_for(LARGE_FILE_SIZE);
     c.bench(
         "download large file",
         ParameterizedBenchmark::new(
             "with varying levels of parallelism",
-            move |b, i| {
-                b.iter(move || {
-                    let res = FileDownloader {
-                        client: https::get_client(*i),
-                        uri: LARGE_FILE_URL.parse::<Uri>().unwrap(),
-                        path: PathBuf::from(PATH),
-                        file_size: LARGE_FILE_SIZE,
-                        etag: None,
-                        parallelism: *i,
-                    }
-                    .fetch();
-
-                    Runtime::new().unwrap().block_on(res).unwrap();
-                    std::fs::remove_file(&PATH).unwrap();
-                })
-            },
-            vec![1, 6, 12, 24, 48],
+            move |b, i| b.iter(|| download(&server, LARGE_FILE_SIZE, *i)),
+            // a single (capped) connection takes most of a minute per sample
+            vec![6, 12, 24, 48],
+        )
+        .sample_size(10),
+    );
+}
+
+// http/1.1 opens a (capped) connection per piece in flight, where http/2 multiplexes them all over one
+fn medium_file_http1_vs_http2(c: &mut Criterion) {
+    let server = Arc::new(server_for(MEDIUM_FILE_SIZE));
+    let http2 = server.clone();
+    c.bench(
+        "download medium file over http/1.1 and http/2",
+        ParameterizedBenchmark::new(
+            "http/1.1",
+            move |b, i| b.iter(|| download_over(&server, MEDIUM_FILE_SIZE, *i, HttpVersion::Http1)),
+            vec![1, 6, 12, 24],
         )
+        .with_function("http/2", move |b, i| {
+            b.iter(|| download_over(&http2, MEDIUM_FILE_SIZE, *i, HttpVersion::Http2))
+        })
         .sample_size(20),
     );
 }
@@ -116,5 +151,6 @@ criterion_group!(
     small_file_varying_parallelism,
     medium_file_varying_parallelism,
     large_file_varying_parallelism,
+    medium_file_http1_vs_http2,
 );
 criterion_main!(benches);
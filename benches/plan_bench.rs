@@ -0,0 +1,64 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, Criterion, ParameterizedBenchmark};
+
+use dl::file::{self, Scheduler};
+
+static PARALLELISM: usize = 24;
+
+/// a file of `file_size` bytes, split into pieces the way `dl` would for `PARALLELISM` connections
+fn pieces_for(file_size: u64) -> Vec<file::Piece> {
+    file::plan_pieces(
+        file_size,
+        file::piece_size_for(file_size, PARALLELISM, None),
+    )
+}
+
+fn planning_pieces(c: &mut Criterion) {
+    c.bench(
+        "plan pieces",
+        ParameterizedBenchmark::new(
+            "for 24 connections",
+            |b, file_size| b.iter(|| pieces_for(black_box(*file_size))),
+            vec![50 * 1000, 25 * 1000 * 1000, 600 * 1000 * 1000, 8 << 30],
+        )
+        .with_function("of the smallest size", |b, file_size| {
+            b.iter(|| file::plan_pieces(black_box(*file_size), file::MIN_PIECE_SIZE))
+        }),
+    );
+}
+
+/// hands out every piece (and then the tails of the biggest ones, until none is worth splitting), claiming
+/// their bytes a chunk at a time, the way the workers fetching a file do
+fn schedule(pieces: Vec<file::Piece>) {
+    let mut scheduler = Scheduler::new(pieces);
+    let mut in_flight = vec![];
+    while let Some(piece) = scheduler.next_queued() {
+        in_flight.push(piece.index);
+    }
+    while let Some((_, tail)) = scheduler.split_largest() {
+        in_flight.push(tail.index);
+    }
+    for index in in_flight {
+        while let (_, claimed, false) = scheduler.claim(index, 16 * 1024) {
+            black_box(claimed);
+        }
+        black_box(scheduler.finish(index));
+    }
+}
+
+fn scheduling_pieces(c: &mut Criterion) {
+    c.bench(
+        "schedule pieces",
+        ParameterizedBenchmark::new(
+            "for 24 connections",
+            |b, file_size| b.iter(|| schedule(pieces_for(*file_size))),
+            vec![50 * 1000, 25 * 1000 * 1000, 600 * 1000 * 1000],
+        )
+        .sample_size(20),
+    );
+}
+
+criterion_group!(benches, planning_pieces, scheduling_pieces);
+criterion_main!(benches);
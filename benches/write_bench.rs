@@ -0,0 +1,154 @@
+#[macro_use]
+extern crate criterion;
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use criterion::{Criterion, ParameterizedBenchmark};
+use futures::{future, stream, Future, Stream};
+use tokio::runtime::Runtime;
+use tokio_fs::{File, OpenOptions};
+use tokio_io::io;
+
+use dl::disk;
+use dl::error::DlError;
+use dl::file::{self, Piece};
+use dl::sink::{FileSink, Sink};
+
+static PATH: &str = "data/foo_write.bin";
+
+static FILE_SIZE: u64 = 32 * 1024 * 1024;
+// roughly the size of the chunks hyper hands us off the wire
+static CHUNK_SIZE: usize = 16 * 1024;
+
+/// the write path as it was: create an empty file, then open it again and seek for every piece
+fn open_and_seek(pieces: Vec<Piece>) -> impl Future<Item = (), Error = DlError> {
+    File::create(PATH)
+        .map_err(DlError::Io)
+        .and_then(move |_| {
+            future::join_all(pieces.into_iter().map(|piece| {
+                OpenOptions::new()
+                    .write(true)
+                    .open(PATH)
+                    .and_then(move |file| file.seek(SeekFrom::Start(piece.offset)))
+                    .and_then(move |(file, _)| {
+                        stream::iter_ok(chunks(piece)).fold(file, |file, chunk| {
+                            io::write_all(file, chunk).map(|(file, _)| file)
+                        })
+                    })
+                    .map_err(DlError::Io)
+            }))
+        })
+        .map(|_| ())
+}
+
+/// the write path as it is: preallocate the file, then `pwrite` every piece through one shared handle
+fn preallocate_and_pwrite(pieces: Vec<Piece>) -> impl Future<Item = (), Error = DlError> {
+    disk::run_blocking(|| disk::preallocate(Path::new(PATH), FILE_SIZE))
+        .and_then(move |file| {
+            let file = Arc::new(file);
+            future::join_all(pieces.into_iter().map(move |piece| {
+                let file = file.clone();
+                stream::iter_ok(chunks(piece).into_iter().enumerate()).for_each(
+                    move |(i, chunk)| {
+                        let at = piece.offset + (i * CHUNK_SIZE) as u64;
+                        disk::write_at_async(file.clone(), chunk, at)
+                    },
+                )
+            }))
+        })
+        .map(|_| ())
+}
+
+/// the write path as `FileDownloader` takes it: every chunk through a `Sink`, which is finalized at the end
+fn through_sink(
+    sink: Arc<dyn Sink>,
+    pieces: Vec<Piece>,
+) -> impl Future<Item = (), Error = DlError> {
+    let writes = sink.clone();
+    future::join_all(pieces.into_iter().map(move |piece| {
+        let sink = writes.clone();
+        stream::iter_ok(chunks(piece).into_iter().enumerate()).for_each(move |(i, chunk)| {
+            let at = piece.offset + (i * CHUNK_SIZE) as u64;
+            sink.write_at(Bytes::from(chunk), at)
+        })
+    }))
+    .and_then(move |_| sink.finalize())
+}
+
+fn chunks(piece: Piece) -> Vec<Vec<u8>> {
+    let chunk = vec![piece.index as u8; CHUNK_SIZE];
+    (0..piece.length)
+        .step_by(CHUNK_SIZE)
+        .map(|at| chunk[..std::cmp::min(CHUNK_SIZE as u64, piece.length - at) as usize].to_vec())
+        .collect()
+}
+
+fn writing_pieces(c: &mut Criterion) {
+    c.bench(
+        "write 32MB file",
+        ParameterizedBenchmark::new(
+            "open and seek per piece",
+            |b, piece_size| {
+                b.iter(|| {
+                    let pieces = file::plan_pieces(FILE_SIZE, *piece_size);
+                    Runtime::new()
+                        .unwrap()
+                        .block_on(open_and_seek(pieces))
+                        .unwrap();
+                    std::fs::remove_file(PATH).unwrap();
+                })
+            },
+            vec![256 * 1024, 1024 * 1024, 4 * 1024 * 1024],
+        )
+        .with_function("preallocate and pwrite", |b, piece_size| {
+            b.iter(|| {
+                let pieces = file::plan_pieces(FILE_SIZE, *piece_size);
+                Runtime::new()
+                    .unwrap()
+                    .block_on(preallocate_and_pwrite(pieces))
+                    .unwrap();
+                std::fs::remove_file(PATH).unwrap();
+            })
+        })
+        .with_function("through a file sink", |b, piece_size| {
+            b.iter(|| {
+                let pieces = file::plan_pieces(FILE_SIZE, *piece_size);
+                let sink = FileSink::create(Path::new(PATH), FILE_SIZE).unwrap();
+                Runtime::new()
+                    .unwrap()
+                    .block_on(through_sink(Arc::new(sink), pieces))
+                    .unwrap();
+                std::fs::remove_file(PATH).unwrap();
+            })
+        })
+        .with_function("through a mapped sink", |b, piece_size| {
+            b.iter(|| {
+                let pieces = file::plan_pieces(FILE_SIZE, *piece_size);
+                let sink = mapped_sink();
+                Runtime::new()
+                    .unwrap()
+                    .block_on(through_sink(sink, pieces))
+                    .unwrap();
+                std::fs::remove_file(PATH).unwrap();
+            })
+        })
+        .sample_size(10),
+    );
+}
+
+#[cfg(target_os = "linux")]
+fn mapped_sink() -> Arc<dyn Sink> {
+    Arc::new(dl::sink::MmapSink::create(Path::new(PATH), FILE_SIZE).unwrap())
+}
+
+// (there is no mapped sink off linux: fall back to writing the file)
+#[cfg(not(target_os = "linux"))]
+fn mapped_sink() -> Arc<dyn Sink> {
+    Arc::new(FileSink::create(Path::new(PATH), FILE_SIZE).unwrap())
+}
+
+criterion_group!(benches, writing_pieces);
+criterion_main!(benches);
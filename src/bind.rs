@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+
+use crate::dns::IpPreference;
+use crate::error::DlError;
+
+/// the local address to bind connections to for `spec`: an ip address, or the name of a network interface (for
+/// its first address of the family `preference` favours, ipv4's unless it's for ipv6). link-local ipv6 addresses
+/// are never picked, since they'd only reach hosts on the same link
+pub fn local_address(spec: &str, preference: IpPreference) -> Result<IpAddr, DlError> {
+    if let Ok(addr) = spec.parse::<IpAddr>() {
+        return Ok(addr);
+    }
+    let (v6, v4): (Vec<_>, Vec<_>) = interface_addresses(spec)?
+        .into_iter()
+        .filter(|addr| !is_link_local(addr))
+        .partition(IpAddr::is_ipv6);
+    let mut addrs = match preference {
+        IpPreference::Ipv6 => v6.into_iter().chain(v4),
+        IpPreference::Auto | IpPreference::Ipv4 => v4.into_iter().chain(v6),
+    };
+    addrs
+        .next()
+        .ok_or_else(|| DlError::InvalidSettings(format!("interface {}: no usable addresses", spec)))
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
+/// every address of the network interface called `name`
+#[cfg(target_os = "linux")]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, DlError> {
+    use std::ffi::CStr;
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(DlError::Io(io::Error::last_os_error()));
+    }
+    let (mut found, mut addrs) = (false, vec![]);
+    let mut next = ifaddrs;
+    while !next.is_null() {
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        if unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        // (an interface without any ip address still has an entry, of another family)
+        found = true;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(IpAddr::from(Ipv4Addr::from(u32::from_be(
+                    sin.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.push(IpAddr::from(sin6.sin6_addr.s6_addr));
+            }
+            _ => (),
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    match found {
+        true => Ok(addrs),
+        false => Err(DlError::InvalidSettings(format!(
+            "interface {}: no such interface",
+            name
+        ))),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, DlError> {
+    Err(DlError::InvalidSettings(format!(
+        "interface {}: binding to interfaces by name is only supported on linux",
+        name
+    )))
+}
+
+#[cfg(test)]
+mod bind_tests {
+    use super::*;
+
+    #[test]
+    fn finding_local_addresses() {
+        assert_eq!(
+            local_address("10.0.0.2", IpPreference::Ipv6).unwrap(),
+            IpAddr::from([10, 0, 0, 2])
+        );
+        assert_eq!(
+            local_address("::1", IpPreference::Ipv4).unwrap(),
+            IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])
+        );
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(!is_link_local(&"fd00::1".parse().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finding_the_addresses_of_interfaces() {
+        assert_eq!(
+            local_address("lo", IpPreference::Ipv4).unwrap(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+        assert_eq!(
+            local_address("dl-no-such0", IpPreference::Auto)
+                .unwrap_err()
+                .to_string(),
+            "Invalid settings: interface dl-no-such0: no such interface"
+        );
+    }
+}
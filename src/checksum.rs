@@ -1,7 +1,9 @@
 use crate::error::DlError;
+use crate::file::PieceReport;
 use futures::{Future, IntoFuture};
 use hex;
 use md5::{Digest, Md5};
+use serde::Serialize;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
@@ -11,17 +13,76 @@ use std::path::PathBuf;
 pub struct HashChecker {
     pub path: PathBuf,
     pub etag: Option<String>,
+    pub pieces: Vec<PieceReport>,
+}
+
+/// what to check a downloaded file against before moving it into place
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verify {
+    /// the md5 digest in the server's etag (failing if the server didn't send one)
+    Etag,
+    /// the md5 digest in the server's etag, if it sent one (otherwise, nothing)
+    EtagIfPresent,
+    /// a known md5 digest (hex-encoded)
+    Md5(String),
+    /// nothing at all
+    Skip,
+}
+
+impl Verify {
+    /// the (hex-encoded) md5 digest a file with the given `etag` should have, if we're checking one
+    pub fn expected_md5(&self, etag: Option<&String>) -> Result<Option<String>, DlError> {
+        match *self {
+            Verify::Etag => etag.cloned().map(Some).ok_or(DlError::EtagAbsent),
+            Verify::EtagIfPresent => Ok(etag.cloned()),
+            Verify::Md5(ref digest) => Ok(Some(digest.clone())),
+            Verify::Skip => Ok(None),
+        }
+    }
+}
+
+/// the outcome of comparing a downloaded file's digest against the one the server advertised
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DigestReport {
+    pub algorithm: &'static str,
+    pub expected: String,
+    pub actual: String,
+    pub verified: bool,
 }
 
 impl HashChecker {
     pub fn check(self) -> impl Future<Item = bool, Error = DlError> {
-        let p = self.path.clone();
+        self.verify().map(|digest| digest.verified)
+    }
+
+    /// hashes the downloaded file and compares the result against its etag (if present)
+    pub fn verify(&self) -> impl Future<Item = DigestReport, Error = DlError> {
         match self.etag {
             None => Err(DlError::EtagAbsent),
-            Some(etag) => md5sum_check(&PathBuf::from(p.clone()), &etag),
+            Some(ref etag) => self.compare_md5(etag),
         }
         .into_future()
     }
+
+    /// hashes the downloaded file and compares the result against the (hex-encoded) md5 digest `expected`
+    pub fn verify_md5(&self, expected: &str) -> impl Future<Item = DigestReport, Error = DlError> {
+        self.compare_md5(expected).into_future()
+    }
+
+    fn compare_md5(&self, expected: &str) -> Result<DigestReport, DlError> {
+        md5sum(&self.path).map(|actual| md5_report(expected, &actual))
+    }
+}
+
+/// compares an `actual` md5 digest against the (hex-encoded) digest `expected`
+pub fn md5_report(expected: &str, actual: &[u8]) -> DigestReport {
+    let actual = hex::encode(actual);
+    DigestReport {
+        algorithm: "md5",
+        expected: expected.to_string(),
+        verified: actual == expected.to_lowercase(),
+        actual,
+    }
 }
 
 pub fn md5sum_check(path: &Path, sum_hex: &str) -> Result<bool, DlError> {
@@ -48,7 +109,6 @@ pub fn md5sum(path: &Path) -> Result<Vec<u8>, DlError> {
 #[cfg(test)]
 mod checksum_tests {
     use super::*;
-    use std::error::Error;
     use tokio::runtime::Runtime;
 
     #[test]
@@ -61,14 +121,11 @@ mod checksum_tests {
 
     #[test]
     fn checking_md5sum() {
-        assert_eq!(
-            md5sum_check(
-                &PathBuf::from("data/foo.txt"),
-                "d3b07384d113edec49eaa6238ad5ff00"
-            )
-            .unwrap(),
-            true,
+        assert!(md5sum_check(
+            &PathBuf::from("data/foo.txt"),
+            "d3b07384d113edec49eaa6238ad5ff00"
         )
+        .unwrap())
     }
 
     #[test]
@@ -76,9 +133,50 @@ mod checksum_tests {
         let hc = HashChecker {
             path: PathBuf::from("data/foo.txt"),
             etag: Some(String::from("d3b07384d113edec49eaa6238ad5ff00")),
+            pieces: vec![],
         };
         let valid = Runtime::new().unwrap().block_on(hc.check()).unwrap();
-        assert_eq!(valid, true);
+        assert!(valid);
+    }
+
+    #[test]
+    fn reporting_digests() {
+        let hc = HashChecker {
+            path: PathBuf::from("data/foo.txt"),
+            etag: Some(String::from("00000000000000000000000000000000")),
+            pieces: vec![],
+        };
+        let digest = Runtime::new().unwrap().block_on(hc.verify()).unwrap();
+        assert_eq!(
+            digest,
+            DigestReport {
+                algorithm: "md5",
+                expected: String::from("00000000000000000000000000000000"),
+                actual: String::from("d3b07384d113edec49eaa6238ad5ff00"),
+                verified: false,
+            }
+        );
+    }
+
+    #[test]
+    fn picking_expected_digests() {
+        let etag = String::from("d3b07384d113edec49eaa6238ad5ff00");
+        assert_eq!(
+            Verify::Etag.expected_md5(Some(&etag)).unwrap(),
+            Some(etag.clone())
+        );
+        assert_eq!(
+            Verify::Etag.expected_md5(None).unwrap_err().code(),
+            "etag_absent"
+        );
+        assert_eq!(Verify::EtagIfPresent.expected_md5(None).unwrap(), None);
+        assert_eq!(
+            Verify::Md5(String::from("abc"))
+                .expected_md5(Some(&etag))
+                .unwrap(),
+            Some(String::from("abc"))
+        );
+        assert_eq!(Verify::Skip.expected_md5(Some(&etag)).unwrap(), None);
     }
 
     #[test]
@@ -86,8 +184,9 @@ mod checksum_tests {
         let hc = HashChecker {
             path: PathBuf::from("data/foo.txt"),
             etag: None,
+            pieces: vec![],
         };
         let err = Runtime::new().unwrap().block_on(hc.check()).err().unwrap();
-        assert_eq!(err.description(), DlError::EtagAbsent.description());
+        assert_eq!(err.to_string(), DlError::EtagAbsent.to_string());
     }
 }
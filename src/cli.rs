@@ -0,0 +1,1158 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+
+use dl::checksum::Verify;
+use dl::download::Destination;
+use dl::error::DlError;
+use dl::output::{Event, OutputFormat};
+use dl::settings::Profile;
+use dl::timeout::{self, MinSpeed, Timeouts, DEFAULT_SPEED_TIME_SECS};
+use dl::{Download, DownloadBuilder};
+
+/// names `dl <url> <path>` can't start with without being mistaken for a subcommand (or a request for help)
+const RESERVED: [&str; 10] = [
+    "get",
+    "info",
+    "verify",
+    "batch",
+    "config",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+/// what the user asked `dl` to do
+#[derive(Debug)]
+pub enum Command {
+    /// download a single file
+    Get(DownloadBuilder),
+    /// print what the server says about a file
+    Info(DownloadBuilder),
+    /// check a local file against a digest
+    Verify { path: PathBuf, expected: Expected },
+    /// download every `<url> <path>` pair listed in `list` (a file, or `-` for stdin), `jobs` at a time
+    Batch {
+        list: String,
+        builder: DownloadBuilder,
+        jobs: usize,
+    },
+    /// print the settings a download (of the url the builder points at, if any) would use, and their sources
+    ConfigShow(DownloadBuilder),
+}
+
+/// the digest `dl verify` checks a file against
+#[derive(Debug)]
+pub enum Expected {
+    Md5(String),
+    /// the md5 digest in the etag of the url the builder points at
+    Etag(Box<DownloadBuilder>),
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub output_format: OutputFormat,
+    /// whether the file is being written to stdout (so progress has to go to stderr)
+    pub streaming: bool,
+}
+
+/// the `--output-format` argv asks for, found without parsing the rest of it (which may not parse)
+pub fn output_format_of(args: &[String]) -> OutputFormat {
+    let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+    let mut format = OutputFormat::Text;
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--output-format" => args.next().map(String::as_str),
+            _ => arg.strip_prefix("--output-format="),
+        };
+        if let Some(value) = value.and_then(|value| value.parse::<OutputFormat>().ok()) {
+            format = value;
+        }
+    }
+    format
+}
+
+/// the `Event` reporting the argv clap couldn't parse (minus the usage it would print alongside)
+pub fn error_event(err: &clap::Error) -> Event {
+    let message = err.message.lines().next().unwrap_or_default();
+    let reason = message.trim_start_matches("error: ").to_string();
+    Event::from_error(&DlError::InvalidArguments(reason))
+}
+
+/// parses argv (treating `dl <url> <path> [int]` as `dl get <url> <path> [int]`), layering the options it
+/// gives on top of `profile`
+pub fn parse(args: Vec<String>, profile: &Profile) -> Result<Cli, clap::Error> {
+    let app = match output_format_of(&args) {
+        // (clap's errors end up in json strings, where terminal colors don't belong)
+        OutputFormat::Json => app().setting(AppSettings::ColorNever),
+        OutputFormat::Text => app(),
+    };
+    let matches = app.get_matches_from_safe(with_default_subcommand(args))?;
+    let (name, matches) = match matches.subcommand() {
+        ("config", Some(config)) => match config.subcommand() {
+            (name, Some(matches)) => (name, matches),
+            _ => unreachable!(),
+        },
+        (name, Some(matches)) => (name, matches),
+        // `SubcommandRequiredElseHelp` keeps us from getting here
+        _ => unreachable!(),
+    };
+    let output_format = matches
+        .value_of("output-format")
+        .and_then(|f| f.parse::<OutputFormat>().ok())
+        .unwrap_or(OutputFormat::Text);
+
+    let command = match name {
+        "get" => {
+            let mut builder = download_builder(matches, profile, output_format)
+                .url(matches.value_of("url").unwrap_or_default());
+            builder = match matches.value_of("path") {
+                Some("-") => builder.destination(Destination::Stdout),
+                path => builder.path(path.unwrap_or_default()),
+            };
+            if let Some(bytes) = parsed(matches, "buffer-size") {
+                builder = builder.buffer_size(bytes);
+            }
+            if let Some(p) = parsed(matches, "PARALLELISM") {
+                builder = builder.parallelism(p);
+            }
+            if let Some(digest) = matches.value_of("md5") {
+                builder = builder.verify(Verify::Md5(digest.to_lowercase()));
+            }
+            Command::Get(builder)
+        }
+        "info" => Command::Info(
+            request_builder(matches, profile, output_format)
+                .url(matches.value_of("url").unwrap_or_default()),
+        ),
+        "verify" => Command::Verify {
+            path: PathBuf::from(matches.value_of("path").unwrap_or_default()),
+            expected: match (matches.value_of("md5"), matches.value_of("url")) {
+                (Some(digest), _) => Expected::Md5(digest.to_lowercase()),
+                (None, url) => Expected::Etag(Box::new(
+                    request_builder(matches, profile, output_format).url(url.unwrap_or_default()),
+                )),
+            },
+        },
+        "batch" => Command::Batch {
+            list: matches.value_of("list").unwrap_or("-").to_string(),
+            builder: download_builder(matches, profile, output_format),
+            jobs: parsed(matches, "jobs").unwrap_or(1),
+        },
+        _ => {
+            let builder = download_builder(matches, profile, output_format);
+            Command::ConfigShow(match matches.value_of("url") {
+                Some(url) => builder.url(url),
+                None => builder,
+            })
+        }
+    };
+
+    Ok(Cli {
+        streaming: name == "get" && matches.value_of("path") == Some("-"),
+        command,
+        output_format,
+    })
+}
+
+/// parses the lines of a batch file into `(url, path)` pairs, skipping blank lines and `#` comments
+pub fn parse_batch(list: &str) -> Result<Vec<(String, String)>, DlError> {
+    list.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [url, path] => Ok((url.to_string(), path.to_string())),
+                _ => Err(DlError::InvalidBatch(n)),
+            }
+        })
+        .collect()
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("dl")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Downloads files over http(s), in parallel pieces, and checks them before moving them into place")
+        .after_help("`dl <url> <path> [parallelism]` is short for `dl get <url> <path> [parallelism]`.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Downloads a file")
+                .arg(Arg::with_name("url").help("Url (or local path) to download from").required(true))
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path to save the file to (`-` to write it to stdout, in order)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("PARALLELISM")
+                        .help("Same as --parallelism")
+                        .validator(is_positive_int)
+                        .conflicts_with("parallelism"),
+                )
+                .arg(
+                    Arg::with_name("md5")
+                        .long("md5")
+                        .value_name("DIGEST")
+                        .help("Checks the file against this (hex-encoded) md5 digest instead of its etag")
+                        .validator(is_md5)
+                        .conflicts_with("no-verify"),
+                )
+                .arg(
+                    Arg::with_name("buffer-size")
+                        .long("buffer-size")
+                        .value_name("BYTES")
+                        .help("Most of the file to hold in memory while writing it to stdout [default: 64 MiB]")
+                        .validator(is_positive_int),
+                )
+                .args(&download_args())
+                .args(&request_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Prints what the server says about a file, without downloading it")
+                .arg(Arg::with_name("url").help("Url of the file").required(true))
+                .arg(mirror_arg())
+                .args(&request_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks a local file against an md5 digest, or the etag of a url")
+                .arg(Arg::with_name("path").help("Path of the file to check").required(true))
+                .arg(
+                    Arg::with_name("md5")
+                        .long("md5")
+                        .value_name("DIGEST")
+                        .help("Hex-encoded md5 digest the file should have")
+                        .validator(is_md5),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .value_name("URL")
+                        .help("Url whose etag the file's md5 digest should match"),
+                )
+                .group(
+                    ArgGroup::with_name("expected")
+                        .args(&["md5", "url"])
+                        .required(true),
+                )
+                .args(&request_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Downloads every file listed (as `<url> <path>` lines) in a file")
+                .arg(
+                    Arg::with_name("list")
+                        .help("File listing the downloads (`-` for stdin)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("N")
+                        .help("How many files to download at once [default: 1]")
+                        .validator(is_positive_int),
+                )
+                .args(&download_args())
+                .args(&request_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Inspects the settings from the config file and environment")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Prints the settings a download would use, and where each came from")
+                        .arg(Arg::with_name("url").help("Url to show the settings for (including its host's)"))
+                        .args(&download_args())
+                        .args(&request_args()),
+                ),
+        )
+}
+
+/// options for how a file is downloaded (shared by `get` and `batch`)
+fn download_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        mirror_arg(),
+        Arg::with_name("parallelism")
+            .short("p")
+            .long("parallelism")
+            .value_name("N")
+            .help("How many connections to download pieces over at once [default: number of cpus]")
+            .validator(is_positive_int),
+        Arg::with_name("piece-size")
+            .long("piece-size")
+            .value_name("BYTES")
+            .help("Size of the pieces the file is split into [default: picked from the file size]")
+            .validator(is_positive_int),
+        Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help("How many times to retry a piece after transient failures [default: 5]")
+            .validator(is_int),
+        Arg::with_name("retry-backoff")
+            .long("retry-backoff")
+            .value_name("SECS")
+            .help("How long to wait before the first retry, doubling with each one after that [default: 0.1]")
+            .validator(is_backoff),
+        Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .value_name("BYTES/SEC")
+            .help("Caps the download's combined speed across all its connections")
+            .validator(is_positive_int),
+        Arg::with_name("no-verify")
+            .long("no-verify")
+            .help("Skips checking the file against its etag"),
+        Arg::with_name("keep-partial")
+            .long("keep-partial")
+            .help("Leaves <path>.part behind when a download fails"),
+    ]
+}
+
+fn mirror_arg() -> Arg<'static, 'static> {
+    Arg::with_name("mirror")
+        .long("mirror")
+        .value_name("URL")
+        .help("Another url (or local path) serving the same file (may be repeated)")
+        .multiple(true)
+        .number_of_values(1)
+}
+
+/// options for how requests are made and reported (shared by every subcommand that makes requests)
+fn request_args() -> Vec<Arg<'static, 'static>> {
+    let timeout = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("SECS")
+            .help(help)
+            .validator(is_timeout)
+    };
+    vec![
+        Arg::with_name("header")
+            .short("H")
+            .long("header")
+            .value_name("NAME: VALUE")
+            .help("A header to send with every request (may be repeated)")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(is_header),
+        timeout(
+            "connect-timeout",
+            "Gives up on connecting after this long (0 for never) [default: 30]",
+        ),
+        timeout(
+            "tls-timeout",
+            "Gives up on the tls handshake after this long (0 for never) [default: 30]",
+        ),
+        timeout(
+            "first-byte-timeout",
+            "Gives up on a response's headers after this long (0 for never) [default: 60]",
+        ),
+        timeout(
+            "idle-timeout",
+            "Gives up on a response body that stalls for this long (0 for never) [default: 60]",
+        ),
+        timeout(
+            "max-time",
+            "Gives up on the whole download after this long (0 for never)",
+        ),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .help("Sends every request through this http proxy (tunnelling https with CONNECT)"),
+        Arg::with_name("ca-bundle")
+            .long("ca-bundle")
+            .value_name("PATH")
+            .help("Trusts the (PEM) certificates in this file, as well as the system's"),
+        Arg::with_name("http")
+            .long("http")
+            .value_name("VERSION")
+            .help("Speaks this version of http (auto: http/2 with servers that offer it, and http/3 with those that advertise it, when built with http/3) [default: auto]")
+            .possible_values(&["auto", "1.1", "2", "3"]),
+        Arg::with_name("resolve")
+            .long("resolve")
+            .value_name("HOST:PORT:ADDR[,ADDR...]")
+            .help("Connects to HOST on PORT at these addresses, whatever it resolves to (still checking its certificate against HOST)")
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("dns-server")
+            .long("dns-server")
+            .value_name("ADDR[:PORT]")
+            .help("Looks hosts up with this nameserver, rather than the system's resolver"),
+        Arg::with_name("hosts-file")
+            .long("hosts-file")
+            .value_name("PATH")
+            .help("Looks hosts up in this file (in the format of /etc/hosts) first"),
+        Arg::with_name("bind")
+            .long("bind")
+            .value_name("ADDR|INTERFACE")
+            .help("Binds connections to this local address or network interface (may be repeated, to spread a file's connections across them)")
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("ip-preference")
+            .long("ip-preference")
+            .value_name("FAMILY")
+            .help("Which family of addresses to connect to first [default: auto]")
+            .possible_values(&["auto", "ipv4", "ipv6"]),
+        Arg::with_name("all-addresses")
+            .long("all-addresses")
+            .help("Spreads connections across every address the url's host resolves to (ipv4 and ipv6), reporting on each"),
+        Arg::with_name("speed-limit")
+            .long("speed-limit")
+            .value_name("BYTES/SEC")
+            .help("Aborts responses averaging less than this over --speed-time")
+            .validator(is_positive_int),
+        Arg::with_name("speed-time")
+            .long("speed-time")
+            .value_name("SECS")
+            .help("The window --speed-limit is averaged over [default: 30]")
+            .validator(is_secs),
+        Arg::with_name("output-format")
+            .long("output-format")
+            .value_name("FORMAT")
+            .help("Prints progress as prose or json-lines [default: text]")
+            .possible_values(&["text", "json"]),
+    ]
+}
+
+/// a builder with `profile` and the options from `request_args` (and only those) filled in
+fn request_builder(
+    matches: &ArgMatches,
+    profile: &Profile,
+    output_format: OutputFormat,
+) -> DownloadBuilder {
+    let mut builder = Download::builder()
+        .profile(profile.clone())
+        .output_format(output_format)
+        .timeouts(timeouts(matches));
+    if let Some(proxy) = matches.value_of("proxy") {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = matches.value_of("ca-bundle") {
+        builder = builder.ca_bundle(path);
+    }
+    if let Some(version) = parsed(matches, "http") {
+        builder = builder.http_version(version);
+    }
+    for entry in matches.values_of("resolve").into_iter().flatten() {
+        builder = builder.resolve_host(entry);
+    }
+    if let Some(addr) = matches.value_of("dns-server") {
+        builder = builder.dns_server(addr);
+    }
+    if let Some(path) = matches.value_of("hosts-file") {
+        builder = builder.hosts_file(path);
+    }
+    for local in matches.values_of("bind").into_iter().flatten() {
+        builder = builder.bind(local);
+    }
+    if let Some(preference) = parsed(matches, "ip-preference") {
+        builder = builder.ip_preference(preference);
+    }
+    if matches.is_present("all-addresses") {
+        builder = builder.all_addresses(true);
+    }
+    for header in matches.values_of("header").into_iter().flatten() {
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            builder = builder.header(name.trim(), value.trim());
+        }
+    }
+    for mirror in matches.values_of("mirror").into_iter().flatten() {
+        builder = builder.mirror(mirror);
+    }
+    builder
+}
+
+/// a builder with the options from `download_args` and `request_args` filled in
+fn download_builder(
+    matches: &ArgMatches,
+    profile: &Profile,
+    output_format: OutputFormat,
+) -> DownloadBuilder {
+    let mut builder = request_builder(matches, profile, output_format)
+        .keep_partial(matches.is_present("keep-partial"));
+    if let Some(p) = parsed(matches, "parallelism") {
+        builder = builder.parallelism(p);
+    }
+    if let Some(size) = parsed(matches, "piece-size") {
+        builder = builder.piece_size(size);
+    }
+    if let Some(retries) = parsed(matches, "retries") {
+        builder = builder.max_retries(retries);
+    }
+    if let Some(secs) = parsed(matches, "retry-backoff") {
+        builder = builder.retry_backoff(Duration::from_secs_f64(secs));
+    }
+    if let Some(bytes_per_sec) = parsed(matches, "rate-limit") {
+        builder = builder.rate_limit(bytes_per_sec);
+    }
+    if matches.is_present("no-verify") {
+        builder = builder.verify(Verify::Skip);
+    }
+    builder
+}
+
+fn timeouts(matches: &ArgMatches) -> Timeouts {
+    let mut timeouts = Timeouts::default();
+    for (name, timeout) in [
+        ("connect-timeout", &mut timeouts.connect),
+        ("tls-timeout", &mut timeouts.tls_handshake),
+        ("first-byte-timeout", &mut timeouts.first_byte),
+        ("idle-timeout", &mut timeouts.idle),
+        ("max-time", &mut timeouts.total),
+    ] {
+        if let Some(secs) = matches.value_of(name) {
+            // "0" (no limit) is the only valid value `parse_secs` rejects
+            *timeout = timeout::parse_secs(secs);
+        }
+    }
+
+    // as with curl, either flag on its own turns the check on (with a limit of 1 byte/sec or a 30 sec window)
+    let speed_limit = parsed::<u64>(matches, "speed-limit");
+    let speed_time = matches.value_of("speed-time").and_then(timeout::parse_secs);
+    if speed_limit.is_some() || speed_time.is_some() {
+        timeouts.min_speed = Some(MinSpeed {
+            bytes_per_sec: speed_limit.unwrap_or(1),
+            window: speed_time.unwrap_or(Duration::from_secs(DEFAULT_SPEED_TIME_SECS)),
+        });
+    }
+    timeouts
+}
+
+/// the value of an (already validated) option
+fn parsed<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|v| v.parse::<T>().ok())
+}
+
+fn with_default_subcommand(mut args: Vec<String>) -> Vec<String> {
+    match args.get(1) {
+        Some(arg) if !RESERVED.contains(&arg.as_str()) => args.insert(1, String::from("get")),
+        _ => (),
+    }
+    args
+}
+
+fn is_int(v: String) -> Result<(), String> {
+    v.parse::<u32>()
+        .map(|_| ())
+        .map_err(|_| format!("expected a whole number, got '{}'", v))
+}
+
+fn is_positive_int(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("expected a positive whole number, got '{}'", v)),
+    }
+}
+
+fn is_secs(v: String) -> Result<(), String> {
+    timeout::parse_secs(&v)
+        .map(|_| ())
+        .ok_or_else(|| format!("expected a positive number of seconds, got '{}'", v))
+}
+
+fn is_timeout(v: String) -> Result<(), String> {
+    match v.as_str() {
+        "0" => Ok(()),
+        _ => is_secs(v).map_err(|e| format!("{} (or 0 for no limit)", e)),
+    }
+}
+
+fn is_backoff(v: String) -> Result<(), String> {
+    match v.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(format!(
+            "expected a non-negative number of seconds, got '{}'",
+            v
+        )),
+    }
+}
+
+fn is_md5(v: String) -> Result<(), String> {
+    match v.len() == 32 && hex::decode(&v).is_ok() {
+        true => Ok(()),
+        false => Err(String::from("expected 32 hexadecimal characters")),
+    }
+}
+
+fn is_header(v: String) -> Result<(), String> {
+    match v.split(':').next() {
+        Some(name) if v.contains(':') && !name.trim().is_empty() => Ok(()),
+        _ => Err(format!("expected 'NAME: VALUE', got '{}'", v)),
+    }
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+    use clap::ErrorKind;
+    use dl::dns::IpPreference;
+    use dl::file::RetryPolicy;
+    use dl::https::Network;
+    use dl::reorder::DEFAULT_BUFFER_SIZE;
+    use dl::settings::{Settings, Source};
+    use dl::{Config, DEFAULT_PARALLELISM};
+    use hyper::header::HeaderValue;
+    use hyper::{HeaderMap, Uri};
+    use std::collections::BTreeMap;
+    use std::net::{IpAddr, SocketAddr};
+
+    fn cli_with(args: &[&str], profile: &Profile) -> Result<Cli, clap::Error> {
+        parse(args.iter().map(|a| a.to_string()).collect(), profile)
+    }
+
+    fn cli(args: &[&str]) -> Result<Cli, clap::Error> {
+        cli_with(args, &Profile::default())
+    }
+
+    fn config_with(args: &[&str], profile: &Profile) -> Config {
+        match cli_with(args, profile).unwrap().command {
+            Command::Get(builder) => builder.build().unwrap().config().clone(),
+            command => panic!("expected a get command, got {:?}", command),
+        }
+    }
+
+    /// the config for `args` (leaving out where its settings came from, which `parsing_settings_from_a_profile`
+    /// covers)
+    fn config(args: &[&str]) -> Config {
+        Config {
+            sources: BTreeMap::new(),
+            ..config_with(args, &Profile::default())
+        }
+    }
+
+    fn error(args: &[&str]) -> ErrorKind {
+        cli(args).unwrap_err().kind
+    }
+
+    fn expected_config(parallelism: usize, output_format: OutputFormat) -> Config {
+        Config {
+            uri: Uri::from_static("https://foo.com"),
+            mirrors: vec![],
+            headers: HeaderMap::new(),
+            destination: Destination::File(PathBuf::from("bar/baz")),
+            parallelism,
+            piece_size: None,
+            retry: RetryPolicy::default(),
+            rate_limit: None,
+            network: Network::default(),
+            verify: Verify::Etag,
+            keep_partial: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            timeouts: Timeouts::default(),
+            output_format,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn parsing_valid_cli_args() {
+        let expected = expected_config(*DEFAULT_PARALLELISM, OutputFormat::Text);
+        assert_eq!(config(&["dl", "https://foo.com", "bar/baz"]), expected);
+        assert_eq!(
+            config(&["dl", "get", "https://foo.com", "bar/baz"]),
+            expected
+        );
+    }
+
+    #[test]
+    fn parsing_parallelism() {
+        let expected = expected_config(4, OutputFormat::Text);
+        assert_eq!(config(&["dl", "https://foo.com", "bar/baz", "4"]), expected);
+        assert_eq!(
+            config(&["dl", "get", "-p", "4", "https://foo.com", "bar/baz"]),
+            expected
+        );
+        assert_eq!(
+            error(&["dl", "https://foo.com", "bar/baz", "many"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            error(&["dl", "https://foo.com", "bar/baz", "4", "-p", "4"]),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn parsing_output_format_flag() {
+        let expected = expected_config(4, OutputFormat::Json);
+        for args in [
+            vec![
+                "dl",
+                "--output-format",
+                "json",
+                "https://foo.com",
+                "bar/baz",
+                "4",
+            ],
+            vec![
+                "dl",
+                "https://foo.com",
+                "bar/baz",
+                "4",
+                "--output-format=json",
+            ],
+        ] {
+            assert_eq!(config(&args), expected);
+        }
+        assert_eq!(
+            cli(&["dl", "info", "https://foo.com", "--output-format=json"])
+                .unwrap()
+                .output_format,
+            OutputFormat::Json
+        );
+    }
+
+    #[test]
+    fn reporting_unparsable_args_as_json() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let (text, json) = (
+            args(&["dl", "https://foo.com", "bar/baz", "--bogus"]),
+            args(&[
+                "dl",
+                "--bogus",
+                "--output-format",
+                "json",
+                "https://foo.com",
+            ]),
+        );
+        assert_eq!(output_format_of(&text), OutputFormat::Text);
+        assert_eq!(output_format_of(&json), OutputFormat::Json);
+        assert_eq!(
+            output_format_of(&args(&["dl", "--", "--output-format=json"])),
+            OutputFormat::Text
+        );
+
+        let err = parse(json, &Profile::default()).unwrap_err();
+        match error_event(&err) {
+            Event::Error { code, message } => {
+                assert_eq!(code, "invalid_arguments");
+                assert_eq!(
+                    message,
+                    "Invalid arguments: Found argument '--bogus' which wasn't expected, or isn't valid in this context"
+                );
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn parsing_invalid_output_format_flag() {
+        assert_eq!(
+            error(&[
+                "dl",
+                "https://foo.com",
+                "bar/baz",
+                "--output-format",
+                "yaml"
+            ]),
+            ErrorKind::InvalidValue
+        )
+    }
+
+    #[test]
+    fn parsing_download_flags() {
+        let cfg = config(&[
+            "dl",
+            "https://foo.com",
+            "--piece-size=1048576",
+            "bar/baz",
+            "--retries",
+            "0",
+            "--keep-partial",
+            "--no-verify",
+        ]);
+        assert_eq!(cfg.piece_size, Some(1_048_576));
+        assert_eq!(cfg.retry.max_retries, 0);
+        assert!(cfg.keep_partial);
+        assert_eq!(cfg.verify, Verify::Skip);
+
+        for size in ["0", "-1", "big"].iter() {
+            assert_eq!(
+                error(&[
+                    "dl",
+                    "https://foo.com",
+                    "bar/baz",
+                    &format!("--piece-size={}", size)
+                ]),
+                ErrorKind::ValueValidation
+            )
+        }
+    }
+
+    #[test]
+    fn parsing_mirror_and_header_flags() {
+        let cfg = config(&[
+            "dl",
+            "https://foo.com",
+            "--mirror",
+            "https://bar.com",
+            "--header=X-Foo: bar",
+            "--mirror=http://baz.com",
+            "-H",
+            "X-Foo:baz",
+            "bar/baz",
+        ]);
+        assert_eq!(
+            cfg.mirrors,
+            vec![
+                Uri::from_static("https://bar.com"),
+                Uri::from_static("http://baz.com")
+            ]
+        );
+        assert_eq!(
+            cfg.headers.get_all("x-foo").iter().collect::<Vec<_>>(),
+            vec![
+                &HeaderValue::from_static("bar"),
+                &HeaderValue::from_static("baz")
+            ]
+        );
+        assert_eq!(
+            error(&["dl", "https://foo.com", "bar/baz", "--header", "X-Foo"]),
+            ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn parsing_verification_flags() {
+        let digest = "AC89AC31A669C13EC4CE037F1203022C";
+        let cfg = config(&["dl", "https://foo.com", "bar/baz", "--md5", digest]);
+        assert_eq!(cfg.verify, Verify::Md5(digest.to_lowercase()));
+
+        assert_eq!(
+            error(&["dl", "https://foo.com", "bar/baz", "--md5", "abc"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            error(&[
+                "dl",
+                "https://foo.com",
+                "bar/baz",
+                "--md5",
+                digest,
+                "--no-verify"
+            ]),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn parsing_timeout_flags() {
+        let cfg = config(&[
+            "dl",
+            "https://foo.com",
+            "bar/baz",
+            "--connect-timeout=2.5",
+            "--idle-timeout",
+            "0",
+            "--max-time",
+            "600",
+            "--speed-limit",
+            "1024",
+        ]);
+        assert_eq!(
+            cfg.timeouts,
+            Timeouts {
+                connect: Some(Duration::from_millis(2500)),
+                idle: None,
+                total: Some(Duration::from_secs(600)),
+                min_speed: Some(MinSpeed {
+                    bytes_per_sec: 1024,
+                    window: Duration::from_secs(DEFAULT_SPEED_TIME_SECS),
+                }),
+                ..Timeouts::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parsing_invalid_timeout_flags() {
+        for flag in [
+            "--tls-timeout=-1",
+            "--first-byte-timeout=soon",
+            "--speed-time=0",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error(&["dl", "https://foo.com", "bar/baz", flag]),
+                ErrorKind::ValueValidation
+            )
+        }
+    }
+
+    #[test]
+    fn parsing_stdout_destinations() {
+        let parsed = cli(&["dl", "https://foo.com", "-", "--buffer-size=1048576"]).unwrap();
+        assert!(parsed.streaming);
+        match parsed.command {
+            Command::Get(builder) => {
+                let cfg = builder.build().unwrap().config().clone();
+                assert_eq!(cfg.destination, Destination::Stdout);
+                assert_eq!(cfg.buffer_size, 1_048_576);
+            }
+            command => panic!("expected a get command, got {:?}", command),
+        }
+        assert!(
+            !cli(&["dl", "https://foo.com", "bar/baz"])
+                .unwrap()
+                .streaming
+        );
+        assert_eq!(
+            error(&["dl", "https://foo.com", "-", "--buffer-size=0"]),
+            ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn parsing_network_flags() {
+        let cfg = config(&[
+            "dl",
+            "https://foo.com",
+            "bar/baz",
+            "--proxy",
+            "http://proxy.local:3128",
+            "--rate-limit=1024",
+            "--retry-backoff=0.5",
+        ]);
+        assert_eq!(
+            cfg.network.proxy,
+            Some(Uri::from_static("http://proxy.local:3128"))
+        );
+        assert_eq!(cfg.rate_limit, Some(1024));
+        assert_eq!(cfg.retry.backoff, Duration::from_millis(500));
+        assert!(!cfg.network.all_addresses);
+        assert!(
+            config(&["dl", "https://foo.com", "bar/baz", "--all-addresses"])
+                .network
+                .all_addresses
+        );
+        let cfg = config(&[
+            "dl",
+            "https://foo.com",
+            "bar/baz",
+            "--resolve=foo.com:443:10.0.0.1",
+            "--resolve=foo.com:80:[::1]",
+            "--dns-server=10.0.0.53",
+        ]);
+        let bound = config(&[
+            "dl",
+            "https://foo.com",
+            "bar/baz",
+            "--bind=10.0.0.2",
+            "--bind=::1",
+            "--ip-preference=ipv6",
+        ]);
+        assert_eq!(
+            bound.network.local_addresses,
+            vec![
+                IpAddr::from([10, 0, 0, 2]),
+                IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])
+            ]
+        );
+        assert_eq!(bound.network.ip_preference, IpPreference::Ipv6);
+        assert_eq!(cfg.network.resolve.len(), 2);
+        assert_eq!(cfg.network.ip_preference, IpPreference::Auto);
+        assert_eq!(
+            cfg.network.resolve[1].addrs,
+            vec![IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]
+        );
+        assert_eq!(
+            cfg.network.dns_server,
+            Some(SocketAddr::from(([10, 0, 0, 53], 53)))
+        );
+
+        for flag in [
+            "--rate-limit=0",
+            "--retry-backoff=-1",
+            "--retry-backoff=soon",
+            "--retry-backoff=1e20",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error(&["dl", "https://foo.com", "bar/baz", flag]),
+                ErrorKind::ValueValidation
+            )
+        }
+    }
+
+    #[test]
+    fn parsing_settings_from_a_profile() {
+        let profile = Profile {
+            file: None,
+            env: Settings {
+                parallelism: Some(3),
+                rate_limit: Some(2048),
+                ..Settings::default()
+            },
+        };
+        let cfg = config_with(&["dl", "https://foo.com", "bar/baz"], &profile);
+        assert_eq!(cfg.parallelism, 3);
+        assert_eq!(cfg.rate_limit, Some(2048));
+        assert_eq!(
+            cfg.sources["parallelism"],
+            Source::Env(String::from("DL_PARALLELISM"))
+        );
+        assert_eq!(cfg.sources["retries"], Source::Default);
+
+        let cfg = config_with(
+            &["dl", "https://foo.com", "bar/baz", "4", "--rate-limit=1"],
+            &profile,
+        );
+        assert_eq!(cfg.parallelism, 4);
+        assert_eq!(cfg.rate_limit, Some(1));
+        assert_eq!(cfg.sources["parallelism"], Source::CommandLine);
+        assert_eq!(cfg.sources["rate_limit"], Source::CommandLine);
+    }
+
+    #[test]
+    fn parsing_config_show_subcommand() {
+        match cli(&["dl", "config", "show", "https://foo.com", "-p", "2"])
+            .unwrap()
+            .command
+        {
+            Command::ConfigShow(builder) => {
+                let resolved = builder.resolve();
+                assert_eq!(resolved.settings.parallelism, Some(2));
+                assert_eq!(resolved.sources["parallelism"], Source::CommandLine);
+            }
+            command => panic!("expected a config show command, got {:?}", command),
+        }
+        match cli(&["dl", "config", "show", "--output-format=json"]).unwrap() {
+            Cli {
+                command: Command::ConfigShow(_),
+                output_format,
+                ..
+            } => assert_eq!(output_format, OutputFormat::Json),
+            cli => panic!("expected a config show command, got {:?}", cli.command),
+        }
+        assert_eq!(
+            error(&["dl", "config"]),
+            ErrorKind::MissingArgumentOrSubcommand
+        );
+    }
+
+    #[test]
+    fn parsing_empty_cli_args() {
+        assert_eq!(error(&["dl"]), ErrorKind::MissingArgumentOrSubcommand);
+        assert_eq!(
+            error(&["dl", "https://foo.com"]),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn parsing_help_and_version() {
+        assert_eq!(error(&["dl", "--help"]), ErrorKind::HelpDisplayed);
+        assert_eq!(error(&["dl", "get", "--help"]), ErrorKind::HelpDisplayed);
+        assert_eq!(error(&["dl", "--version"]), ErrorKind::VersionDisplayed);
+    }
+
+    #[test]
+    fn parsing_info_subcommand() {
+        match cli(&["dl", "info", "https://foo.com", "-H", "X-Foo: bar"])
+            .unwrap()
+            .command
+        {
+            Command::Info(builder) => {
+                let cfg = builder.build_for_info().unwrap().config().clone();
+                assert_eq!(cfg.uri, Uri::from_static("https://foo.com"));
+                assert_eq!(cfg.headers.get("x-foo").unwrap(), "bar");
+            }
+            command => panic!("expected an info command, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parsing_verify_subcommand() {
+        let digest = "ac89ac31a669c13ec4ce037f1203022c";
+        match cli(&["dl", "verify", "bar/baz", "--md5", digest])
+            .unwrap()
+            .command
+        {
+            Command::Verify {
+                path,
+                expected: Expected::Md5(md5),
+            } => {
+                assert_eq!(path, PathBuf::from("bar/baz"));
+                assert_eq!(md5, digest);
+            }
+            command => panic!("expected a verify command, got {:?}", command),
+        }
+        match cli(&["dl", "verify", "bar/baz", "--url", "https://foo.com"])
+            .unwrap()
+            .command
+        {
+            Command::Verify {
+                expected: Expected::Etag(_),
+                ..
+            } => (),
+            command => panic!("expected a verify command, got {:?}", command),
+        }
+        assert_eq!(
+            error(&["dl", "verify", "bar/baz"]),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn parsing_batch_subcommand() {
+        match cli(&["dl", "batch", "-", "-j", "3", "--retries", "1"])
+            .unwrap()
+            .command
+        {
+            Command::Batch {
+                list,
+                builder,
+                jobs,
+            } => {
+                assert_eq!(list, "-");
+                assert_eq!(jobs, 3);
+                let cfg = builder
+                    .url("https://foo.com")
+                    .path("bar/baz")
+                    .build()
+                    .unwrap()
+                    .config()
+                    .clone();
+                assert_eq!(cfg.retry.max_retries, 1);
+            }
+            command => panic!("expected a batch command, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parsing_batch_files() {
+        let list = "# books\nhttps://foo.com/a  a.pdf\n\n  https://foo.com/b\tb.pdf  \n";
+        assert_eq!(
+            parse_batch(list).unwrap(),
+            vec![
+                (String::from("https://foo.com/a"), String::from("a.pdf")),
+                (String::from("https://foo.com/b"), String::from("b.pdf")),
+            ]
+        );
+        assert_eq!(
+            parse_batch("https://foo.com/a a.pdf\nhttps://foo.com/b\n")
+                .unwrap_err()
+                .code(),
+            "invalid_batch"
+        );
+        assert_eq!(
+            parse_batch("\nhttps://foo.com/b\n")
+                .unwrap_err()
+                .to_string(),
+            "Invalid batch file: line 2 should be '<url> <path>'"
+        );
+    }
+}
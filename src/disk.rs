@@ -0,0 +1,223 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::{future, Async, Future};
+use tokio_threadpool::blocking;
+
+use crate::error::DlError;
+
+/// appended to the output path while a download is in progress
+pub const PART_EXTENSION: &str = ".part";
+
+/// where a download bound for `path` is written until it has been verified (`<path>.part`)
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_EXTENSION);
+    PathBuf::from(part)
+}
+
+/// creates (or truncates) the file at `path` and reserves `size` bytes of disk for it up front,
+/// so that we find out the disk is full before downloading anything (and the file ends up less fragmented)
+pub fn preallocate(path: &Path, size: u64) -> Result<File, DlError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    allocate(&file, size)?;
+    Ok(file)
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if size == 0 {
+        return Ok(());
+    }
+    match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) } {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            // not every filesystem supports fallocate: settle for a (sparse) file of the right size
+            ref err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => file.set_len(size),
+            err => Err(err),
+        },
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)
+}
+
+/// flushes everything written to `file` to disk, checking that it ended up exactly `size` bytes long
+pub fn sync(file: &File, size: u64) -> Result<(), DlError> {
+    file.sync_all()?;
+    match file.metadata()?.len() {
+        len if len == size => Ok(()),
+        len => Err(DlError::LengthMismatch(size, len)),
+    }
+}
+
+/// moves a verified download from `part` into its final place at `path`
+pub fn commit(part: &Path, path: &Path) -> Result<(), DlError> {
+    fs::rename(part, path).map_err(DlError::Io)
+}
+
+/// deletes a failed download's `part` file (if it was ever created)
+pub fn discard(part: &Path) -> Result<(), DlError> {
+    match fs::remove_file(part) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(DlError::Io),
+    }
+}
+
+/// writes all of `buf` into `file` at `offset` (with `pwrite` on unix), without touching the file's cursor,
+/// so many pieces can be written through one shared handle at once
+#[cfg(unix)]
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// reads into `buf` from `file` at `offset` (with `pread` on unix), returning how much was read (less than asked
+/// for only at the end of the file), without touching the file's cursor, so many pieces can be read at once
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.seek_read(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// `write_at`, run on tokio's blocking pool (so as not to stall the reactor while the disk catches up)
+pub fn write_at_async<B>(
+    file: Arc<File>,
+    buf: B,
+    offset: u64,
+) -> impl Future<Item = (), Error = DlError> + Send
+where
+    B: AsRef<[u8]> + Send,
+{
+    run_blocking(move || write_at(&file, buf.as_ref(), offset).map_err(DlError::Io))
+}
+
+/// runs a blocking (file system) operation on tokio's blocking pool
+pub fn run_blocking<F, T>(mut f: F) -> impl Future<Item = T, Error = DlError> + Send
+where
+    F: FnMut() -> Result<T, DlError> + Send,
+    T: Send,
+{
+    future::poll_fn(move || match blocking(&mut f) {
+        Ok(Async::Ready(result)) => result.map(Async::Ready),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(_) => Err(DlError::Io(io::Error::other(
+            "blocking file operations must be run on a tokio threadpool",
+        ))),
+    })
+}
+
+#[cfg(test)]
+mod disk_tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn preallocating_files() {
+        let path = PathBuf::from("data/foo_prealloc.bin");
+        let file = preallocate(&path, 1_000_000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1_000_000);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn naming_part_files() {
+        assert_eq!(
+            part_path(Path::new("data/foo.pdf")),
+            PathBuf::from("data/foo.pdf.part")
+        );
+    }
+
+    #[test]
+    fn committing_and_discarding_part_files() {
+        let path = PathBuf::from("data/foo_commit.bin");
+        let part = part_path(&path);
+        let file = preallocate(&part, 3).unwrap();
+        write_at(&file, b"foo", 0).unwrap();
+
+        assert_eq!(sync(&file, 4).err().unwrap().code(), "length_mismatch");
+        sync(&file, 3).unwrap();
+        commit(&part, &path).unwrap();
+        assert!(!part.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), b"foo");
+
+        preallocate(&part, 3).unwrap();
+        discard(&part).unwrap();
+        assert!(!part.exists());
+        discard(&part).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writing_at_offsets_through_a_shared_handle() {
+        let path = PathBuf::from("data/foo_write_at.bin");
+        let file = Arc::new(preallocate(&path, 6).unwrap());
+
+        let writes = future::join_all(vec![
+            write_at_async(file.clone(), b"baz".to_vec(), 3),
+            write_at_async(file.clone(), b"foo".to_vec(), 0),
+        ]);
+        Runtime::new().unwrap().block_on(writes).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"foobaz");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
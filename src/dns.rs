@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Stream};
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::HttpConnector;
+use tokio::runtime::current_thread;
+use tokio_tcp::TcpStream;
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::AsyncResolver;
+
+use crate::error::DlError;
+
+/// resolves the hosts our connections are to: with the system's resolver, a nameserver of our choosing or a hosts
+/// file, or (for connections pinned to particular addresses) to those addresses, whatever the host
+#[derive(Clone)]
+pub enum Resolver {
+    System(GaiResolver),
+    /// asks a particular nameserver, through a client running (and doing its lookups) on a thread of its own
+    Nameserver(mpsc::UnboundedSender<(String, oneshot::Sender<io::Result<Vec<IpAddr>>>)>),
+    /// a hosts file's addresses for the names in it, and the resolver's for the rest
+    Hosts(Arc<HashMap<String, Vec<IpAddr>>>, Box<Resolver>),
+    /// tried in order, but with happy eyeballs between the families: if the first address doesn't answer within
+    /// 300ms, the first of the other family is tried alongside it
+    Pinned(Vec<IpAddr>),
+    /// the resolver's addresses of one family (ipv6's, if `ipv6`) ahead of the rest, or (if `only`) alone
+    Family {
+        ipv6: bool,
+        only: bool,
+        resolver: Box<Resolver>,
+    },
+}
+
+pub type Addrs = std::vec::IntoIter<IpAddr>;
+
+impl Resolver {
+    /// the system's resolver, doing its (blocking) lookups on a pool of `threads`
+    pub fn system(threads: usize) -> Resolver {
+        Resolver::System(GaiResolver::new(threads))
+    }
+
+    /// asks the nameserver at `addr` (over udp, or tcp for answers too long for it) for both ipv4 and ipv6
+    /// addresses, ignoring the system's resolver (and its hosts file) entirely
+    pub fn nameserver(addr: SocketAddr) -> Result<Resolver, DlError> {
+        let (lookups, requested) = mpsc::unbounded();
+        let (started, start) = std::sync::mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("dl-dns"))
+            .spawn(move || {
+                let mut runtime = match current_thread::Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(err) => return started.send(Err(err)).unwrap_or(()),
+                };
+                let servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port());
+                let mut options = ResolverOpts::default();
+                options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+                options.use_hosts_file = false;
+                let (resolver, background) =
+                    AsyncResolver::new(ResolverConfig::from_parts(None, vec![], servers), options);
+                runtime.spawn(background);
+                let _ = started.send(Ok(()));
+                // (until every clone of the `Resolver` has been dropped)
+                let _ = runtime.block_on(requested.for_each(
+                    move |(host, answer): (String, oneshot::Sender<_>)| {
+                        current_thread::spawn(resolver.lookup_ip(host.as_str()).then(
+                            move |found| {
+                                let found = found
+                                    .map(|ips| ips.iter().collect())
+                                    .map_err(|err| io::Error::other(err.to_string()));
+                                answer.send(found).map_err(|_| ())
+                            },
+                        ));
+                        Ok(())
+                    },
+                ));
+            })?;
+        start
+            .recv()
+            .unwrap_or_else(|_| Err(io::ErrorKind::Other.into()))?;
+        Ok(Resolver::Nameserver(lookups))
+    }
+
+    /// the entries of the hosts file at `path` (see `load_hosts_file`), in front of `fallback`
+    pub fn hosts_file(path: &Path, fallback: Resolver) -> Result<Resolver, DlError> {
+        Ok(Resolver::Hosts(
+            Arc::new(load_hosts_file(path)?),
+            Box::new(fallback),
+        ))
+    }
+
+    /// the resolver's addresses, in `preference`'s order
+    pub fn preferring(self, preference: IpPreference) -> Resolver {
+        match preference {
+            IpPreference::Auto => self,
+            IpPreference::Ipv4 | IpPreference::Ipv6 => Resolver::Family {
+                ipv6: preference == IpPreference::Ipv6,
+                only: false,
+                resolver: Box::new(self),
+            },
+        }
+    }
+
+    /// only the resolver's addresses of `local`'s family (the only ones a connection bound to it can reach)
+    pub fn reachable_from(self, local: IpAddr) -> Resolver {
+        Resolver::Family {
+            ipv6: local.is_ipv6(),
+            only: true,
+            resolver: Box::new(self),
+        }
+    }
+
+    /// every address `host` resolves to (once, up front, rather than for a connection)
+    pub fn lookup(
+        &self,
+        host: &str,
+    ) -> Box<dyn Future<Item = Vec<IpAddr>, Error = DlError> + Send> {
+        let name = match Name::from_str(host) {
+            Ok(name) => name,
+            Err(_) => return Box::new(future::err(DlError::InvalidConfig("invalid host name"))),
+        };
+        Box::new(
+            self.resolve(name)
+                .map(Iterator::collect)
+                .map_err(DlError::Io),
+        )
+    }
+}
+
+impl Resolve for Resolver {
+    type Addrs = Addrs;
+    type Future = Box<dyn Future<Item = Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        match *self {
+            Resolver::System(ref gai) => Box::new(
+                gai.resolve(name)
+                    .map(|addrs| addrs.collect::<Vec<_>>().into_iter()),
+            ),
+            Resolver::Nameserver(ref lookups) => {
+                let (answer, answered) = oneshot::channel();
+                let stopped = || io::Error::other("dns client stopped");
+                match lookups.unbounded_send((name.as_str().to_string(), answer)) {
+                    Ok(()) => Box::new(answered.then(move |found| match found {
+                        Ok(found) => found.map(Vec::into_iter),
+                        Err(_) => Err(stopped()),
+                    })),
+                    Err(_) => Box::new(future::err(stopped())),
+                }
+            }
+            Resolver::Hosts(ref hosts, ref fallback) => {
+                match hosts.get(&name.as_str().to_lowercase()) {
+                    Some(addrs) => Box::new(future::ok(addrs.clone().into_iter())),
+                    None => fallback.resolve(name),
+                }
+            }
+            Resolver::Pinned(ref addrs) => Box::new(future::ok(addrs.clone().into_iter())),
+            Resolver::Family {
+                ipv6,
+                only,
+                ref resolver,
+            } => Box::new(resolver.resolve(name).and_then(move |addrs| {
+                let (mut first, rest): (Vec<_>, Vec<_>) =
+                    addrs.partition(|addr| addr.is_ipv6() == ipv6);
+                if !only {
+                    first.extend(rest);
+                }
+                match first.is_empty() {
+                    true => Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("no ipv{} addresses", if ipv6 { 6 } else { 4 }),
+                    )),
+                    false => Ok(first.into_iter()),
+                }
+            })),
+        }
+    }
+}
+
+/// a curl-style `--resolve` entry, `host:port:addr[,addr...]` (with ipv6 addresses in brackets): connections to
+/// `host` on `port` go to `addrs`, whatever it resolves to. tls still expects `host`'s certificate from them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl FromStr for Override {
+    type Err = DlError;
+
+    fn from_str(s: &str) -> Result<Override, DlError> {
+        let invalid =
+            || DlError::InvalidConfig("overrides must look like host:port:addr[,addr...]");
+        let mut parts = s.splitn(3, ':');
+        let (host, port, addrs) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(host), Some(port), Some(addrs)) if !host.is_empty() => (host, port, addrs),
+            _ => return Err(invalid()),
+        };
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                addr.trim()
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+            })
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|_| invalid())?;
+        Ok(Override {
+            host: host.to_lowercase(),
+            port: port.parse().map_err(|_| invalid())?,
+            addrs,
+        })
+    }
+}
+
+/// the override (if any) among `overrides` for `host` on `port`
+pub fn override_for<'a>(overrides: &'a [Override], host: &str, port: u16) -> Option<&'a Override> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    overrides
+        .iter()
+        .find(|o| o.port == port && o.host.eq_ignore_ascii_case(host))
+}
+
+/// the port a url's connections go to: its own, or its scheme's
+pub fn port_of(scheme: Option<&str>, port: Option<u16>) -> u16 {
+    port.unwrap_or(match scheme {
+        Some("https") => 443,
+        Some("ftp") => 21,
+        Some("ftps") => 990,
+        _ => 80,
+    })
+}
+
+/// every address `host` resolves to on `port`: its override's, if it has one, and otherwise `resolver`'s
+pub fn lookup(
+    resolver: &Resolver,
+    overrides: &[Override],
+    host: &str,
+    port: u16,
+) -> Box<dyn Future<Item = Vec<IpAddr>, Error = DlError> + Send> {
+    match override_for(overrides, host, port) {
+        Some(o) => Box::new(future::ok(o.addrs.clone())),
+        None => resolver.lookup(host.trim_start_matches('[').trim_end_matches(']')),
+    }
+}
+
+/// reads a hosts file (in the format of `/etc/hosts`: an address, then the names it's for, on each line) into the
+/// addresses for each (lowercased) name, in the order they appear
+pub fn load_hosts_file(path: &Path) -> Result<HashMap<String, Vec<IpAddr>>, DlError> {
+    let invalid = |reason: String| {
+        DlError::InvalidSettings(format!("hosts file {}: {}", path.display(), reason))
+    };
+    let contents = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+        let addr = match fields.next() {
+            Some(addr) => addr.parse::<IpAddr>().map_err(|_| {
+                invalid(format!(
+                    "line {}: '{}' isn't an ip address",
+                    number + 1,
+                    addr
+                ))
+            })?,
+            None => continue,
+        };
+        for name in fields {
+            hosts.entry(name.to_lowercase()).or_default().push(addr);
+        }
+    }
+    Ok(hosts)
+}
+
+/// connects through an `HttpConnector` resolving hosts with the resolver it was built with, except to hosts (and
+/// ports) with overrides, which get connectors of their own pinned to the overrides' addresses. the destination
+/// itself is left alone, so tls (a layer above) still checks the original host's certificate (and sends its sni)
+#[derive(Clone)]
+pub struct OverridingConnector {
+    default: HttpConnector<Resolver>,
+    overrides: Vec<(Override, HttpConnector<Resolver>)>,
+}
+
+impl OverridingConnector {
+    /// `connector` builds each of the connectors (configured alike) given its resolver
+    pub fn new<F>(resolver: Resolver, overrides: &[Override], connector: F) -> OverridingConnector
+    where
+        F: Fn(Resolver) -> HttpConnector<Resolver>,
+    {
+        OverridingConnector {
+            default: connector(resolver),
+            overrides: overrides
+                .iter()
+                .map(|o| (o.clone(), connector(Resolver::Pinned(o.addrs.clone()))))
+                .collect(),
+        }
+    }
+}
+
+impl Connect for OverridingConnector {
+    type Transport = TcpStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let port = port_of(Some(dst.scheme()), dst.port());
+        let connector = self
+            .overrides
+            .iter()
+            .find(|(o, _)| o.port == port && o.host.eq_ignore_ascii_case(dst.host()))
+            .map_or(&self.default, |(_, connector)| connector);
+        Box::new(connector.connect(dst))
+    }
+}
+
+/// which family of addresses to connect to first (falling back on the other, happy eyeballs style, either way)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpPreference {
+    /// in the order the resolver gives them (ipv6 first, when they're interleaved)
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+impl FromStr for IpPreference {
+    type Err = DlError;
+
+    fn from_str(s: &str) -> Result<IpPreference, DlError> {
+        match s {
+            "auto" => Ok(IpPreference::Auto),
+            "ipv4" => Ok(IpPreference::Ipv4),
+            "ipv6" => Ok(IpPreference::Ipv6),
+            _ => Err(DlError::InvalidConfig(
+                "ip preference must be one of auto, ipv4 or ipv6",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpPreference::Auto => write!(f, "auto"),
+            IpPreference::Ipv4 => write!(f, "ipv4"),
+            IpPreference::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+/// orders `addrs` the way happy eyeballs (rfc 8305) tries them: alternating between the families, starting with
+/// ipv6 (unless `preference` is for ipv4), so that spreading connections over the first few still reaches both
+pub fn interleave(mut addrs: Vec<IpAddr>, preference: IpPreference) -> Vec<IpAddr> {
+    let mut seen = HashSet::new();
+    addrs.retain(|addr| seen.insert(*addr));
+    let ipv4_first = preference == IpPreference::Ipv4;
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() != ipv4_first);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// what a connection pinned to `addr` resolves its host to: `addr`, then the first of `addrs` of the other family
+/// (for happy eyeballs to fall back on, should `addr`'s family turn out to be unreachable)
+pub fn pinned(addr: IpAddr, addrs: &[IpAddr]) -> Resolver {
+    let fallback = addrs.iter().find(|other| other.is_ipv6() != addr.is_ipv6());
+    Resolver::Pinned(std::iter::once(addr).chain(fallback.cloned()).collect())
+}
+
+#[cfg(test)]
+mod dns_tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    /// a nameserver (on a udp port of its own) answering every `A` query with `addr`, and any other with nothing
+    fn nameserver(addr: Ipv4Addr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let local = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut query = [0u8; 512];
+            while let Ok((_, from)) = socket.recv_from(&mut query) {
+                // the question (a name, then its type and class) follows the 12-byte header
+                let mut end = 12;
+                while query[end] != 0 {
+                    end += 1 + query[end] as usize;
+                }
+                let a = query[end + 1..end + 3] == [0, 1];
+                let mut answer = query[..2].to_vec();
+                answer.extend_from_slice(&[0x81, 0x80, 0, 1, 0, a as u8, 0, 0, 0, 0]);
+                answer.extend_from_slice(&query[12..end + 5]);
+                if a {
+                    answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    answer.extend_from_slice(&addr.octets());
+                }
+                let _ = socket.send_to(&answer, from);
+            }
+        });
+        local
+    }
+
+    fn ips(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaving_address_families() {
+        assert_eq!(
+            interleave(
+                ips(&["1.1.1.1", "1.1.1.2", "1.1.1.3", "::1", "::2"]),
+                IpPreference::Auto
+            ),
+            ips(&["::1", "1.1.1.1", "::2", "1.1.1.2", "1.1.1.3"])
+        );
+        assert_eq!(
+            interleave(ips(&["1.1.1.1", "::1", "1.1.1.1"]), IpPreference::Auto),
+            ips(&["::1", "1.1.1.1"])
+        );
+        assert_eq!(
+            interleave(ips(&["::1", "::2", "1.1.1.1"]), IpPreference::Ipv4),
+            ips(&["1.1.1.1", "::1", "::2"])
+        );
+    }
+
+    #[test]
+    fn pinning_connections_to_addresses() {
+        let addrs = ips(&["::1", "1.1.1.1", "::2", "1.1.1.2"]);
+        let resolved = |resolver: Resolver| {
+            Runtime::new()
+                .unwrap()
+                .block_on(resolver.lookup("example.com"))
+                .unwrap()
+        };
+        assert_eq!(resolved(pinned(addrs[2], &addrs)), ips(&["::2", "1.1.1.1"]));
+        assert_eq!(resolved(pinned(addrs[3], &addrs[1..2])), ips(&["1.1.1.2"]));
+    }
+
+    #[test]
+    fn parsing_overrides() {
+        assert_eq!(
+            "Staging.example.com:443:10.0.0.1,[::1]"
+                .parse::<Override>()
+                .unwrap(),
+            Override {
+                host: String::from("staging.example.com"),
+                port: 443,
+                addrs: ips(&["10.0.0.1", "::1"]),
+            }
+        );
+        for invalid in [
+            "example.com:443",
+            ":443:10.0.0.1",
+            "example.com:https:10.0.0.1",
+            "example.com:443:",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                invalid.parse::<Override>().unwrap_err().code(),
+                "invalid_config"
+            );
+        }
+
+        let overrides = vec!["example.com:443:10.0.0.1".parse().unwrap()];
+        assert!(override_for(&overrides, "EXAMPLE.com", 443).is_some());
+        assert!(override_for(&overrides, "example.com", port_of(Some("http"), None)).is_none());
+    }
+
+    #[test]
+    fn looking_hosts_up_in_a_hosts_file() {
+        let path = PathBuf::from("data/foo_hosts.txt");
+        fs::write(&path, "# staging\n10.0.0.1  Staging.example.com cdn.example.com\n\n::1 cdn.example.com # v6\n").unwrap();
+        let hosts = Resolver::hosts_file(&path, Resolver::Pinned(ips(&["10.9.9.9"])));
+        fs::write(&path, "localhost 127.0.0.1\n").unwrap();
+        let invalid = load_hosts_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let hosts = hosts.unwrap();
+        let mut rt = Runtime::new().unwrap();
+        assert_eq!(
+            rt.block_on(hosts.lookup("staging.example.com")).unwrap(),
+            ips(&["10.0.0.1"])
+        );
+        assert_eq!(
+            rt.block_on(hosts.lookup("cdn.example.com")).unwrap(),
+            ips(&["10.0.0.1", "::1"])
+        );
+        assert_eq!(
+            rt.block_on(hosts.lookup("example.com")).unwrap(),
+            ips(&["10.9.9.9"])
+        );
+        assert_eq!(
+            invalid.unwrap_err().to_string(),
+            "Invalid settings: hosts file data/foo_hosts.txt: line 1: 'localhost' isn't an ip address"
+        );
+    }
+
+    #[test]
+    fn looking_hosts_up_with_a_nameserver() {
+        let resolver = Resolver::nameserver(nameserver(Ipv4Addr::new(127, 0, 0, 7))).unwrap();
+        let mut rt = Runtime::new().unwrap();
+        assert_eq!(
+            rt.block_on(resolver.lookup("staging.example.com")).unwrap(),
+            ips(&["127.0.0.7"])
+        );
+        // (overrides win)
+        let overrides = vec!["staging.example.com:443:10.0.0.1".parse().unwrap()];
+        assert_eq!(
+            rt.block_on(lookup(&resolver, &overrides, "staging.example.com", 443))
+                .unwrap(),
+            ips(&["10.0.0.1"])
+        );
+    }
+}
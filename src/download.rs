@@ -0,0 +1,776 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Future;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Uri};
+
+use crate::bind;
+use crate::checksum::Verify;
+use crate::dns::{self, IpPreference, Override};
+use crate::error::DlError;
+use crate::file::{RetryPolicy, DEFAULT_MAX_RETRIES, MIN_PIECE_SIZE};
+use crate::ftp;
+use crate::https::{self, HttpVersion, Network};
+use crate::local;
+use crate::output::{FileInfo, OutputFormat, Summary};
+use crate::reorder::DEFAULT_BUFFER_SIZE;
+use crate::settings::{Profile, Resolved, Settings};
+use crate::sink::{MakeSink, Sink};
+use crate::stream::DownloadStream;
+use crate::timeout::Timeouts;
+use crate::transport::{SharedTransport, Transport};
+use crate::{Config, DEFAULT_PARALLELISM};
+
+/// where a download ends up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    /// a file on the local file system (written to `<path>.part` until it has been verified)
+    File(PathBuf),
+    /// standard output, in order (pieces that arrive early are held in memory until the ones before them do)
+    Stdout,
+    /// a `Sink` of the caller's, written in order (as stdout is) so that the file can be verified on the way
+    Sink(MakeSink),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Destination::File(ref path) => write!(f, "{}", path.display()),
+            Destination::Stdout => write!(f, "-"),
+            Destination::Sink(_) => write!(f, "<sink>"),
+        }
+    }
+}
+
+/// a download whose options have all been checked, ready to `run`
+///
+/// ```no_run
+/// # use dl::download::Download;
+/// let download = Download::builder()
+///     .url("https://example.com/big.iso")
+///     .mirror("https://mirror.example.com/big.iso")
+///     .header("Authorization", "Bearer t0k3n")
+///     .piece_size(4 * 1024 * 1024)
+///     .path("big.iso")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Download {
+    config: Config,
+}
+
+impl Download {
+    pub fn builder() -> DownloadBuilder {
+        DownloadBuilder::default()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// fetches metadata, downloads the file in parallel, verifies it and moves it into place
+    pub fn run(self) -> impl Future<Item = Summary, Error = DlError> {
+        crate::run(self.config)
+    }
+
+    /// downloads the file as an ordered stream of its bytes (also an `AsyncRead`), ignoring the destination
+    pub fn stream(self) -> DownloadStream {
+        DownloadStream::new(self.config)
+    }
+
+    /// fetches the file's metadata (from the url and each of its mirrors) without downloading it
+    pub fn info(self) -> impl Future<Item = FileInfo, Error = DlError> {
+        crate::info(self.config)
+    }
+}
+
+/// collects the options for a `Download` (falling back to the `profile`'s), checking them all in `build`
+#[derive(Debug, Clone)]
+pub struct DownloadBuilder {
+    url: Option<String>,
+    mirrors: Vec<String>,
+    headers: Vec<(String, String)>,
+    resolve: Vec<String>,
+    destination: Option<Destination>,
+    settings: Settings,
+    profile: Profile,
+    verify: Verify,
+    keep_partial: bool,
+    buffer_size: u64,
+    timeouts: Timeouts,
+    output_format: OutputFormat,
+    transport: Option<SharedTransport>,
+}
+
+impl Default for DownloadBuilder {
+    fn default() -> DownloadBuilder {
+        DownloadBuilder {
+            url: None,
+            mirrors: vec![],
+            headers: vec![],
+            resolve: vec![],
+            destination: None,
+            settings: Settings::default(),
+            profile: Profile::default(),
+            verify: Verify::Etag,
+            keep_partial: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            timeouts: Timeouts::default(),
+            output_format: OutputFormat::Text,
+            transport: None,
+        }
+    }
+}
+
+impl DownloadBuilder {
+    /// the url (or local path) to download from
+    pub fn url<S: Into<String>>(mut self, url: S) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// another url serving the same file (connections are spread across the url and its mirrors)
+    pub fn mirror<S: Into<String>>(mut self, url: S) -> Self {
+        self.mirrors.push(url.into());
+        self
+    }
+
+    /// a header to send with every request (replacing any of the same name from the profile)
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn destination(mut self, destination: Destination) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// shorthand for `destination(Destination::File(path))`
+    pub fn path<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.destination(Destination::File(path.into()))
+    }
+
+    /// shorthand for `destination(Destination::Sink(..))`, writing the file into the sink `make_sink` makes for it
+    /// once its size is known
+    pub fn sink<F>(self, make_sink: F) -> Self
+    where
+        F: Fn(u64) -> Result<Arc<dyn Sink>, DlError> + Send + Sync + 'static,
+    {
+        self.destination(Destination::Sink(MakeSink::new(make_sink)))
+    }
+
+    /// how many connections to download pieces over at once
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.settings.parallelism = Some(parallelism);
+        self
+    }
+
+    /// size (in bytes) of the pieces the file is split into (picked from the file size by default)
+    pub fn piece_size(mut self, piece_size: u64) -> Self {
+        self.settings.piece_size = Some(piece_size);
+        self
+    }
+
+    /// how many times to retry a piece after transient failures before giving up
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.settings.retries = Some(max_retries);
+        self
+    }
+
+    /// how long to wait before the first retry of a piece (doubling with each one after that)
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.settings.retry_backoff = Some(backoff.as_secs_f64());
+        self
+    }
+
+    /// caps the download's combined throughput (in bytes/sec)
+    pub fn rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.settings.rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// an http proxy to send every request through
+    pub fn proxy<S: Into<String>>(mut self, url: S) -> Self {
+        self.settings.proxy = Some(url.into());
+        self
+    }
+
+    /// a pem file of certificate authorities to trust (on top of the system's)
+    pub fn ca_bundle<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.settings.ca_bundle = Some(path.into());
+        self
+    }
+
+    /// which version of http to speak (by default, http/2 with servers that offer it)
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.settings.http_version = Some(version.to_string());
+        self
+    }
+
+    /// a curl-style `host:port:addr[,addr...]` override of where to connect to `host` (see `dns::Override`)
+    pub fn resolve_host<S: Into<String>>(mut self, entry: S) -> Self {
+        self.resolve.push(entry.into());
+        self
+    }
+
+    /// a nameserver (`addr`, or `addr:port`) to look hosts up with, rather than the system's resolver
+    pub fn dns_server<S: Into<String>>(mut self, addr: S) -> Self {
+        self.settings.dns_server = Some(addr.into());
+        self
+    }
+
+    /// a hosts file (like `/etc/hosts`) to look hosts up in first
+    pub fn hosts_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.settings.hosts_file = Some(path.into());
+        self
+    }
+
+    /// a local address, or network interface, to bind connections to (spreading pieces across several)
+    pub fn bind<S: Into<String>>(mut self, local: S) -> Self {
+        let local = local.into();
+        self.settings.bind = Some(match self.settings.bind.take() {
+            Some(bind) => format!("{},{}", bind, local),
+            None => local,
+        });
+        self
+    }
+
+    /// which family of addresses to connect to first (see `dns::IpPreference`)
+    pub fn ip_preference(mut self, preference: IpPreference) -> Self {
+        self.settings.ip_preference = Some(preference.to_string());
+        self
+    }
+
+    /// whether to spread connections across every address the url's host resolves to
+    pub fn all_addresses(mut self, all_addresses: bool) -> Self {
+        self.settings.all_addresses = Some(all_addresses);
+        self
+    }
+
+    /// sends every request over `transport` (a `FakeTransport`, say) rather than an https client of `dl`'s own
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(SharedTransport::new(transport));
+        self
+    }
+
+    /// the config file and environment to take defaults from (nothing, by default)
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// what to check the file against before moving it into place (its etag, by default)
+    pub fn verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// whether to leave `<path>.part` behind (rather than delete it) when the download fails
+    pub fn keep_partial(mut self, keep_partial: bool) -> Self {
+        self.keep_partial = keep_partial;
+        self
+    }
+
+    /// how many bytes of the file to hold in memory, at most, while streaming it to stdout
+    pub fn buffer_size(mut self, bytes: u64) -> Self {
+        self.buffer_size = bytes;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// how to report progress (and errors) while running
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// merges the builder's settings with its profile's (for the url's host, if it has a valid url)
+    pub fn resolve(&self) -> Resolved {
+        let uri = self.url.as_ref().and_then(|url| url.parse::<Uri>().ok());
+        let mut explicit = self.settings.clone();
+        for (name, value) in self.headers.iter() {
+            explicit.headers.insert(name.to_lowercase(), value.clone());
+        }
+        self.profile
+            .resolve(&explicit, uri.as_ref().and_then(|uri| uri.host()))
+    }
+
+    /// like `build`, for downloads we only want to ask about (with `Download::info`)
+    pub fn build_for_info(mut self) -> Result<Download, DlError> {
+        if self.destination.is_none() {
+            self.destination = Some(Destination::File(PathBuf::new()));
+        }
+        self.build()
+    }
+
+    /// like `build`, for downloads we only want to `Download::stream` (reporting progress to stderr)
+    pub fn build_for_stream(mut self) -> Result<Download, DlError> {
+        if self.destination.is_none() {
+            self.destination = Some(Destination::Stdout);
+        }
+        self.build()
+    }
+
+    /// checks every option, returning a `Download` if they all make sense (or the first that doesn't)
+    pub fn build(self) -> Result<Download, DlError> {
+        let uri = match self.url {
+            None => return Err(DlError::InvalidConfig("a url is required")),
+            Some(ref url) => parse_url(url)?,
+        };
+        let Resolved { settings, sources } = self.resolve();
+        let mirrors = self
+            .mirrors
+            .iter()
+            .map(|url| parse_url(url))
+            .collect::<Result<Vec<Uri>, DlError>>()?;
+
+        // headers set on the builder replace those of the same name from the profile (rather than adding to them)
+        let explicit = |name: &str| {
+            self.headers
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case(name))
+        };
+        let mut headers = HeaderMap::new();
+        let profile_headers = settings.headers.iter().filter(|(name, _)| !explicit(name));
+        for (name, value) in profile_headers.chain(self.headers.iter().map(|(n, v)| (n, v))) {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| DlError::InvalidConfig("invalid header name"))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| DlError::InvalidConfig("invalid header value"))?;
+            headers.append(name, value);
+        }
+        let destination = self
+            .destination
+            .ok_or(DlError::InvalidConfig("a destination is required"))?;
+
+        let parallelism = settings.parallelism.unwrap_or(*DEFAULT_PARALLELISM);
+        if parallelism == 0 {
+            return Err(DlError::InvalidConfig("parallelism must be at least 1"));
+        }
+        if settings.piece_size == Some(0) {
+            return Err(DlError::InvalidConfig("piece size must be at least 1 byte"));
+        }
+        if settings.rate_limit == Some(0) {
+            return Err(DlError::InvalidConfig(
+                "rate limit must be at least 1 byte/sec",
+            ));
+        }
+        let backoff = match settings.retry_backoff {
+            None => RetryPolicy::default().backoff,
+            Some(secs) => Duration::try_from_secs_f64(secs).map_err(|_| {
+                DlError::InvalidConfig("retry backoff must be a non-negative number of seconds")
+            })?,
+        };
+        let proxy = match settings.proxy {
+            None => None,
+            Some(ref url) => match url.parse::<Uri>() {
+                Ok(ref uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => {
+                    Some(uri.clone())
+                }
+                _ => return Err(DlError::InvalidConfig("proxies must be http urls")),
+            },
+        };
+        if let Some(ref path) = settings.ca_bundle {
+            https::load_ca_bundle(path)?;
+        }
+        let http_version = match settings.http_version {
+            None => HttpVersion::default(),
+            Some(ref version) => version.parse::<HttpVersion>()?,
+        };
+        if http_version == HttpVersion::Http3 && proxy.is_some() {
+            return Err(DlError::InvalidConfig("http/3 can't go through a proxy"));
+        }
+        let resolve = self
+            .resolve
+            .iter()
+            .map(|entry| entry.parse::<Override>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let dns_server = match settings.dns_server {
+            None => None,
+            Some(ref addr) => Some(parse_dns_server(addr)?),
+        };
+        if let Some(ref path) = settings.hosts_file {
+            dns::load_hosts_file(path)?;
+        }
+        let ip_preference = match settings.ip_preference {
+            None => IpPreference::default(),
+            Some(ref preference) => preference.parse::<IpPreference>()?,
+        };
+        let local_addresses = settings
+            .bind
+            .iter()
+            .flat_map(|bind| bind.split(','))
+            .map(str::trim)
+            .filter(|local| !local.is_empty())
+            .map(|local| bind::local_address(local, ip_preference))
+            .collect::<Result<Vec<_>, _>>()?;
+        let all_addresses = settings.all_addresses.unwrap_or(false);
+        if all_addresses && proxy.is_some() {
+            return Err(DlError::InvalidConfig(
+                "a proxy connects to the host's addresses itself",
+            ));
+        }
+        if self.buffer_size < MIN_PIECE_SIZE {
+            return Err(DlError::InvalidConfig(
+                "buffer size must be at least 64 KiB",
+            ));
+        }
+        if let Verify::Md5(ref digest) = self.verify {
+            if digest.len() != 32 || hex::decode(digest).is_err() {
+                return Err(DlError::InvalidConfig(
+                    "md5 digests must be 32 hexadecimal characters",
+                ));
+            }
+        }
+        // (local files and ftp servers have no etags to check against, so only their digests, if given, are)
+        let verify = match self.verify {
+            Verify::Etag if local::is_local(&uri) || ftp::is_ftp(&uri) => Verify::EtagIfPresent,
+            ref verify => verify.clone(),
+        };
+        if let Some(ref min_speed) = self.timeouts.min_speed {
+            if min_speed.bytes_per_sec == 0 || min_speed.window.as_secs_f64() == 0.0 {
+                return Err(DlError::InvalidConfig(
+                    "minimum speed and its window must be positive",
+                ));
+            }
+        }
+
+        Ok(Download {
+            config: Config {
+                uri,
+                mirrors,
+                headers,
+                destination,
+                parallelism,
+                piece_size: settings.piece_size,
+                retry: RetryPolicy {
+                    max_retries: settings.retries.unwrap_or(DEFAULT_MAX_RETRIES),
+                    backoff,
+                },
+                rate_limit: settings.rate_limit,
+                network: Network {
+                    proxy,
+                    ca_bundle: settings.ca_bundle,
+                    transport: self.transport,
+                    http_version,
+                    all_addresses,
+                    resolve,
+                    dns_server,
+                    hosts_file: settings.hosts_file,
+                    local_addresses,
+                    ip_preference,
+                },
+                verify,
+                keep_partial: self.keep_partial,
+                buffer_size: self.buffer_size,
+                timeouts: self.timeouts,
+                output_format: self.output_format,
+                sources,
+            },
+        })
+    }
+}
+
+/// parses a nameserver's address: an ip address, with or without a port (53, by default)
+fn parse_dns_server(addr: &str) -> Result<SocketAddr, DlError> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| {
+            DlError::InvalidConfig("dns servers must be ip addresses (with an optional port)")
+        })
+}
+
+/// parses a url we can download from: an http(s) or ftp(s) one with a host, a `file://` one, or a path
+fn parse_url(url: &str) -> Result<Uri, DlError> {
+    if let Some(path) = local::local_path(url)? {
+        return local::uri_of(&path);
+    }
+    let uri = url.parse::<Uri>()?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http"), Some(_)) | (Some("https"), Some(_)) => Ok(uri),
+        (Some("ftp"), Some(_)) | (Some("ftps"), Some(_)) => Ok(uri),
+        _ => Err(DlError::InvalidConfig(
+            "urls must be absolute http(s), ftp(s) or file urls, or paths",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod download_builder_tests {
+    use super::*;
+    use crate::settings::Source;
+    use crate::timeout::MinSpeed;
+
+    fn builder() -> DownloadBuilder {
+        Download::builder().url("https://foo.com/a").path("bar/baz")
+    }
+
+    fn invalid(builder: DownloadBuilder) -> String {
+        builder.build().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn building_downloads() {
+        let download = builder()
+            .mirror("http://bar.com/a")
+            .header("Authorization", "Bearer foo")
+            .parallelism(4)
+            .piece_size(1024)
+            .max_retries(0)
+            .verify(Verify::Skip)
+            .keep_partial(true)
+            .output_format(OutputFormat::Json)
+            .build()
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer foo"));
+        assert_eq!(
+            *download.config(),
+            Config {
+                uri: Uri::from_static("https://foo.com/a"),
+                mirrors: vec![Uri::from_static("http://bar.com/a")],
+                headers,
+                destination: Destination::File(PathBuf::from("bar/baz")),
+                parallelism: 4,
+                piece_size: Some(1024),
+                retry: RetryPolicy {
+                    max_retries: 0,
+                    ..RetryPolicy::default()
+                },
+                rate_limit: None,
+                network: Network::default(),
+                verify: Verify::Skip,
+                keep_partial: true,
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                timeouts: Timeouts::default(),
+                output_format: OutputFormat::Json,
+                sources: download.config().sources.clone(),
+            }
+        );
+        let sources = &download.config().sources;
+        assert_eq!(sources["parallelism"], Source::CommandLine);
+        assert_eq!(sources["headers.authorization"], Source::CommandLine);
+        assert_eq!(sources["proxy"], Source::Default);
+
+        assert_eq!(
+            builder()
+                .bind("10.0.0.2")
+                .bind(" ::1")
+                .build()
+                .unwrap()
+                .config
+                .network
+                .local_addresses,
+            vec![
+                IpAddr::from([10, 0, 0, 2]),
+                IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])
+            ]
+        );
+    }
+
+    #[test]
+    fn building_downloads_from_a_profile() {
+        let profile = Profile {
+            file: None,
+            env: Settings {
+                parallelism: Some(3),
+                piece_size: Some(4096),
+                rate_limit: Some(1024),
+                proxy: Some(String::from("http://proxy.local:3128")),
+                headers: vec![
+                    (String::from("authorization"), String::from("Bearer foo")),
+                    (String::from("user-agent"), String::from("dl")),
+                ]
+                .into_iter()
+                .collect(),
+                ..Settings::default()
+            },
+        };
+        let cfg = builder()
+            .profile(profile)
+            .piece_size(8192)
+            .header("Authorization", "Bearer bar")
+            .build()
+            .unwrap()
+            .config;
+
+        assert_eq!(cfg.parallelism, 3);
+        assert_eq!(cfg.piece_size, Some(8192));
+        assert_eq!(cfg.rate_limit, Some(1024));
+        assert_eq!(
+            cfg.network.proxy,
+            Some(Uri::from_static("http://proxy.local:3128"))
+        );
+        assert_eq!(cfg.headers["authorization"], "Bearer bar");
+        assert_eq!(cfg.headers.get_all("authorization").iter().count(), 1);
+        assert_eq!(cfg.headers["user-agent"], "dl");
+        assert_eq!(
+            cfg.sources["parallelism"],
+            Source::Env(String::from("DL_PARALLELISM"))
+        );
+        assert_eq!(cfg.sources["piece_size"], Source::CommandLine);
+    }
+
+    #[test]
+    fn building_downloads_with_defaults() {
+        let cfg = builder().build().unwrap().config;
+        assert_eq!(cfg.parallelism, *DEFAULT_PARALLELISM);
+        assert_eq!(cfg.retry, RetryPolicy::default());
+        assert_eq!(cfg.retry.max_retries, DEFAULT_MAX_RETRIES);
+        assert_eq!(cfg.verify, Verify::Etag);
+        assert_eq!(cfg.output_format, OutputFormat::Text);
+
+        // local files have no etags to check
+        let cfg = builder().url("/tmp/a b").build().unwrap().config;
+        assert_eq!(cfg.uri, Uri::from_static("file://localhost/tmp/a%20b"));
+        assert_eq!(cfg.verify, Verify::EtagIfPresent);
+    }
+
+    #[test]
+    fn building_downloads_for_info() {
+        let download = Download::builder()
+            .url("https://foo.com/a")
+            .build_for_info()
+            .unwrap();
+        assert_eq!(download.config().uri, Uri::from_static("https://foo.com/a"));
+        assert_eq!(
+            Download::builder()
+                .url("https://foo.com/a")
+                .build_for_stream()
+                .unwrap()
+                .config()
+                .destination,
+            Destination::Stdout
+        );
+        assert_eq!(
+            Download::builder()
+                .build_for_info()
+                .unwrap_err()
+                .to_string(),
+            "Invalid download configuration: a url is required"
+        );
+    }
+
+    #[test]
+    fn rejecting_invalid_options() {
+        assert_eq!(
+            invalid(Download::builder().path("bar/baz")),
+            "Invalid download configuration: a url is required"
+        );
+        assert_eq!(
+            invalid(Download::builder().url("https://foo.com/a")),
+            "Invalid download configuration: a destination is required"
+        );
+        assert_eq!(
+            invalid(builder().mirror("file://foo.com/a")),
+            "Invalid download configuration: file urls must be local (file:///path or file://localhost/path)"
+        );
+        assert_eq!(
+            invalid(builder().url("gopher://foo.com/a")),
+            "Invalid download configuration: urls must be absolute http(s), ftp(s) or file urls, or paths"
+        );
+        assert_eq!(
+            invalid(builder().header("bad header", "foo")),
+            "Invalid download configuration: invalid header name"
+        );
+        assert_eq!(
+            invalid(builder().header("x-foo", "bad\nvalue")),
+            "Invalid download configuration: invalid header value"
+        );
+        assert_eq!(
+            invalid(builder().parallelism(0)),
+            "Invalid download configuration: parallelism must be at least 1"
+        );
+        assert_eq!(
+            invalid(builder().piece_size(0)),
+            "Invalid download configuration: piece size must be at least 1 byte"
+        );
+        // (as set by `DL_RETRY_BACKOFF`, say)
+        let backoff = |secs: f64| Profile {
+            file: None,
+            env: Settings {
+                retry_backoff: Some(secs),
+                ..Settings::default()
+            },
+        };
+        for secs in [-1.0, f64::NAN, 1e20].iter() {
+            assert_eq!(
+                invalid(builder().profile(backoff(*secs))),
+                "Invalid download configuration: retry backoff must be a non-negative number of seconds"
+            );
+        }
+        assert_eq!(
+            invalid(builder().verify(Verify::Md5(String::from("abc")))),
+            "Invalid download configuration: md5 digests must be 32 hexadecimal characters"
+        );
+        assert_eq!(
+            invalid(builder().timeouts(Timeouts {
+                min_speed: Some(MinSpeed {
+                    bytes_per_sec: 0,
+                    window: Duration::from_secs(1),
+                }),
+                ..Timeouts::default()
+            })),
+            "Invalid download configuration: minimum speed and its window must be positive"
+        );
+        assert_eq!(
+            invalid(builder().buffer_size(1024)),
+            "Invalid download configuration: buffer size must be at least 64 KiB"
+        );
+        assert_eq!(
+            invalid(builder().rate_limit(0)),
+            "Invalid download configuration: rate limit must be at least 1 byte/sec"
+        );
+        assert_eq!(
+            invalid(builder().proxy("socks5://proxy.local")),
+            "Invalid download configuration: proxies must be http urls"
+        );
+        assert_eq!(
+            invalid(builder().proxy("http://proxy.local").all_addresses(true)),
+            "Invalid download configuration: a proxy connects to the host's addresses itself"
+        );
+        assert_eq!(
+            invalid(builder().resolve_host("foo.com:443")),
+            "Invalid download configuration: overrides must look like host:port:addr[,addr...]"
+        );
+        assert_eq!(
+            invalid(builder().dns_server("ns.foo.com")),
+            "Invalid download configuration: dns servers must be ip addresses (with an optional port)"
+        );
+        assert_eq!(
+            builder()
+                .hosts_file("data/no_such_hosts")
+                .build()
+                .unwrap_err()
+                .code(),
+            "invalid_settings"
+        );
+        assert_eq!(
+            builder()
+                .ca_bundle("data/no_such_bundle.pem")
+                .build()
+                .unwrap_err()
+                .code(),
+            "invalid_settings"
+        );
+        assert_eq!(
+            builder()
+                .url("https://foo bar/a")
+                .build()
+                .unwrap_err()
+                .code(),
+            "invalid_uri"
+        );
+    }
+}
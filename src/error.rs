@@ -1,6 +1,9 @@
 use http;
 use std::error::Error;
 use std::fmt;
+use std::time::Duration;
+
+use crate::timeout::Elapsed;
 
 /**************************************************************************
  * TODO:
@@ -10,31 +13,104 @@ use std::fmt;
 
 #[derive(Debug)]
 pub enum DlError {
+    BatchFailed(usize, usize),
     Checksum,
+    ConnectTimeout,
+    ContentRangeMismatch,
+    DigestMismatch(String, String),
+    DownloadTimeout,
     EtagAbsent,
+    FirstByteTimeout,
+    Ftp(String),
     Http(http::Error),
+    Http3(String),
     Hyper(hyper::error::Error),
+    IdleTimeout,
+    InvalidArguments(String),
+    InvalidBatch(usize),
+    InvalidConfig(&'static str),
+    InvalidSettings(String),
     InvalidUri(http::uri::InvalidUri),
     Io(std::io::Error),
+    LengthMismatch(u64, u64),
+    MirrorMismatch(u64, u64),
     ParseContentLength,
+    RangeIgnored,
     RangeMetadataAbsent,
+    RemoteChanged,
     RequestFailed(u16),
+    RetryAfter(u16, Duration),
+    ShortRead,
     StreamProcessing,
+    Timer(tokio::timer::Error),
+    TlsHandshakeTimeout,
+    TooSlow(u64),
+    TooManyRedirects,
 }
 
 impl fmt::Display for DlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            DlError::BatchFailed(failed, total) => {
+                write!(f, "{} of {} downloads failed", failed, total)
+            }
             DlError::Checksum => write!(f, "Failed checksum (hashing or hex encoding failed)"),
+            DlError::ConnectTimeout => write!(f, "Timed out connecting to server"),
+            DlError::ContentRangeMismatch => {
+                write!(f, "Server responded with a different range than requested")
+            }
+            DlError::DigestMismatch(ref expected, ref actual) => write!(
+                f,
+                "Downloaded file hashed to {} but server advertised {}",
+                actual, expected
+            ),
+            DlError::DownloadTimeout => write!(f, "Download did not finish in time"),
             DlError::EtagAbsent => write!(f, "File does not have an etag"),
+            DlError::FirstByteTimeout => write!(f, "Timed out waiting for server to respond"),
+            DlError::Ftp(ref reason) => write!(f, "FTP request failed: {}", reason),
             DlError::Http(ref err) => err.fmt(f),
+            DlError::Http3(ref reason) => write!(f, "HTTP/3 request failed: {}", reason),
             DlError::Hyper(ref err) => err.fmt(f),
+            DlError::IdleTimeout => write!(f, "Timed out waiting for more of the response body"),
+            DlError::InvalidBatch(line) => write!(
+                f,
+                "Invalid batch file: line {} should be '<url> <path>'",
+                line
+            ),
+            DlError::InvalidConfig(reason) => {
+                write!(f, "Invalid download configuration: {}", reason)
+            }
+            DlError::InvalidSettings(ref reason) => write!(f, "Invalid settings: {}", reason),
+            DlError::InvalidArguments(ref reason) => write!(f, "Invalid arguments: {}", reason),
             DlError::InvalidUri(ref err) => err.fmt(f),
             DlError::Io(ref err) => err.fmt(f),
+            DlError::LengthMismatch(expected, actual) => {
+                write!(f, "Expected {} bytes but server sent {}", expected, actual)
+            }
+            DlError::MirrorMismatch(expected, actual) => write!(
+                f,
+                "Mirror serves {} bytes but the primary source serves {}",
+                actual, expected
+            ),
             DlError::ParseContentLength => write!(f, "Failed to parse content length header"),
+            DlError::RangeIgnored => write!(f, "Server ignored range request"),
             DlError::RangeMetadataAbsent => write!(f, "Server does not support range requests"),
+            DlError::RemoteChanged => write!(f, "Remote file changed during download"),
             DlError::RequestFailed(code) => write!(f, "Request failed with status code {}", code),
+            DlError::RetryAfter(code, after) => write!(
+                f,
+                "Request failed with status code {} (server asked us to retry after {}s)",
+                code,
+                after.as_secs()
+            ),
+            DlError::ShortRead => {
+                write!(f, "Response ended before the requested range was received")
+            }
             DlError::StreamProcessing => write!(f, "Stream processing error"),
+            DlError::Timer(ref err) => err.fmt(f),
+            DlError::TlsHandshakeTimeout => write!(f, "Timed out during TLS handshake"),
+            DlError::TooSlow(rate) => write!(f, "Transfer too slow ({} bytes/sec)", rate),
+            DlError::TooManyRedirects => write!(f, "Too many redirects"),
         }
     }
 }
@@ -46,20 +122,123 @@ impl Error for DlError {
     #[allow(deprecated)]
     fn description(&self) -> &str {
         match *self {
+            DlError::BatchFailed(_, _) => "Some downloads in the batch failed",
             DlError::Checksum => "Failed checksum (hashing or hex encoding failed)",
+            DlError::ConnectTimeout => "Timed out connecting to server",
+            DlError::ContentRangeMismatch => {
+                "Server responded with a different range than requested"
+            }
+            DlError::DigestMismatch(_, _) => "Downloaded file does not match the advertised digest",
+            DlError::DownloadTimeout => "Download did not finish in time",
             DlError::EtagAbsent => "File does not have an etag",
+            DlError::FirstByteTimeout => "Timed out waiting for server to respond",
+            DlError::Ftp(_) => "FTP request failed",
             DlError::Http(ref err) => err.description(),
+            DlError::Http3(_) => "HTTP/3 request failed",
             DlError::Hyper(ref err) => err.description(),
+            DlError::IdleTimeout => "Timed out waiting for more of the response body",
+            DlError::InvalidBatch(_) => "Invalid batch file",
+            DlError::InvalidConfig(_) => "Invalid download configuration",
+            DlError::InvalidSettings(_) => "Invalid settings",
+            DlError::InvalidArguments(_) => "Invalid arguments",
             DlError::InvalidUri(ref err) => err.description(),
             DlError::Io(ref err) => err.description(),
+            DlError::LengthMismatch(_, _) => {
+                "Server sent a different number of bytes than expected"
+            }
+            DlError::MirrorMismatch(_, _) => {
+                "Mirror serves a different file than the primary source"
+            }
             DlError::ParseContentLength => "Failed to parse content length header",
+            DlError::RangeIgnored => "Server ignored range request",
             DlError::RangeMetadataAbsent => "Server does not support range requests",
+            DlError::RemoteChanged => "Remote file changed during download",
             DlError::RequestFailed(_) => "Request failed",
+            DlError::RetryAfter(_, _) => "Request failed (server asked us to retry later)",
+            DlError::ShortRead => "Response ended before the requested range was received",
             DlError::StreamProcessing => "Stream processing error",
+            DlError::Timer(ref err) => err.description(),
+            DlError::TlsHandshakeTimeout => "Timed out during TLS handshake",
+            DlError::TooSlow(_) => "Transfer too slow",
+            DlError::TooManyRedirects => "Too many redirects",
         }
     }
 }
 
+impl DlError {
+    /// a stable, machine-readable name for each variant (used in json output)
+    pub fn code(&self) -> &'static str {
+        match *self {
+            DlError::BatchFailed(_, _) => "batch_failed",
+            DlError::Checksum => "checksum",
+            DlError::ConnectTimeout => "connect_timeout",
+            DlError::ContentRangeMismatch => "content_range_mismatch",
+            DlError::DigestMismatch(_, _) => "digest_mismatch",
+            DlError::DownloadTimeout => "download_timeout",
+            DlError::EtagAbsent => "etag_absent",
+            DlError::FirstByteTimeout => "first_byte_timeout",
+            DlError::Ftp(_) => "ftp",
+            DlError::Http(_) => "http",
+            DlError::Http3(_) => "http3",
+            DlError::Hyper(_) => "hyper",
+            DlError::IdleTimeout => "idle_timeout",
+            DlError::InvalidBatch(_) => "invalid_batch",
+            DlError::InvalidConfig(_) => "invalid_config",
+            DlError::InvalidSettings(_) => "invalid_settings",
+            DlError::InvalidArguments(_) => "invalid_arguments",
+            DlError::InvalidUri(_) => "invalid_uri",
+            DlError::Io(_) => "io",
+            DlError::LengthMismatch(_, _) => "length_mismatch",
+            DlError::MirrorMismatch(_, _) => "mirror_mismatch",
+            DlError::ParseContentLength => "parse_content_length",
+            DlError::RangeIgnored => "range_ignored",
+            DlError::RangeMetadataAbsent => "range_metadata_absent",
+            DlError::RemoteChanged => "remote_changed",
+            DlError::RequestFailed(_) => "request_failed",
+            DlError::RetryAfter(_, _) => "retry_after",
+            DlError::ShortRead => "short_read",
+            DlError::StreamProcessing => "stream_processing",
+            DlError::Timer(_) => "timer",
+            DlError::TlsHandshakeTimeout => "tls_handshake_timeout",
+            DlError::TooSlow(_) => "too_slow",
+            DlError::TooManyRedirects => "too_many_redirects",
+        }
+    }
+
+    /// whether retrying the request that produced this error might succeed
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            DlError::ContentRangeMismatch
+            | DlError::Ftp(_)
+            | DlError::Http3(_)
+            | DlError::ShortRead => true,
+            DlError::Hyper(ref err) => is_connection_error(err),
+            DlError::ConnectTimeout
+            | DlError::FirstByteTimeout
+            | DlError::IdleTimeout
+            | DlError::TlsHandshakeTimeout
+            | DlError::TooSlow(_)
+            | DlError::RetryAfter(_, _) => true,
+            DlError::RequestFailed(code) => code == 408 || code == 429 || code >= 500,
+            // `--max-time` bounds the whole download, retries included
+            DlError::DownloadTimeout => false,
+            _ => false,
+        }
+    }
+}
+
+/// whether `err` is the connection's doing (it couldn't be made, or it broke, or the server reset the http/2
+/// stream) rather than the request's or the response's
+fn is_connection_error(err: &hyper::Error) -> bool {
+    err.is_connect()
+        || err.is_closed()
+        || err.is_canceled()
+        || err.is_incomplete_message()
+        || err
+            .source()
+            .is_some_and(|cause| cause.is::<std::io::Error>() || cause.is::<h2::Error>())
+}
+
 impl From<http::uri::InvalidUri> for DlError {
     fn from(cause: http::uri::InvalidUri) -> DlError {
         DlError::InvalidUri(cause)
@@ -73,8 +252,26 @@ impl From<http::Error> for DlError {
 }
 
 impl From<hyper::error::Error> for DlError {
+    /// picks out the connect and handshake timeouts raised by our connectors (see `crate::timeout::Elapsed`),
+    /// however many `io::Error`s they were wrapped in on their way out (the proxy connector adds one)
     fn from(cause: hyper::error::Error) -> DlError {
-        DlError::Hyper(cause)
+        let mut err = cause.source();
+        let mut elapsed = None;
+        while let Some(e) = err {
+            if let Some(e) = e.downcast_ref::<Elapsed>() {
+                elapsed = Some(*e);
+                break;
+            }
+            err = e
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.get_ref())
+                .map(|e| e as &(dyn Error + 'static));
+        }
+        match elapsed {
+            Some(Elapsed::Connect) => DlError::ConnectTimeout,
+            Some(Elapsed::TlsHandshake) => DlError::TlsHandshakeTimeout,
+            None => DlError::Hyper(cause),
+        }
     }
 }
 
@@ -83,3 +280,36 @@ impl From<std::io::Error> for DlError {
         DlError::Io(cause)
     }
 }
+
+#[cfg(test)]
+mod error_tests {
+    use std::io;
+
+    use futures::{stream, Future, Stream};
+    use hyper::{Body, Chunk};
+
+    use super::*;
+
+    #[test]
+    fn classifying_transient_errors() {
+        assert!(DlError::ShortRead.is_transient());
+        assert!(DlError::ContentRangeMismatch.is_transient());
+        assert!(DlError::RequestFailed(503).is_transient());
+        assert!(DlError::RequestFailed(429).is_transient());
+        assert!(!DlError::RequestFailed(416).is_transient());
+        assert!(!DlError::RangeIgnored.is_transient());
+        assert!(!DlError::EtagAbsent.is_transient());
+        assert!(DlError::IdleTimeout.is_transient());
+        assert!(DlError::TooSlow(10).is_transient());
+        assert!(!DlError::DownloadTimeout.is_transient());
+
+        // a connection that breaks mid-body is worth retrying, a body that can't be read at all isn't
+        let body_error = |cause: Box<dyn Error + Send + Sync>| {
+            let body = Body::wrap_stream(stream::once::<Chunk, _>(Err(cause)));
+            DlError::Hyper(body.concat2().wait().unwrap_err())
+        };
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+        assert!(body_error(Box::new(reset)).is_transient());
+        assert!(!body_error("not a chunk".into()).is_transient());
+    }
+}
@@ -1,153 +1,869 @@
-use std::cmp::min;
-use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use futures::{future, stream, Future, Stream};
+use futures::future::{self, Either, IntoFuture, Loop};
+use futures::{Future, Stream};
 use hyper;
-use hyper::Response;
-use hyper::{Body, Request, Uri};
-use tokio_fs::{File, OpenOptions};
-use tokio_io::io;
-use tokio_io::AsyncWrite;
+use hyper::header::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Uri};
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use tokio::timer::Delay;
+use tokio_fs::File;
 
 use crate::checksum::HashChecker;
+use crate::disk;
+use crate::dns;
+use crate::download::Destination;
 use crate::error::DlError;
-use crate::https::HttpsClient;
+use crate::https::{self, Network, Protocol};
 use crate::metadata::Metadata;
 use crate::metadata::MetadataDownloader;
-use crate::DEFAULT_PARALLELISM;
+use crate::metadata::{Mirror, Validator};
+use crate::output::{Event, Reporter};
+use crate::reorder::{Ordered, ReorderBuffer};
+use crate::sink::{FileSink, Sink};
+use crate::throttle::{throttle, RateLimiter};
+use crate::timeout::{self, Timeouts};
+use crate::transport::{SharedTransport, Transport};
+
+pub const DEFAULT_PIECES_PER_CONNECTION: u64 = 4;
+pub const MIN_PIECE_SIZE: u64 = 64 * 1024;
+pub const MAX_PIECE_SIZE: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+pub const BACKOFF_BASE_MILLIS: u64 = 100;
+/// the longest we'll wait before a retry when a server asks us to (with `Retry-After`)
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// the longest we'll wait before a retry of our own
+pub const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// how hard to try before giving up on a piece
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// how many times to retry a piece after transient failures
+    pub max_retries: u32,
+    /// how long to wait before the first retry (doubling with each one after that)
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: Duration::from_millis(BACKOFF_BASE_MILLIS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// how long to wait before retrying a piece for the `retries + 1`th time
+    pub fn backoff(&self, retries: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << min(retries, 6))
+            .map_or(MAX_BACKOFF, |backoff| min(backoff, MAX_BACKOFF))
+    }
+}
 
 pub struct FileDownloader {
-    pub client: HttpsClient,
+    pub transport: SharedTransport,
     pub uri: Uri,
-    pub path: PathBuf,
+    pub destination: Destination,
     pub file_size: u64,
     pub etag: Option<String>,
+    /// sent with every request so that we notice if the remote file changes partway through the download
+    pub validator: Option<Validator>,
+    /// other uris serving the same file, which connections are spread across (along with `uri`)
+    pub mirrors: Vec<Mirror>,
+    /// the addresses `uri`'s host resolved to, each with a transport pinned to it: `uri`'s connections are spread
+    /// across them (each a source of its own) rather than left to `transport` (see `Network::all_addresses`)
+    pub addresses: Vec<(IpAddr, SharedTransport)>,
+    /// a transport bound to each of the local addresses that connections to `uri` (but for those to `addresses`,
+    /// which are bound already) and its mirrors are spread across, the first being `transport` itself (see
+    /// `Network::local_addresses`)
+    pub bound: Vec<(IpAddr, SharedTransport)>,
+    /// sent with every request (as well as the headers `dl` sets itself)
+    pub headers: HeaderMap<HeaderValue>,
     pub parallelism: usize,
+    pub piece_size: u64,
+    pub retry: RetryPolicy,
+    /// caps the download's combined throughput (in bytes/sec)
+    pub rate_limit: Option<u64>,
+    pub timeouts: Timeouts,
+    pub reporter: Reporter,
+}
+
+/// a contiguous range of bytes in the file, fetched with a single range request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Piece {
+    pub index: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Piece {
+    /// the (exclusive) offset at which the piece ends
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// hands out pieces to the workers fetching a file and keeps track of how far along each in-flight piece is.
+///
+/// Once the queue is empty, idle workers split the in-flight piece with the most bytes left and take its tail
+/// (aria2-style), so that a single slow connection can't hold up the end of a download.
+#[derive(Debug)]
+pub struct Scheduler {
+    queue: VecDeque<Piece>,
+    in_flight: BTreeMap<u64, Progress>,
+    next_index: u64,
+}
+
+/// the bytes of an in-flight piece: `[offset, position)` has been claimed for writing, `[position, end)` has not
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Progress {
+    offset: u64,
+    position: u64,
+    end: u64,
+}
+
+impl Scheduler {
+    pub fn new(pieces: Vec<Piece>) -> Scheduler {
+        Scheduler {
+            next_index: pieces.iter().map(|p| p.index + 1).max().unwrap_or(0),
+            queue: pieces.into_iter().collect(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// pops the next piece off the queue (marking it in flight)
+    pub fn next_queued(&mut self) -> Option<Piece> {
+        let piece = self.queue.pop_front()?;
+        self.start(piece);
+        Some(piece)
+    }
+
+    /// cuts the in-flight piece with the most unclaimed bytes in half, returning the index of the piece that was
+    /// split and a new (in-flight) piece covering its tail. Pieces with less than `2 * MIN_PIECE_SIZE` bytes left
+    /// are not worth a new request, so are never split.
+    pub fn split_largest(&mut self) -> Option<(u64, Piece)> {
+        let (&index, progress) = self
+            .in_flight
+            .iter_mut()
+            .max_by_key(|(_, progress)| progress.end - progress.position)?;
+        let remaining = progress.end - progress.position;
+        if remaining < 2 * MIN_PIECE_SIZE {
+            return None;
+        }
+
+        let at = progress.position + remaining / 2;
+        let tail = Piece {
+            index: self.next_index,
+            offset: at,
+            length: progress.end - at,
+        };
+        progress.end = at;
+        self.next_index += 1;
+        self.start(tail);
+        Some((index, tail))
+    }
+
+    /// claims up to `len` of the next bytes of piece `index` for writing, returning the file offset they should
+    /// be written at, how many bytes were claimed (fewer than `len` if the piece has been cut short by a split)
+    /// and whether the piece is now done
+    pub fn claim(&mut self, index: u64, len: u64) -> (u64, u64, bool) {
+        match self.in_flight.get_mut(&index) {
+            None => (0, 0, true),
+            Some(progress) => {
+                let at = progress.position;
+                let claimed = min(len, progress.end - at);
+                progress.position += claimed;
+                (at, claimed, progress.position == progress.end)
+            }
+        }
+    }
+
+    /// the part of in-flight piece `index` that has not been written yet
+    pub fn remaining(&self, index: u64) -> Option<Piece> {
+        self.in_flight.get(&index).map(|progress| Piece {
+            index,
+            offset: progress.position,
+            length: progress.end - progress.position,
+        })
+    }
+
+    /// marks piece `index` as done, returning the (possibly shortened) part of the file it ended up covering
+    pub fn finish(&mut self, index: u64) -> Option<Piece> {
+        self.in_flight.remove(&index).map(|progress| Piece {
+            index,
+            offset: progress.offset,
+            length: progress.end - progress.offset,
+        })
+    }
+
+    fn start(&mut self, piece: Piece) {
+        self.in_flight.insert(
+            piece.index,
+            Progress {
+                offset: piece.offset,
+                position: piece.offset,
+                end: piece.end(),
+            },
+        );
+    }
+}
+
+/// whether `uri`'s requests go over http (rather than to the local file system, or an ftp server)
+fn over_http(uri: &Uri) -> bool {
+    matches!(uri.scheme_str(), Some("http") | Some("https"))
+}
+
+/// `network`, with connections bound to `local` alone
+fn bound_to(network: &Network, local: IpAddr) -> Network {
+    Network {
+        local_addresses: vec![local],
+        ..network.clone()
+    }
+}
+
+/// records which part of the file a completed piece covered, how many times we had to retry it, which version
+/// of http it came over (none, if it was split off in its entirety before we asked for any of it) and which
+/// source it came from (a uri, or an address of its host)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PieceReport {
+    pub index: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub retries: u32,
+    pub protocol: Option<Protocol>,
+    pub source: String,
 }
 
 impl FileDownloader {
-    /// constructs a `FileDownloader` from a `MetadataDownloader`
-    pub fn from_metadata(mdd: MetadataDownloader, md: Metadata) -> FileDownloader {
+    /// constructs a `FileDownloader` from a `MetadataDownloader` (and the `mirrors` it found the file on)
+    pub fn from_metadata(
+        mdd: MetadataDownloader,
+        md: Metadata,
+        mirrors: Vec<Mirror>,
+    ) -> FileDownloader {
         Self {
-            client: mdd.client,
+            transport: mdd.transport,
             uri: mdd.uri,
-            path: mdd.path,
+            destination: mdd.destination,
             file_size: md.file_size,
             etag: md.etag,
+            validator: md.validator,
+            mirrors,
+            addresses: vec![],
+            bound: vec![],
+            headers: mdd.headers,
             parallelism: mdd.parallelism,
+            piece_size: piece_size_for(md.file_size, mdd.parallelism, mdd.piece_size),
+            retry: mdd.retry,
+            rate_limit: mdd.rate_limit,
+            timeouts: mdd.timeouts,
+            reporter: mdd.reporter,
         }
     }
 
-    /// given an http `client`, a file's `uri`, a known `file_size`, a desired `piece_size` (in bytes) and an output `path`:
-    /// - create an empty file of the correct size on the local file system
-    /// - download pieces of the file in parallel
-    /// - write each piece to the correct offset in the blank file (also in parallel)
+    /// spreads the connections to `uri` (and its mirrors) across `network`'s local addresses, if it has more than
+    /// one, and across every address `uri`'s host resolves to, if it says to (see `Network::all_addresses`): as many
+    /// of them as there are connections to go round, and that a local address can reach. each gets a transport of
+    /// its own, bound to one of the local addresses (if there are any). those pinned to an address fall back on one
+    /// of the other family (happy eyeballs style) should their own turn out to be unreachable. a `network` bringing
+    /// its own transport is left to it
+    pub fn spread_over(
+        self,
+        network: &Network,
+    ) -> Box<dyn Future<Item = FileDownloader, Error = DlError> + Send> {
+        if network.transport.is_some() {
+            return Box::new(future::ok(self));
+        }
+        let bound = match network.local_addresses.len() {
+            0 | 1 => Ok(vec![]),
+            _ => network
+                .local_addresses
+                .iter()
+                .enumerate()
+                .map(|(i, local)| match i {
+                    // (the transport we have is already bound to the first)
+                    0 => Ok((*local, self.transport.clone())),
+                    _ => https::build_client(1, &self.timeouts, &bound_to(network, *local), false)
+                        .map(|client| (*local, SharedTransport::new(client))),
+                })
+                .collect::<Result<Vec<_>, _>>(),
+        };
+        let file_downloader = match bound {
+            Ok(bound) => FileDownloader { bound, ..self },
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let host = match file_downloader.uri.host() {
+            Some(host) if network.all_addresses && over_http(&file_downloader.uri) => {
+                host.to_string()
+            }
+            _ => return Box::new(future::ok(file_downloader)),
+        };
+        let uri = &file_downloader.uri;
+        let port = dns::port_of(uri.scheme_str(), uri.port_u16());
+        let resolver = match https::resolver_for(1, network) {
+            Ok(resolver) => resolver,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let lookup = dns::lookup(&resolver, &network.resolve, &host, port);
+        // (the addresses are already those of the host's override, if it has one)
+        let network = Network {
+            resolve: vec![],
+            ..network.clone()
+        };
+        Box::new(lookup.and_then(move |addrs| {
+            let addrs = dns::interleave(addrs, network.ip_preference);
+            // each address goes from the local addresses of its family in turn (if any are to be bound to)
+            let mut assigned = vec![];
+            for addr in addrs.iter() {
+                let locals = network
+                    .local_addresses
+                    .iter()
+                    .filter(|local| local.is_ipv6() == addr.is_ipv6())
+                    .collect::<Vec<_>>();
+                match (network.local_addresses.is_empty(), locals.len()) {
+                    (true, _) => assigned.push((*addr, None)),
+                    (false, 0) => (),
+                    (false, n) => assigned.push((*addr, Some(*locals[assigned.len() % n]))),
+                }
+            }
+            let addresses = assigned
+                .into_iter()
+                .take(max(file_downloader.parallelism, 1))
+                .map(|(addr, local)| {
+                    let network = match local {
+                        Some(local) => bound_to(&network, local),
+                        None => network.clone(),
+                    };
+                    let resolver = dns::pinned(addr, &addrs);
+                    https::build_client_with(resolver, &file_downloader.timeouts, &network, true)
+                        .map(|client| (addr, SharedTransport::new(client)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(FileDownloader {
+                addresses,
+                ..file_downloader
+            })
+        }))
+    }
+
+    /// given a `transport`, a file's `uri`, a known `file_size`, a desired `piece_size` (in bytes) and an output `path`:
+    /// - create a `<path>.part` file on the local file system and preallocate `file_size` bytes of disk for it
+    /// - plan a queue of `piece_size`(d) pieces covering the file
+    /// - download pieces of the file in parallel, keeping `parallelism` requests in flight by pulling from the queue
+    ///   (with connections spread round-robin across `uri`, or the `addresses` of its host, and its `mirrors`, and
+    ///   the local addresses they're `bound` to)
+    /// - write each piece to the correct offset in the file (also in parallel, through one shared handle)
+    /// - flush the file to disk once every piece is written, checking that it is `file_size` bytes long
+    ///
+    /// (the `.part` file is only moved to `path` once it has been verified: see `crate::run`)
+    ///
+    /// if the server turns out to ignore range requests, falls back to downloading the file in a single stream
     pub fn fetch(self) -> impl Future<Item = HashChecker, Error = DlError> + Send {
         // TODO increase fault tolerance by:
-        //   - inspecting completed futures for success/error retrying all failed requests/writes until no failures
         //   - persisting state of downloads in hashmap, serializing to disk at interval (to be able to restart on crash)
+        let path = match self.destination {
+            Destination::File(ref path) => Ok(path.clone()),
+            Destination::Stdout | Destination::Sink(_) => Err(DlError::InvalidConfig(
+                "only files can be fetched into place (use `fetch_ordered` to stream them)",
+            )),
+        };
+        let file_size = self.file_size;
+        let etag = self.etag.clone();
+
+        future::result(path).and_then(move |path| {
+            let part = disk::part_path(&path);
+            let p = part.clone();
+            disk::run_blocking(move || FileSink::create(&p, file_size))
+                .and_then(move |sink| self.fetch_into(Arc::new(sink)))
+                .map(move |pieces| HashChecker {
+                    path: part,
+                    etag,
+                    pieces,
+                })
+        })
+    }
+
+    /// downloads the file just as `fetch` does, but into `sink` (which is finalized once every piece has been
+    /// written into it, or aborted if the download fails). resolves with the pieces the file was fetched in,
+    /// sorted by offset
+    pub fn fetch_into(
+        self,
+        sink: Arc<dyn Sink>,
+    ) -> impl Future<Item = Vec<PieceReport>, Error = DlError> + Send {
+        self.work(sink.clone()).then(move |result| match result {
+            Ok(mut pieces) => {
+                pieces.sort_by_key(|piece| piece.offset);
+                Either::A(sink.finalize().map(move |_| pieces))
+            }
+            Err(err) => Either::B(sink.abort().then(move |_| Err(err))),
+        })
+    }
+
+    /// downloads the file just as `fetch` does, but yields its bytes in order rather than writing them to disk:
+    /// pieces that arrive early are held in a `ReorderBuffer` of about `buffer_size` bytes, and no more pieces
+    /// are requested while it is full. the stream fails if the file can't be downloaded in full
+    pub fn fetch_ordered(self, buffer_size: u64) -> Ordered {
+        let file_size = self.file_size;
+        let buffer = ReorderBuffer::new(buffer_size);
+        // small enough that every connection can have a piece in flight without overflowing the buffer
+        let piece_size = min(
+            self.piece_size,
+            max(
+                buffer_size / max(self.parallelism, 1) as u64,
+                MIN_PIECE_SIZE,
+            ),
+        );
+        Ordered::new(
+            buffer.clone(),
+            file_size,
+            Box::new(FileDownloader { piece_size, ..self }.fetch_into(Arc::new(buffer))),
+        )
+    }
+
+    /// downloads the file in `piece_size`d pieces into `sink`, keeping `parallelism` connections busy (and
+    /// falling back to a single stream if the server ignores range requests)
+    fn work(
+        self,
+        sink: Arc<dyn Sink>,
+    ) -> impl Future<Item = Vec<PieceReport>, Error = DlError> + Send {
         let Self {
-            client,
+            transport,
             file_size,
-            path,
             uri,
-            etag,
+            validator,
+            mirrors,
+            addresses,
+            bound,
+            headers,
             parallelism,
+            piece_size,
+            retry,
+            rate_limit,
+            timeouts,
+            reporter,
             ..
         } = self;
 
-        let piece_size = file_size / parallelism as u64;
-        let p = path.clone();
-        let u = uri.clone();
-
-        File::create(path.clone())
-            .map_err(DlError::Io)
-            .and_then(move |_| {
-                gen_offsets(file_size, piece_size)
-                    .map(move |offset| {
-                        download_piece(&client, &u, file_size, piece_size, offset, p.clone())
+        let piece_downloader = PieceDownloader {
+            source: uri.to_string(),
+            transport,
+            uri,
+            validator,
+            headers,
+            sink,
+            file_size,
+            retry,
+            limiter: rate_limit.map(RateLimiter::new),
+            timeouts,
+            scheduler: Arc::new(Mutex::new(Scheduler::new(plan_pieces(
+                file_size, piece_size,
+            )))),
+            reporter,
+        };
+        let single_stream_downloader = piece_downloader.clone();
+        // every source (the file's uri, or each of its host's addresses, and each mirror), as a worker would fetch
+        // from it: from each of the local addresses in turn, but for the host's addresses (which are bound already)
+        // and local files and ftp servers
+        let from_each_local = |source: PieceDownloader| -> Vec<PieceDownloader> {
+            match bound.is_empty() || !over_http(&source.uri) {
+                true => vec![source],
+                false => bound
+                    .iter()
+                    .map(|(local, transport)| PieceDownloader {
+                        source: format!("{} via {}", source.source, local),
+                        transport: transport.clone(),
+                        ..source.clone()
                     })
-                    .map_err(|_| DlError::StreamProcessing)
-                    .buffer_unordered(parallelism)
-                    .collect()
+                    .collect(),
+            }
+        };
+        let primary: Vec<PieceDownloader> = match addresses.is_empty() {
+            true => from_each_local(piece_downloader.clone()),
+            false => addresses
+                .into_iter()
+                .map(|(addr, transport)| PieceDownloader {
+                    source: addr.to_string(),
+                    transport,
+                    ..piece_downloader.clone()
+                })
+                .collect(),
+        };
+        let sources: Vec<PieceDownloader> = primary
+            .into_iter()
+            .chain(mirrors.into_iter().flat_map(|mirror| {
+                from_each_local(PieceDownloader {
+                    source: mirror.uri.to_string(),
+                    uri: mirror.uri,
+                    validator: mirror.validator,
+                    ..piece_downloader.clone()
+                })
+            }))
+            .collect();
+
+        future::join_all(
+            (0..max(parallelism, 1)).map(move |i| sources[i % sources.len()].clone().work()),
+        )
+        .map(|reports| reports.into_iter().flatten().collect::<Vec<PieceReport>>())
+        .or_else(move |err| match err {
+            DlError::RangeIgnored => {
+                reporter.emit(&Event::SingleStream);
+                Either::A(
+                    single_stream_downloader
+                        .fetch_single_stream()
+                        .map(|report| vec![report]),
+                )
+            }
+            err => Either::B(future::err(err)),
+        })
+    }
+}
+
+/// everything a worker needs to download pieces of a file and write them into place
+#[derive(Clone)]
+pub struct PieceDownloader {
+    /// what the worker's pieces are reported as having come from
+    pub source: String,
+    pub transport: SharedTransport,
+    pub uri: Uri,
+    pub validator: Option<Validator>,
+    pub headers: HeaderMap<HeaderValue>,
+    pub sink: Arc<dyn Sink>,
+    pub file_size: u64,
+    pub retry: RetryPolicy,
+    /// shared by every worker, so that the rate limit applies to the download as a whole
+    pub limiter: Option<RateLimiter>,
+    pub timeouts: Timeouts,
+    pub scheduler: Arc<Mutex<Scheduler>>,
+    pub reporter: Reporter,
+}
+
+impl PieceDownloader {
+    /// keeps one connection busy: pulls pieces off the queue (or splits in-flight pieces once the queue is empty)
+    /// and downloads them until there is nothing left to do
+    pub fn work(self) -> impl Future<Item = Vec<PieceReport>, Error = DlError> + Send {
+        future::loop_fn(Vec::new(), move |mut reports| {
+            let next = {
+                let mut s = self.scheduler.lock().expect("Scheduler lock poisoned");
+                s.next_queued()
+                    .map(|piece| (piece, None))
+                    .or_else(|| s.split_largest().map(|(from, piece)| (piece, Some(from))))
+            };
+            let reporter = self.reporter;
+            match next {
+                None => Either::A(future::ok(Loop::Break(reports))),
+                Some((piece, split_from)) => {
+                    if let Some(index) = split_from {
+                        reporter.emit(&Event::PieceSplit {
+                            index,
+                            at: piece.offset,
+                            new_index: piece.index,
+                        });
+                    }
+                    Either::B(self.download(piece).map(move |report| {
+                        reporter.emit(&Event::PieceComplete(report.clone()));
+                        reports.push(report);
+                        Loop::Continue(reports)
+                    }))
+                }
+            }
+        })
+    }
+
+    /// downloads a `piece` of the file and writes it into place, retrying whatever is left of the piece
+    /// (with exponential backoff) after transient failures such as dropped connections or short reads
+    pub fn download(
+        &self,
+        piece: Piece,
+    ) -> Box<dyn Future<Item = PieceReport, Error = DlError> + Send> {
+        let this = self.clone();
+        let scheduler = self.scheduler.clone();
+        let source = self.source.clone();
+        let attempts = future::loop_fn(0, move |retries| {
+            let this = this.clone();
+            let remaining = this
+                .scheduler
+                .lock()
+                .expect("Scheduler lock poisoned")
+                .remaining(piece.index)
+                .unwrap_or(Piece { length: 0, ..piece });
+            this.download_range(remaining)
+                .then(move |result| match result {
+                    Ok(protocol) => Either::A(future::ok(Loop::Break((retries, protocol)))),
+                    Err(ref err) if err.is_transient() && retries < this.retry.max_retries => {
+                        this.reporter.emit(&Event::Retrying {
+                            index: piece.index,
+                            retries: retries + 1,
+                            code: err.code(),
+                            message: err.to_string(),
+                        });
+                        let backoff = match *err {
+                            DlError::RetryAfter(_, after) => {
+                                max(this.retry.backoff(retries), min(after, MAX_RETRY_AFTER))
+                            }
+                            _ => this.retry.backoff(retries),
+                        };
+                        Either::B(
+                            Delay::new(Instant::now() + backoff)
+                                .map_err(DlError::Timer)
+                                .map(move |_| Loop::Continue(retries + 1)),
+                        )
+                    }
+                    Err(err) => Either::A(future::err(err)),
+                })
+        });
+        Box::new(self.sink.admit(piece).and_then(move |_| attempts).map(
+            move |(retries, protocol)| {
+                let done = scheduler
+                    .lock()
+                    .expect("Scheduler lock poisoned")
+                    .finish(piece.index)
+                    .unwrap_or(piece);
+                PieceReport {
+                    index: done.index,
+                    offset: done.offset,
+                    length: done.length,
+                    retries,
+                    protocol,
+                    source,
+                }
+            },
+        ))
+    }
+
+    /// issues a single range request for `piece`, checks that the server answered with exactly that range and
+    /// writes the response into place (stopping early if the `scheduler` cuts the piece short), returning the
+    /// version of http it came over (if there was anything left to ask for)
+    fn download_range(
+        &self,
+        piece: Piece,
+    ) -> Box<dyn Future<Item = Option<Protocol>, Error = DlError> + Send> {
+        if piece.length == 0 {
+            return Box::new(future::ok(None));
+        }
+        let mut req = match build_range_request(&self.uri, piece, self.validator.as_ref()) {
+            Err(err) => return Box::new(future::err(err)),
+            Ok(req) => req,
+        };
+        https::add_headers(&mut req, &self.headers);
+
+        let file_size = self.file_size;
+        let validator = self.validator.clone();
+        let sink = self.sink.clone();
+        let scheduler = self.scheduler.clone();
+        let timeouts = self.timeouts;
+        let limiter = self.limiter.clone();
+        let response = timeout::deadline(
+            self.transport.request(req),
+            timeouts.first_byte,
+            DlError::FirstByteTimeout,
+        );
+        Box::new(
+            response
+                .and_then(move |res| {
+                    validate_range_response(&res, piece, file_size, validator.as_ref()).map(|_| res)
+                })
+                .and_then(move |res| {
+                    let protocol = Protocol::of_response(&res);
+                    write_to_sink(res, sink, piece, scheduler, &timeouts, limiter).and_then(
+                        move |done| match done {
+                            true => Ok(Some(protocol)),
+                            false => Err(DlError::ShortRead),
+                        },
+                    )
+                }),
+        )
+    }
+
+    /// downloads the whole file with one plain GET (for servers that ignore range requests), checking that
+    /// the body is exactly as long as the file (and, if we have a validator, that it is the same version of it)
+    pub fn fetch_single_stream(self) -> impl Future<Item = PieceReport, Error = DlError> + Send {
+        let file_size = self.file_size;
+        let mut req = Request::get(&self.uri);
+        if let Some(Validator::Etag(ref etag)) = self.validator {
+            req.header("If-Match", etag.as_str());
+        }
+        let headers = self.headers.clone();
+        let req = req
+            .body(Body::empty())
+            .map_err(DlError::Http)
+            .map(|mut req| {
+                https::add_headers(&mut req, &headers);
+                req
+            });
+        let transport = self.transport.clone();
+        let validator = self.validator.clone();
+        let timeouts = self.timeouts;
+        let response = req.into_future().and_then(move |req| {
+            timeout::deadline(
+                transport.request(req),
+                timeouts.first_byte,
+                DlError::FirstByteTimeout,
+            )
+        });
+        let response = response.and_then(move |res| match res.status() {
+            StatusCode::OK => match validator {
+                Some(ref v) if !v.matches(res.headers()) => Err(DlError::RemoteChanged),
+                _ => Ok(res),
+            },
+            StatusCode::PRECONDITION_FAILED => Err(DlError::RemoteChanged),
+            _ => Err(request_failed(&res)),
+        });
+        let sink = self.sink.clone();
+        let limiter = self.limiter.clone();
+        let source = self.source.clone();
+
+        response.and_then(move |res| {
+            let protocol = Protocol::of_response(&res);
+            throttle(
+                timeout::watch(res.into_body().map_err(DlError::from), &timeouts),
+                limiter,
+            )
+            .fold(0, move |written, chunk| {
+                let at = written;
+                let written = written + chunk.len() as u64;
+                match written > file_size {
+                    true => Either::A(future::err(DlError::LengthMismatch(file_size, written))),
+                    false => Either::B(sink.write_at(chunk.into_bytes(), at).map(move |_| written)),
+                }
+            })
+            .and_then(move |written| match written == file_size {
+                true => Ok(PieceReport {
+                    index: 0,
+                    offset: 0,
+                    length: file_size,
+                    retries: 0,
+                    protocol: Some(protocol),
+                    source,
+                }),
+                false => Err(DlError::LengthMismatch(file_size, written)),
             })
-            .map(move |_| HashChecker { path, etag })
+        })
     }
 }
 
-/// downloads a `piece_size`(d) chunk of the file, seeks to position `offset` and writes the chunk there
-pub fn download_piece(
-    client: &HttpsClient,
-    uri: &Uri,
+/// makes sure a response to a range request for `piece` is a `206 Partial Content` whose `Content-Range`
+/// covers exactly the requested bytes of a file of `file_size` bytes.
+///
+/// given a `validator`, a `412 Precondition Failed` (or a `200 OK` for a different version of the file than
+/// the one the validator describes) means the remote file has changed since we fetched its metadata
+fn validate_range_response(
+    res: &Response<Body>,
+    piece: Piece,
     file_size: u64,
-    piece_size: u64,
-    offset: u64,
-    path: PathBuf,
-) -> Box<dyn Future<Item = u64, Error = DlError> + Send> {
-    match build_range_request(uri, file_size, piece_size, offset) {
-        Err(err) => Box::new(future::err(err)),
-        Ok(req) => {
-            let response = client.request(req).map_err(|err| DlError::Hyper(err));
-            let file = OpenOptions::new()
-                .write(true)
-                .open(path)
-                .map_err(DlError::Io);
-            Box::new(
-                response
-                    .join(file)
-                    .and_then(move |(r, f)| write_to_file(r, f, offset))
-                    .map(move |_| offset),
-            )
+    validator: Option<&Validator>,
+) -> Result<(), DlError> {
+    match (res.status(), validator) {
+        (StatusCode::PARTIAL_CONTENT, _) => (),
+        (StatusCode::OK, Some(v)) if !v.matches(res.headers()) => {
+            return Err(DlError::RemoteChanged)
         }
+        (StatusCode::OK, _) => return Err(DlError::RangeIgnored),
+        (StatusCode::PRECONDITION_FAILED, Some(_)) => return Err(DlError::RemoteChanged),
+        _ => return Err(request_failed(res)),
     }
+    let expected = (piece.offset, piece.end() - 1, file_size);
+    res.headers()
+        .get("content-range")
+        .and_then(|val| val.to_str().ok())
+        .and_then(parse_content_range)
+        .filter(|actual| *actual == expected)
+        .map(|_| ())
+        .ok_or(DlError::ContentRangeMismatch)
 }
 
-/// parses a `response` into a stream and writes it to `offset` in file
-fn write_to_file(
-    response: Response<Body>,
-    file: File,
-    offset: u64,
-) -> impl Future<Item = File, Error = DlError> + Send {
-    file.seek(SeekFrom::Start(offset))
-        .map_err(DlError::Io)
-        .and_then(move |(file, _)| {
-            response
-                .into_body()
-                .map_err(DlError::Hyper)
-                .fold(file, write_chunk)
-        })
+/// the error for a response that failed outright: a `429 Too Many Requests` or `503 Service Unavailable` with a
+/// `Retry-After` (in seconds) tells us how long to wait before retrying
+fn request_failed(res: &Response<Body>) -> DlError {
+    let status = res.status();
+    let retry_after = res
+        .headers()
+        .get("retry-after")
+        .and_then(|val| val.to_str().ok())
+        .and_then(|secs| secs.trim().parse::<u64>().ok());
+    match (status, retry_after) {
+        (StatusCode::TOO_MANY_REQUESTS, Some(secs))
+        | (StatusCode::SERVICE_UNAVAILABLE, Some(secs)) => {
+            DlError::RetryAfter(status.as_u16(), Duration::from_secs(secs))
+        }
+        _ => DlError::RequestFailed(status.as_u16()),
+    }
+}
+
+/// parses a `Content-Range` header value of the form `bytes <first>-<last>/<total>`
+fn parse_content_range(header: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = header.strip_prefix("bytes ")?.splitn(2, '/');
+    let mut range = parts.next()?.splitn(2, '-');
+    let first = range.next()?.trim().parse::<u64>().ok()?;
+    let last = range.next()?.trim().parse::<u64>().ok()?;
+    let total = parts.next()?.trim().parse::<u64>().ok()?;
+    Some((first, last, total))
 }
 
-/// writes the contents of a buffer into a file, returning a handle to the file
-fn write_chunk<F, B>(file: F, buf: B) -> impl Future<Item = F, Error = DlError>
-where
-    F: AsyncWrite,
-    B: AsRef<[u8]>,
-{
-    io::write_all(file, buf)
-        .map(move |(f, _)| f)
-        .map_err(DlError::Io)
+/// parses a `response` into a stream and writes each chunk to its offset in the shared `sink`, claiming bytes
+/// from the `scheduler` as it goes and dropping the response as soon as the piece's (possibly moved) end is
+/// reached. resolves with whether the whole piece was written (`false` if the body ended early), failing if
+/// the body stalls or trickles in for longer than the idle and min-speed `timeouts` allow
+fn write_to_sink(
+    response: Response<Body>,
+    sink: Arc<dyn Sink>,
+    piece: Piece,
+    scheduler: Arc<Mutex<Scheduler>>,
+    timeouts: &Timeouts,
+    limiter: Option<RateLimiter>,
+) -> impl Future<Item = bool, Error = DlError> + Send {
+    let body = throttle(
+        timeout::watch(response.into_body().map_err(DlError::from), timeouts),
+        limiter,
+    );
+    future::loop_fn(body, move |body| {
+        let sink = sink.clone();
+        let scheduler = scheduler.clone();
+        body.into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(chunk, body)| match chunk {
+                None => Either::A(future::ok(Loop::Break(false))),
+                Some(chunk) => {
+                    let (at, claimed, done) = scheduler
+                        .lock()
+                        .expect("Scheduler lock poisoned")
+                        .claim(piece.index, chunk.len() as u64);
+                    let bytes = chunk.into_bytes().slice_to(claimed as usize);
+                    Either::B(sink.write_at(bytes, at).map(move |_| match done {
+                        true => Loop::Break(true),
+                        false => Loop::Continue(body),
+                    }))
+                }
+            })
+    })
 }
 
-/// builds a range GET request with appropriate begin and end points
+/// builds a range GET request with appropriate begin and end points, made conditional on the remote file
+/// still being the version described by `validator` (if any): `If-Range` makes a server that has a different
+/// version answer `200 OK` (rather than mixing bytes of two versions), and `If-Match` makes it answer `412`
 fn build_range_request(
     uri: &Uri,
-    file_size: u64,
-    piece_size: u64,
-    offset: u64,
+    piece: Piece,
+    validator: Option<&Validator>,
 ) -> Result<Request<Body>, DlError> {
-    Request::get(uri)
-        .header(
-            "Range",
-            format!(
-                "bytes={}-{}",
-                offset,
-                min(offset + piece_size, file_size) - 1
-            ),
-        )
-        .body(Body::empty())
-        .map_err(DlError::Http)
+    let mut req = Request::get(uri);
+    req.header(
+        "Range",
+        format!("bytes={}-{}", piece.offset, piece.end() - 1),
+    );
+    if let Some(v) = validator {
+        req.header("If-Range", v.header_value());
+        if let Validator::Etag(ref etag) = *v {
+            req.header("If-Match", etag.as_str());
+        }
+    }
+    req.body(Body::empty()).map_err(DlError::Http)
 }
 
 /// extracts the size (in bytes) from a file on disk
@@ -155,73 +871,754 @@ pub fn get_file_size(file: File) -> impl Future<Item = u64, Error = DlError> + S
     file.metadata().map(|(_, md)| md.len()).map_err(DlError::Io)
 }
 
-fn gen_offsets(file_size: u64, piece_size: u64) -> impl Stream<Item = u64, Error = ()> {
-    stream::iter_ok::<_, ()>((0..file_size).step_by(piece_size as usize))
+/// picks a piece size for a file: the configured size if there is one, otherwise one that gives each of
+/// `parallelism` connections several pieces to pull from the queue (either way, clamped to sane bounds)
+pub fn piece_size_for(file_size: u64, parallelism: usize, configured: Option<u64>) -> u64 {
+    let wanted = configured.unwrap_or_else(|| {
+        file_size / (max(parallelism, 1) as u64 * DEFAULT_PIECES_PER_CONNECTION)
+    });
+    wanted.clamp(MIN_PIECE_SIZE, MAX_PIECE_SIZE)
+}
+
+/// splits a file of `file_size` bytes into a queue of contiguous pieces of (at most) `piece_size` bytes
+pub fn plan_pieces(file_size: u64, piece_size: u64) -> Vec<Piece> {
+    let piece_size = max(piece_size, 1);
+    (0..file_size.div_ceil(piece_size))
+        .map(|index| {
+            let offset = index * piece_size;
+            Piece {
+                index,
+                offset,
+                length: min(piece_size, file_size - offset),
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
-mod download_tests {
-    use std::path::Path;
+pub(crate) mod download_tests {
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
 
+    use futures::stream;
+    use hyper::service::service_fn_ok;
+    use hyper::{Server, StatusCode};
     use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
 
     use crate::checksum;
-    use crate::https;
+    use crate::https::Network;
+    use crate::output::OutputFormat;
+    use crate::sink::MemorySink;
+    use crate::test_server::TestServer;
+    use crate::transport::{parse_range, FakeFile, FakeTransport, Reply};
+    use crate::DEFAULT_PARALLELISM;
+    use proptest::prelude::*;
 
     use super::*;
 
-    const FILE_URL: &'static str = "https://recurse-uploads-production.s3.amazonaws.com/b9349b0c-359a-473a-9441-c1bc54a96ca6/austin_guest_resume.pdf";
     const FILE_SIZE: u64 = 53_143;
-    const FILE_MD5_SUM: &'static str = "ac89ac31a669c13ec4ce037f1203022c";
 
     #[test]
     fn downloading_file_in_parallel() {
+        let file = FakeFile::generated(FILE_SIZE);
+        let md5 = file.md5();
+        let server = TestServer::builder()
+            .file("/resume.pdf", file)
+            .https()
+            .start();
         let fd = FileDownloader {
-            client: https::get_client(*DEFAULT_PARALLELISM),
-            uri: FILE_URL.parse::<Uri>().unwrap(),
-            path: PathBuf::from("data/foo_par.pdf"),
+            transport: server.transport(),
+            uri: server.url("/resume.pdf").parse::<Uri>().unwrap(),
+            destination: Destination::File(PathBuf::from("data/foo_par.pdf")),
             file_size: FILE_SIZE,
             etag: None,
+            validator: None,
+            mirrors: vec![],
+            addresses: vec![],
+            bound: vec![],
+            headers: HeaderMap::new(),
             parallelism: *DEFAULT_PARALLELISM,
+            piece_size: 4096,
+            retry: RetryPolicy::default(),
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+            reporter: Reporter::new(OutputFormat::Text),
         };
 
         let result = fd
             .fetch()
-            .and_then(|_| tokio_fs::metadata(Path::new("data/foo_par.pdf")).map_err(DlError::Io))
-            .map(|md| {
+            .and_then(|_| {
+                tokio_fs::metadata(Path::new("data/foo_par.pdf.part")).map_err(DlError::Io)
+            })
+            .map(move |md| {
                 assert_eq!(md.len(), FILE_SIZE);
                 assert!(
-                    checksum::md5sum_check(&Path::new("data/foo_par.pdf"), FILE_MD5_SUM)
+                    checksum::md5sum_check(Path::new("data/foo_par.pdf.part"), &md5)
                         .unwrap_or(false)
                 );
             });
 
         Runtime::new().unwrap().block_on(result).unwrap();
-        std::fs::remove_file(&Path::new("data/foo_par.pdf")).unwrap();
+        // one range request per (4 KiB) piece
+        assert_eq!(server.requests().len(), 13);
+        std::fs::remove_file(Path::new("data/foo_par.pdf.part")).unwrap();
+    }
+
+    fn offsets(pieces: Vec<Piece>) -> Vec<u64> {
+        pieces.iter().map(|piece| piece.offset).collect()
     }
 
     #[test]
-    fn generating_offsets() {
+    fn planning_pieces() {
         assert_eq!(
-            gen_offsets(10, 3).collect().wait().unwrap(),
-            vec![0, 3, 6, 9]
+            plan_pieces(10, 3),
+            vec![
+                Piece {
+                    index: 0,
+                    offset: 0,
+                    length: 3
+                },
+                Piece {
+                    index: 1,
+                    offset: 3,
+                    length: 3
+                },
+                Piece {
+                    index: 2,
+                    offset: 6,
+                    length: 3
+                },
+                Piece {
+                    index: 3,
+                    offset: 9,
+                    length: 1
+                },
+            ]
         );
         assert_eq!(
-            gen_offsets(FILE_SIZE, 4096).collect().wait().unwrap(),
+            offsets(plan_pieces(FILE_SIZE, 4096)),
             vec![
                 0, 4096, 8192, 12288, 16384, 20480, 24576, 28672, 32768, 36864, 40960, 45056, 49152
             ]
         )
     }
 
+    #[test]
+    fn planning_pieces_for_tiny_and_empty_files() {
+        assert_eq!(
+            plan_pieces(3, piece_size_for(3, 12, None)),
+            vec![Piece {
+                index: 0,
+                offset: 0,
+                length: 3
+            }]
+        );
+        assert_eq!(plan_pieces(0, piece_size_for(0, 12, None)), vec![]);
+    }
+
+    #[test]
+    fn backing_off() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.backoff(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(400));
+        assert_eq!(retry.backoff(20), Duration::from_millis(6400));
+        let retry = RetryPolicy {
+            backoff: Duration::from_secs(u64::MAX / 2),
+            ..retry
+        };
+        assert_eq!(retry.backoff(3), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn picking_piece_sizes() {
+        assert_eq!(piece_size_for(FILE_SIZE, 12, None), MIN_PIECE_SIZE);
+        assert_eq!(
+            piece_size_for(480 * 1024 * 1024, 12, None),
+            10 * 1024 * 1024
+        );
+        assert_eq!(piece_size_for(1 << 40, 1, None), MAX_PIECE_SIZE);
+        assert_eq!(piece_size_for(1 << 40, 0, Some(1_000_000)), 1_000_000);
+        assert_eq!(piece_size_for(1 << 40, 12, Some(1)), MIN_PIECE_SIZE);
+    }
+
+    #[test]
+    fn splitting_the_largest_in_flight_piece() {
+        let mut scheduler = Scheduler::new(plan_pieces(8 * MIN_PIECE_SIZE, 4 * MIN_PIECE_SIZE));
+        let first = scheduler.next_queued().unwrap();
+        let second = scheduler.next_queued().unwrap();
+        assert_eq!(scheduler.next_queued(), None);
+
+        assert_eq!(
+            scheduler.claim(first.index, MIN_PIECE_SIZE),
+            (0, MIN_PIECE_SIZE, false)
+        );
+        assert_eq!(
+            scheduler.claim(second.index, 3 * MIN_PIECE_SIZE),
+            (second.offset, 3 * MIN_PIECE_SIZE, false)
+        );
+
+        // the first piece has 3 * MIN_PIECE_SIZE left, so its tail gets split off...
+        let (from, tail) = scheduler.split_largest().unwrap();
+        assert_eq!(from, first.index);
+        assert_eq!(
+            tail,
+            Piece {
+                index: 2,
+                offset: 2 * MIN_PIECE_SIZE + MIN_PIECE_SIZE / 2,
+                length: MIN_PIECE_SIZE + MIN_PIECE_SIZE / 2,
+            }
+        );
+
+        // ...so the original request is cut short when it reaches the split point
+        assert_eq!(
+            scheduler.claim(first.index, 2 * MIN_PIECE_SIZE),
+            (MIN_PIECE_SIZE, MIN_PIECE_SIZE + MIN_PIECE_SIZE / 2, true)
+        );
+        assert_eq!(
+            scheduler.finish(first.index).unwrap(),
+            Piece {
+                index: 0,
+                offset: 0,
+                length: tail.offset,
+            }
+        );
+
+        // and nothing with less than 2 * MIN_PIECE_SIZE left is worth splitting
+        assert_eq!(scheduler.split_largest(), None);
+    }
+
+    pub(crate) fn test_content(size: u64) -> Arc<Vec<u8>> {
+        Arc::new((0..size).map(|i| (i % 251) as u8).collect())
+    }
+
+    /// serves whatever `respond` returns for each request (given the requested range) over http
+    pub(crate) fn serve<F>(rt: &mut Runtime, respond: F) -> SocketAddr
+    where
+        F: Fn(Option<(u64, u64)>) -> Response<Body> + Send + Sync + 'static,
+    {
+        let respond = Arc::new(respond);
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+            let respond = respond.clone();
+            service_fn_ok(move |req: Request<Body>| {
+                let range = req.headers().get("range").map(|val| {
+                    let mut bounds = val.to_str().unwrap()[6..]
+                        .split('-')
+                        .map(|n| n.parse::<u64>().unwrap());
+                    (bounds.next().unwrap(), bounds.next().unwrap())
+                });
+                respond(range)
+            })
+        });
+        let addr = server.local_addr();
+        rt.spawn(server.map_err(|err| panic!("test server failed: {}", err)));
+        addr
+    }
+
+    /// a `206` for bytes `start..=end` of `content`, streamed in 16 KiB chunks, one every `delay_millis`
+    pub(crate) fn partial_content(
+        content: &[u8],
+        start: u64,
+        end: u64,
+        delay_millis: u64,
+    ) -> Response<Body> {
+        let chunks: Vec<Vec<u8>> = content[start as usize..=end as usize]
+            .chunks(16 * 1024)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        let body = stream::iter_ok::<_, tokio::timer::Error>(chunks).and_then(move |chunk| {
+            Delay::new(Instant::now() + Duration::from_millis(delay_millis)).map(|_| chunk)
+        });
+        let mut res = Response::new(Body::wrap_stream(body));
+        *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+        res.headers_mut().insert(
+            "content-range",
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, content.len())).unwrap(),
+        );
+        res
+    }
+
+    pub(crate) fn local_downloader(
+        addr: SocketAddr,
+        path: &Path,
+        file_size: u64,
+    ) -> FileDownloader {
+        let client =
+            https::build_client(2, &Timeouts::default(), &Network::default(), false).unwrap();
+        let uri = format!("http://{}/file", addr).parse::<Uri>().unwrap();
+        downloader(SharedTransport::new(client), uri, path, file_size)
+    }
+
+    /// a downloader of `path` that gets the file from `transport` (as if from `https://example.com/file`)
+    fn fake_downloader(transport: FakeTransport, path: &Path, file_size: u64) -> FileDownloader {
+        let uri = Uri::from_static("https://example.com/file");
+        downloader(SharedTransport::new(transport), uri, path, file_size)
+    }
+
+    fn downloader(
+        transport: SharedTransport,
+        uri: Uri,
+        path: &Path,
+        file_size: u64,
+    ) -> FileDownloader {
+        FileDownloader {
+            transport,
+            uri,
+            destination: Destination::File(path.to_path_buf()),
+            file_size,
+            etag: None,
+            validator: None,
+            mirrors: vec![],
+            addresses: vec![],
+            bound: vec![],
+            headers: HeaderMap::new(),
+            parallelism: 2,
+            piece_size: file_size / 2,
+            retry: RetryPolicy::default(),
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+            reporter: Reporter::new(OutputFormat::Text),
+        }
+    }
+
+    #[test]
+    fn splitting_slow_pieces_near_the_end_of_a_download() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 16 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let c = content.clone();
+        // trickle out any response starting at the beginning of the file
+        let addr = serve(&mut rt, move |range| {
+            let (start, end) = range.unwrap();
+            partial_content(&c, start, end, if start == 0 { 20 } else { 0 })
+        });
+        let path = PathBuf::from("data/foo_split.bin");
+
+        let hc = rt
+            .block_on(local_downloader(addr, &path, file_size).fetch())
+            .unwrap();
+
+        // everything lands in the `.part` file, leaving `path` alone until the download is verified
+        assert_eq!(hc.path, disk::part_path(&path));
+        assert!(!path.exists());
+        // the second connection finishes its (fast) piece, then keeps stealing the tail of the (slow) first one
+        assert!(hc.pieces.len() > 2);
+        assert!(hc.pieces[0].length < file_size / 2);
+        assert_eq!(hc.pieces.iter().map(|p| p.length).sum::<u64>(), file_size);
+        assert_eq!(std::fs::read(&hc.path).unwrap(), *content);
+
+        std::fs::remove_file(&hc.path).unwrap();
+    }
+
+    #[test]
+    fn validating_range_responses() {
+        let content = test_content(1000);
+        let piece = Piece {
+            index: 0,
+            offset: 100,
+            length: 100,
+        };
+        let wrong_total = {
+            let mut res = partial_content(&content, 100, 199, 0);
+            res.headers_mut().insert(
+                "content-range",
+                HeaderValue::from_static("bytes 100-199/999"),
+            );
+            res
+        };
+        let mut not_satisfiable = Response::new(Body::empty());
+        *not_satisfiable.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+
+        assert!(validate_range_response(
+            &partial_content(&content, 100, 199, 0),
+            piece,
+            1000,
+            None
+        )
+        .is_ok());
+        assert_eq!(
+            validate_range_response(&partial_content(&content, 100, 299, 0), piece, 1000, None)
+                .unwrap_err()
+                .code(),
+            "content_range_mismatch"
+        );
+        assert_eq!(
+            validate_range_response(&wrong_total, piece, 1000, None)
+                .unwrap_err()
+                .code(),
+            "content_range_mismatch"
+        );
+        assert_eq!(
+            validate_range_response(&Response::new(Body::empty()), piece, 1000, None)
+                .unwrap_err()
+                .code(),
+            "range_ignored"
+        );
+        assert_eq!(
+            validate_range_response(&not_satisfiable, piece, 1000, None)
+                .unwrap_err()
+                .to_string(),
+            "Request failed with status code 416"
+        );
+    }
+
+    #[test]
+    fn detecting_remote_changes() {
+        let content = test_content(1000);
+        let piece = Piece {
+            index: 0,
+            offset: 100,
+            length: 100,
+        };
+        let validator = Validator::Etag(String::from("\"v1\""));
+        let full_body = |etag| {
+            let mut res = Response::new(Body::empty());
+            res.headers_mut()
+                .insert("etag", HeaderValue::from_static(etag));
+            res
+        };
+        let mut precondition_failed = Response::new(Body::empty());
+        *precondition_failed.status_mut() = StatusCode::PRECONDITION_FAILED;
+
+        let validate = |res: &Response<Body>| {
+            validate_range_response(res, piece, 1000, Some(&validator)).map_err(|err| err.code())
+        };
+        assert_eq!(validate(&partial_content(&content, 100, 199, 0)), Ok(()));
+        assert_eq!(validate(&full_body("\"v2\"")), Err("remote_changed"));
+        assert_eq!(validate(&full_body("\"v1\"")), Err("range_ignored"));
+        assert_eq!(validate(&precondition_failed), Err("remote_changed"));
+    }
+
+    #[test]
+    fn building_conditional_range_requests() {
+        let uri = Uri::from_static("http://foo.com/file");
+        let piece = Piece {
+            index: 0,
+            offset: 100,
+            length: 100,
+        };
+        let header = |req: &Request<Body>, name| {
+            req.headers()
+                .get(name)
+                .map(|val| val.to_str().unwrap().to_string())
+        };
+
+        let req = build_range_request(&uri, piece, None).unwrap();
+        assert_eq!(header(&req, "range"), Some(String::from("bytes=100-199")));
+        assert_eq!(header(&req, "if-range"), None);
+
+        let etag = Validator::Etag(String::from("\"v1\""));
+        let req = build_range_request(&uri, piece, Some(&etag)).unwrap();
+        assert_eq!(header(&req, "if-range"), Some(String::from("\"v1\"")));
+        assert_eq!(header(&req, "if-match"), Some(String::from("\"v1\"")));
+
+        let date = Validator::LastModified(String::from("Wed, 21 Oct 2015 07:28:00 GMT"));
+        let req = build_range_request(&uri, piece, Some(&date)).unwrap();
+        assert_eq!(
+            header(&req, "if-range"),
+            Some(String::from("Wed, 21 Oct 2015 07:28:00 GMT"))
+        );
+        assert_eq!(header(&req, "if-match"), None);
+    }
+
+    #[test]
+    fn aborting_when_the_remote_file_changes() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let requests = Arc::new(Mutex::new(0));
+        // serve the first piece, then pretend the file was replaced
+        let addr = serve(&mut rt, move |range| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            match *requests {
+                1 => {
+                    let (start, end) = range.unwrap();
+                    partial_content(&content, start, end, 0)
+                }
+                _ => {
+                    let mut res = Response::new(Body::empty());
+                    *res.status_mut() = StatusCode::PRECONDITION_FAILED;
+                    res
+                }
+            }
+        });
+        let path = PathBuf::from("data/foo_changed.bin");
+
+        let fd = FileDownloader {
+            parallelism: 1,
+            piece_size: MIN_PIECE_SIZE,
+            validator: Some(Validator::Etag(String::from("\"v1\""))),
+            ..local_downloader(addr, &path, file_size)
+        };
+        let err = rt.block_on(fd.fetch()).err().unwrap();
+
+        assert_eq!(err.code(), "remote_changed");
+        assert!(!err.is_transient());
+
+        std::fs::remove_file(disk::part_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn parsing_content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-99/1000"), Some((0, 99, 1000)));
+        assert_eq!(parse_content_range("bytes 0-99/*"), None);
+        assert_eq!(parse_content_range("0-99/1000"), None);
+    }
+
+    #[test]
+    fn retrying_short_reads() {
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let file = FakeFile::generated(file_size).without_content_length();
+        let f = file.clone();
+        let requests = Arc::new(Mutex::new(0));
+        // end the body of the first two responses halfway through (while claiming the whole range)
+        let transport = FakeTransport::new(move |req| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            let range = req.headers()["range"].to_str().unwrap();
+            let (start, end) = parse_range(range).unwrap();
+            match *requests <= 2 {
+                true => {
+                    let half = Request::get("/")
+                        .header("range", format!("bytes={}-{}", start, (start + end) / 2))
+                        .body(Body::empty())
+                        .unwrap();
+                    let mut res = f.respond(&half);
+                    res.headers_mut().insert(
+                        "content-range",
+                        HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, file_size))
+                            .unwrap(),
+                    );
+                    Reply::ok(res)
+                }
+                false => Reply::ok(f.respond(req)),
+            }
+        });
+        let path = PathBuf::from("data/foo_short.bin");
+
+        let hc = Runtime::new()
+            .unwrap()
+            .block_on(fake_downloader(transport, &path, file_size).fetch())
+            .unwrap();
+
+        assert!(hc.pieces.iter().all(|p| p.retries == 1));
+        assert_eq!(hc.pieces.iter().map(|p| p.length).sum::<u64>(), file_size);
+        assert_eq!(std::fs::read(&hc.path).unwrap(), *file.content);
+
+        std::fs::remove_file(&hc.path).unwrap();
+    }
+
+    #[test]
+    fn retrying_stalled_pieces() {
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let file = FakeFile::generated(file_size);
+        let f = file.clone();
+        let requests = Arc::new(Mutex::new(0));
+        // the first response sends its headers, then stalls
+        let transport = FakeTransport::new(move |req| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            match *requests {
+                1 => Reply::ok(f.respond(req)).trickle(Duration::from_secs(60)),
+                _ => Reply::ok(f.respond(req)),
+            }
+        });
+        let path = PathBuf::from("data/foo_stall.bin");
+
+        let fd = FileDownloader {
+            parallelism: 1,
+            piece_size: file_size,
+            timeouts: Timeouts {
+                idle: Some(Duration::from_millis(100)),
+                ..Timeouts::default()
+            },
+            ..fake_downloader(transport, &path, file_size)
+        };
+        let hc = Runtime::new().unwrap().block_on(fd.fetch()).unwrap();
+
+        assert_eq!(hc.pieces.len(), 1);
+        assert_eq!(hc.pieces[0].retries, 1);
+        assert_eq!(std::fs::read(&hc.path).unwrap(), *file.content);
+
+        std::fs::remove_file(&hc.path).unwrap();
+    }
+
+    #[test]
+    fn giving_up_on_mismatched_content_ranges() {
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let file = FakeFile::generated(file_size);
+        // always answer with the first 100 bytes of the file, whatever was asked for
+        let transport = FakeTransport::new(move |_| {
+            let first = Request::get("/")
+                .header("range", "bytes=0-99")
+                .body(Body::empty())
+                .unwrap();
+            Reply::ok(file.respond(&first))
+        });
+        let path = PathBuf::from("data/foo_mismatch.bin");
+
+        let fd = FileDownloader {
+            retry: RetryPolicy {
+                max_retries: 1,
+                ..RetryPolicy::default()
+            },
+            ..fake_downloader(transport, &path, file_size)
+        };
+        let err = Runtime::new().unwrap().block_on(fd.fetch()).err().unwrap();
+
+        assert_eq!(err.code(), "content_range_mismatch");
+        // the file was preallocated, but none of the mismatched bytes made it into it
+        let written = std::fs::read(disk::part_path(&path)).unwrap();
+        assert_eq!(written.len() as u64, file_size);
+        assert!(written.iter().all(|b| *b == 0));
+
+        std::fs::remove_file(disk::part_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn falling_back_to_a_single_stream_when_range_is_ignored() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let c = content.clone();
+        let addr = serve(&mut rt, move |_| Response::new(Body::from(c.to_vec())));
+        let path = PathBuf::from("data/foo_single.bin");
+
+        let hc = rt
+            .block_on(local_downloader(addr, &path, file_size).fetch())
+            .unwrap();
+
+        assert_eq!(
+            hc.pieces,
+            vec![PieceReport {
+                index: 0,
+                offset: 0,
+                length: file_size,
+                retries: 0,
+                protocol: Some(Protocol::Http11),
+                source: format!("http://{}/file", addr),
+            }]
+        );
+        assert_eq!(std::fs::read(&hc.path).unwrap(), *content);
+
+        std::fs::remove_file(&hc.path).unwrap();
+    }
+
+    #[test]
+    fn streaming_pieces_in_order() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 16 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let c = content.clone();
+        let requested = Arc::new(Mutex::new(vec![]));
+        let r = requested.clone();
+        // the first piece trickles in, so the others arrive (and have to be held back) first
+        let addr = serve(&mut rt, move |range| {
+            let (start, end) = range.unwrap();
+            r.lock().unwrap().push(start);
+            partial_content(&c, start, end, if start == 0 { 20 } else { 0 })
+        });
+        let downloader = FileDownloader {
+            destination: Destination::Stdout,
+            parallelism: 4,
+            ..local_downloader(addr, Path::new("-"), file_size)
+        };
+
+        let bytes = rt
+            .block_on(downloader.fetch_ordered(4 * MIN_PIECE_SIZE).concat2())
+            .unwrap();
+
+        assert_eq!(bytes.as_ref(), &content[..]);
+        // pieces are sized to fit the buffer, and none past it are requested until the first one is done
+        let requested = requested.lock().unwrap();
+        assert_eq!(requested.len(), 16);
+        assert!(requested[..4]
+            .iter()
+            .all(|&start| start < 4 * MIN_PIECE_SIZE));
+
+        let addr = serve(&mut rt, move |_| {
+            Response::new(Body::from(content.to_vec()))
+        });
+        let single = local_downloader(addr, Path::new("-"), file_size);
+        let bytes = rt
+            .block_on(single.fetch_ordered(MIN_PIECE_SIZE).concat2())
+            .unwrap();
+        assert_eq!(bytes.len() as u64, file_size);
+    }
+
+    #[test]
+    fn fetching_into_any_sink() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 4 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let c = content.clone();
+        let addr = serve(&mut rt, move |range| match range {
+            Some((start, end)) if start < file_size => partial_content(&c, start, end, 0),
+            _ => {
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                res
+            }
+        });
+        let downloader = |file_size| FileDownloader {
+            parallelism: 4,
+            ..local_downloader(addr, Path::new("-"), file_size)
+        };
+
+        let memory = MemorySink::new(file_size);
+        let pieces = rt
+            .block_on(downloader(file_size).fetch_into(Arc::new(memory.clone())))
+            .unwrap();
+        assert_eq!(memory.contents(), *content);
+        assert_eq!(pieces.iter().map(|p| p.length).sum::<u64>(), file_size);
+
+        // a sink is aborted if the download fails
+        let memory = MemorySink::new(2 * file_size);
+        let result = rt.block_on(downloader(2 * file_size).fetch_into(Arc::new(memory.clone())));
+        assert_eq!(result.unwrap_err().code(), "request_failed");
+        assert!(memory.contents().is_empty());
+    }
+
     #[test]
     fn buffering_a_stream() {
-        let results = gen_offsets(64, 2)
-            .map(|n| future::ok(n * 2))
+        let results = stream::iter_ok::<_, ()>(plan_pieces(64, 2))
+            .map(|piece| future::ok(piece.offset * 2))
             .buffered(8)
             .collect()
             .wait()
             .unwrap();
         assert_eq!(results, (0..128).step_by(4).collect::<Vec<u64>>());
     }
+
+    proptest! {
+        #[test]
+        fn planned_pieces_cover_the_file_exactly(
+            (piece_size, file_size) in (1u64..1 << 32)
+                .prop_flat_map(|piece_size| (Just(piece_size), 0..piece_size * 2_000))
+        ) {
+            let pieces = plan_pieces(file_size, piece_size);
+
+            let mut next_offset = 0;
+            for (i, piece) in pieces.iter().enumerate() {
+                prop_assert_eq!(piece.index, i as u64);
+                prop_assert_eq!(piece.offset, next_offset);
+                prop_assert!(piece.length > 0 && piece.length <= piece_size);
+                next_offset = piece.end();
+            }
+            prop_assert_eq!(next_offset, file_size);
+        }
+
+        #[test]
+        fn picked_piece_sizes_are_within_bounds(
+            file_size in 0u64..1 << 42,
+            parallelism in 0usize..256,
+            configured in proptest::option::of(1u64..1 << 32),
+        ) {
+            let piece_size = piece_size_for(file_size, parallelism, configured);
+            prop_assert!((MIN_PIECE_SIZE..=MAX_PIECE_SIZE).contains(&piece_size));
+            prop_assert!(plan_pieces(file_size, piece_size).len() as u64 <= file_size / MIN_PIECE_SIZE + 1);
+        }
+    }
 }
@@ -0,0 +1,673 @@
+use std::cmp::min;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{future, stream, Future};
+use hyper::header::HeaderValue;
+use hyper::{Body, Chunk, Method, Request, Response, StatusCode, Uri};
+use native_tls::{HandshakeError, TlsConnector};
+
+use crate::disk;
+use crate::dns::{self, Override, Resolver};
+use crate::error::DlError;
+use crate::https::{self, Network, Protocol};
+use crate::local;
+use crate::timeout::Timeouts;
+use crate::transport::{parse_range, ResponseFuture, SharedTransport, Transport};
+
+/// how much of a file is read from a data connection at a time
+pub const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// whether `uri` is that of a file on an ftp server (`ftps` speaks implicit tls, on port 990 by default)
+pub fn is_ftp(uri: &Uri) -> bool {
+    matches!(uri.scheme_str(), Some("ftp") | Some("ftps"))
+}
+
+/// a `Transport` that answers `ftp://` and `ftps://` requests as a range-supporting web server would (one ftp
+/// session per request), and sends every other request on to `remote`
+pub struct FtpTransport {
+    remote: SharedTransport,
+    resolver: Resolver,
+    resolve: Vec<Override>,
+    /// for `ftps`, trusting `network`'s ca bundle
+    tls: TlsConnector,
+    timeouts: Timeouts,
+}
+
+impl FtpTransport {
+    pub fn new(
+        remote: SharedTransport,
+        timeouts: &Timeouts,
+        network: &Network,
+    ) -> Result<FtpTransport, DlError> {
+        Ok(FtpTransport {
+            remote,
+            resolver: https::resolver_for(1, network)?,
+            resolve: network.resolve.clone(),
+            tls: https::tls_connector(network.ca_bundle.as_deref(), &[])?,
+            timeouts: *timeouts,
+        })
+    }
+}
+
+impl Transport for FtpTransport {
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        if !is_ftp(req.uri()) {
+            return self.remote.request(req);
+        }
+        let target = match Target::of(req.uri()) {
+            Ok(target) => target,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(String::from)
+        };
+        let (range, if_range) = (header("range"), header("if-range"));
+        let head = *req.method() == Method::HEAD;
+        let tls = match req.uri().scheme_str() {
+            Some("ftps") => Some(self.tls.clone()),
+            _ => None,
+        };
+        let timeouts = self.timeouts;
+        let addrs = dns::lookup(&self.resolver, &self.resolve, &target.host, target.port);
+        Box::new(addrs.and_then(move |addrs| {
+            disk::run_blocking(move || {
+                let mut session = Session::connect(&addrs, &target, tls.clone(), &timeouts)?;
+                fetch(
+                    &mut session,
+                    &target,
+                    range.as_deref(),
+                    if_range.as_deref(),
+                    head,
+                )
+                .map(|fetched| (session, fetched))
+            })
+            .map(|(session, fetched)| respond(session, fetched))
+        }))
+    }
+}
+
+/// what a uri asks an ftp server for
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    /// relative to the login directory, unless it starts with `/` (as in `ftp://host//abs/path`)
+    path: String,
+}
+
+impl Target {
+    fn of(uri: &Uri) -> Result<Target, DlError> {
+        let invalid = || DlError::InvalidConfig("ftp urls must be percent-encoded");
+        // (a line break would end the command it's sent in, and start another)
+        let decode = |part: &str| match local::percent_decode(part).map(String::from_utf8) {
+            Some(Ok(part)) if part.contains(['\r', '\n', '\0']) => Err(DlError::InvalidConfig(
+                "ftp urls can't contain line breaks or nul characters",
+            )),
+            Some(Ok(part)) => Ok(part),
+            _ => Err(invalid()),
+        };
+        let authority = uri
+            .authority_part()
+            .map_or("", |authority| authority.as_str());
+        let (user, password) = match authority.rsplit_once('@') {
+            Some((userinfo, _)) => match userinfo.split_once(':') {
+                Some((user, password)) => (decode(user)?, decode(password)?),
+                None => (decode(userinfo)?, String::new()),
+            },
+            None => (String::from("anonymous"), String::from("dl@")),
+        };
+        Ok(Target {
+            host: uri.host().unwrap_or_default().to_string(),
+            port: dns::port_of(uri.scheme_str(), uri.port_u16()),
+            user,
+            password,
+            path: decode(uri.path().strip_prefix('/').unwrap_or(""))?,
+        })
+    }
+}
+
+/// a control (or data) connection: plain tcp, or tls over it
+trait Channel: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Channel for T {}
+
+/// the code of a server's reply, and its (last) line
+#[derive(Debug, Clone, PartialEq)]
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+impl Reply {
+    /// the reply as a failure: `4xx` ones fail the request (to be retried), and others become http statuses
+    fn failure(self) -> Result<Fetched, DlError> {
+        match self.code {
+            400..=499 => Err(DlError::Ftp(format!("{} {}", self.code, self.text))),
+            530 | 532 => Ok(Fetched::Status(StatusCode::FORBIDDEN)),
+            550 => Ok(Fetched::Status(StatusCode::NOT_FOUND)),
+            _ => Ok(Fetched::Status(StatusCode::BAD_GATEWAY)),
+        }
+    }
+}
+
+/// a control connection (which `fetch` logs in)
+struct Session {
+    control: BufReader<Box<dyn Channel>>,
+    /// the server's address, where passive data connections go whatever a `PASV` reply says
+    addr: SocketAddr,
+    host: String,
+    tls: Option<TlsConnector>,
+    timeouts: Timeouts,
+}
+
+/// what a session fetched for a request (`modified` is the file's `MDTM` as an http date)
+enum Fetched {
+    /// the file's size (for a `HEAD`)
+    Size { size: u64, modified: Option<String> },
+    /// the data connection the file is being sent over, from `start` up to `end`
+    Data {
+        data: Box<dyn Channel>,
+        size: u64,
+        modified: Option<String>,
+        start: u64,
+        end: u64,
+    },
+    /// a failure to answer with this status
+    Status(StatusCode),
+}
+
+impl Session {
+    /// connects to the first of `addrs` (on `target`'s port) to answer, and waits for its greeting
+    fn connect(
+        addrs: &[IpAddr],
+        target: &Target,
+        tls: Option<TlsConnector>,
+        timeouts: &Timeouts,
+    ) -> Result<Session, DlError> {
+        let mut last = DlError::Ftp(format!("{}: no addresses", target.host));
+        for addr in addrs {
+            let addr = SocketAddr::new(*addr, target.port);
+            let control = match open(addr, timeouts) {
+                Ok(tcp) => tcp,
+                Err(err) => {
+                    last = err;
+                    continue;
+                }
+            };
+            let host = target.host.trim_start_matches('[').trim_end_matches(']');
+            let mut session = Session {
+                control: BufReader::new(secure(tls.as_ref(), host, Box::new(control))?),
+                addr,
+                host: host.to_string(),
+                tls,
+                timeouts: *timeouts,
+            };
+            session.expect(&[220])?;
+            return Ok(session);
+        }
+        Err(last)
+    }
+
+    /// the server's next reply (reading past the lines of a multi-line one)
+    fn reply(&mut self) -> Result<Reply, DlError> {
+        let mut code = None;
+        loop {
+            let mut line = String::new();
+            if self.control.read_line(&mut line).map_err(failed)? == 0 {
+                return Err(DlError::Ftp(String::from("connection closed")));
+            }
+            let line = line.trim_end();
+            let unexpected = || DlError::Ftp(format!("unexpected reply: {}", line));
+            let expected = match code {
+                Some(code) => code,
+                None => *code.insert(
+                    line.get(..3)
+                        .and_then(|code| code.parse::<u16>().ok())
+                        .ok_or_else(unexpected)?,
+                ),
+            };
+            // (a multi-line reply ends with a line of its code, followed by a space or nothing)
+            let last = line.get(..3) == Some(expected.to_string().as_str())
+                && matches!(line.get(3..4), None | Some(" "));
+            if last {
+                return Ok(Reply {
+                    code: expected,
+                    text: line.get(4..).unwrap_or_default().to_string(),
+                });
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<Reply, DlError> {
+        let control = self.control.get_mut();
+        control
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .and_then(|_| control.flush())
+            .map_err(failed)?;
+        self.reply()
+    }
+
+    /// the next reply, failing unless it has one of the `codes`
+    fn expect(&mut self, codes: &[u16]) -> Result<Reply, DlError> {
+        let reply = self.reply()?;
+        match codes.contains(&reply.code) {
+            true => Ok(reply),
+            false => Err(DlError::Ftp(format!("{} {}", reply.code, reply.text))),
+        }
+    }
+
+    /// opens a passive data connection, with `EPSV` or else `PASV`
+    fn passive(&mut self) -> Result<Result<TcpStream, Reply>, DlError> {
+        let mut reply = self.command("EPSV")?;
+        let port = match reply.code {
+            229 => extended_passive_port(&reply.text),
+            _ => {
+                reply = self.command("PASV")?;
+                match reply.code {
+                    227 => passive_port(&reply.text),
+                    _ => return Ok(Err(reply)),
+                }
+            }
+        };
+        let port = port.ok_or_else(|| DlError::Ftp(format!("unexpected reply: {}", reply.text)))?;
+        open(SocketAddr::new(self.addr.ip(), port), &self.timeouts).map(Ok)
+    }
+
+    /// says goodbye (if the server's still listening)
+    fn quit(mut self) {
+        let _ = self.command("QUIT");
+    }
+}
+
+/// logs `session` in and fetches the file's size, or the `range` of it asked for (unless `if_range` is stale)
+fn fetch(
+    session: &mut Session,
+    target: &Target,
+    range: Option<&str>,
+    if_range: Option<&str>,
+    head: bool,
+) -> Result<Fetched, DlError> {
+    let mut reply = session.command(&format!("USER {}", target.user))?;
+    if reply.code == 331 {
+        reply = session.command(&format!("PASS {}", target.password))?;
+    }
+    if reply.code != 230 && reply.code != 202 {
+        return reply.failure();
+    }
+    if session.tls.is_some() {
+        // (so that data connections are encrypted too)
+        session.command("PBSZ 0")?;
+        let reply = session.command("PROT P")?;
+        if reply.code != 200 {
+            return reply.failure();
+        }
+    }
+    let reply = session.command("TYPE I")?;
+    if reply.code != 200 {
+        return reply.failure();
+    }
+    let reply = session.command(&format!("SIZE {}", target.path))?;
+    let size = match (reply.code, reply.text.trim().parse::<u64>()) {
+        (213, Ok(size)) => size,
+        (213, Err(_)) => return Err(DlError::ParseContentLength),
+        _ => return reply.failure(),
+    };
+    // (servers that don't know `MDTM` just don't give the file a validator)
+    let reply = session.command(&format!("MDTM {}", target.path))?;
+    let modified = match reply.code {
+        213 => modified_time(&reply.text).map(local::http_date),
+        _ => None,
+    };
+    if head {
+        return Ok(Fetched::Size { size, modified });
+    }
+    let range = range
+        .filter(|_| if_range.is_none_or(|date| modified.as_deref() == Some(date)))
+        .and_then(parse_range);
+    let (start, end) = match range {
+        None => (0, size),
+        Some((start, end)) if start <= end && end < size => (start, end + 1),
+        Some(_) => return Ok(Fetched::Status(StatusCode::RANGE_NOT_SATISFIABLE)),
+    };
+    let data = match session.passive()? {
+        Ok(data) => data,
+        Err(reply) => return reply.failure(),
+    };
+    if start > 0 {
+        let reply = session.command(&format!("REST {}", start))?;
+        if reply.code != 350 {
+            return reply.failure();
+        }
+    }
+    let reply = session.command(&format!("RETR {}", target.path))?;
+    if reply.code != 150 && reply.code != 125 {
+        return reply.failure();
+    }
+    Ok(Fetched::Data {
+        data: secure(session.tls.as_ref(), &session.host, Box::new(data))?,
+        size,
+        modified,
+        start,
+        end,
+    })
+}
+
+/// answers a request with what its session fetched, streaming the data connection as the body
+fn respond(session: Session, fetched: Fetched) -> Response<Body> {
+    let (status, body, (size, modified, start, end)) = match fetched {
+        Fetched::Size { size, modified } => {
+            session.quit();
+            (StatusCode::OK, Body::empty(), (size, modified, 0, size))
+        }
+        Fetched::Status(status) => {
+            session.quit();
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = status;
+            res.extensions_mut().insert(Protocol::Ftp);
+            return res;
+        }
+        Fetched::Data {
+            data,
+            size,
+            modified,
+            start,
+            end,
+        } => {
+            let status = match (start, end) {
+                (0, end) if end == size => StatusCode::OK,
+                _ => StatusCode::PARTIAL_CONTENT,
+            };
+            let data = Arc::new(Mutex::new(Some((session, data))));
+            let chunks = stream::unfold(start, move |at| {
+                let data = data.clone();
+                if at >= end {
+                    // (a transfer that isn't over yet is aborted by its connections closing)
+                    data.lock().unwrap().take();
+                    return None;
+                }
+                let len = min(READ_CHUNK_SIZE as u64, end - at) as usize;
+                Some(disk::run_blocking(move || {
+                    let mut data = data.lock().unwrap();
+                    let (_, ref mut channel) = data.as_mut().ok_or(DlError::ShortRead)?;
+                    let mut buf = vec![0; len];
+                    match channel.read(&mut buf).map_err(failed)? {
+                        0 => Err(DlError::ShortRead),
+                        n => {
+                            buf.truncate(n);
+                            Ok((Chunk::from(buf), at + n as u64))
+                        }
+                    }
+                }))
+            });
+            (
+                status,
+                Body::wrap_stream(chunks),
+                (size, modified, start, end),
+            )
+        }
+    };
+
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    res.extensions_mut().insert(Protocol::Ftp);
+    let headers = res.headers_mut();
+    headers.insert("content-length", HeaderValue::from(end - start));
+    headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+    if let Some(date) = modified {
+        headers.insert("last-modified", HeaderValue::from_str(&date).unwrap());
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        let range = format!("bytes {}-{}/{}", start, end - 1, size);
+        headers.insert("content-range", HeaderValue::from_str(&range).unwrap());
+    }
+    res
+}
+
+/// `channel` wrapped in tls for `host`, if there's a `tls` connector
+fn secure(
+    tls: Option<&TlsConnector>,
+    host: &str,
+    channel: Box<dyn Channel>,
+) -> Result<Box<dyn Channel>, DlError> {
+    match tls {
+        None => Ok(channel),
+        Some(tls) => tls
+            .connect(host, channel)
+            .map(|tls| Box::new(tls) as Box<dyn Channel>)
+            .map_err(|err| match err {
+                HandshakeError::Failure(err) => DlError::Ftp(format!("tls: {}", err)),
+                // (a blocking handshake only stops short when the connection's read timeout ran out)
+                HandshakeError::WouldBlock(_) => DlError::TlsHandshakeTimeout,
+            }),
+    }
+}
+
+/// a tcp connection to `addr`, with the `connect` timeout, and the `idle` one for its reads and writes
+fn open(addr: SocketAddr, timeouts: &Timeouts) -> Result<TcpStream, DlError> {
+    let tcp = match timeouts.connect {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    };
+    let tcp = tcp.map_err(|err| match err.kind() {
+        io::ErrorKind::TimedOut => DlError::ConnectTimeout,
+        _ => DlError::Ftp(format!("{}: {}", addr, err)),
+    })?;
+    let idle = timeouts.idle.or(timeouts.first_byte);
+    tcp.set_read_timeout(idle)
+        .and_then(|_| tcp.set_write_timeout(idle))
+        .and_then(|_| tcp.set_nodelay(true))
+        .map_err(DlError::Io)?;
+    Ok(tcp)
+}
+
+/// an error reading or writing a connection, as a (transient) `DlError`
+fn failed(err: io::Error) -> DlError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => DlError::IdleTimeout,
+        _ => DlError::Ftp(err.to_string()),
+    }
+}
+
+/// the time in an `MDTM` reply: `YYYYMMDDhhmmss[.sss]`, in utc
+fn modified_time(text: &str) -> Option<SystemTime> {
+    let text = text.trim();
+    let field = |at: usize, len: usize| match text.get(at..at + len) {
+        Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => digits.parse::<u64>().ok(),
+        _ => None,
+    };
+    let (year, month, day) = (field(0, 4)?, field(4, 2)?, field(6, 2)?);
+    let (hour, minute, second) = (field(8, 2)?, field(10, 2)?, field(12, 2)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    // the days since the epoch of the civil date, counting years from march (as `local::http_date` does)
+    let year = year.checked_sub(u64::from(month <= 2))?;
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hour * 3600 + minute * 60 + second))
+}
+
+/// the port in an `EPSV` reply: `Entering Extended Passive Mode (|||<port>|)`
+fn extended_passive_port(text: &str) -> Option<u16> {
+    let inner = parenthesized(text)?;
+    inner.trim_matches('|').parse().ok()
+}
+
+/// what's between the parentheses of a reply, if anything
+fn parenthesized(text: &str) -> Option<&str> {
+    text.get(text.find('(')? + 1..text.rfind(')')?)
+}
+
+/// the port in a `PASV` reply: `Entering Passive Mode (h1,h2,h3,h4,p1,p2)` (ignoring the address, as curl does)
+fn passive_port(text: &str) -> Option<u16> {
+    let inner = parenthesized(text)?;
+    let numbers = inner
+        .split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    match numbers[..] {
+        [_, _, _, _, p1, p2] => Some(u16::from(p1) << 8 | u16::from(p2)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod ftp_tests {
+    use super::*;
+    use crate::test_server::TestServer;
+    use crate::transport::FakeFile;
+    use futures::Stream;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn reading_ftp_urls() {
+        let target = |url: &str| Target::of(&url.parse::<Uri>().unwrap()).unwrap();
+        assert_eq!(
+            target("ftp://ftp.example.com/pub/a%20b.iso"),
+            Target {
+                host: String::from("ftp.example.com"),
+                port: 21,
+                user: String::from("anonymous"),
+                password: String::from("dl@"),
+                path: String::from("pub/a b.iso"),
+            }
+        );
+        let target = target("ftps://me:p%40ss@[::1]:2121//srv/a");
+        assert_eq!((target.port, target.user.as_str()), (2121, "me"));
+        assert_eq!(
+            (target.password.as_str(), target.path.as_str()),
+            ("p@ss", "/srv/a")
+        );
+        assert_eq!(dns::port_of(Some("ftps"), None), 990);
+        for injected in [
+            "ftp://host/a%0D%0ADELE%20b",
+            "ftp://me%0A:pw@host/a",
+            "ftp://host/a%00",
+        ] {
+            assert_eq!(
+                Target::of(&injected.parse::<Uri>().unwrap())
+                    .unwrap_err()
+                    .to_string(),
+                "Invalid download configuration: ftp urls can't contain line breaks or nul characters"
+            );
+        }
+
+        assert_eq!(
+            extended_passive_port("Entering Extended Passive Mode (|||6446|)"),
+            Some(6446)
+        );
+        assert_eq!(
+            passive_port("Entering Passive Mode (10,0,0,2,25,46)."),
+            Some(25 << 8 | 46)
+        );
+        assert_eq!(passive_port("Entering Passive Mode (10,0,0,2)"), None);
+        assert_eq!(passive_port(") ("), None);
+        assert_eq!(extended_passive_port(")|||6446|("), None);
+
+        let date = |text: &str| modified_time(text).map(local::http_date);
+        assert_eq!(
+            date("20151021072800").as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert_eq!(
+            date("20000229235959.123").as_deref(),
+            Some("Tue, 29 Feb 2000 23:59:59 GMT")
+        );
+        assert_eq!(date("2015102107280"), None);
+        assert_eq!(date("20151321072800"), None);
+        assert_eq!(date("+0151021072800"), None);
+        assert_eq!(date("19691231235959"), None);
+    }
+
+    #[test]
+    fn fetching_files_from_ftp_servers() {
+        let file = FakeFile::generated(100_000);
+        let mut rt = Runtime::new().unwrap();
+        for server in [
+            TestServer::builder()
+                .file("/file", file.clone())
+                .ftp()
+                .start(),
+            TestServer::builder()
+                .file("/file", file.clone())
+                .ftp()
+                .https()
+                .start(),
+        ] {
+            let transport = server.transport();
+            let mut send = |req: Request<Body>| {
+                rt.block_on(transport.request(req).and_then(|res| {
+                    let (parts, body) = res.into_parts();
+                    body.concat2()
+                        .map_err(DlError::from)
+                        .map(move |body| (parts, body.to_vec()))
+                }))
+            };
+
+            let (head, _) = send(
+                Request::head(server.url("/file"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(head.status, StatusCode::OK);
+            assert_eq!(head.headers["content-length"], "100000");
+            assert_eq!(head.headers["accept-ranges"], "bytes");
+            assert_eq!(head.extensions.get::<Protocol>(), Some(&Protocol::Ftp));
+            let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+            assert_eq!(head.headers["last-modified"], last_modified);
+
+            let get = |range: &str, if_range: &str| {
+                Request::get(server.url("/file"))
+                    .header("range", range)
+                    .header("if-range", if_range)
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (part, body) = send(get("bytes=70000-79999", last_modified)).unwrap();
+            assert_eq!(part.status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(part.headers["content-range"], "bytes 70000-79999/100000");
+            assert_eq!(body, file.content[70_000..80_000].to_vec());
+            // (a file that has changed since is sent whole)
+            let stale = "Tue, 20 Oct 2015 07:28:00 GMT";
+            let (whole, body) = send(get("bytes=70000-79999", stale)).unwrap();
+            assert_eq!(whole.status, StatusCode::OK);
+            assert_eq!(body, *file.content);
+            let (unsatisfiable, _) = send(get("bytes=100000-100009", last_modified)).unwrap();
+            assert_eq!(unsatisfiable.status, StatusCode::RANGE_NOT_SATISFIABLE);
+            let (missing, _) = send(
+                Request::head(server.url("/missing"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(missing.status, StatusCode::NOT_FOUND);
+
+            let commands = server.commands();
+            assert!(commands.contains(&String::from("USER anonymous")));
+            assert!(commands.contains(&String::from("REST 70000")));
+            assert!(commands.contains(&String::from("RETR file")));
+            assert_eq!(
+                commands.contains(&String::from("PROT P")),
+                server.url("/").starts_with("ftps")
+            );
+            assert_eq!(server.connections(), 5);
+        }
+    }
+}
@@ -0,0 +1,473 @@
+//! an http/3 transport (with the `http3` feature): requests to servers that speak http/3 go over quic, each range
+//! request a stream of its own on one connection per server, and every other request goes over the hyper client
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes1::{Buf, Bytes};
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use futures03::compat::Future01CompatExt;
+use futures03::future::{poll_fn, select_ok, FutureExt};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Chunk, Request, Response, StatusCode, Uri};
+use tokio1::runtime::Runtime;
+
+use crate::dns::{self, Override, Resolver};
+use crate::error::DlError;
+use crate::https::{self, Network, Protocol, H2_CONNECTION_WINDOW, H2_STREAM_WINDOW};
+use crate::timeout::Timeouts;
+use crate::transport::{ResponseFuture, SharedTransport, Transport};
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+type RequestStream = h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// how long to remember that a server speaks http/3 when its `Alt-Svc` doesn't say (as per rfc 7838)
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// how many chunks of a response body may be waiting for us to read them
+const BODY_BUFFER: usize = 16;
+
+/// sends requests to https servers that speak http/3 (those that have told us so with an `Alt-Svc` header, or
+/// every one, when `forced`) over quic, and the rest over `fallback` (as it does those to servers we can't reach
+/// over quic). quic runs on a (tokio 1) runtime of the transport's own, from which responses are handed back
+pub struct Http3Transport {
+    fallback: SharedTransport,
+    runtime: Option<Runtime>,
+    shared: Arc<Shared>,
+}
+
+/// what the transport's requests share (on its runtime)
+struct Shared {
+    forced: bool,
+    config: quinn::ClientConfig,
+    handshake: Option<Duration>,
+    /// how hosts are looked up (overrides aside)
+    resolver: Resolver,
+    /// where to connect to particular hosts instead (see `Network::resolve`)
+    resolve: Vec<Override>,
+    /// the local address to bind to (which only reaches addresses of its own family)
+    local: Option<IpAddr>,
+    /// the port each origin (`host:port`) has said it speaks http/3 on, and until when
+    alternatives: Mutex<HashMap<String, (u16, Instant)>>,
+    /// the origins we've failed to reach over quic (and no longer try to)
+    unreachable: Mutex<HashSet<String>>,
+    /// the connection to each origin (numbered, so that a closed one is only forgotten if it hasn't been replaced)
+    connections: tokio1::sync::Mutex<HashMap<String, (usize, SendRequest)>>,
+    connected: AtomicUsize,
+}
+
+/// how sending a request over http/3 went wrong
+enum Failure {
+    /// no connection could be made, so the request should go over the fallback instead
+    Unreachable,
+    Failed(DlError),
+}
+
+/// what an `Alt-Svc` header says about http/3
+#[derive(Debug, PartialEq)]
+enum AltSvc {
+    /// spoken on this port (of the same host) for this long
+    H3(u16, Duration),
+    /// not spoken (anymore)
+    Clear,
+}
+
+impl Http3Transport {
+    pub fn new(
+        fallback: SharedTransport,
+        timeouts: &Timeouts,
+        network: &Network,
+        forced: bool,
+    ) -> Result<Http3Transport, DlError> {
+        let runtime = tokio1::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("dl-http3")
+            .enable_all()
+            .build()?;
+        Ok(Http3Transport {
+            fallback,
+            runtime: Some(runtime),
+            shared: Arc::new(Shared {
+                forced,
+                config: client_config(network)?,
+                handshake: https::handshake_timeout(timeouts),
+                resolver: https::resolver_for(1, network)?,
+                resolve: network.resolve.clone(),
+                local: network.local_addresses.first().cloned(),
+                alternatives: Mutex::new(HashMap::new()),
+                unreachable: Mutex::new(HashSet::new()),
+                connections: tokio1::sync::Mutex::new(HashMap::new()),
+                connected: AtomicUsize::new(0),
+            }),
+        })
+    }
+}
+
+impl Transport for Http3Transport {
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        let (origin, host) = match origin_of(req.uri()) {
+            Some(origin) => origin,
+            None => return self.fallback.request(req),
+        };
+        let port = match self.shared.port_for(&origin, req.uri()) {
+            Some(port) => port,
+            None => return discover(&self.shared, &self.fallback, origin, req),
+        };
+        let (parts, body) = req.into_parts();
+        let h3_req = match to_h3(&parts) {
+            Ok(h3_req) => h3_req,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        let (sent, response) = oneshot::channel();
+        let shared = self.shared.clone();
+        let key = origin.clone();
+        let runtime = self
+            .runtime
+            .as_ref()
+            .expect("HTTP/3 runtime already shut down");
+        runtime.spawn(async move {
+            let _ = sent.send(shared.send(&key, &host, port, h3_req).await);
+        });
+        let (shared, fallback) = (self.shared.clone(), self.fallback.clone());
+        Box::new(response.then(move |response| match response {
+            Ok(Ok(res)) => Either::A(future::ok(res)),
+            Ok(Err(Failure::Unreachable)) => Either::B(discover(
+                &shared,
+                &fallback,
+                origin,
+                Request::from_parts(parts, body),
+            )),
+            Ok(Err(Failure::Failed(err))) => Either::A(future::err(err)),
+            Err(_) => Either::A(future::err(DlError::Http3(String::from(
+                "runtime shut down",
+            )))),
+        }))
+    }
+}
+
+impl Drop for Http3Transport {
+    fn drop(&mut self) {
+        // (without waiting on dns lookups in flight)
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// sends `req` over `fallback`, noting whether its response says that `origin` speaks http/3
+fn discover(
+    shared: &Arc<Shared>,
+    fallback: &SharedTransport,
+    origin: String,
+    req: Request<Body>,
+) -> ResponseFuture {
+    let shared = shared.clone();
+    Box::new(fallback.request(req).map(move |res| {
+        let alt_svc = res
+            .headers()
+            .get_all("alt-svc")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(parse_alt_svc);
+        let mut alternatives = shared.alternatives.lock().expect("Alt-Svc cache poisoned");
+        match alt_svc {
+            Some(AltSvc::H3(port, max_age)) => {
+                alternatives.insert(origin, (port, Instant::now() + max_age));
+            }
+            Some(AltSvc::Clear) => {
+                alternatives.remove(&origin);
+            }
+            None => {}
+        }
+        res
+    }))
+}
+
+impl Shared {
+    /// the port to reach `origin` (at `uri`) on over quic, if we should
+    fn port_for(&self, origin: &str, uri: &Uri) -> Option<u16> {
+        if self
+            .unreachable
+            .lock()
+            .expect("Unreachable origins poisoned")
+            .contains(origin)
+        {
+            return None;
+        }
+        if self.forced {
+            return Some(uri.port_u16().unwrap_or(443));
+        }
+        self.alternatives
+            .lock()
+            .expect("Alt-Svc cache poisoned")
+            .get(origin)
+            .filter(|(_, until)| *until > Instant::now())
+            .map(|(port, _)| *port)
+    }
+
+    async fn send(
+        self: &Arc<Self>,
+        origin: &str,
+        host: &str,
+        port: u16,
+        req: http1::Request<()>,
+    ) -> Result<Response<Body>, Failure> {
+        let mut conn = match self.connection(origin, host, port).await {
+            Ok(conn) => conn,
+            Err(_) => {
+                self.unreachable
+                    .lock()
+                    .expect("Unreachable origins poisoned")
+                    .insert(origin.to_string());
+                return Err(Failure::Unreachable);
+            }
+        };
+        let failed = |err: &dyn std::fmt::Display| Failure::Failed(DlError::Http3(err.to_string()));
+        let mut stream = conn.send_request(req).await.map_err(|err| failed(&err))?;
+        stream.finish().await.map_err(|err| failed(&err))?;
+        let res = stream.recv_response().await.map_err(|err| failed(&err))?;
+
+        let (chunks, body) = mpsc::channel(BODY_BUFFER);
+        tokio1::spawn(forward(stream, chunks));
+        let body = body.then(|chunk| match chunk {
+            Ok(chunk) => chunk,
+            Err(()) => Err(DlError::StreamProcessing),
+        });
+        from_h3(res, Body::wrap_stream(body)).map_err(Failure::Failed)
+    }
+
+    /// the connection to `origin` (at `host`, on udp `port`): the one we have, or a new one
+    async fn connection(
+        self: &Arc<Self>,
+        origin: &str,
+        host: &str,
+        port: u16,
+    ) -> Result<SendRequest, DlError> {
+        // (held while we connect, so that requests that arrive meanwhile wait for this connection)
+        let mut connections = self.connections.lock().await;
+        if let Some((_, conn)) = connections.get(origin) {
+            return Ok(conn.clone());
+        }
+        let (mut driver, conn) = match self.handshake {
+            Some(limit) => tokio1::time::timeout(limit, self.connect(host, port))
+                .await
+                .map_err(|_| DlError::TlsHandshakeTimeout)??,
+            None => self.connect(host, port).await?,
+        };
+        let id = self.connected.fetch_add(1, Ordering::SeqCst);
+        connections.insert(origin.to_string(), (id, conn.clone()));
+
+        let (shared, origin) = (self.clone(), origin.to_string());
+        tokio1::spawn(async move {
+            let _ = poll_fn(|cx| driver.poll_close(cx)).await;
+            let mut connections = shared.connections.lock().await;
+            if connections.get(&origin).map(|(current, _)| *current) == Some(id) {
+                connections.remove(&origin);
+            }
+        });
+        Ok(conn)
+    }
+
+    /// connects to `host` on udp `port`, trying every address it resolves to at once and keeping whichever
+    /// handshake finishes first
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<
+        (
+            h3::client::Connection<h3_quinn::Connection, Bytes>,
+            SendRequest,
+        ),
+        DlError,
+    > {
+        let http3 = |err: &dyn std::fmt::Display| DlError::Http3(err.to_string());
+        let addrs = dns::lookup(&self.resolver, &self.resolve, host, port)
+            .compat()
+            .await?;
+        let attempts = addrs
+            .into_iter()
+            .filter(|ip| {
+                self.local
+                    .is_none_or(|local| local.is_ipv6() == ip.is_ipv6())
+            })
+            .map(|ip| self.connect_to(SocketAddr::new(ip, port), host).boxed())
+            .collect::<Vec<_>>();
+        if attempts.is_empty() {
+            return Err(DlError::Http3(format!("{} has no addresses", host)));
+        }
+        let (conn, _) = select_ok(attempts).await?;
+        h3::client::new(h3_quinn::Connection::new(conn))
+            .await
+            .map_err(|err| http3(&err))
+    }
+
+    async fn connect_to(&self, addr: SocketAddr, host: &str) -> Result<quinn::Connection, DlError> {
+        let http3 = |err: &dyn std::fmt::Display| DlError::Http3(err.to_string());
+        let local = match (self.local, addr) {
+            (Some(local), _) => SocketAddr::new(local, 0),
+            (None, SocketAddr::V4(_)) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            (None, SocketAddr::V6(_)) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let mut endpoint = quinn::Endpoint::client(local)?;
+        endpoint.set_default_client_config(self.config.clone());
+        endpoint
+            .connect(addr, host)
+            .map_err(|err| http3(&err))?
+            .await
+            .map_err(|err| http3(&err))
+    }
+}
+
+/// hands the body of the response on `stream` to `chunks`, until it ends, fails or the response is dropped
+async fn forward(mut stream: RequestStream, mut chunks: mpsc::Sender<Result<Chunk, DlError>>) {
+    loop {
+        let chunk = match stream.recv_data().await {
+            Ok(Some(mut data)) => {
+                let mut chunk = vec![0; data.remaining()];
+                data.copy_to_slice(&mut chunk);
+                Ok(Chunk::from(chunk))
+            }
+            Ok(None) => return,
+            Err(err) => Err(DlError::Http3(err.to_string())),
+        };
+        let failed = chunk.is_err();
+        chunks = match chunks.send(chunk).compat().await {
+            Ok(chunks) if !failed => chunks,
+            _ => return,
+        };
+    }
+}
+
+/// the origin (`host:port`) of an https `uri`, and its host (as a name to resolve, and to check its certificate
+/// against)
+fn origin_of(uri: &Uri) -> Option<(String, String)> {
+    if uri.scheme_str() != Some("https") {
+        return None;
+    }
+    let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+    let origin = format!("{}:{}", uri.host()?, uri.port_u16().unwrap_or(443));
+    Some((origin, host.to_string()))
+}
+
+/// the first http/3 alternative (on the same host) an `Alt-Svc` header offers, or whether it clears them all
+fn parse_alt_svc(value: &str) -> Option<AltSvc> {
+    if value.trim() == "clear" {
+        return Some(AltSvc::Clear);
+    }
+    value.split(',').find_map(|alternative| {
+        let mut params = alternative.split(';').map(str::trim);
+        let (protocol, authority) = params.next()?.split_once('=')?;
+        let port = match (protocol, authority.trim_matches('"').split_once(':')) {
+            ("h3", Some(("", port))) => port.parse::<u16>().ok()?,
+            _ => return None,
+        };
+        let max_age = params
+            .filter_map(|param| param.strip_prefix("ma="))
+            .find_map(|secs| secs.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MAX_AGE);
+        Some(AltSvc::H3(port, max_age))
+    })
+}
+
+/// a tls config (for quic) trusting the system's certificate authorities, plus those in `network`'s ca bundle
+fn client_config(network: &Network) -> Result<quinn::ClientConfig, DlError> {
+    let invalid =
+        |err: &dyn std::fmt::Display| DlError::InvalidSettings(format!("http/3: {}", err));
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    if let Some(ref path) = network.ca_bundle {
+        for cert in https::load_ca_bundle(path)? {
+            let der = cert.to_der().map_err(|err| invalid(&err))?;
+            roots.add(der.into()).map_err(|err| invalid(&err))?;
+        }
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|err| invalid(&err))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto =
+        quinn::crypto::rustls::QuicClientConfig::try_from(tls).map_err(|err| invalid(&err))?;
+    // (the same windows as over http/2, for the same reason)
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .stream_receive_window(H2_STREAM_WINDOW.into())
+        .receive_window(H2_CONNECTION_WINDOW.into());
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// `parts` as a (bodiless: dl sends none) request for `h3`, which speaks version 1 of the `http` crate
+fn to_h3(parts: &http::request::Parts) -> Result<http1::Request<()>, DlError> {
+    let mut req = http1::Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string());
+    for (name, value) in parts.headers.iter() {
+        req = req.header(name.as_str(), value.as_bytes());
+    }
+    req.body(()).map_err(|err| DlError::Http3(err.to_string()))
+}
+
+/// an `h3` response as one of hyper's, with `body` (and marked as having come over http/3)
+fn from_h3(res: http1::Response<()>, body: Body) -> Result<Response<Body>, DlError> {
+    let mut out = Response::new(body);
+    *out.status_mut() = StatusCode::from_u16(res.status().as_u16())
+        .map_err(|err| DlError::Http3(err.to_string()))?;
+    for (name, value) in res.headers().iter() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_str().as_bytes()),
+            HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            out.headers_mut().append(name, value);
+        }
+    }
+    out.extensions_mut().insert(Protocol::Http3);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod http3_tests {
+    use super::*;
+
+    #[test]
+    fn parsing_alt_svc_headers() {
+        assert_eq!(
+            parse_alt_svc(r#"h3=":443"; ma=3600, h2=":443""#),
+            Some(AltSvc::H3(443, Duration::from_secs(3600)))
+        );
+        assert_eq!(
+            parse_alt_svc(r#"h3-29=":8443", h3=":8443""#),
+            Some(AltSvc::H3(8443, DEFAULT_MAX_AGE))
+        );
+        assert_eq!(parse_alt_svc("clear"), Some(AltSvc::Clear));
+        // alternatives on other hosts aren't ones we'd trust
+        assert_eq!(parse_alt_svc(r#"h3="cdn.example.com:443""#), None);
+        assert_eq!(parse_alt_svc(r#"h2=":443""#), None);
+    }
+
+    #[test]
+    fn finding_the_origins_of_uris() {
+        let origin = |uri: &str| origin_of(&uri.parse::<Uri>().unwrap());
+        assert_eq!(
+            origin("https://example.com/foo"),
+            Some((String::from("example.com:443"), String::from("example.com")))
+        );
+        assert_eq!(
+            origin("https://[::1]:8443/foo"),
+            Some((String::from("[::1]:8443"), String::from("::1")))
+        );
+        assert_eq!(origin("http://example.com/foo"), None);
+    }
+}
@@ -1,9 +1,161 @@
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::Future;
+use hyper::client::connect::{Connect, Connected, Destination};
 use hyper::client::Client;
 use hyper::client::HttpConnector;
-use hyper::Body;
-use hyper_tls::HttpsConnector;
+use hyper::header::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response, Uri, Version};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use native_tls::{Certificate, TlsConnector};
+use serde::Serialize;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::dns::{IpPreference, Override, OverridingConnector, Resolver};
+use crate::error::DlError;
+use crate::ftp::FtpTransport;
+use crate::local::LocalTransport;
+use crate::timeout::{DeadlineConnector, Elapsed, Timeouts};
+use crate::transport::SharedTransport;
+
+pub type Connector = DeadlineConnector<
+    ProxyConnector<AlpnConnector<HttpsConnector<DeadlineConnector<OverridingConnector>>>>,
+>;
+pub type HttpsClient = Client<Connector, Body>;
+
+/// how to reach servers: directly or through a proxy, finding them how, and trusting which certificate authorities
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Network {
+    /// an http proxy every request goes through (https is tunneled through it with `CONNECT`)
+    pub proxy: Option<Uri>,
+    /// a pem file of certificate authorities to trust (on top of the system's)
+    pub ca_bundle: Option<PathBuf>,
+    /// sends every request in place of the client `dl` would otherwise build (in which case `proxy` and
+    /// `ca_bundle` are up to it)
+    pub transport: Option<SharedTransport>,
+    pub http_version: HttpVersion,
+    /// resolves the file's host once and spreads its connections across every address it resolves to, each a
+    /// source of its own (rather than leaving it to the connection pool, which sticks to one per host)
+    pub all_addresses: bool,
+    /// where to connect to particular hosts (on particular ports) instead of wherever they resolve to. with a
+    /// proxy, these only apply to the proxy itself (which resolves the hosts it connects to)
+    pub resolve: Vec<Override>,
+    /// a nameserver to look hosts up with, rather than the system's resolver
+    pub dns_server: Option<SocketAddr>,
+    /// a hosts file to look hosts up in before `dns_server` (or the system's resolver)
+    pub hosts_file: Option<PathBuf>,
+    /// local addresses to bind connections to (one per network interface, say), which a file's connections are
+    /// spread across. the first is the one the rest of a download's connections are bound to
+    pub local_addresses: Vec<IpAddr>,
+    /// which family of addresses to connect to first
+    pub ip_preference: IpPreference,
+}
+
+/// which version of http to speak to servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersion {
+    /// http/2 with servers that offer it (over tls, via alpn), http/1.1 with the rest
+    #[default]
+    Auto,
+    /// http/1.1 only (a connection per parallel piece)
+    Http1,
+    /// http/2 only, failing with servers that don't speak it (over plain http, with prior knowledge)
+    Http2,
+    /// http/3 (over quic) with every https server, falling back to http/2 or http/1.1 with those we can't reach
+    /// over it (only with the `http3` feature)
+    Http3,
+}
+
+impl FromStr for HttpVersion {
+    type Err = DlError;
+
+    fn from_str(s: &str) -> Result<HttpVersion, DlError> {
+        match s {
+            "auto" => Ok(HttpVersion::Auto),
+            "1.1" => Ok(HttpVersion::Http1),
+            "2" => Ok(HttpVersion::Http2),
+            #[cfg(feature = "http3")]
+            "3" => Ok(HttpVersion::Http3),
+            #[cfg(not(feature = "http3"))]
+            "3" => Err(DlError::InvalidConfig(
+                "http/3 needs dl built with the http3 feature",
+            )),
+            _ => Err(DlError::InvalidConfig(
+                "http version must be one of auto, 1.1, 2 or 3",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpVersion::Auto => write!(f, "auto"),
+            HttpVersion::Http1 => write!(f, "1.1"),
+            HttpVersion::Http2 => write!(f, "2"),
+            HttpVersion::Http3 => write!(f, "3"),
+        }
+    }
+}
+
+/// the version of http a response came over (or ftp, for files on ftp servers)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Protocol {
+    #[serde(rename = "HTTP/1.0")]
+    Http10,
+    #[serde(rename = "HTTP/1.1")]
+    Http11,
+    #[serde(rename = "HTTP/2")]
+    Http2,
+    #[serde(rename = "HTTP/3")]
+    Http3,
+    #[serde(rename = "FTP")]
+    Ftp,
+}
+
+impl Protocol {
+    pub fn of(version: Version) -> Protocol {
+        match version {
+            Version::HTTP_2 => Protocol::Http2,
+            Version::HTTP_11 => Protocol::Http11,
+            // (hyper won't speak http/0.9, so there's no telling it apart from 1.0)
+            _ => Protocol::Http10,
+        }
+    }
+
+    /// the protocol `res` came over: as marked by the transport that sent it (in its extensions), if it's one
+    /// `Version` can't tell (http/3, or ftp), and otherwise its version's
+    pub fn of_response<T>(res: &Response<T>) -> Protocol {
+        match res.extensions().get::<Protocol>() {
+            Some(protocol) => *protocol,
+            None => Protocol::of(res.version()),
+        }
+    }
+}
 
-pub type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Http10 => write!(f, "HTTP/1.0"),
+            Protocol::Http11 => write!(f, "HTTP/1.1"),
+            Protocol::Http2 => write!(f, "HTTP/2"),
+            Protocol::Http3 => write!(f, "HTTP/3"),
+            Protocol::Ftp => write!(f, "FTP"),
+        }
+    }
+}
+
+/// how much an http/2 stream (a piece) may have in flight before we've read it: big enough that one stream can
+/// keep a fast connection busy (the protocol's default of 64 KiB can't)
+pub const H2_STREAM_WINDOW: u32 = 4 * 1024 * 1024;
+/// how much all of a connection's http/2 streams together may have in flight
+pub const H2_CONNECTION_WINDOW: u32 = 32 * 1024 * 1024;
 
 /// returns a (hyper) async https client with threadpool of given size
 pub fn get_client(pool_size: usize) -> HttpsClient {
@@ -11,9 +163,222 @@ pub fn get_client(pool_size: usize) -> HttpsClient {
 }
 
 pub fn get_client_of(thread_pool_size: usize) -> HttpsClient {
-    let mut https = HttpsConnector::new(thread_pool_size).expect("TLS initialization failed");
-    https.https_only(true);
-    Client::builder().build::<_, hyper::Body>(https)
+    build_client(
+        thread_pool_size,
+        &Timeouts::default(),
+        &Network::default(),
+        true,
+    )
+    .expect("TLS initialization failed")
+}
+
+/// the transport to send a download's requests over: `network`'s own, if it has one, or else one for http(s), ftp(s)
+/// and local file uris
+pub fn transport_for(
+    thread_pool_size: usize,
+    timeouts: &Timeouts,
+    network: &Network,
+) -> Result<SharedTransport, DlError> {
+    if let Some(ref transport) = network.transport {
+        return Ok(transport.clone());
+    }
+    let remote = remote_transport_for(thread_pool_size, timeouts, network)?;
+    let remote = SharedTransport::new(FtpTransport::new(remote, timeouts, network)?);
+    Ok(SharedTransport::new(LocalTransport::new(remote)))
+}
+
+fn remote_transport_for(
+    thread_pool_size: usize,
+    timeouts: &Timeouts,
+    network: &Network,
+) -> Result<SharedTransport, DlError> {
+    let client =
+        build_client(thread_pool_size, timeouts, network, false).map(SharedTransport::new)?;
+    #[cfg(feature = "http3")]
+    {
+        // (quic can't go through an http proxy)
+        let forced = match network.http_version {
+            HttpVersion::Auto => Some(false),
+            HttpVersion::Http3 => Some(true),
+            HttpVersion::Http1 | HttpVersion::Http2 => None,
+        };
+        if let (Some(forced), None) = (forced, &network.proxy) {
+            return crate::http3::Http3Transport::new(client, timeouts, network, forced)
+                .map(SharedTransport::new);
+        }
+    }
+    Ok(client)
+}
+
+/// returns a (hyper) async client with threadpool of given size, whose connections give up after the
+/// `connect` and `tls_handshake` timeouts, go through `network`'s proxy, trust its ca bundle and speak its
+/// `http_version` (and which also speaks plain http unless `https_only`).
+///
+/// once a connection to a host negotiates http/2, every request to that host goes over it (each piece a stream
+/// of its own) rather than a connection per piece. https tunnelled through a proxy always speaks http/1.1
+pub fn build_client(
+    thread_pool_size: usize,
+    timeouts: &Timeouts,
+    network: &Network,
+    https_only: bool,
+) -> Result<HttpsClient, DlError> {
+    build_client_with(
+        resolver_for(thread_pool_size, network)?,
+        timeouts,
+        network,
+        https_only,
+    )
+}
+
+/// how `network` looks hosts up (overrides aside): in its hosts file, if it has one, and then with its nameserver
+/// or the system's resolver (on a pool of `thread_pool_size`)
+pub fn resolver_for(thread_pool_size: usize, network: &Network) -> Result<Resolver, DlError> {
+    let resolver = match network.dns_server {
+        Some(addr) => Resolver::nameserver(addr)?,
+        None => Resolver::system(thread_pool_size),
+    };
+    let resolver = match network.hosts_file {
+        Some(ref path) => Resolver::hosts_file(path, resolver)?,
+        None => resolver,
+    };
+    Ok(resolver.preferring(network.ip_preference))
+}
+
+/// builds a client just as `build_client` does, but whose connections find the hosts they're to with `resolver`
+/// (unless `network` overrides where they are). either way, they're bound to `network`'s first local address (if
+/// it has any), and so only go to addresses of its family
+pub fn build_client_with(
+    resolver: Resolver,
+    timeouts: &Timeouts,
+    network: &Network,
+    https_only: bool,
+) -> Result<HttpsClient, DlError> {
+    let local = network.local_addresses.first().cloned();
+    let http = OverridingConnector::new(resolver, &network.resolve, |resolver| {
+        let mut http = HttpConnector::new_with_resolver(match local {
+            Some(local) => resolver.reachable_from(local),
+            None => resolver,
+        });
+        http.enforce_http(false);
+        http.set_local_address(local);
+        http
+    });
+    let alpn: &[&str] = match network.http_version {
+        HttpVersion::Auto | HttpVersion::Http3 => &["h2", "http/1.1"],
+        HttpVersion::Http1 => &[],
+        HttpVersion::Http2 => &["h2"],
+    };
+    let mut https = HttpsConnector::from((
+        DeadlineConnector::new(http, timeouts.connect, Elapsed::Connect),
+        tls_connector(network.ca_bundle.as_deref(), alpn)?,
+    ));
+    // the proxy itself is spoken to in plain http, even when we tunnel https through it
+    https.https_only(https_only && network.proxy.is_none());
+
+    let mut proxied = ProxyConnector::unsecured(AlpnConnector(https));
+    if let Some(ref proxy) = network.proxy {
+        proxied.add_proxy(Proxy::new(Intercept::All, proxy.clone()));
+        // (the proxy connector doesn't tell hyper what a tunnel negotiated, so tunnels must stick to http/1.1)
+        proxied.set_tls(Some(tls_connector(network.ca_bundle.as_deref(), &[])?));
+    }
+
+    Ok(Client::builder()
+        .http2_only(network.http_version == HttpVersion::Http2)
+        .http2_initial_stream_window_size(H2_STREAM_WINDOW)
+        .http2_initial_connection_window_size(H2_CONNECTION_WINDOW)
+        .build::<_, hyper::Body>(DeadlineConnector::new(
+            proxied,
+            handshake_timeout(timeouts),
+            Elapsed::TlsHandshake,
+        )))
+}
+
+/// how long to give a connection to be made and its tls handshake to finish: the handshake can't start until
+/// we're connected, so it gets the connect timeout's time on top of its own
+pub(crate) fn handshake_timeout(timeouts: &Timeouts) -> Option<Duration> {
+    match (timeouts.connect, timeouts.tls_handshake) {
+        (Some(connect), Some(handshake)) => Some(connect + handshake),
+        (_, handshake) => handshake,
+    }
+}
+
+/// tells hyper when a tls connection has negotiated http/2 (via alpn), so that it speaks http/2 over it (and
+/// sends every other request to the same host over it too)
+#[derive(Debug, Clone)]
+pub struct AlpnConnector<C>(C);
+
+impl<C, T> Connect for AlpnConnector<C>
+where
+    C: Connect<Transport = MaybeHttpsStream<T>>,
+    C::Future: 'static,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    type Transport = C::Transport;
+    type Error = C::Error;
+    type Future = Box<dyn Future<Item = (C::Transport, Connected), Error = C::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        Box::new(self.0.connect(dst).map(|(stream, connected)| {
+            let h2 = match stream {
+                MaybeHttpsStream::Https(ref tls) => {
+                    tls.get_ref().negotiated_alpn().ok().flatten().as_deref() == Some(&b"h2"[..])
+                }
+                MaybeHttpsStream::Http(_) => false,
+            };
+            match h2 {
+                true => (stream, connected.negotiated_h2()),
+                false => (stream, connected),
+            }
+        }))
+    }
+}
+
+/// a tls connector trusting the system's certificate authorities, plus those in `ca_bundle` (if given), and
+/// offering the `alpn` protocols (if any)
+pub(crate) fn tls_connector(
+    ca_bundle: Option<&Path>,
+    alpn: &[&str],
+) -> Result<TlsConnector, DlError> {
+    let mut builder = TlsConnector::builder();
+    builder.request_alpns(alpn);
+    if let Some(path) = ca_bundle {
+        for cert in load_ca_bundle(path)? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    builder
+        .build()
+        .map_err(|err| DlError::InvalidSettings(format!("tls: {}", err)))
+}
+
+/// reads every certificate in a pem file
+pub fn load_ca_bundle(path: &Path) -> Result<Vec<Certificate>, DlError> {
+    let invalid = |reason: String| {
+        DlError::InvalidSettings(format!("ca bundle {}: {}", path.display(), reason))
+    };
+    let pem = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let certs = pem
+        .split_inclusive(PEM_END)
+        .filter(|block| block.contains(PEM_BEGIN))
+        .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(err.to_string()))?;
+    match certs.is_empty() {
+        true => Err(invalid(String::from("no certificates found"))),
+        false => Ok(certs),
+    }
+}
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// adds user-supplied `headers` to a request (without replacing any header the request already has)
+pub fn add_headers(req: &mut Request<Body>, headers: &HeaderMap<HeaderValue>) {
+    for (name, value) in headers.iter() {
+        if !req.headers().contains_key(name) {
+            req.headers_mut().append(name.clone(), value.clone());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -32,4 +397,128 @@ mod https_tests {
         let c = get_client_of(2);
         assert_eq!(format!("{:?}", c), "Client")
     }
+
+    #[test]
+    fn parsing_http_versions() {
+        for version in [HttpVersion::Auto, HttpVersion::Http1, HttpVersion::Http2] {
+            assert_eq!(version.to_string().parse::<HttpVersion>().unwrap(), version);
+        }
+        assert_eq!(
+            "4".parse::<HttpVersion>().unwrap_err().code(),
+            "invalid_config"
+        );
+        assert_eq!("3".parse::<HttpVersion>().is_ok(), cfg!(feature = "http3"));
+        assert_eq!(Protocol::of(Version::HTTP_2).to_string(), "HTTP/2");
+    }
+
+    #[test]
+    fn adding_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer foo"));
+        headers.insert("range", HeaderValue::from_static("bytes=0-0"));
+        let mut req = Request::get("https://foo.com")
+            .header("range", "bytes=1-2")
+            .body(Body::empty())
+            .unwrap();
+
+        add_headers(&mut req, &headers);
+        assert_eq!(req.headers()["authorization"], "Bearer foo");
+        assert_eq!(req.headers()["range"], "bytes=1-2");
+    }
+
+    #[test]
+    fn loading_ca_bundles() {
+        let empty = Path::new("data/foo_empty.pem");
+        fs::write(empty, "# nothing here\n").unwrap();
+        assert_eq!(
+            load_ca_bundle(empty).map(|_| ()).unwrap_err().code(),
+            "invalid_settings"
+        );
+
+        let garbage = Path::new("data/foo_garbage.pem");
+        fs::write(
+            garbage,
+            format!("{}\nnot base64!\n{}\n", PEM_BEGIN, PEM_END),
+        )
+        .unwrap();
+        assert!(load_ca_bundle(garbage).is_err());
+
+        let missing = Path::new("data/foo_missing.pem");
+        assert!(load_ca_bundle(missing)
+            .map(|_| ())
+            .unwrap_err()
+            .to_string()
+            .contains("data/foo_missing.pem"));
+
+        fs::remove_file(empty).unwrap();
+        fs::remove_file(garbage).unwrap();
+    }
+
+    #[test]
+    fn sending_requests_through_a_proxy() {
+        use std::io::{Read, Write};
+        use tokio::runtime::Runtime;
+
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let network = Network {
+            proxy: Some(
+                format!("http://{}", proxy.local_addr().unwrap())
+                    .parse::<Uri>()
+                    .unwrap(),
+            ),
+            ca_bundle: None,
+            ..Network::default()
+        };
+        let handle = std::thread::spawn(move || {
+            let (mut conn, _) = proxy.accept().unwrap();
+            let mut buf = [0; 1024];
+            let n = conn.read(&mut buf).unwrap();
+            conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        let client = build_client(1, &Timeouts::default(), &network, false).unwrap();
+        let res = Runtime::new()
+            .unwrap()
+            .block_on(client.get(Uri::from_static("http://dl.invalid/foo")))
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(handle
+            .join()
+            .unwrap()
+            .starts_with("GET http://dl.invalid/foo HTTP/1.1"));
+    }
+
+    #[test]
+    fn timing_out_connections() {
+        use crate::error::DlError;
+        use futures::Future;
+        use hyper::Uri;
+        use std::time::Duration;
+        use tokio::runtime::Runtime;
+
+        // a listener that never accepts: connecting succeeds (via the backlog) but no tls handshake ever happens
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("https://{}/", listener.local_addr().unwrap())
+            .parse::<Uri>()
+            .unwrap();
+        let timeouts = Timeouts {
+            connect: None,
+            tls_handshake: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(
+                build_client(1, &timeouts, &Network::default(), true)
+                    .unwrap()
+                    .get(uri)
+                    .map_err(DlError::from),
+            )
+            .unwrap_err();
+        assert_eq!(err.code(), "tls_handshake_timeout");
+        assert!(err.is_transient());
+    }
 }
@@ -2,23 +2,88 @@
 #[macro_use]
 extern crate lazy_static;
 
+use crate::checksum::{DigestReport, HashChecker, Verify};
+use crate::download::Destination;
+use crate::file::{FileDownloader, RetryPolicy};
+use crate::https::Network;
 use crate::metadata::MetadataDownloader;
+use crate::output::{Event, FileInfo, OutputFormat, Reporter, Summary};
+use crate::settings::Source;
+use crate::sink::{MakeSink, Sink};
+use crate::stream::DownloadStream;
 use error::DlError;
-use futures::Future;
-use hyper::Uri;
+use futures::future::{self, Either, Loop};
+use futures::{Future, IntoFuture, Stream};
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Uri};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use timeout::Timeouts;
 
+pub mod bind;
 pub mod checksum;
+pub mod disk;
+pub mod dns;
+pub mod download;
 pub mod error;
 pub mod file;
+pub mod ftp;
+#[cfg(feature = "http3")]
+pub mod http3;
 pub mod https;
+pub mod local;
 pub mod metadata;
+pub mod output;
+pub mod reorder;
+pub mod settings;
+pub mod sink;
+pub mod stream;
+#[cfg(any(test, feature = "test-server"))]
+pub mod test_server;
+pub mod throttle;
+pub mod timeout;
+pub mod transport;
 
-#[derive(Debug, PartialEq)]
+pub use crate::download::{Download, DownloadBuilder};
+
+/// everything `run` needs to know about a download (built, and checked, by `Download::builder`)
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     pub uri: Uri,
-    pub path: PathBuf,
+    /// other uris serving the same file
+    pub mirrors: Vec<Uri>,
+    /// sent with every request
+    pub headers: HeaderMap<HeaderValue>,
+    pub destination: Destination,
     pub parallelism: usize,
+    /// size (in bytes) of the pieces the file is split into (picked from the file size if `None`)
+    pub piece_size: Option<u64>,
+    pub retry: RetryPolicy,
+    /// caps the download's combined throughput (in bytes/sec)
+    pub rate_limit: Option<u64>,
+    pub network: Network,
+    pub verify: Verify,
+    /// whether to leave `<path>.part` behind (rather than delete it) when a download fails
+    pub keep_partial: bool,
+    /// how many bytes of the file to hold in memory, at most, while streaming it to stdout
+    pub buffer_size: u64,
+    pub timeouts: Timeouts,
+    pub output_format: OutputFormat,
+    /// where each setting that can also come from a config file (or the environment) came from
+    pub sources: BTreeMap<String, Source>,
+}
+
+impl Config {
+    /// reports progress on stdout, unless that is where the file is going
+    pub fn reporter(&self) -> Reporter {
+        match self.destination {
+            Destination::File(_) | Destination::Sink(_) => Reporter::new(self.output_format),
+            Destination::Stdout => Reporter::stderr(self.output_format),
+        }
+    }
 }
 
 lazy_static! {
@@ -27,153 +92,360 @@ lazy_static! {
     pub static ref DEFAULT_PARALLELISM: usize = num_cpus::get();
 }
 
-// these macros are weird but we need them b/c we cannot concat constant string constants in rust
-// nor can we pass `String` objects (which we can concatenate) to `Err`
-// see: https://github.com/rust-lang/rust/issues/31383
-macro_rules! usage {
-    () => {
-        "> Correct usage: dl <valid_url> <output_path> <optional int>)"
-    };
+/// downloads the file described by `cfg` to its destination: for a file, into `<path>.part`, which is verified
+/// and only then moved to `path` (on failure, the `.part` file is deleted unless `cfg.keep_partial` is set); for
+/// stdout or a sink, in order, verifying it on the way out
+pub fn run(cfg: Config) -> impl Future<Item = Summary, Error = DlError> {
+    match cfg.destination.clone() {
+        Destination::File(path) => Either::A(run_to_file(cfg, path)),
+        Destination::Stdout => Either::B(Either::A(run_to_stdout(cfg))),
+        Destination::Sink(make_sink) => Either::B(Either::B(run_to_sink(cfg, make_sink))),
+    }
 }
 
-macro_rules! insufficient_args {
-    () => {
-        concat!(
-            "> Error: please provide at least 2 arguments",
-            "\n",
-            usage!()
-        )
-    };
-}
+fn run_to_file(cfg: Config, path: PathBuf) -> impl Future<Item = Summary, Error = DlError> {
+    // TODO: use logger instead of println (to clean up test output)
+    let started = Instant::now();
+    let reporter = cfg.reporter();
+    let url = cfg.uri.to_string();
+    let part = disk::part_path(&path);
+    let keep_partial = cfg.keep_partial;
+    let total_timeout = cfg.timeouts.total;
+    let verify_policy = cfg.verify.clone();
 
-macro_rules! invalid_uri {
-    () => {
-        concat!("> Error: invalid uri", "\n", usage!())
-    };
+    let download = prepare(cfg, reporter)
+        .and_then(move |file_downloader| {
+            let final_url = file_downloader.uri.to_string();
+            let size = file_downloader.file_size;
+            file_downloader
+                .fetch()
+                .map(move |hash_checker| (url, final_url, size, hash_checker))
+        })
+        .and_then(move |(url, final_url, size, hash_checker)| {
+            verify(hash_checker, &verify_policy, reporter).and_then(
+                move |(hash_checker, digests)| {
+                    let p = path.clone();
+                    let part = hash_checker.path.clone();
+                    disk::run_blocking(move || disk::commit(&part, &p)).map(move |_| {
+                        let path = path.to_string_lossy().into_owned();
+                        reporter.emit(&Event::Downloaded { path: path.clone() });
+                        let duration_secs = duration_secs(started);
+                        let summary = Summary {
+                            url,
+                            final_url,
+                            path,
+                            size,
+                            etag: hash_checker.etag,
+                            digests,
+                            duration_secs,
+                            throughput_bytes_per_sec: size as f64 / duration_secs,
+                            protocols: Summary::protocols_of(&hash_checker.pieces),
+                            sources: Summary::sources_of(&hash_checker.pieces, duration_secs),
+                            pieces: hash_checker.pieces,
+                        };
+                        reporter.emit(&Event::Summary(summary.clone()));
+                        summary
+                    })
+                },
+            )
+        });
+
+    timeout::deadline(download, total_timeout, DlError::DownloadTimeout).or_else(move |err| {
+        match keep_partial {
+            true => Either::A(future::err(err)),
+            false => {
+                Either::B(disk::run_blocking(move || disk::discard(&part)).then(move |_| Err(err)))
+            }
+        }
+    })
 }
 
-macro_rules! invalid_parallelism {
-    () => {
-        concat!("> Error: invalid parallelism", "\n", usage!())
-    };
+/// streams the file to stdout (through a `DownloadStream`, which hashes it on the way out since there is no file
+/// to check afterwards). a file that fails verification has already been written by the time we find out, so
+/// all we can do is fail loudly
+fn run_to_stdout(cfg: Config) -> impl Future<Item = Summary, Error = DlError> {
+    let started = Instant::now();
+    let reporter = cfg.reporter();
+    let url = cfg.uri.to_string();
+
+    future::loop_fn(DownloadStream::new(cfg), |stream| {
+        stream
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(chunk, stream)| match chunk {
+                None => Either::A(future::ok(Loop::Break(stream))),
+                Some(chunk) => Either::B(
+                    disk::run_blocking(move || {
+                        let mut stdout = io::stdout();
+                        stdout
+                            .write_all(&chunk)
+                            .and_then(|_| stdout.flush())
+                            .map_err(DlError::Io)
+                    })
+                    .map(move |_| Loop::Continue(stream)),
+                ),
+            })
+    })
+    .map(move |stream| streamed(&stream, url, Destination::Stdout, started, reporter))
 }
 
-impl Config {
-    pub fn new(args: Vec<String>) -> Result<Config, &'static str> {
-        if args.len() < 3 {
-            return Err(insufficient_args!());
-        }
+/// writes the file into the sink `make_sink` makes for it (once we know how big it is), in order, through a
+/// `DownloadStream` that verifies it on the way. the sink is aborted if the download fails (or the file turns
+/// out not to match its digest), and finalized otherwise
+fn run_to_sink(cfg: Config, make_sink: MakeSink) -> impl Future<Item = Summary, Error = DlError> {
+    let started = Instant::now();
+    let reporter = cfg.reporter();
+    let url = cfg.uri.to_string();
+    let destination = cfg.destination.clone();
 
-        let uri = match args[1].parse::<Uri>() {
-            Ok(u) => u,
-            _ => return Err(invalid_uri!()),
-        };
+    let made = DownloadStream::new(cfg).started().and_then(move |stream| {
+        let size = stream.metadata().map(|md| md.size);
+        make_sink
+            .make(size.expect("stream started without metadata"))
+            .map(|sink| (stream, sink, 0))
+    });
+    made.and_then(|start| {
+        future::loop_fn(start, |(stream, sink, offset)| {
+            stream.into_future().then(move |polled| match polled {
+                Err((err, _)) => Either::A(aborted(sink, err)),
+                Ok((None, stream)) => {
+                    Either::B(Either::A(sink.finalize().map(move |_| Loop::Break(stream))))
+                }
+                Ok((Some(chunk), stream)) => {
+                    let next = offset + chunk.len() as u64;
+                    Either::B(Either::B(sink.write_at(chunk, offset).then(
+                        move |written| match written {
+                            Ok(()) => Either::A(future::ok(Loop::Continue((stream, sink, next)))),
+                            Err(err) => Either::B(aborted(sink, err)),
+                        },
+                    )))
+                }
+            })
+        })
+    })
+    .map(move |stream| streamed(&stream, url, destination, started, reporter))
+}
 
-        let path = PathBuf::from(&args[2]);
+/// fails with `err`, once `sink` has been aborted
+fn aborted<T>(sink: Arc<dyn Sink>, err: DlError) -> impl Future<Item = T, Error = DlError> {
+    sink.abort().then(move |_| Err(err))
+}
 
-        let parallelism = if args.len() == 4 {
-            match args[3].parse::<usize>() {
-                Ok(u) => u,
-                _ => return Err(invalid_parallelism!()),
-            }
-        } else {
-            *DEFAULT_PARALLELISM
-        };
+/// reports (and returns) the summary of a download that `stream` streamed to `destination`
+fn streamed(
+    stream: &DownloadStream,
+    url: String,
+    destination: Destination,
+    started: Instant,
+    reporter: Reporter,
+) -> Summary {
+    let metadata = stream
+        .metadata()
+        .cloned()
+        .expect("stream ended without metadata");
+    let duration_secs = duration_secs(started);
+    let summary = Summary {
+        url,
+        final_url: metadata.final_url,
+        path: destination.to_string(),
+        size: metadata.size,
+        etag: metadata.etag,
+        digests: stream.digests().to_vec(),
+        duration_secs,
+        throughput_bytes_per_sec: metadata.size as f64 / duration_secs,
+        pieces: stream.pieces().to_vec(),
+        protocols: Summary::protocols_of(stream.pieces()),
+        sources: Summary::sources_of(stream.pieces(), duration_secs),
+    };
+    reporter.emit(&Event::Summary(summary.clone()));
+    summary
+}
 
-        Ok(Config {
-            uri,
-            path,
-            parallelism,
+/// fetches the metadata of the file described by `cfg`, reporting what we find, ready to download it
+fn prepare(cfg: Config, reporter: Reporter) -> impl Future<Item = FileDownloader, Error = DlError> {
+    let url = cfg.uri.to_string();
+    reporter.emit(&Event::FetchingMetadata { url: url.clone() });
+    MetadataDownloader::from_config(cfg)
+        .into_future()
+        .and_then(MetadataDownloader::fetch)
+        .map(move |file_downloader| {
+            reporter.emit(&Event::Metadata {
+                url,
+                final_url: file_downloader.uri.to_string(),
+                size: file_downloader.file_size,
+                etag: file_downloader.etag.clone(),
+            });
+            reporter.emit(&Event::Downloading {
+                path: file_downloader.destination.to_string(),
+                size: file_downloader.file_size,
+            });
+            file_downloader
         })
-    }
 }
 
-pub fn run(cfg: Config) -> impl Future<Item = (), Error = DlError> {
-    // TODO: use logger instead of println (to clean up test output)
-    println!("> fetching file metadata...");
+/// fetches what the server (and mirrors) in `cfg` say about the file, without downloading it
+pub fn info(cfg: Config) -> impl Future<Item = FileInfo, Error = DlError> {
+    let url = cfg.uri.to_string();
     MetadataDownloader::from_config(cfg)
-        .fetch()
-        .and_then(move |file_downloader| {
-            println!(
-                "> ...found metadata. file size: {}, etag: {}",
-                &file_downloader.file_size,
-                &file_downloader.etag.clone().unwrap_or(String::from("N/A")),
-            );
-            println!("> downloading file...");
-            file_downloader.fetch()
+        .into_future()
+        .and_then(MetadataDownloader::fetch)
+        .map(move |file_downloader| FileInfo {
+            url,
+            final_url: file_downloader.uri.to_string(),
+            size: file_downloader.file_size,
+            etag: file_downloader.etag,
+            validator: file_downloader
+                .validator
+                .map(|v| v.header_value().to_string()),
+            mirrors: file_downloader
+                .mirrors
+                .iter()
+                .map(|m| m.uri.to_string())
+                .collect(),
         })
-        .and_then(move |hash_checker| {
-            println!("> ...file downloaded!");
-            println!(
-                "\n>>>>> file ready at: {} <<<<<\n",
-                &hash_checker.path.to_str().unwrap()
-            );
-            println!("> verifying etag (if present)...");
-            hash_checker.check().map(move |valid| {
-                match valid {
-                    true => println!("> ...hashes match!"),
-                    false => println!("> ...hashes do not match. :("),
-                };
-            })
+}
+
+/// checks a downloaded file against the digest `policy` calls for (if any), failing if it doesn't match
+fn verify(
+    hash_checker: HashChecker,
+    policy: &Verify,
+    reporter: Reporter,
+) -> impl Future<Item = (HashChecker, Vec<DigestReport>), Error = DlError> {
+    policy
+        .expected_md5(hash_checker.etag.as_ref())
+        .into_future()
+        .and_then(move |expected| match expected {
+            None => Either::A(future::ok((hash_checker, vec![]))),
+            Some(expected) => {
+                reporter.emit(&Event::Verifying);
+                Either::B(
+                    hash_checker
+                        .verify_md5(&expected)
+                        .and_then(move |digest| verified(digest, reporter))
+                        .map(move |digest| (hash_checker, vec![digest])),
+                )
+            }
         })
-        .map(|_| ())
+}
+
+/// reports the outcome of a digest check, failing if the digests didn't match
+fn verified(digest: DigestReport, reporter: Reporter) -> Result<DigestReport, DlError> {
+    reporter.emit(&Event::Verified(digest.clone()));
+    match digest.verified {
+        true => Ok(digest),
+        false => Err(DlError::DigestMismatch(digest.expected, digest.actual)),
+    }
+}
+
+fn duration_secs(started: Instant) -> f64 {
+    let elapsed = started.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
 }
 
 #[cfg(test)]
 mod lib_tests {
     use super::*;
     use crate::checksum::md5sum_check;
-    use std::error::Error;
+    use crate::dns::IpPreference;
+    use crate::https::{HttpVersion, Protocol};
+    use crate::sink::MemorySink;
+    use crate::test_server::TestServer;
+    use crate::transport::{FakeFile, FakeTransport, Reply};
+    use hyper::Method;
+    use std::net::IpAddr;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
     use tokio::runtime::Runtime;
 
-    #[test]
-    fn parsing_valid_cli_args() {
-        assert_eq!(
-            Config::new(vec![
-                String::from("dl"),
-                String::from("https://foo.com"),
-                String::from("bar/baz")
-            ])
-            .unwrap(),
-            Config {
-                uri: Uri::from_static("https://foo.com"),
-                path: PathBuf::from("bar/baz"),
-                parallelism: *DEFAULT_PARALLELISM,
-            }
-        )
+    /// a download of `path` on `server` (which is trusted) to `to`
+    fn download_from(server: &TestServer, path: &str, to: &Path) -> Download {
+        Download::builder()
+            .url(server.url(path))
+            .path(to)
+            .ca_bundle(server.ca_bundle())
+            .build()
+            .unwrap()
     }
 
     #[test]
-    fn parsing_empty_cli_args() {
-        assert_eq!(Config::new(vec![]).err().unwrap(), insufficient_args!());
+    fn running_the_app_against_happy_path() {
+        let path = PathBuf::from("data/happy.pdf");
+        let file = FakeFile::generated(53_143);
+        let md5 = file.md5();
+        let server = TestServer::builder()
+            .file("/resume.pdf", file.etag(&md5))
+            .https()
+            .start();
+
+        let download = download_from(&server, "/resume.pdf", &path);
+        Runtime::new().unwrap().block_on(download.run()).unwrap();
+        assert!(&path.exists());
+        assert!(md5sum_check(&path, &md5).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn parsing_invalid_cli_args() {
-        assert_eq!(
-            Config::new(vec![
-                String::from("dl"),
-                String::from("foo bar"),
-                String::from("bar/baz")
-            ])
-            .err()
-            .unwrap(),
-            invalid_uri!()
-        )
+    fn running_the_app_into_a_sink() {
+        let file = FakeFile::generated(300_000);
+        let download = |file: &FakeFile, verify: Verify, total: Option<Duration>| {
+            // (the file's metadata arrives straight away, but its pieces may never do)
+            let (file, stalled) = (file.clone(), total.is_some());
+            let transport = FakeTransport::new(move |req| match *req.method() {
+                Method::GET if stalled => {
+                    Reply::ok(file.respond(req)).after(Duration::from_secs(60))
+                }
+                _ => Reply::ok(file.respond(req)),
+            });
+            let memory = MemorySink::new(300_000);
+            let sink = memory.clone();
+            let download = Download::builder()
+                .url("https://example.com/file")
+                .transport(transport)
+                .sink(move |_| Ok(Arc::new(sink.clone()) as Arc<dyn Sink>))
+                .parallelism(4)
+                .piece_size(64 * 1024)
+                .verify(verify)
+                .timeouts(Timeouts {
+                    total,
+                    ..Timeouts::default()
+                })
+                .build()
+                .unwrap();
+            let result = Runtime::new().unwrap().block_on(download.run());
+            (result, memory)
+        };
+
+        // the file is checked as it's written...
+        let (summary, memory) = download(&file, Verify::Md5(file.md5()), None);
+        let summary = summary.unwrap();
+        assert_eq!(memory.contents(), *file.content);
+        assert_eq!(summary.path, "<sink>");
+        assert_eq!(summary.size, 300_000);
+        assert!(summary.digests[0].verified);
+        assert_eq!(summary.pieces.len(), 5);
+
+        // ...and the sink is aborted if it doesn't match, or the download runs out of time
+        let (result, memory) = download(&file, Verify::Md5("0".repeat(32)), None);
+        assert_eq!(result.unwrap_err().code(), "digest_mismatch");
+        assert!(memory.contents().is_empty());
+        let (result, memory) = download(&file, Verify::Skip, Some(Duration::from_millis(100)));
+        assert_eq!(result.unwrap_err().code(), "download_timeout");
+        assert!(memory.contents().is_empty());
     }
 
     #[test]
-    fn running_the_app_against_happy_path() {
-        let path = PathBuf::from("data/happy.pdf");
-        let cfg = Config {
-            uri: "https://recurse-uploads-production.s3.amazonaws.com/b9349b0c-359a-473a-9441-c1bc54a96ca6/austin_guest_resume.pdf".parse::<Uri>().unwrap(),
-            path: path.clone(),
-            parallelism: *DEFAULT_PARALLELISM,
-        };
+    fn running_the_app_over_plain_http() {
+        let path = PathBuf::from("data/plain.pdf");
+        let file = FakeFile::generated(53_143);
+        let md5 = file.md5();
+        let server = TestServer::builder()
+            .file("/resume.pdf", file.etag(&md5))
+            .start();
 
-        Runtime::new().unwrap().block_on(run(cfg)).unwrap();
-        assert!(&path.exists());
-        assert!(md5sum_check(&path, "ac89ac31a669c13ec4ce037f1203022c").unwrap());
+        let download = download_from(&server, "/resume.pdf", &path);
+        Runtime::new().unwrap().block_on(download.run()).unwrap();
+        assert!(md5sum_check(&path, &md5).unwrap());
 
         std::fs::remove_file(&path).unwrap();
     }
@@ -181,33 +453,384 @@ mod lib_tests {
     #[test]
     fn running_the_app_against_no_range_link() {
         let path = PathBuf::from("whack");
-        let cfg = Config {
-            uri: "https://google.com".parse::<Uri>().unwrap(),
-            path: path.clone(),
-            parallelism: *DEFAULT_PARALLELISM,
-        };
+        // a page that doesn't advertise range support (like a search engine's home page)
+        let server = TestServer::builder()
+            .file("/", FakeFile::generated(1000).without_ranges())
+            .https()
+            .start();
 
-        let err = Runtime::new().unwrap().block_on(run(cfg)).err().unwrap();
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(download_from(&server, "/", &path).run())
+            .err()
+            .unwrap();
         assert!(!&path.exists());
-        assert_eq!(
-            err.description(),
-            DlError::RangeMetadataAbsent.description()
-        );
+        assert_eq!(err.to_string(), DlError::RangeMetadataAbsent.to_string());
     }
 
     #[test]
     fn running_the_app_against_no_etag_link() {
         let path = PathBuf::from("data/logo.png");
-        let cfg = Config {
-            uri: "https://en.wikipedia.org/wiki/White-tailed_tropicbird#/media/File:White-tailed_tropicbird.jpg".parse::<Uri>().unwrap(),
-            path: path.clone(),
-            parallelism: *DEFAULT_PARALLELISM,
+        let server = TestServer::builder()
+            .file("/logo.png", FakeFile::generated(53_143))
+            .https()
+            .start();
+
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(download_from(&server, "/logo.png", &path).run())
+            .err()
+            .unwrap();
+        assert!(!&path.exists());
+        assert!(!disk::part_path(&path).exists());
+        assert_eq!(err.to_string(), DlError::EtagAbsent.to_string());
+    }
+
+    #[test]
+    fn running_the_app_over_http2_and_http1() {
+        let path = PathBuf::from("data/multiplexed.bin");
+        let file = FakeFile::generated(512 * 1024);
+        let file = file.clone().etag(&file.md5());
+        let download = |server: &TestServer, version: HttpVersion| {
+            let download = Download::builder()
+                .url(server.url("/file"))
+                .path(&path)
+                .ca_bundle(server.ca_bundle())
+                .parallelism(4)
+                .piece_size(64 * 1024)
+                .http_version(version)
+                .build()
+                .unwrap();
+            let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            summary
         };
 
-        let err = Runtime::new().unwrap().block_on(run(cfg)).err().unwrap();
-        assert!(&path.exists());
-        assert_eq!(err.description(), DlError::EtagAbsent.description());
+        // every piece is a stream on the connection the metadata came over
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .https()
+            .start();
+        let summary = download(&server, HttpVersion::Auto);
+        assert_eq!(summary.protocols, vec![Protocol::Http2]);
+        assert_eq!(server.connections(), 1);
 
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .https()
+            .start();
+        let summary = download(&server, HttpVersion::Http1);
+        assert_eq!(summary.protocols, vec![Protocol::Http11]);
+        assert!(server.connections() > 1);
+
+        // servers that don't offer http/2 get http/1.1
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .https()
+            .http1_only()
+            .start();
+        let summary = download(&server, HttpVersion::Auto);
+        assert_eq!(summary.protocols, vec![Protocol::Http11]);
+    }
+
+    #[test]
+    fn running_the_app_over_all_addresses() {
+        let path = PathBuf::from("data/addresses.bin");
+        let file = FakeFile::generated(512 * 1024);
+        let server = TestServer::builder()
+            .file("/file", file.clone().etag(&file.md5()))
+            .https()
+            .start();
+        let download = Download::builder()
+            .url(server.url("/file"))
+            .path(&path)
+            .ca_bundle(server.ca_bundle())
+            .parallelism(4)
+            .piece_size(64 * 1024)
+            .all_addresses(true)
+            .build()
+            .unwrap();
+        let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), *file.content);
         std::fs::remove_file(&path).unwrap();
+
+        // each address localhost resolves to is a source with connections of its own, besides the metadata's (its
+        // ipv6 one, if it has one, falling back on the server's ipv4 address)
+        let localhost = ["127.0.0.1", "::1"];
+        assert!(!summary.sources.is_empty());
+        assert!(summary
+            .sources
+            .iter()
+            .all(|source| localhost.contains(&source.source.as_str()) && source.failures == 0));
+        let bytes: u64 = summary.sources.iter().map(|source| source.bytes).sum();
+        assert_eq!(bytes, 512 * 1024);
+        assert!(server.connections() > summary.sources.len());
+    }
+
+    #[test]
+    fn running_the_app_from_several_local_addresses() {
+        let path = PathBuf::from("data/bound.bin");
+        let file = FakeFile::generated(512 * 1024);
+        let server = TestServer::builder()
+            .file("/file", file.clone().etag(&file.md5()))
+            .https()
+            .start();
+        let download = Download::builder()
+            .url(server.url("/file"))
+            .path(&path)
+            .ca_bundle(server.ca_bundle())
+            .parallelism(4)
+            .piece_size(64 * 1024)
+            .bind("127.0.0.1")
+            .bind("127.0.0.2")
+            .ip_preference(IpPreference::Ipv4)
+            .build()
+            .unwrap();
+        let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+        std::fs::remove_file(&path).unwrap();
+
+        // the metadata comes from the first, and the pieces from each in turn
+        let url = server.url("/file");
+        assert_eq!(
+            summary
+                .sources
+                .iter()
+                .map(|source| source.source.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                format!("{} via 127.0.0.1", url),
+                format!("{} via 127.0.0.2", url)
+            ]
+        );
+        let peers = server.peers();
+        assert_eq!(peers[0], IpAddr::from([127, 0, 0, 1]));
+        assert!(peers.contains(&IpAddr::from([127, 0, 0, 2])));
+    }
+
+    #[test]
+    fn running_the_app_with_resolve_overrides() {
+        let path = PathBuf::from("data/overridden.bin");
+        let file = FakeFile::generated(128 * 1024);
+        let server = TestServer::builder()
+            .file("/file", file.clone().etag(&file.md5()))
+            .https()
+            .start();
+        let port = server.addr().port();
+        let download = |url: String, resolve: String| {
+            let download = Download::builder()
+                .url(url)
+                .path(&path)
+                .ca_bundle(server.ca_bundle())
+                .resolve_host(resolve)
+                .build()
+                .unwrap();
+            Runtime::new().unwrap().block_on(download.run())
+        };
+
+        // connections go where the override says (here, to an address the server isn't listening on)...
+        let err = download(server.url("/file"), format!("localhost:{}:127.0.0.2", port));
+        assert!(err.is_err());
+        assert!(!path.exists());
+
+        download(server.url("/file"), server.resolve("localhost")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+        std::fs::remove_file(&path).unwrap();
+
+        // ...but their certificates are still checked against the url's host
+        let err = download(
+            format!("https://staging.example.com:{}/file", port),
+            server.resolve("staging.example.com"),
+        );
+        assert!(err.is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn running_the_app_from_local_files() {
+        let (original, path) = (
+            PathBuf::from("data/local original.bin"),
+            PathBuf::from("data/local.bin"),
+        );
+        let file = FakeFile::generated(512 * 1024);
+        std::fs::write(&original, &file.content[..]).unwrap();
+        let download = |url: &str, verify: Verify| {
+            let download = Download::builder()
+                .url(url)
+                .path(&path)
+                .parallelism(4)
+                .piece_size(64 * 1024)
+                .verify(verify)
+                .build()
+                .unwrap();
+            let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+            std::fs::remove_file(&path).unwrap();
+            summary
+        };
+
+        // read in pieces, as a server's would be (local files have no etags, so only a given digest is checked)
+        let local_url = local::uri_of(&std::path::absolute(&original).unwrap())
+            .unwrap()
+            .to_string();
+        let summary = download("data/local original.bin", Verify::Etag);
+        assert!(summary.digests.is_empty());
+        assert_eq!(summary.sources.len(), 1);
+        assert_eq!(summary.sources[0].source, local_url);
+        let summary = download(&local_url, Verify::Md5(file.md5()));
+        assert!(summary.digests[0].verified);
+        let err = Download::builder()
+            .url("data/no such file.bin")
+            .path(&path)
+            .build()
+            .unwrap()
+            .run();
+        assert!(Runtime::new().unwrap().block_on(err).is_err());
+
+        // ...and alongside a server's copy
+        let server = TestServer::builder()
+            .file("/file", file.clone().etag(&file.md5()))
+            .https()
+            .start();
+        let url = server.url("/file");
+        let builder = Download::builder()
+            .url(url.clone())
+            .mirror("data/local original.bin")
+            .path(&path)
+            .ca_bundle(server.ca_bundle())
+            .parallelism(4)
+            .piece_size(64 * 1024);
+        let summary = Runtime::new()
+            .unwrap()
+            .block_on(builder.build().unwrap().run())
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+        std::fs::remove_file(&path).unwrap();
+        assert!(summary.digests[0].verified);
+        let mut sources = summary
+            .sources
+            .iter()
+            .map(|source| source.source.clone())
+            .collect::<Vec<_>>();
+        sources.sort();
+        assert_eq!(sources, vec![local_url, url]);
+
+        std::fs::remove_file(&original).unwrap();
+    }
+
+    #[test]
+    fn running_the_app_over_ftp() {
+        let path = PathBuf::from("data/ftp.bin");
+        let file = FakeFile::generated(512 * 1024);
+        for server in [
+            TestServer::builder()
+                .file("/file", file.clone())
+                .ftp()
+                .start(),
+            TestServer::builder()
+                .file("/file", file.clone())
+                .ftp()
+                .https()
+                .start(),
+        ] {
+            let download = Download::builder()
+                .url(server.url("/file"))
+                .path(&path)
+                .ca_bundle(server.ca_bundle())
+                .parallelism(4)
+                .piece_size(64 * 1024)
+                .verify(Verify::Md5(file.md5()))
+                .build()
+                .unwrap();
+            let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+            std::fs::remove_file(&path).unwrap();
+            assert!(summary.digests[0].verified);
+            assert_eq!(summary.protocols, vec![Protocol::Ftp]);
+
+            // each piece over a data connection of its own, from where it starts
+            let commands = server.commands();
+            let restarts = commands
+                .iter()
+                .filter(|command| command.starts_with("REST "))
+                .collect::<Vec<_>>();
+            assert_eq!(restarts.len(), 7);
+            assert!(restarts.contains(&&String::from("REST 458752")));
+            assert_eq!(
+                commands
+                    .iter()
+                    .filter(|command| *command == "RETR file")
+                    .count(),
+                8
+            );
+            assert_eq!(server.connections(), 9);
+        }
+
+        // a file that isn't there fails the download (rather than being retried)
+        let server = TestServer::builder().ftp().start();
+        let download = Download::builder()
+            .url(server.url("/file"))
+            .path(&path)
+            .build()
+            .unwrap();
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(download.run())
+            .unwrap_err();
+        assert_eq!(err.to_string(), DlError::RequestFailed(404).to_string());
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "http3")]
+    #[test]
+    fn running_the_app_over_http3() {
+        let path = PathBuf::from("data/quic.bin");
+        let file = FakeFile::generated(512 * 1024);
+        let file = file.clone().etag(&file.md5());
+        let download = |server: &TestServer, version: HttpVersion| {
+            let download = Download::builder()
+                .url(server.url("/file"))
+                .path(&path)
+                .ca_bundle(server.ca_bundle())
+                .parallelism(4)
+                .piece_size(64 * 1024)
+                .http_version(version)
+                .timeouts(Timeouts {
+                    connect: Some(Duration::from_millis(500)),
+                    tls_handshake: Some(Duration::from_millis(500)),
+                    ..Timeouts::default()
+                })
+                .build()
+                .unwrap();
+            let summary = Runtime::new().unwrap().block_on(download.run()).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), *file.content);
+            std::fs::remove_file(&path).unwrap();
+            summary
+        };
+
+        // the metadata comes over http/2, advertising http/3 (via `Alt-Svc`), which every piece then comes over
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .http3()
+            .start();
+        let summary = download(&server, HttpVersion::Auto);
+        assert_eq!(summary.protocols, vec![Protocol::Http3]);
+        assert_eq!(server.connections(), 2);
+
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .http3()
+            .start();
+        let summary = download(&server, HttpVersion::Http3);
+        assert_eq!(summary.protocols, vec![Protocol::Http3]);
+        assert_eq!(server.connections(), 1);
+
+        // servers that don't speak http/3 get http/2 (once we've given up on reaching them over quic)
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .https()
+            .start();
+        let summary = download(&server, HttpVersion::Http3);
+        assert_eq!(summary.protocols, vec![Protocol::Http2]);
     }
 }
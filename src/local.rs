@@ -0,0 +1,348 @@
+use std::cmp::min;
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{stream, Future};
+use hyper::header::HeaderValue;
+use hyper::{Body, Chunk, Method, Request, Response, StatusCode, Uri};
+
+use crate::disk;
+use crate::error::DlError;
+use crate::transport::{parse_range, ResponseFuture, SharedTransport, Transport};
+
+/// how much of a local file is read at a time
+pub const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// whether `uri` is that of a file on the local file system
+pub fn is_local(uri: &Uri) -> bool {
+    uri.scheme_str() == Some("file")
+}
+
+/// the local file `source` names, if it's a `file://` url (on no host, or `localhost`) or a path, made absolute
+pub fn local_path(source: &str) -> Result<Option<PathBuf>, DlError> {
+    let path = match source.strip_prefix("file://") {
+        Some(rest) if rest.starts_with('/') => decode(rest)?,
+        Some(rest) => match rest.strip_prefix("localhost") {
+            Some(path) if path.starts_with('/') => decode(path)?,
+            _ => {
+                return Err(DlError::InvalidConfig(
+                    "file urls must be local (file:///path or file://localhost/path)",
+                ))
+            }
+        },
+        None if source.is_empty() || source.contains("://") => return Ok(None),
+        None => PathBuf::from(source),
+    };
+    std::path::absolute(path).map(Some).map_err(DlError::Io)
+}
+
+/// the uri `dl` uses for the local file at `path` (an absolute path): `file://localhost/<percent-encoded path>`
+pub fn uri_of(path: &Path) -> Result<Uri, DlError> {
+    let mut uri = String::from("file://localhost");
+    for &b in path_bytes(path).iter() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    Ok(uri.parse::<Uri>()?)
+}
+
+/// the local file a `file://` uri (as made by `uri_of`) is that of
+pub fn path_of(uri: &Uri) -> Result<PathBuf, DlError> {
+    decode(uri.path())
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// percent-decodes a url's path into the path it stands for
+fn decode(encoded: &str) -> Result<PathBuf, DlError> {
+    let invalid = || DlError::InvalidConfig("file urls must be percent-encoded");
+    let bytes = percent_decode(encoded).ok_or_else(invalid)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(bytes)
+            .map(PathBuf::from)
+            .map_err(|_| invalid())
+    }
+}
+
+/// the bytes a percent-encoded part of a url stands for (`None` if one of its escapes isn't two hex digits)
+pub(crate) fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    Some(bytes)
+}
+
+/// a `Transport` that answers `file://` requests from the local file system, and sends every other request on
+/// to `remote`
+pub struct LocalTransport {
+    remote: SharedTransport,
+}
+
+impl LocalTransport {
+    pub fn new(remote: SharedTransport) -> LocalTransport {
+        LocalTransport { remote }
+    }
+}
+
+impl Transport for LocalTransport {
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        match is_local(req.uri()) {
+            true => serve(&req),
+            false => self.remote.request(req),
+        }
+    }
+}
+
+/// answers `req` as a range-supporting web server would, with the file's modification date as its validator
+fn serve(req: &Request<Body>) -> ResponseFuture {
+    let path = match path_of(req.uri()) {
+        Ok(path) => path,
+        Err(err) => return Box::new(futures::future::err(err)),
+    };
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|val| val.to_str().ok())
+            .map(String::from)
+    };
+    let (range, if_range) = (header("range"), header("if-range"));
+    let head = *req.method() == Method::HEAD;
+    let opened = disk::run_blocking(move || {
+        let named = |err: io::Error| {
+            DlError::Io(io::Error::new(
+                err.kind(),
+                format!("{}: {}", path.display(), err),
+            ))
+        };
+        let file = File::open(&path).map_err(named)?;
+        let metadata = file.metadata().map_err(named)?;
+        match metadata.is_file() {
+            true => Ok((file, metadata)),
+            false => Err(named(io::Error::other("not a file"))),
+        }
+    });
+    Box::new(opened.map(move |(file, metadata)| {
+        respond(file, &metadata, range.as_deref(), if_range.as_deref(), head)
+    }))
+}
+
+fn respond(
+    file: File,
+    metadata: &Metadata,
+    range: Option<&str>,
+    if_range: Option<&str>,
+    head: bool,
+) -> Response<Body> {
+    let len = metadata.len();
+    let last_modified = metadata.modified().ok().map(http_date);
+    let range = range
+        .filter(|_| if_range.is_none_or(|date| last_modified.as_deref() == Some(date)))
+        .and_then(parse_range);
+    let (status, start, end) = match range {
+        None => (StatusCode::OK, 0, len),
+        Some((start, end)) if start <= end && end < len => {
+            (StatusCode::PARTIAL_CONTENT, start, end + 1)
+        }
+        Some(_) => (StatusCode::RANGE_NOT_SATISFIABLE, 0, 0),
+    };
+
+    let mut res = match head {
+        true => Response::new(Body::empty()),
+        false => {
+            let file = Arc::new(file);
+            let chunks = stream::unfold(start, move |at| {
+                if at >= end {
+                    return None;
+                }
+                let file = file.clone();
+                let len = min(READ_CHUNK_SIZE as u64, end - at) as usize;
+                Some(disk::run_blocking(move || {
+                    let mut buf = vec![0; len];
+                    // (a file that shrinks while it's read comes up short)
+                    match disk::read_at(&file, &mut buf, at)? {
+                        0 => Err(DlError::ShortRead),
+                        n => {
+                            buf.truncate(n);
+                            Ok((Chunk::from(buf), at + n as u64))
+                        }
+                    }
+                }))
+            });
+            Response::new(Body::wrap_stream(chunks))
+        }
+    };
+    *res.status_mut() = status;
+    let headers = res.headers_mut();
+    headers.insert("content-length", HeaderValue::from(end - start));
+    headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+    if let Some(date) = last_modified {
+        headers.insert("last-modified", HeaderValue::from_str(&date).unwrap());
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        let range = format!("bytes {}-{}/{}", start, end - 1, len);
+        headers.insert("content-range", HeaderValue::from_str(&range).unwrap());
+    }
+    res
+}
+
+/// formats `time` as an http date (e.g. `Wed, 21 Oct 2015 07:28:00 GMT`)
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // the civil date `days` after the epoch, counting years from march (so leap days fall at their end)
+    let era = (days + 719_468) / 146_097;
+    let day_of_era = days + 719_468 - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = (month_from_march + 2) % 12;
+    let year = era * 400 + year_of_era + u64::from(month < 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod local_tests {
+    use super::*;
+    use crate::transport::FakeTransport;
+    use futures::Stream;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn naming_local_files() {
+        let path = |source: &str| local_path(source).unwrap();
+        assert_eq!(path("https://foo.com/a"), None);
+        assert_eq!(path(""), None);
+        assert_eq!(path("/tmp/a b"), Some(PathBuf::from("/tmp/a b")));
+        assert_eq!(path("file:///tmp/a%20b"), Some(PathBuf::from("/tmp/a b")));
+        assert_eq!(
+            path("file://localhost/tmp/a"),
+            Some(PathBuf::from("/tmp/a"))
+        );
+        assert_eq!(
+            path("data/a"),
+            Some(std::env::current_dir().unwrap().join("data/a"))
+        );
+        assert_eq!(
+            local_path("file://foo.com/tmp/a").unwrap_err().code(),
+            "invalid_config"
+        );
+
+        let uri = uri_of(Path::new("/tmp/a b%/ü")).unwrap();
+        assert_eq!(uri.to_string(), "file://localhost/tmp/a%20b%25/%C3%BC");
+        assert!(is_local(&uri));
+        assert_eq!(path_of(&uri).unwrap(), PathBuf::from("/tmp/a b%/ü"));
+    }
+
+    #[test]
+    fn formatting_http_dates() {
+        let date = |secs| http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(1_445_412_480), "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(date(951_825_600), "Tue, 29 Feb 2000 12:00:00 GMT");
+    }
+
+    #[test]
+    fn serving_local_files() {
+        let path = PathBuf::from("data/foo_local.bin");
+        std::fs::write(&path, (0..200).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        let uri = uri_of(&std::path::absolute(&path).unwrap()).unwrap();
+        let remote = FakeTransport::new(|_| crate::transport::Reply::status(StatusCode::NOT_FOUND));
+        let transport = LocalTransport::new(SharedTransport::new(remote.clone()));
+        let mut rt = Runtime::new().unwrap();
+        let mut send = |req: Request<Body>| {
+            rt.block_on(transport.request(req).and_then(|res| {
+                let (parts, body) = res.into_parts();
+                body.concat2()
+                    .map_err(DlError::from)
+                    .map(move |body| (parts, body.to_vec()))
+            }))
+        };
+
+        let (head, _) = send(Request::head(&uri).body(Body::empty()).unwrap()).unwrap();
+        assert_eq!(head.status, StatusCode::OK);
+        assert_eq!(head.headers["content-length"], "200");
+        assert_eq!(head.headers["accept-ranges"], "bytes");
+        let last_modified = head.headers["last-modified"].to_str().unwrap().to_string();
+
+        let get = |range: &str, if_range: &str| {
+            Request::get(&uri)
+                .header("range", range)
+                .header("if-range", if_range)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (part, body) = send(get("bytes=100-199", &last_modified)).unwrap();
+        assert_eq!(part.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(part.headers["content-range"], "bytes 100-199/200");
+        assert_eq!(body, (100..200).map(|i| i as u8).collect::<Vec<_>>());
+        let (stale, body) = send(get("bytes=0-9", "Thu, 01 Jan 1970 00:00:00 GMT")).unwrap();
+        assert_eq!(stale.status, StatusCode::OK);
+        assert_eq!(body.len(), 200);
+        let (unsatisfiable, _) = send(get("bytes=200-209", &last_modified)).unwrap();
+        assert_eq!(unsatisfiable.status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let missing = uri_of(&std::path::absolute("data/foo_missing.bin").unwrap()).unwrap();
+        let err = send(Request::head(&missing).body(Body::empty()).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("foo_missing.bin"));
+        let (remote_res, _) = send(
+            Request::head("https://foo.com/a")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(remote_res.status, StatusCode::NOT_FOUND);
+        assert_eq!(remote.requests().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
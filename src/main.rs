@@ -1,21 +1,141 @@
-use dl::Config;
-use futures::future::Future;
+use clap::ErrorKind;
+use dl::checksum::HashChecker;
+use dl::error::DlError;
+use dl::output::{Event, OutputFormat, Reporter};
+use dl::settings::Profile;
+use futures::future::{self, Future};
+use futures::stream::{self, Stream};
 use hyper::rt;
 use std::env;
+use std::fs;
+use std::io::{self, Read};
 use std::process;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+use crate::cli::{Command, Expected};
+
+mod cli;
 
-    let cfg = Config::new(args).unwrap_or_else(|err| {
-        eprintln!("{}", err);
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let output_format = cli::output_format_of(&args);
+    let profile = Profile::load().unwrap_or_else(|err| {
+        Reporter::new(output_format).emit(&Event::from_error(&err));
         process::exit(1);
     });
+    let cli = cli::parse(args, &profile).unwrap_or_else(|err| match (err.kind, output_format) {
+        (ErrorKind::HelpDisplayed, _)
+        | (ErrorKind::VersionDisplayed, _)
+        | (_, OutputFormat::Text) => err.exit(),
+        (_, OutputFormat::Json) => {
+            Reporter::new(output_format).emit(&cli::error_event(&err));
+            process::exit(1);
+        }
+    });
+    let reporter = match cli.streaming {
+        true => Reporter::stderr(cli.output_format),
+        false => Reporter::new(cli.output_format),
+    };
 
-    rt::run(rt::lazy(|| {
-        dl::run(cfg).map_err(|err| {
-            eprintln!("> Error: {}", err);
+    let command = execute(cli.command, reporter).unwrap_or_else(|err| {
+        reporter.emit(&Event::from_error(&err));
+        process::exit(1);
+    });
+    rt::run(rt::lazy(move || {
+        command.map_err(move |err| {
+            reporter.emit(&Event::from_error(&err));
             process::exit(1);
         })
     }));
 }
+
+/// checks everything `command` needs up front, returning a future that carries it out
+fn execute(
+    command: Command,
+    reporter: Reporter,
+) -> Result<Box<dyn Future<Item = (), Error = DlError> + Send>, DlError> {
+    match command {
+        Command::Get(builder) => Ok(Box::new(builder.build()?.run().map(|_| ()))),
+        Command::Info(builder) => Ok(Box::new(
+            builder
+                .build_for_info()?
+                .info()
+                .map(move |info| reporter.emit(&Event::Info(info))),
+        )),
+        Command::Verify { path, expected } => {
+            let expected: Box<dyn Future<Item = String, Error = DlError> + Send> = match expected {
+                Expected::Md5(digest) => Box::new(future::ok(digest)),
+                Expected::Etag(builder) => Box::new(
+                    builder
+                        .build_for_info()?
+                        .info()
+                        .and_then(|info| info.etag.ok_or(DlError::EtagAbsent)),
+                ),
+            };
+            Ok(Box::new(expected.and_then(move |expected| {
+                reporter.emit(&Event::Verifying);
+                let hash_checker = HashChecker {
+                    path,
+                    etag: None,
+                    pieces: vec![],
+                };
+                hash_checker.verify_md5(&expected).and_then(move |digest| {
+                    reporter.emit(&Event::Verified(digest.clone()));
+                    match digest.verified {
+                        true => Ok(()),
+                        false => Err(DlError::DigestMismatch(digest.expected, digest.actual)),
+                    }
+                })
+            })))
+        }
+        Command::ConfigShow(builder) => {
+            reporter.emit(&Event::Settings {
+                settings: builder.resolve().report(),
+            });
+            Ok(Box::new(future::ok(())))
+        }
+        Command::Batch {
+            list,
+            builder,
+            jobs,
+        } => {
+            let list = match list.as_str() {
+                "-" => {
+                    let mut list = String::new();
+                    io::stdin().read_to_string(&mut list)?;
+                    list
+                }
+                path => fs::read_to_string(path)?,
+            };
+            // build every download before starting any, so that a typo on the last line doesn't leave
+            // the batch half done
+            let downloads = cli::parse_batch(&list)?
+                .into_iter()
+                .map(|(url, path)| builder.clone().url(url).path(path).build())
+                .collect::<Result<Vec<_>, DlError>>()?;
+            let total = downloads.len();
+
+            Ok(Box::new(
+                stream::iter_ok(downloads)
+                    .map(|download| download.run().then(Ok::<_, DlError>))
+                    .buffer_unordered(jobs)
+                    .fold(0, move |failed, result| {
+                        if let Err(err) = result {
+                            reporter.emit(&Event::from_error(&err));
+                            return Ok::<_, DlError>(failed + 1);
+                        }
+                        Ok(failed)
+                    })
+                    .and_then(move |failed| {
+                        reporter.emit(&Event::BatchComplete {
+                            downloaded: total - failed,
+                            failed,
+                        });
+                        match failed {
+                            0 => Ok(()),
+                            _ => Err(DlError::BatchFailed(failed, total)),
+                        }
+                    }),
+            ))
+        }
+    }
+}
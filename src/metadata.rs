@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::time::Duration;
 
-use futures::future::IntoFuture;
+use futures::future::{self, IntoFuture};
 use hyper;
 use hyper::header::HeaderValue;
 use hyper::rt::Future;
@@ -9,79 +9,237 @@ use hyper::StatusCode;
 use hyper::{Body, Request};
 use hyper::{Method, Uri};
 
+use crate::download::Destination;
 use crate::error::DlError;
-use crate::file::FileDownloader;
-use crate::https::{self, HttpsClient};
+use crate::file::{FileDownloader, RetryPolicy};
+use crate::https::{self, Network};
+use crate::output::Reporter;
+use crate::timeout::{self, Timeouts};
+use crate::transport::{SharedTransport, Transport};
 use crate::Config;
 
-pub const BYTES_RANGE_TYPE: &'static str = "bytes";
-pub const BINARY_CONTENT_TYPE: &'static str = "binary/octet-stream";
+pub const BYTES_RANGE_TYPE: &str = "bytes";
+pub const BINARY_CONTENT_TYPE: &str = "binary/octet-stream";
+pub const MAX_REDIRECTS: usize = 10;
 
 #[derive(Debug, PartialEq)]
 pub struct Metadata {
     pub file_size: u64,
     pub etag: Option<String>,
+    pub validator: Option<Validator>,
+}
+
+/// identifies the version of the remote file we fetched metadata for, so that every later request can ask
+/// the server to make sure it is still serving that same version (via `If-Range` / `If-Match`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Validator {
+    /// a strong etag, exactly as the server sent it (quotes included)
+    Etag(String),
+    /// a `Last-Modified` date, exactly as the server sent it
+    LastModified(String),
+}
+
+impl Validator {
+    /// the value to send in an `If-Range` header
+    pub fn header_value(&self) -> &str {
+        match *self {
+            Validator::Etag(ref etag) => etag,
+            Validator::LastModified(ref date) => date,
+        }
+    }
+
+    /// whether a response's headers describe the same version of the file as this validator
+    pub fn matches(&self, headers: &HeaderMap<HeaderValue>) -> bool {
+        let (name, expected) = match *self {
+            Validator::Etag(ref etag) => ("etag", etag),
+            Validator::LastModified(ref date) => ("last-modified", date),
+        };
+        headers
+            .get(name)
+            .and_then(|val| val.to_str().ok())
+            .is_some_and(|actual| actual == expected)
+    }
+}
+
+/// another uri serving the same file as the primary one, from which pieces can be downloaded in parallel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirror {
+    pub uri: Uri,
+    pub validator: Option<Validator>,
 }
 
 #[derive(Debug)]
 pub struct MetadataDownloader {
-    pub client: HttpsClient,
+    pub transport: SharedTransport,
     pub uri: Uri,
-    pub path: PathBuf,
+    pub mirrors: Vec<Uri>,
+    pub headers: HeaderMap<HeaderValue>,
+    pub destination: Destination,
     pub parallelism: usize,
+    pub piece_size: Option<u64>,
+    pub retry: RetryPolicy,
+    pub rate_limit: Option<u64>,
+    pub timeouts: Timeouts,
+    pub reporter: Reporter,
+    pub network: Network,
 }
 
 impl MetadataDownloader {
-    /// constructs a `MetadataDownloader` from a `Config` struct
-    pub fn from_config(cfg: Config) -> MetadataDownloader {
-        Self {
-            client: https::get_client(cfg.parallelism),
+    /// constructs a `MetadataDownloader` from a `Config` struct (failing if its ca bundle can't be loaded)
+    pub fn from_config(cfg: Config) -> Result<MetadataDownloader, DlError> {
+        let reporter = cfg.reporter();
+        Ok(Self {
+            transport: https::transport_for(cfg.parallelism, &cfg.timeouts, &cfg.network)?,
             uri: cfg.uri,
-            path: cfg.path,
+            mirrors: cfg.mirrors,
+            headers: cfg.headers,
+            destination: cfg.destination,
             parallelism: cfg.parallelism,
-        }
+            piece_size: cfg.piece_size,
+            retry: cfg.retry,
+            rate_limit: cfg.rate_limit,
+            timeouts: cfg.timeouts,
+            reporter,
+            network: cfg.network,
+        })
     }
 
-    /// Tries several strategies to return file metadata and returns an error if not possible
+    /// Tries several strategies to return file metadata and returns an error if not possible (spreading the
+    /// `FileDownloader`'s connections across its host's addresses and the local addresses to bind to, if the
+    /// `network` says to)
     pub fn fetch(self) -> impl Future<Item = FileDownloader, Error = DlError> {
         // TODO: write a `fetch` that tries several strategies
+        let network = self.network.clone();
         self.fetch_head()
+            .and_then(move |file_downloader| file_downloader.spread_over(&network))
     }
 
-    /// Issues a HEAD request to the downloader's `uri`.
+    /// Issues a HEAD request to the downloader's `uri` and each of its `mirrors` (following up to
+    /// `MAX_REDIRECTS` redirects).
     ///
-    /// Inspects the response to determine:
-    /// - whether the uri supports range requests or not
+    /// Inspects the responses to determine:
+    /// - whether the uris support range requests or not
     /// - the size of the file, and (optionally) its etag
     ///
-    /// **Happy path:** Resolves future with `Metadata` struct
+    /// **Happy path:** Resolves future with a `FileDownloader` for the file
     ///
     /// **Sad path:** Resolves future with `Error` indicating whether:
     /// - request or header parsing failed
     /// - metadata headers not present
+    /// - a mirror serves a file of a different size than the primary uri
     pub fn fetch_head(self) -> impl Future<Item = FileDownloader, Error = DlError> {
-        let req = Request::builder()
-            .uri(&self.uri)
-            .method(Method::HEAD)
-            .body(Body::empty())
-            .expect("Failed to build request object");
-
-        self.client
-            .request(req)
-            .map_err(DlError::Hyper)
-            .and_then(|res| {
-                let (status, headers) = (res.status(), res.headers());
-                is_success(status)
-                    .and_then(|_| have_file_metadata(headers))
-                    .and_then(|_| parse_file_metadata(headers))
-                    .map(|md| FileDownloader::from_metadata(self, md))
-                    .into_future()
-            })
+        let head = |uri: &Uri| {
+            fetch_head_following(
+                &self.transport,
+                uri.clone(),
+                &self.headers,
+                self.timeouts.first_byte,
+                MAX_REDIRECTS,
+            )
+        };
+        let primary = head(&self.uri);
+        let mirrors = future::join_all(self.mirrors.iter().map(head).collect::<Vec<_>>());
+
+        primary.join(mirrors).and_then(move |((uri, md), mirrors)| {
+            if let Some((_, mismatch)) = mirrors.iter().find(|(_, m)| m.file_size != md.file_size) {
+                return Err(DlError::MirrorMismatch(md.file_size, mismatch.file_size));
+            }
+            let mirrors = mirrors
+                .into_iter()
+                .map(|(uri, m)| Mirror {
+                    uri,
+                    validator: m.validator,
+                })
+                .collect();
+            Ok(FileDownloader::from_metadata(
+                Self { uri, ..self },
+                md,
+                mirrors,
+            ))
+        })
+    }
+}
+
+/// issues a HEAD request to `uri`, resolving with the uri the file was finally found at (after up to
+/// `redirects_left` redirects) and its `Metadata`
+fn fetch_head_following(
+    transport: &SharedTransport,
+    uri: Uri,
+    headers: &HeaderMap<HeaderValue>,
+    first_byte_timeout: Option<Duration>,
+    redirects_left: usize,
+) -> Box<dyn Future<Item = (Uri, Metadata), Error = DlError> + Send> {
+    let mut req = Request::builder()
+        .uri(&uri)
+        .method(Method::HEAD)
+        .body(Body::empty())
+        .expect("Failed to build request object");
+    https::add_headers(&mut req, headers);
+
+    let response = timeout::deadline(
+        transport.request(req),
+        first_byte_timeout,
+        DlError::FirstByteTimeout,
+    );
+    let (transport, headers) = (transport.clone(), headers.clone());
+    Box::new(response.and_then(
+        move |res| -> Box<dyn Future<Item = (Uri, Metadata), Error = DlError> + Send> {
+            let (status, res_headers) = (res.status(), res.headers());
+            match redirect_location(&uri, status, res_headers) {
+                Some(Ok(_)) if redirects_left == 0 => {
+                    Box::new(future::err(DlError::TooManyRedirects))
+                }
+                Some(Ok(location)) => Box::new(fetch_head_following(
+                    &transport,
+                    location,
+                    &headers,
+                    first_byte_timeout,
+                    redirects_left - 1,
+                )),
+                Some(Err(err)) => Box::new(future::err(err)),
+                None => Box::new(
+                    is_success(status)
+                        .and_then(|_| have_file_metadata(res_headers))
+                        .and_then(|_| parse_file_metadata(res_headers))
+                        .map(|md| (uri, md))
+                        .into_future(),
+                ),
+            }
+        },
+    ))
+}
+
+/// returns the (absolute) uri a response redirects us to, if any
+fn redirect_location(
+    base: &Uri,
+    status: StatusCode,
+    headers: &HeaderMap<HeaderValue>,
+) -> Option<Result<Uri, DlError>> {
+    match status.is_redirection() && status != StatusCode::NOT_MODIFIED {
+        false => None,
+        true => headers
+            .get("location")
+            .and_then(|val| val.to_str().ok())
+            .map(|location| resolve_location(base, location)),
+    }
+}
+
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri, DlError> {
+    let uri = location.parse::<Uri>()?;
+    match (uri.scheme_part(), base.scheme_part(), base.authority_part()) {
+        (Some(_), _, _) => Ok(uri),
+        (None, Some(scheme), Some(authority)) => Uri::builder()
+            .scheme(scheme.clone())
+            .authority(authority.clone())
+            .path_and_query(uri.path_and_query().map_or("/", |pq| pq.as_str()))
+            .build()
+            .map_err(DlError::Http),
+        _ => Ok(uri),
     }
 }
 
 fn is_success(status: StatusCode) -> Result<(), DlError> {
-    match status.is_success() || status.is_redirection() {
+    match status.is_success() {
         true => Ok(()),
         false => Err(DlError::RequestFailed(status.as_u16())),
     }
@@ -98,7 +256,12 @@ fn have_file_metadata(headers: &HeaderMap<HeaderValue>) -> Result<(), DlError> {
 
 fn parse_file_metadata(headers: &HeaderMap<HeaderValue>) -> Result<Metadata, DlError> {
     let etag: Option<String> = parse_etag(headers);
-    parse_length(headers).map(|file_size| Metadata { file_size, etag })
+    let validator = parse_validator(headers);
+    parse_length(headers).map(|file_size| Metadata {
+        file_size,
+        etag,
+        validator,
+    })
 }
 
 fn parse_length(headers: &HeaderMap<HeaderValue>) -> Result<u64, DlError> {
@@ -109,35 +272,66 @@ fn parse_length(headers: &HeaderMap<HeaderValue>) -> Result<u64, DlError> {
         .ok_or(DlError::ParseContentLength)
 }
 
+/// the etag's opaque tag, without its quotes (or its `W/`, if it is weak): `None` if it isn't quoted
 fn parse_etag(headers: &HeaderMap<HeaderValue>) -> Option<String> {
-    headers
-        .get("etag")
-        .map(|val| val.to_str())
-        .and_then(|maybe_str| maybe_str.ok().map(|s| s[1..s.len() - 1].to_string()))
-    // remove escaped quotes
+    let etag = headers.get("etag")?.to_str().ok()?;
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    etag.strip_prefix('"')?.strip_suffix('"').map(String::from)
+}
+
+/// prefers a strong etag (weak ones aren't allowed in `If-Range`), falling back to `Last-Modified`
+fn parse_validator(headers: &HeaderMap<HeaderValue>) -> Option<Validator> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|val: &HeaderValue| val.to_str().ok())
+            .map(String::from)
+    };
+    match header("etag") {
+        Some(ref etag) if !etag.starts_with("W/") => Some(Validator::Etag(etag.clone())),
+        _ => header("last-modified").map(Validator::LastModified),
+    }
 }
 
 #[cfg(test)]
 mod metadata_tests {
-    use std::error::Error;
+    use std::path::PathBuf;
 
+    use hyper::Response;
     use tokio::runtime::Runtime;
 
-    use crate::https;
+    use crate::output::OutputFormat;
+    use crate::test_server::TestServer;
+    use crate::transport::{FakeFile, FakeTransport, Reply};
     use crate::DEFAULT_PARALLELISM;
 
     use super::*;
 
-    const SMALL_FILE_URL: &'static str = "https://recurse-uploads-production.s3.amazonaws.com/b9349b0c-359a-473a-9441-c1bc54a96ca6/austin_guest_resume.pdf";
+    fn metadata_downloader(transport: SharedTransport, url: &str) -> MetadataDownloader {
+        MetadataDownloader {
+            transport,
+            uri: url.parse::<Uri>().unwrap(),
+            mirrors: vec![],
+            headers: HeaderMap::new(),
+            destination: Destination::File(PathBuf::from("data/foo_meta.pdf")),
+            parallelism: *DEFAULT_PARALLELISM,
+            piece_size: None,
+            retry: RetryPolicy::default(),
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+            reporter: Reporter::new(OutputFormat::Text),
+            network: Network::default(),
+        }
+    }
 
     #[test]
     fn fetching_file_metadata() {
-        let mdd = MetadataDownloader {
-            client: https::get_client(*DEFAULT_PARALLELISM),
-            uri: SMALL_FILE_URL.parse::<Uri>().unwrap(),
-            path: PathBuf::from("data/foo_meta.pdf"),
-            parallelism: *DEFAULT_PARALLELISM,
-        };
+        let file = FakeFile::generated(53143).etag("ac89ac31a669c13ec4ce037f1203022c");
+        let server = TestServer::builder()
+            .file("/resume.pdf", file)
+            .https()
+            .start();
+        let mdd = metadata_downloader(server.transport(), &server.url("/resume.pdf"));
 
         let fd = Runtime::new().unwrap().block_on(mdd.fetch()).unwrap();
 
@@ -146,17 +340,71 @@ mod metadata_tests {
             fd.etag,
             Some(String::from("ac89ac31a669c13ec4ce037f1203022c"))
         );
+        assert_eq!(server.requests()[0].method, Method::HEAD);
     }
 
     #[test]
-    fn handling_absent_file_metadata() {
-        let mdd = MetadataDownloader {
-            client: https::get_client(*DEFAULT_PARALLELISM),
-            uri: "https://google.com".parse::<Uri>().unwrap(),
-            path: PathBuf::from("data/foo_meta.pdf"),
-            parallelism: *DEFAULT_PARALLELISM,
+    fn following_redirects_to_file_metadata() {
+        let file = FakeFile::new(vec![0; 100]);
+        let transport = FakeTransport::new(move |req| match req.uri().path() {
+            "/file" => Reply::ok(file.respond(req)),
+            _ => {
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::FOUND;
+                res.headers_mut()
+                    .insert("location", HeaderValue::from_static("/file"));
+                Reply::ok(res)
+            }
+        });
+
+        let fd = Runtime::new()
+            .unwrap()
+            .block_on(
+                metadata_downloader(SharedTransport::new(transport), "https://example.com/old")
+                    .fetch(),
+            )
+            .unwrap();
+
+        assert_eq!(fd.uri, "https://example.com/file".parse::<Uri>().unwrap());
+        assert_eq!(fd.file_size, 100);
+    }
+
+    #[test]
+    fn giving_up_on_redirects() {
+        let transport = FakeTransport::new(move |req| {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::FOUND;
+            if req.uri().path() == "/loop" {
+                res.headers_mut()
+                    .insert("location", HeaderValue::from_static("/loop"));
+            }
+            Reply::ok(res)
+        });
+        let transport = SharedTransport::new(transport);
+        let fetch = |url| {
+            Runtime::new()
+                .unwrap()
+                .block_on(metadata_downloader(transport.clone(), url).fetch())
+                .err()
+                .unwrap()
         };
 
+        let err = fetch("https://example.com/loop");
+        assert_eq!(err.code(), "too_many_redirects");
+        // a redirect with nowhere to go isn't a file
+        let err = fetch("https://example.com/nowhere");
+        assert_eq!(err.to_string(), DlError::RequestFailed(302).to_string());
+    }
+
+    #[test]
+    fn handling_absent_file_metadata() {
+        // a page that doesn't advertise range support (like a search engine's home page)
+        let server = TestServer::builder()
+            .file("/", FakeFile::generated(100).without_ranges())
+            .https()
+            .start();
+        let mdd = metadata_downloader(server.transport(), &server.url("/"));
+
         let future_result = mdd.fetch();
         let err = Runtime::new()
             .unwrap()
@@ -164,9 +412,61 @@ mod metadata_tests {
             .err()
             .unwrap();
 
+        assert_eq!(err.to_string(), DlError::RangeMetadataAbsent.to_string());
+    }
+
+    #[test]
+    fn parsing_validators() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_validator(&headers), None);
+
+        headers.insert(
+            "last-modified",
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        let last_modified = Validator::LastModified(String::from("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_validator(&headers), Some(last_modified.clone()));
+
+        headers.insert("etag", HeaderValue::from_static("W/\"abc\""));
+        assert_eq!(parse_validator(&headers), Some(last_modified));
+
+        headers.insert("etag", HeaderValue::from_static("\"abc\""));
+        let etag = Validator::Etag(String::from("\"abc\""));
+        assert_eq!(parse_validator(&headers), Some(etag.clone()));
+        assert_eq!(etag.header_value(), "\"abc\"");
+        assert!(etag.matches(&headers));
+
+        headers.insert("etag", HeaderValue::from_static("\"def\""));
+        assert!(!etag.matches(&headers));
+
+        let etag = |value| {
+            let mut headers = HeaderMap::new();
+            headers.insert("etag", HeaderValue::from_static(value));
+            parse_etag(&headers)
+        };
+        assert_eq!(etag("\"abc\""), Some(String::from("abc")));
+        assert_eq!(etag("W/\"abc\""), Some(String::from("abc")));
+        assert_eq!(etag("\"\""), Some(String::new()));
+        assert_eq!(etag("\""), None);
+        assert_eq!(etag(""), None);
+        assert_eq!(etag("abc"), None);
+    }
+
+    #[test]
+    fn resolving_absolute_redirect_locations() {
+        let base = "https://foo.com/a/b".parse::<Uri>().unwrap();
+        assert_eq!(
+            resolve_location(&base, "https://bar.com/c?d=e").unwrap(),
+            "https://bar.com/c?d=e".parse::<Uri>().unwrap()
+        );
+    }
+
+    #[test]
+    fn resolving_relative_redirect_locations() {
+        let base = "https://foo.com/a/b".parse::<Uri>().unwrap();
         assert_eq!(
-            err.description(),
-            DlError::RangeMetadataAbsent.description()
+            resolve_location(&base, "/c?d=e").unwrap(),
+            "https://foo.com/c?d=e".parse::<Uri>().unwrap()
         );
     }
 }
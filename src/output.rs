@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::checksum::DigestReport;
+use crate::error::DlError;
+use crate::file::PieceReport;
+use crate::https::Protocol;
+
+/// how `dl` reports its progress: human-readable lines or machine-readable json-lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<OutputFormat, ()> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// everything we know about a completed download (emitted as the final json event)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub url: String,
+    pub final_url: String,
+    pub path: String,
+    pub size: u64,
+    pub etag: Option<String>,
+    pub digests: Vec<DigestReport>,
+    pub duration_secs: f64,
+    pub throughput_bytes_per_sec: f64,
+    pub pieces: Vec<PieceReport>,
+    /// every version of http the pieces came over
+    pub protocols: Vec<Protocol>,
+    /// how much of the file came from each source
+    pub sources: Vec<SourceReport>,
+}
+
+/// how much of a file came from one source (a uri, or an address of its host), and how reliably
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceReport {
+    pub source: String,
+    pub pieces: usize,
+    pub bytes: u64,
+    /// how many attempts at its pieces failed (and were retried)
+    pub failures: u32,
+    /// its share of the download's throughput (the bytes it served over the whole download)
+    pub throughput_bytes_per_sec: f64,
+}
+
+impl Summary {
+    /// the versions of http `pieces` came over (each once, oldest first)
+    pub fn protocols_of(pieces: &[PieceReport]) -> Vec<Protocol> {
+        pieces
+            .iter()
+            .filter_map(|piece| piece.protocol)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// what each source `pieces` came from contributed to a download that took `duration_secs` (by source)
+    pub fn sources_of(pieces: &[PieceReport], duration_secs: f64) -> Vec<SourceReport> {
+        let mut sources = BTreeMap::new();
+        for piece in pieces {
+            let source = sources
+                .entry(piece.source.clone())
+                .or_insert_with(|| SourceReport {
+                    source: piece.source.clone(),
+                    pieces: 0,
+                    bytes: 0,
+                    failures: 0,
+                    throughput_bytes_per_sec: 0.0,
+                });
+            source.pieces += 1;
+            source.bytes += piece.length;
+            source.failures += piece.retries;
+        }
+        sources
+            .into_values()
+            .map(|source| SourceReport {
+                throughput_bytes_per_sec: source.bytes as f64 / duration_secs,
+                ..source
+            })
+            .collect()
+    }
+}
+
+/// what a server tells us about a file, without downloading it (see `dl info`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileInfo {
+    pub url: String,
+    pub final_url: String,
+    pub size: u64,
+    pub etag: Option<String>,
+    /// what later requests would send in `If-Range` (a strong etag or a `Last-Modified` date)
+    pub validator: Option<String>,
+    pub mirrors: Vec<String>,
+}
+
+/// the effective value of a setting, and where it came from (see `dl config show`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SettingReport {
+    pub name: String,
+    pub value: Option<String>,
+    pub source: String,
+}
+
+/// things that happen over the course of a download that a user might want to know about
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    FetchingMetadata {
+        url: String,
+    },
+    Metadata {
+        url: String,
+        final_url: String,
+        size: u64,
+        etag: Option<String>,
+    },
+    Downloading {
+        path: String,
+        size: u64,
+    },
+    PieceSplit {
+        index: u64,
+        at: u64,
+        new_index: u64,
+    },
+    PieceComplete(PieceReport),
+    Retrying {
+        index: u64,
+        retries: u32,
+        code: &'static str,
+        message: String,
+    },
+    SingleStream,
+    Downloaded {
+        path: String,
+    },
+    Verifying,
+    Verified(DigestReport),
+    Summary(Summary),
+    Info(FileInfo),
+    Settings {
+        settings: Vec<SettingReport>,
+    },
+    BatchComplete {
+        downloaded: usize,
+        failed: usize,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl Event {
+    /// constructs an `Error` event, taking its (stable) code from the `DlError` variant
+    pub fn from_error(err: &DlError) -> Event {
+        Event::Error {
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+
+    /// renders the event the way `dl` has always printed it (or `None` if it is only of interest to machines)
+    fn to_text(&self) -> Option<String> {
+        match *self {
+            Event::FetchingMetadata { .. } => Some(String::from("> fetching file metadata...")),
+            Event::Metadata { size, ref etag, .. } => Some(format!(
+                "> ...found metadata. file size: {}, etag: {}",
+                size,
+                etag.clone().unwrap_or_else(|| String::from("N/A")),
+            )),
+            Event::Downloading { .. } => Some(String::from("> downloading file...")),
+            Event::PieceSplit { .. } => None,
+            Event::PieceComplete(_) => None,
+            Event::Retrying {
+                index,
+                retries,
+                ref message,
+                ..
+            } => Some(format!(
+                "> ...retrying piece {} (attempt {}) after error: {}",
+                index,
+                retries + 1,
+                message
+            )),
+            Event::SingleStream => Some(String::from(
+                "> ...server ignored range request, downloading in a single stream...",
+            )),
+            Event::Downloaded { ref path } => Some(format!(
+                "> ...file downloaded!\n\n>>>>> file ready at: {} <<<<<\n",
+                path
+            )),
+            Event::Verifying => Some(String::from("> verifying etag (if present)...")),
+            Event::Verified(ref digest) => match digest.verified {
+                true => Some(String::from("> ...hashes match!")),
+                false => Some(String::from("> ...hashes do not match. :(")),
+            },
+            Event::Summary(ref summary) => {
+                let protocols = match summary.protocols.is_empty() {
+                    true => None,
+                    false => Some(format!(
+                        "> downloaded over {}",
+                        summary
+                            .protocols
+                            .iter()
+                            .map(|p| p.to_string())
+                            .collect::<Vec<_>>()
+                            .join(" and ")
+                    )),
+                };
+                // (only worth a breakdown when there was more than one)
+                let sources = summary.sources.iter().filter(|_| summary.sources.len() > 1).map(|s| {
+                    format!(
+                        "> from {}: {} bytes in {} pieces ({:.0} bytes/sec), {} failures",
+                        s.source, s.bytes, s.pieces, s.throughput_bytes_per_sec, s.failures
+                    )
+                });
+                let lines = protocols.into_iter().chain(sources).collect::<Vec<_>>();
+                match lines.is_empty() {
+                    true => None,
+                    false => Some(lines.join("\n")),
+                }
+            }
+            Event::Info(ref info) => Some(format!(
+                "> url: {}\n> final url: {}\n> size: {}\n> etag: {}\n> validator: {}\n> mirrors: {}",
+                info.url,
+                info.final_url,
+                info.size,
+                info.etag.clone().unwrap_or_else(|| String::from("N/A")),
+                info.validator.clone().unwrap_or_else(|| String::from("N/A")),
+                match info.mirrors.is_empty() {
+                    true => String::from("N/A"),
+                    false => info.mirrors.join(", "),
+                },
+            )),
+            Event::Settings { ref settings } => Some(
+                settings
+                    .iter()
+                    .map(|s| {
+                        format!(
+                            "{} = {} ({})",
+                            s.name,
+                            s.value.clone().unwrap_or_else(|| String::from("N/A")),
+                            s.source
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            Event::BatchComplete { downloaded, failed } => Some(format!(
+                "> batch finished. downloaded: {}, failed: {}",
+                downloaded, failed
+            )),
+            Event::Error { ref message, .. } => Some(format!("> Error: {}", message)),
+        }
+    }
+}
+
+/// prints `Event`s in the user's chosen `OutputFormat`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reporter {
+    pub format: OutputFormat,
+    /// whether to keep stdout clear (for the file itself) by printing everything to stderr
+    pub stderr: bool,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat) -> Reporter {
+        Reporter {
+            format,
+            stderr: false,
+        }
+    }
+
+    /// a reporter that prints everything to stderr (for when stdout is taken)
+    pub fn stderr(format: OutputFormat) -> Reporter {
+        Reporter {
+            format,
+            stderr: true,
+        }
+    }
+
+    /// prints an event to stdout (errors in text mode go to stderr, as they always have)
+    pub fn emit(&self, event: &Event) {
+        let line = match self.format {
+            OutputFormat::Text => match event.to_text() {
+                None => return,
+                Some(line) => line,
+            },
+            OutputFormat::Json => to_json(event),
+        };
+        match (event, self.format) {
+            (Event::Error { .. }, OutputFormat::Text) => eprintln!("{}", line),
+            _ if self.stderr => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+fn to_json(event: &Event) -> String {
+    serde_json::to_string(event).expect("Failed to serialize event")
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+
+    #[test]
+    fn parsing_output_formats() {
+        assert_eq!("text".parse::<OutputFormat>(), Ok(OutputFormat::Text));
+        assert_eq!("json".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("yaml".parse::<OutputFormat>(), Err(()));
+    }
+
+    #[test]
+    fn serializing_piece_events() {
+        let event = Event::PieceComplete(PieceReport {
+            index: 1,
+            offset: 4096,
+            length: 4096,
+            retries: 0,
+            protocol: Some(Protocol::Http2),
+            source: String::from("93.184.216.34"),
+        });
+        assert_eq!(
+            to_json(&event),
+            r#"{"event":"piece_complete","index":1,"offset":4096,"length":4096,"retries":0,"protocol":"HTTP/2","source":"93.184.216.34"}"#
+        );
+        assert_eq!(event.to_text(), None);
+    }
+
+    #[test]
+    fn summarizing_sources() {
+        let piece = |length: u64, retries: u32, source: &str| PieceReport {
+            index: 0,
+            offset: 0,
+            length,
+            retries,
+            protocol: Some(Protocol::Http2),
+            source: String::from(source),
+        };
+        let pieces = vec![
+            piece(4096, 0, "::1"),
+            piece(4096, 2, "127.0.0.1"),
+            piece(2048, 1, "::1"),
+        ];
+        let sources = Summary::sources_of(&pieces, 2.0);
+        assert_eq!(
+            sources
+                .iter()
+                .map(|s| (s.source.as_str(), s.pieces, s.bytes, s.failures))
+                .collect::<Vec<_>>(),
+            vec![("127.0.0.1", 1, 4096, 2), ("::1", 2, 6144, 1)]
+        );
+        assert_eq!(sources[1].throughput_bytes_per_sec, 3072.0);
+    }
+
+    #[test]
+    fn serializing_metadata_events() {
+        let event = Event::Metadata {
+            url: String::from("https://foo.com/a"),
+            final_url: String::from("https://bar.com/a"),
+            size: 53143,
+            etag: None,
+        };
+        assert_eq!(
+            to_json(&event),
+            r#"{"event":"metadata","url":"https://foo.com/a","final_url":"https://bar.com/a","size":53143,"etag":null}"#
+        );
+        assert_eq!(
+            event.to_text(),
+            Some(String::from(
+                "> ...found metadata. file size: 53143, etag: N/A"
+            ))
+        );
+    }
+
+    #[test]
+    fn serializing_info_events() {
+        let event = Event::Info(FileInfo {
+            url: String::from("https://foo.com/a"),
+            final_url: String::from("https://foo.com/a"),
+            size: 53143,
+            etag: Some(String::from("abc")),
+            validator: Some(String::from("\"abc\"")),
+            mirrors: vec![String::from("https://bar.com/a")],
+        });
+        assert_eq!(
+            to_json(&event),
+            r#"{"event":"info","url":"https://foo.com/a","final_url":"https://foo.com/a","size":53143,"etag":"abc","validator":"\"abc\"","mirrors":["https://bar.com/a"]}"#
+        );
+        assert_eq!(
+            event.to_text(),
+            Some(String::from(
+                "> url: https://foo.com/a\n> final url: https://foo.com/a\n> size: 53143\n> etag: abc\n> validator: \"abc\"\n> mirrors: https://bar.com/a"
+            ))
+        );
+    }
+
+    #[test]
+    fn serializing_error_events() {
+        let event = Event::from_error(&DlError::EtagAbsent);
+        assert_eq!(
+            to_json(&event),
+            r#"{"event":"error","code":"etag_absent","message":"File does not have an etag"}"#
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::task::{self, Task};
+use futures::{future, Async, Future, Poll, Stream};
+
+use crate::error::DlError;
+use crate::file::{Piece, PieceReport};
+use crate::sink::{Sink, SinkFuture};
+
+/// how much of a file `dl` holds in memory (by default) while waiting for the bytes before it to arrive
+pub const DEFAULT_BUFFER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// holds the pieces of a file that arrive out of order until every byte before them has been handed on, so
+/// that a file fetched over many connections can still be written to something that can't seek (like stdout).
+///
+/// Memory is kept under `cap` by backpressure rather than by dropping data: a piece is only `admit`ted (and so
+/// only requested) once the bytes between the last one handed on and its end fit in the buffer. Pieces are
+/// admitted strictly in order, so the piece holding up the others can always be fetched.
+#[derive(Debug, Clone)]
+pub struct ReorderBuffer {
+    window: Arc<Mutex<Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    cap: u64,
+    /// everything before this offset has been handed on
+    emitted: u64,
+    /// everything before this offset has been admitted (and so may be sitting in `chunks`)
+    admitted: u64,
+    /// chunks that have arrived but can't be handed on yet, keyed by offset
+    chunks: BTreeMap<u64, Bytes>,
+    /// the task reading from the buffer, if it is waiting on the next chunk
+    reader: Option<Task>,
+    /// tasks waiting for room to admit a piece
+    waiting: Vec<Task>,
+}
+
+impl Window {
+    fn wake_waiting(&mut self) {
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+impl ReorderBuffer {
+    /// a buffer that holds at most (about) `cap` bytes, starting at offset 0
+    pub fn new(cap: u64) -> ReorderBuffer {
+        ReorderBuffer {
+            window: Arc::new(Mutex::new(Window {
+                cap: max(cap, 1),
+                emitted: 0,
+                admitted: 0,
+                chunks: BTreeMap::new(),
+                reader: None,
+                waiting: vec![],
+            })),
+        }
+    }
+
+    /// resolves once there is room in the buffer for `piece` (immediately, if it has already been admitted)
+    pub fn admit(&self, piece: Piece) -> Admit {
+        Admit {
+            buffer: self.clone(),
+            piece,
+        }
+    }
+
+    /// adds a chunk of the file (which must have been admitted) found at offset `at`, ignoring any part of it
+    /// that has already been handed on
+    pub fn insert(&self, at: u64, chunk: Bytes) {
+        let mut window = self.lock();
+        let end = at + chunk.len() as u64;
+        if end <= window.emitted {
+            return;
+        }
+        let (at, chunk) = match at < window.emitted {
+            true => (
+                window.emitted,
+                chunk.slice_from((window.emitted - at) as usize),
+            ),
+            false => (at, chunk),
+        };
+        window.chunks.insert(at, chunk);
+        if let Some(reader) = window.reader.take() {
+            reader.notify();
+        }
+    }
+
+    /// takes the chunk that starts where the last one handed on ended, if it has arrived (otherwise, arranges
+    /// for the current task to be woken when it does)
+    pub fn poll_next(&self) -> Option<Bytes> {
+        let mut window = self.lock();
+        loop {
+            let (at, len) = match window.chunks.iter().next() {
+                None => break,
+                Some((&at, chunk)) => (at, chunk.len() as u64),
+            };
+            if at > window.emitted {
+                break;
+            }
+            let chunk = window.chunks.remove(&at).expect("chunk vanished");
+            if at + len <= window.emitted {
+                // already handed on (as part of a chunk that overlapped this one)
+                continue;
+            }
+            let chunk = chunk.slice_from((window.emitted - at) as usize);
+            window.emitted = at + len;
+            window.wake_waiting();
+            return Some(chunk);
+        }
+        window.reader = Some(task::current());
+        None
+    }
+
+    /// how many bytes have been handed on so far
+    pub fn emitted(&self) -> u64 {
+        self.lock().emitted
+    }
+
+    /// how many bytes the buffer has room for
+    pub fn cap(&self) -> u64 {
+        self.lock().cap
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.window.lock().expect("Reorder buffer lock poisoned")
+    }
+}
+
+/// a sink that holds back requests for pieces until there is room to buffer them, and hands chunks on in
+/// order (to whoever is polling `poll_next`) rather than writing them anywhere
+impl Sink for ReorderBuffer {
+    fn write_at(&self, bytes: Bytes, offset: u64) -> SinkFuture {
+        let buffer = self.clone();
+        let piece = Piece {
+            index: 0,
+            offset,
+            length: bytes.len() as u64,
+        };
+        Box::new(self.admit(piece).map(move |_| buffer.insert(offset, bytes)))
+    }
+
+    fn finalize(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+
+    fn abort(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+
+    fn admit(&self, piece: Piece) -> SinkFuture {
+        Box::new(ReorderBuffer::admit(self, piece))
+    }
+}
+
+/// see `ReorderBuffer::admit`
+pub struct Admit {
+    buffer: ReorderBuffer,
+    piece: Piece,
+}
+
+impl Future for Admit {
+    type Item = ();
+    type Error = DlError;
+
+    fn poll(&mut self) -> Poll<(), DlError> {
+        let mut window = self.buffer.lock();
+        let end = self.piece.end();
+        if end <= window.admitted {
+            return Ok(Async::Ready(()));
+        }
+        let in_turn = self.piece.offset <= window.admitted;
+        // with nothing outstanding, even a piece bigger than the buffer has to be let through
+        let fits = window.admitted == window.emitted || end - window.emitted <= window.cap;
+        if in_turn && fits {
+            window.admitted = end;
+            window.wake_waiting();
+            return Ok(Async::Ready(()));
+        }
+        window.waiting.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// the bytes of a file of `file_size` bytes, in order, as `workers` fetch them into `buffer` (see
+/// `FileDownloader::fetch_ordered`)
+pub struct Ordered {
+    buffer: ReorderBuffer,
+    file_size: u64,
+    workers: Option<Box<dyn Future<Item = Vec<PieceReport>, Error = DlError> + Send>>,
+    pieces: Vec<PieceReport>,
+}
+
+impl Ordered {
+    pub fn new(
+        buffer: ReorderBuffer,
+        file_size: u64,
+        workers: Box<dyn Future<Item = Vec<PieceReport>, Error = DlError> + Send>,
+    ) -> Ordered {
+        Ordered {
+            buffer,
+            file_size,
+            workers: Some(workers),
+            pieces: vec![],
+        }
+    }
+
+    /// the pieces the file was fetched in (once the stream has ended), sorted by offset
+    pub fn pieces(&self) -> &[PieceReport] {
+        &self.pieces
+    }
+}
+
+impl Stream for Ordered {
+    type Item = Bytes;
+    type Error = DlError;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, DlError> {
+        let finished = match self.workers {
+            None => true,
+            Some(ref mut workers) => match workers.poll()? {
+                Async::Ready(mut pieces) => {
+                    pieces.sort_by_key(|piece| piece.offset);
+                    self.pieces = pieces;
+                    true
+                }
+                Async::NotReady => false,
+            },
+        };
+        if finished {
+            self.workers = None;
+        }
+
+        match (self.buffer.poll_next(), finished) {
+            (Some(chunk), _) => Ok(Async::Ready(Some(chunk))),
+            (None, false) => Ok(Async::NotReady),
+            (None, true) => match self.buffer.emitted() {
+                emitted if emitted == self.file_size => Ok(Async::Ready(None)),
+                emitted => Err(DlError::LengthMismatch(self.file_size, emitted)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod reorder_tests {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::Runtime;
+
+    fn piece(index: u64, offset: u64, length: u64) -> Piece {
+        Piece {
+            index,
+            offset,
+            length,
+        }
+    }
+
+    /// polls `f` once (outside of any runtime), returning whether it was ready
+    fn ready<F: Future>(f: &mut F) -> bool {
+        future::poll_fn(|| Ok::<_, ()>(Async::Ready(f.poll())))
+            .wait()
+            .unwrap()
+            .ok()
+            .is_some_and(|a| a.is_ready())
+    }
+
+    #[test]
+    fn handing_on_chunks_in_order() {
+        let buffer = ReorderBuffer::new(100);
+        let next = || future::poll_fn(|| Ok::<_, ()>(Async::Ready(buffer.poll_next())));
+        assert!(ready(&mut buffer.admit(piece(0, 0, 10))));
+        assert!(ready(&mut buffer.admit(piece(1, 10, 10))));
+
+        buffer.insert(10, Bytes::from(vec![1; 10]));
+        assert_eq!(next().wait().unwrap(), None);
+        buffer.insert(0, Bytes::from(vec![0; 5]));
+        buffer.insert(5, Bytes::from(vec![0; 5]));
+        assert_eq!(next().wait().unwrap(), Some(Bytes::from(vec![0; 5])));
+        assert_eq!(next().wait().unwrap(), Some(Bytes::from(vec![0; 5])));
+        assert_eq!(next().wait().unwrap(), Some(Bytes::from(vec![1; 10])));
+        assert_eq!(buffer.emitted(), 20);
+
+        // bytes that have already been handed on (say, by a retried request) are dropped
+        buffer.insert(15, Bytes::from(vec![1; 10]));
+        assert_eq!(next().wait().unwrap(), Some(Bytes::from(vec![1; 5])));
+    }
+
+    #[test]
+    fn admitting_pieces() {
+        let buffer = ReorderBuffer::new(25);
+        let next = || future::poll_fn(|| Ok::<_, ()>(Async::Ready(buffer.poll_next())));
+
+        // pieces are admitted in order...
+        assert!(!ready(&mut buffer.admit(piece(1, 10, 10))));
+        assert!(ready(&mut buffer.admit(piece(0, 0, 10))));
+        assert!(ready(&mut buffer.admit(piece(1, 10, 10))));
+        // ...until the buffer is full
+        let mut third = buffer.admit(piece(2, 20, 10));
+        assert!(!ready(&mut third));
+        // (pieces split off of admitted ones are already paid for)
+        assert!(ready(&mut buffer.admit(piece(3, 15, 5))));
+
+        buffer.insert(0, Bytes::from(vec![0; 10]));
+        assert!(next().wait().unwrap().is_some());
+        assert!(ready(&mut third));
+
+        // with nothing outstanding, a piece bigger than the buffer is let through
+        let buffer = ReorderBuffer::new(5);
+        assert!(ready(&mut buffer.admit(piece(0, 0, 10))));
+    }
+
+    #[test]
+    fn streaming_workers_output_in_order() {
+        let buffer = ReorderBuffer::new(10);
+        let (b, c) = (buffer.clone(), buffer.clone());
+        let report = |index, offset| PieceReport {
+            index,
+            offset,
+            length: 10,
+            retries: 0,
+            protocol: None,
+            source: String::from("https://foo.com/a"),
+        };
+        // the second piece finishes first, but has to wait for room in the buffer to be fetched
+        let second = b.admit(piece(1, 10, 10)).map(move |_| {
+            b.insert(10, Bytes::from(vec![1; 10]));
+            report(1, 10)
+        });
+        let first = c.admit(piece(0, 0, 10)).map(move |_| {
+            c.insert(0, Bytes::from(vec![0; 10]));
+            report(0, 0)
+        });
+        let workers = second.join(first).map(|(a, b)| vec![a, b]);
+
+        let mut ordered = Ordered::new(buffer, 20, Box::new(workers));
+        let mut chunks = vec![];
+        let (bytes, pieces) = Runtime::new()
+            .unwrap()
+            .block_on(future::poll_fn(move || loop {
+                match futures::try_ready!(ordered.poll()) {
+                    Some(chunk) => chunks.extend_from_slice(&chunk),
+                    None => {
+                        return Ok::<_, DlError>(Async::Ready((
+                            chunks.clone(),
+                            ordered.pieces().to_vec(),
+                        )))
+                    }
+                }
+            }))
+            .unwrap();
+        assert_eq!(bytes, [vec![0; 10], vec![1; 10]].concat());
+        assert_eq!(pieces, vec![report(0, 0), report(1, 10)]);
+
+        let missing = Ordered::new(ReorderBuffer::new(10), 5, Box::new(future::ok(vec![])));
+        assert_eq!(
+            Runtime::new()
+                .unwrap()
+                .block_on(missing.collect())
+                .unwrap_err()
+                .code(),
+            "length_mismatch"
+        );
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::DlError;
+use crate::file::{BACKOFF_BASE_MILLIS, DEFAULT_MAX_RETRIES};
+use crate::output::SettingReport;
+use crate::DEFAULT_PARALLELISM;
+
+/// overrides where `dl` looks for its config file
+pub const CONFIG_PATH_VAR: &str = "DL_CONFIG";
+/// prefix of the environment variables that override the config file (`DL_PARALLELISM`, `DL_PROXY`, ...)
+pub const ENV_PREFIX: &str = "DL_";
+/// prefix of the environment variables that set headers (`DL_HEADER_USER_AGENT=dl` sends `user-agent: dl`)
+pub const HEADER_ENV_PREFIX: &str = "DL_HEADER_";
+
+/// the settings that can be given defaults in a config file or the environment (in the order they're shown)
+pub const SETTINGS: [&str; 13] = [
+    "parallelism",
+    "piece_size",
+    "retries",
+    "retry_backoff",
+    "rate_limit",
+    "proxy",
+    "ca_bundle",
+    "http_version",
+    "all_addresses",
+    "dns_server",
+    "hosts_file",
+    "bind",
+    "ip_preference",
+];
+
+/// where the value of a setting came from (variants are listed from lowest precedence to highest)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    /// the top level of the config file at this path
+    File(PathBuf),
+    /// a `[hosts."<host>"]` section of the config file at this path
+    Host(PathBuf, String),
+    /// this environment variable
+    Env(String),
+    /// set explicitly on the `DownloadBuilder` (which `dl` does for its command line flags)
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref path) => write!(f, "{}", path.display()),
+            Source::Host(ref path, ref host) => {
+                write!(f, "{} [hosts.\"{}\"]", path.display(), host)
+            }
+            Source::Env(ref var) => write!(f, "${}", var),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// one layer of settings (any of which may be missing, leaving it to the layers below)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub parallelism: Option<usize>,
+    /// in bytes
+    pub piece_size: Option<u64>,
+    pub retries: Option<u32>,
+    /// in seconds, before the first retry (doubling with each one after that)
+    pub retry_backoff: Option<f64>,
+    /// in bytes/sec, across all of a download's connections
+    pub rate_limit: Option<u64>,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    /// `auto`, `1.1`, `2` or `3` (see `https::HttpVersion`)
+    pub http_version: Option<String>,
+    /// whether to spread connections across every address the url's host resolves to (see `https::Network`)
+    pub all_addresses: Option<bool>,
+    /// a nameserver's address (with an optional port), to look hosts up with instead of the system's resolver
+    pub dns_server: Option<String>,
+    /// a file in the format of `/etc/hosts`, to look hosts up in first
+    pub hosts_file: Option<PathBuf>,
+    /// local addresses or network interfaces to bind connections to (comma-separated)
+    pub bind: Option<String>,
+    /// `auto`, `ipv4` or `ipv6` (see `dns::IpPreference`)
+    pub ip_preference: Option<String>,
+    /// by name (a layer's headers replace those of the same name in the layers below it)
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// the contents of a config file: global defaults, plus overrides for particular hosts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigFile {
+    pub global: Settings,
+    pub hosts: BTreeMap<String, Settings>,
+}
+
+/// every layer of settings below the command line: a config file (if there is one) and the environment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub file: Option<(PathBuf, ConfigFile)>,
+    pub env: Settings,
+}
+
+/// the settings that apply to a download once every layer has been merged, and where each came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub settings: Settings,
+    /// keyed by the names in `SETTINGS` (and `headers.<name>` for each header)
+    pub sources: BTreeMap<String, Source>,
+}
+
+/// a layer of settings, and the source of each (named) setting in it
+type Layer<'a> = (&'a Settings, Box<dyn Fn(&str) -> Source>);
+
+// applies a layer's value for each of `$field`s (if it has one) on top of `$merged`'s, recording its source
+macro_rules! merge {
+    ($merged:expr, $sources:expr, $layer:expr, $source:expr, $($field:ident),*) => {
+        $(
+            if let Some(ref value) = $layer.$field {
+                $merged.$field = Some(value.clone());
+                $sources.insert(String::from(stringify!($field)), $source(stringify!($field)));
+            }
+        )*
+    };
+}
+
+impl Profile {
+    /// reads the config file (`$DL_CONFIG`, or `$XDG_CONFIG_HOME/dl/config.toml`) and the `DL_*` environment
+    pub fn load() -> Result<Profile, DlError> {
+        Profile::load_from(config_path(|var| env::var_os(var)), env::vars())
+    }
+
+    /// reads the config file at `path` (which need not exist) and the `DL_*` variables among `vars`
+    pub fn load_from<I>(path: Option<PathBuf>, vars: I) -> Result<Profile, DlError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let file = match path {
+            None => None,
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(toml) => Some((path.clone(), parse_config_file(&path, &toml)?)),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(invalid(&path.display().to_string(), &err.to_string())),
+            },
+        };
+        Ok(Profile {
+            file,
+            env: parse_env(vars)?,
+        })
+    }
+
+    /// merges the layers that apply to downloads from `host` under the `explicit` settings (from the command
+    /// line or the builder), falling back to `dl`'s defaults
+    pub fn resolve(&self, explicit: &Settings, host: Option<&str>) -> Resolved {
+        let mut merged = Settings::default();
+        let mut sources = BTreeMap::new();
+
+        let mut layers: Vec<Layer> = vec![];
+        if let Some((ref path, ref file)) = self.file {
+            let p = path.clone();
+            layers.push((&file.global, Box::new(move |_| Source::File(p.clone()))));
+            if let Some((host, settings)) = host.and_then(|h| file.hosts.get_key_value(h)) {
+                let (p, h) = (path.clone(), host.clone());
+                layers.push((
+                    settings,
+                    Box::new(move |_| Source::Host(p.clone(), h.clone())),
+                ));
+            }
+        }
+        layers.push((&self.env, Box::new(|name| Source::Env(env_var(name)))));
+        layers.push((explicit, Box::new(|_| Source::CommandLine)));
+
+        for (layer, source) in layers.iter() {
+            merge!(
+                merged,
+                sources,
+                layer,
+                source,
+                parallelism,
+                piece_size,
+                retries,
+                retry_backoff,
+                rate_limit,
+                proxy,
+                ca_bundle,
+                http_version,
+                all_addresses,
+                dns_server,
+                hosts_file,
+                bind,
+                ip_preference
+            );
+            for (name, value) in layer.headers.iter() {
+                let name = name.to_lowercase();
+                let key = format!("headers.{}", name);
+                sources.insert(key.clone(), source(&key));
+                merged.headers.insert(name, value.clone());
+            }
+        }
+
+        merged.parallelism = merged.parallelism.or(Some(*DEFAULT_PARALLELISM));
+        merged.retries = merged.retries.or(Some(DEFAULT_MAX_RETRIES));
+        merged.retry_backoff = merged
+            .retry_backoff
+            .or_else(|| Some(Duration::from_millis(BACKOFF_BASE_MILLIS).as_secs_f64()));
+        for name in SETTINGS.iter() {
+            sources.entry(name.to_string()).or_insert(Source::Default);
+        }
+
+        Resolved {
+            settings: merged,
+            sources,
+        }
+    }
+}
+
+impl Resolved {
+    /// every setting's value (if it has one) and source, for `dl config show`
+    pub fn report(&self) -> Vec<SettingReport> {
+        let s = &self.settings;
+        let values = vec![
+            s.parallelism.map(|v| v.to_string()),
+            s.piece_size.map(|v| v.to_string()),
+            s.retries.map(|v| v.to_string()),
+            s.retry_backoff.map(|v| v.to_string()),
+            s.rate_limit.map(|v| v.to_string()),
+            s.proxy.clone(),
+            s.ca_bundle.as_ref().map(|v| v.display().to_string()),
+            s.http_version.clone(),
+            s.all_addresses.map(|v| v.to_string()),
+            s.dns_server.clone(),
+            s.hosts_file.as_ref().map(|v| v.display().to_string()),
+            s.bind.clone(),
+            s.ip_preference.clone(),
+        ];
+        let headers = s
+            .headers
+            .iter()
+            .map(|(name, value)| (format!("headers.{}", name), Some(value.clone())));
+
+        SETTINGS
+            .iter()
+            .map(|name| name.to_string())
+            .zip(values)
+            .chain(headers)
+            .map(|(name, value)| SettingReport {
+                source: self
+                    .sources
+                    .get(&name)
+                    .cloned()
+                    .unwrap_or(Source::Default)
+                    .to_string(),
+                name,
+                value,
+            })
+            .collect()
+    }
+}
+
+/// where the config file lives: `$DL_CONFIG`, `$XDG_CONFIG_HOME/dl/config.toml` or `~/.config/dl/config.toml`
+pub fn config_path<F>(var: F) -> Option<PathBuf>
+where
+    F: Fn(&str) -> Option<std::ffi::OsString>,
+{
+    let non_empty = |name| var(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    non_empty(CONFIG_PATH_VAR)
+        .or_else(|| non_empty("XDG_CONFIG_HOME").map(|dir| dir.join("dl").join("config.toml")))
+        .or_else(|| non_empty("HOME").map(|home| home.join(".config/dl/config.toml")))
+}
+
+fn parse_config_file(path: &Path, toml: &str) -> Result<ConfigFile, DlError> {
+    let err = |err: toml::de::Error| invalid(&path.display().to_string(), &err.to_string());
+    // the global settings are split out by hand rather than with `#[serde(flatten)]`, which would stop
+    // `deny_unknown_fields` from catching typos in them
+    let mut global = toml::from_str::<toml::value::Table>(toml).map_err(err)?;
+    let hosts = match global.remove("hosts") {
+        Some(hosts) => hosts.try_into().map_err(err)?,
+        None => BTreeMap::new(),
+    };
+    Ok(ConfigFile {
+        global: toml::Value::Table(global).try_into().map_err(err)?,
+        hosts,
+    })
+}
+
+/// picks the `DL_*` settings out of the environment (ignoring variables it doesn't know)
+fn parse_env<I>(vars: I) -> Result<Settings, DlError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut settings = Settings::default();
+    for (var, value) in vars {
+        if let Some(name) = var.strip_prefix(HEADER_ENV_PREFIX) {
+            let name = name.to_lowercase().replace('_', "-");
+            settings.headers.insert(name, value);
+            continue;
+        }
+        let name = match var.strip_prefix(ENV_PREFIX) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        match name.as_str() {
+            "parallelism" => settings.parallelism = Some(parse_var(&var, &value)?),
+            "piece_size" => settings.piece_size = Some(parse_var(&var, &value)?),
+            "retries" => settings.retries = Some(parse_var(&var, &value)?),
+            "retry_backoff" => settings.retry_backoff = Some(parse_var(&var, &value)?),
+            "rate_limit" => settings.rate_limit = Some(parse_var(&var, &value)?),
+            "proxy" => settings.proxy = Some(value),
+            "ca_bundle" => settings.ca_bundle = Some(PathBuf::from(value)),
+            "http_version" => settings.http_version = Some(value),
+            "all_addresses" => settings.all_addresses = Some(parse_var(&var, &value)?),
+            "dns_server" => settings.dns_server = Some(value),
+            "hosts_file" => settings.hosts_file = Some(PathBuf::from(value)),
+            "bind" => settings.bind = Some(value),
+            "ip_preference" => settings.ip_preference = Some(value),
+            _ => (),
+        }
+    }
+    Ok(settings)
+}
+
+fn parse_var<T: FromStr>(var: &str, value: &str) -> Result<T, DlError> {
+    value
+        .parse::<T>()
+        .map_err(|_| invalid(&format!("${}", var), &format!("can't parse '{}'", value)))
+}
+
+/// the environment variable that sets the setting called `name`
+fn env_var(name: &str) -> String {
+    match name.strip_prefix("headers.") {
+        Some(header) => format!(
+            "{}{}",
+            HEADER_ENV_PREFIX,
+            header.to_uppercase().replace('-', "_")
+        ),
+        None => format!("{}{}", ENV_PREFIX, name.to_uppercase()),
+    }
+}
+
+fn invalid(location: &str, reason: &str) -> DlError {
+    DlError::InvalidSettings(format!("{}: {}", location, reason))
+}
+
+#[cfg(test)]
+mod settings_tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        parallelism = 8
+        piece_size = 1048576
+        proxy = "http://proxy.local:3128"
+
+        [headers]
+        User-Agent = "dl"
+
+        [hosts."example.com"]
+        parallelism = 2
+        rate_limit = 1024
+        headers = { Authorization = "Bearer foo" }
+    "#;
+
+    fn profile(vars: &[(&str, &str)]) -> Profile {
+        let path = PathBuf::from("/etc/dl.toml");
+        Profile {
+            file: Some((path.clone(), parse_config_file(&path, CONFIG).unwrap())),
+            env: parse_env(
+                vars.iter()
+                    .map(|(var, value)| (var.to_string(), value.to_string())),
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn parsing_config_files() {
+        let file = profile(&[]).file.unwrap().1;
+        assert_eq!(file.global.parallelism, Some(8));
+        assert_eq!(file.global.headers["User-Agent"], "dl");
+        assert_eq!(file.hosts["example.com"].rate_limit, Some(1024));
+
+        let path = PathBuf::from("dl.toml");
+        let err = parse_config_file(&path, "parallelism = \"lots\"").unwrap_err();
+        assert_eq!(err.code(), "invalid_settings");
+        assert!(parse_config_file(&path, "paralelism = 8").is_err());
+    }
+
+    #[test]
+    fn parsing_the_environment() {
+        let env = profile(&[
+            ("DL_PARALLELISM", "3"),
+            ("DL_RETRY_BACKOFF", "0.5"),
+            ("DL_HEADER_X_API_KEY", "s3cr3t"),
+            ("DL_CONFIG", "/dev/null"),
+            ("PATH", "/bin"),
+        ])
+        .env;
+        assert_eq!(env.parallelism, Some(3));
+        assert_eq!(env.retry_backoff, Some(0.5));
+        assert_eq!(env.headers["x-api-key"], "s3cr3t");
+
+        let err = parse_env(vec![(String::from("DL_RETRIES"), String::from("-1"))]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid settings: $DL_RETRIES: can't parse '-1'"
+        );
+    }
+
+    #[test]
+    fn layering_settings() {
+        let profile = profile(&[("DL_PARALLELISM", "3"), ("DL_RATE_LIMIT", "2048")]);
+        let path = PathBuf::from("/etc/dl.toml");
+
+        let resolved = profile.resolve(&Settings::default(), Some("foo.com"));
+        assert_eq!(resolved.settings.parallelism, Some(3));
+        assert_eq!(resolved.settings.piece_size, Some(1_048_576));
+        assert_eq!(resolved.settings.retries, Some(DEFAULT_MAX_RETRIES));
+        assert_eq!(
+            resolved.sources["parallelism"],
+            Source::Env(String::from("DL_PARALLELISM"))
+        );
+        assert_eq!(resolved.sources["piece_size"], Source::File(path.clone()));
+        assert_eq!(resolved.sources["retries"], Source::Default);
+
+        let explicit = Settings {
+            parallelism: Some(4),
+            ..Settings::default()
+        };
+        let resolved = profile.resolve(&explicit, Some("example.com"));
+        assert_eq!(resolved.settings.parallelism, Some(4));
+        assert_eq!(resolved.sources["parallelism"], Source::CommandLine);
+        // the environment beats the host section, which beats the top of the file
+        assert_eq!(resolved.settings.rate_limit, Some(2048));
+        assert_eq!(
+            resolved.settings.headers.keys().collect::<Vec<_>>(),
+            vec!["authorization", "user-agent"]
+        );
+        assert_eq!(
+            resolved.sources["headers.authorization"],
+            Source::Host(path, String::from("example.com"))
+        );
+    }
+
+    #[test]
+    fn reporting_settings() {
+        let resolved = profile(&[]).resolve(&Settings::default(), None);
+        let report = resolved.report();
+        assert_eq!(report[0].name, "parallelism");
+        assert_eq!(report[0].value, Some(String::from("8")));
+        assert_eq!(report[0].source, "/etc/dl.toml");
+        assert_eq!(report[6].name, "ca_bundle");
+        assert_eq!(report[6].value, None);
+        assert_eq!(report[6].source, "default");
+        assert_eq!(report[7].name, "http_version");
+        assert_eq!(report[8].name, "all_addresses");
+        assert_eq!(report[10].name, "hosts_file");
+        assert_eq!(report[12].name, "ip_preference");
+        assert_eq!(report[13].name, "headers.user-agent");
+    }
+
+    #[test]
+    fn finding_config_files() {
+        let vars = |pairs: Vec<(&'static str, &'static str)>| {
+            move |var: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == var)
+                    .map(|(_, v)| std::ffi::OsString::from(v))
+            }
+        };
+        assert_eq!(
+            config_path(vars(vec![("DL_CONFIG", "/a.toml"), ("HOME", "/home/me")])),
+            Some(PathBuf::from("/a.toml"))
+        );
+        assert_eq!(
+            config_path(vars(vec![
+                ("XDG_CONFIG_HOME", "/cfg"),
+                ("HOME", "/home/me")
+            ])),
+            Some(PathBuf::from("/cfg/dl/config.toml"))
+        );
+        assert_eq!(
+            config_path(vars(vec![("HOME", "/home/me")])),
+            Some(PathBuf::from("/home/me/.config/dl/config.toml"))
+        );
+        assert_eq!(config_path(vars(vec![])), None);
+    }
+
+    #[test]
+    fn loading_missing_config_files() {
+        let profile = Profile::load_from(
+            Some(PathBuf::from("data/no_such_config.toml")),
+            vec![(String::from("DL_PIECE_SIZE"), String::from("4096"))],
+        )
+        .unwrap();
+        assert_eq!(profile.file, None);
+        assert_eq!(profile.env.piece_size, Some(4096));
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::{future, Future};
+
+use crate::disk;
+use crate::error::DlError;
+use crate::file::Piece;
+
+/// what a `Sink`'s operations resolve to
+pub type SinkFuture = Box<dyn Future<Item = (), Error = DlError> + Send>;
+
+/// somewhere the pieces of a file can be written (in any order, and several at once) as they arrive.
+///
+/// a download calls `write_at` for every chunk of every piece, then exactly one of `finalize` (once every byte
+/// has been written) or `abort` (if the download fails)
+pub trait Sink: Send + Sync {
+    /// writes `bytes` at `offset` in the file
+    fn write_at(&self, bytes: Bytes, offset: u64) -> SinkFuture;
+
+    /// makes everything written so far durable (or otherwise ready to use). only `FileSink` checks that it ended
+    /// up the size it was created with: the others take the download's word that every byte was written
+    fn finalize(&self) -> SinkFuture;
+
+    /// gives up on the file (releasing whatever the sink was holding on to)
+    fn abort(&self) -> SinkFuture;
+
+    /// resolves once `piece` may be requested: sinks that can only hold so much at once hold requests back
+    /// until they have room (see `ReorderBuffer`)
+    fn admit(&self, _piece: Piece) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+}
+
+/// makes the sink a download is written into, once it knows how big the file is (two are equal if they are the
+/// same function)
+#[derive(Clone)]
+pub struct MakeSink(Arc<Make>);
+
+type Make = dyn Fn(u64) -> Result<Arc<dyn Sink>, DlError> + Send + Sync;
+
+impl MakeSink {
+    pub fn new<F>(make_sink: F) -> MakeSink
+    where
+        F: Fn(u64) -> Result<Arc<dyn Sink>, DlError> + Send + Sync + 'static,
+    {
+        MakeSink(Arc::new(make_sink))
+    }
+
+    /// a sink for a file of `size` bytes
+    pub fn make(&self, size: u64) -> Result<Arc<dyn Sink>, DlError> {
+        (self.0)(size)
+    }
+}
+
+impl fmt::Debug for MakeSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MakeSink")
+    }
+}
+
+impl PartialEq for MakeSink {
+    fn eq(&self, other: &MakeSink) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// a file on the local file system, preallocated up front and written with positional writes through one
+/// shared handle. aborting leaves the file where it is (for the caller to keep or discard)
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    path: PathBuf,
+    file: Arc<File>,
+    size: u64,
+}
+
+impl FileSink {
+    /// creates (or truncates) the file at `path` and reserves `size` bytes of disk for it (blocking, so best
+    /// run with `disk::run_blocking`)
+    pub fn create(path: &Path, size: u64) -> Result<FileSink, DlError> {
+        Ok(FileSink {
+            path: path.to_path_buf(),
+            file: Arc::new(disk::preallocate(path, size)?),
+            size,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Sink for FileSink {
+    fn write_at(&self, bytes: Bytes, offset: u64) -> SinkFuture {
+        Box::new(disk::write_at_async(self.file.clone(), bytes, offset))
+    }
+
+    fn finalize(&self) -> SinkFuture {
+        let (file, size) = (self.file.clone(), self.size);
+        Box::new(disk::run_blocking(move || disk::sync(&file, size)))
+    }
+
+    fn abort(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+}
+
+/// a buffer in memory, big enough for the whole file. clones share the same buffer, so one can be handed to
+/// a download while another is kept to read the file back
+#[derive(Debug, Clone)]
+pub struct MemorySink {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemorySink {
+    pub fn new(size: u64) -> MemorySink {
+        MemorySink {
+            buf: Arc::new(Mutex::new(vec![0; size as usize])),
+        }
+    }
+
+    /// a copy of what has been written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.buf.lock().expect("Memory sink lock poisoned")
+    }
+}
+
+impl Sink for MemorySink {
+    fn write_at(&self, bytes: Bytes, offset: u64) -> SinkFuture {
+        let mut buf = self.lock();
+        let end = offset + bytes.len() as u64;
+        match end <= buf.len() as u64 {
+            true => {
+                buf[offset as usize..end as usize].copy_from_slice(&bytes);
+                Box::new(future::ok(()))
+            }
+            false => Box::new(future::err(DlError::LengthMismatch(buf.len() as u64, end))),
+        }
+    }
+
+    fn finalize(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+
+    fn abort(&self) -> SinkFuture {
+        self.lock().clear();
+        Box::new(future::ok(()))
+    }
+}
+
+/// throws everything away, only counting how many bytes it was given (for measuring how fast a file can be
+/// downloaded, without the disk getting in the way)
+#[derive(Debug, Clone, Default)]
+pub struct NullSink {
+    written: Arc<AtomicU64>,
+}
+
+impl NullSink {
+    pub fn new() -> NullSink {
+        NullSink::default()
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+}
+
+impl Sink for NullSink {
+    fn write_at(&self, bytes: Bytes, _offset: u64) -> SinkFuture {
+        self.written.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+        Box::new(future::ok(()))
+    }
+
+    fn finalize(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+
+    fn abort(&self) -> SinkFuture {
+        Box::new(future::ok(()))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::mmap::MmapSink;
+
+#[cfg(target_os = "linux")]
+mod mmap {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::ptr;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::future;
+
+    use super::{Sink, SinkFuture};
+    use crate::disk;
+    use crate::error::DlError;
+
+    /// a file mapped into memory (with `mmap`), which pieces are copied straight into. a file under `/dev/shm`
+    /// makes for a download straight into shared memory
+    #[derive(Debug, Clone)]
+    pub struct MmapSink {
+        map: Arc<Mapping>,
+    }
+
+    #[derive(Debug)]
+    struct Mapping {
+        // kept open for as long as it's mapped
+        _file: File,
+        ptr: *mut u8,
+        size: u64,
+    }
+
+    // the mapping is only ever written through `write_at`, which copies into whatever (disjoint) range of it the
+    // download hands it, so it can be shared between threads
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            if self.size > 0 {
+                unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size as usize) };
+            }
+        }
+    }
+
+    impl MmapSink {
+        /// creates (or truncates) the file at `path`, `size` bytes long, and maps it into memory
+        pub fn create(path: &Path, size: u64) -> Result<MmapSink, DlError> {
+            let file = disk::preallocate(path, size)?;
+            let ptr = match size {
+                // there's nothing to map (and mmap refuses to map nothing)
+                0 => ptr::null_mut(),
+                _ => match unsafe {
+                    libc::mmap(
+                        ptr::null_mut(),
+                        size as usize,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED,
+                        file.as_raw_fd(),
+                        0,
+                    )
+                } {
+                    libc::MAP_FAILED => return Err(DlError::Io(io::Error::last_os_error())),
+                    ptr => ptr as *mut u8,
+                },
+            };
+            Ok(MmapSink {
+                map: Arc::new(Mapping {
+                    _file: file,
+                    ptr,
+                    size,
+                }),
+            })
+        }
+    }
+
+    impl Sink for MmapSink {
+        fn write_at(&self, bytes: Bytes, offset: u64) -> SinkFuture {
+            let end = offset + bytes.len() as u64;
+            if end > self.map.size {
+                return Box::new(future::err(DlError::LengthMismatch(self.map.size, end)));
+            }
+            let map = self.map.clone();
+            // copying into the mapping can fault pages in from disk, so is done off the reactor
+            Box::new(disk::run_blocking(move || {
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        bytes.as_ptr(),
+                        map.ptr.add(offset as usize),
+                        bytes.len(),
+                    )
+                };
+                Ok(())
+            }))
+        }
+
+        fn finalize(&self) -> SinkFuture {
+            let map = self.map.clone();
+            Box::new(disk::run_blocking(move || match map.size {
+                0 => Ok(()),
+                size => match unsafe {
+                    libc::msync(map.ptr as *mut libc::c_void, size as usize, libc::MS_SYNC)
+                } {
+                    0 => Ok(()),
+                    _ => Err(DlError::Io(io::Error::last_os_error())),
+                },
+            }))
+        }
+
+        fn abort(&self) -> SinkFuture {
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod sink_tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    /// writes "foobarbaz" to `sink` out of order, then finalizes it
+    fn write_foobarbaz(sink: &dyn Sink) -> Result<(), DlError> {
+        let writes = future::join_all(vec![
+            sink.write_at(Bytes::from_static(b"baz"), 6),
+            sink.write_at(Bytes::from_static(b"foo"), 0),
+            sink.write_at(Bytes::from_static(b"bar"), 3),
+        ]);
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(writes)?;
+        rt.block_on(sink.finalize())
+    }
+
+    #[test]
+    fn writing_to_files() {
+        let path = PathBuf::from("data/foo_sink.bin");
+        let sink = FileSink::create(&path, 9).unwrap();
+        write_foobarbaz(&sink).unwrap();
+        assert_eq!(std::fs::read(sink.path()).unwrap(), b"foobarbaz");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writing_to_memory() {
+        let sink = MemorySink::new(9);
+        write_foobarbaz(&sink).unwrap();
+        assert_eq!(sink.contents(), b"foobarbaz");
+
+        let small = MemorySink::new(6);
+        assert_eq!(
+            write_foobarbaz(&small).unwrap_err().code(),
+            "length_mismatch"
+        );
+        small.abort().wait().unwrap();
+        assert!(small.contents().is_empty());
+    }
+
+    #[test]
+    fn writing_to_nothing() {
+        let sink = NullSink::new();
+        write_foobarbaz(&sink).unwrap();
+        assert_eq!(sink.written(), 9);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn writing_to_mapped_files() {
+        let path = PathBuf::from("data/foo_mmap.bin");
+        let sink = MmapSink::create(&path, 9).unwrap();
+        write_foobarbaz(&sink).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"foobarbaz");
+        assert!(write_foobarbaz(&MmapSink::create(&path, 6).unwrap()).is_err());
+
+        MmapSink::create(&path, 0).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
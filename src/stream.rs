@@ -0,0 +1,318 @@
+use std::cmp::min;
+use std::io::{self, Read};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::{future, try_ready, Async, Future, IntoFuture, Poll, Stream};
+use md5::{Digest, Md5};
+use tokio::timer::Delay;
+use tokio_io::AsyncRead;
+
+use crate::checksum::{self, DigestReport, Verify};
+use crate::error::DlError;
+use crate::file::{FileDownloader, PieceReport};
+use crate::metadata::MetadataDownloader;
+use crate::output::{Event, Reporter};
+use crate::reorder::Ordered;
+use crate::Config;
+
+/// the bytes of a download, in order, as they arrive (see `Download::stream`).
+///
+/// pieces are fetched in parallel (and retried) just as they are for a download to a file, and held in a
+/// `ReorderBuffer` of `Config::buffer_size` bytes until the bytes before them have been read. the file is
+/// hashed as it is read, and checked according to `Config::verify` once it has all arrived: a stream whose
+/// file fails the check ends with an error rather than ending cleanly.
+///
+/// as well as a `Stream` of `Bytes`, a `DownloadStream` is an `AsyncRead` (don't mix the two)
+pub struct DownloadStream {
+    url: String,
+    state: State,
+    reporter: Reporter,
+    verify: Verify,
+    buffer_size: u64,
+    deadline: Option<Delay>,
+    hasher: Md5,
+    /// the part of the last chunk that a `read` didn't have room for
+    unread: Bytes,
+    /// what we learned from the server, once we have
+    metadata: Option<StreamMetadata>,
+    pieces: Vec<PieceReport>,
+    digests: Vec<DigestReport>,
+}
+
+enum State {
+    Metadata(Box<dyn Future<Item = FileDownloader, Error = DlError> + Send>),
+    Streaming(Ordered),
+    Done,
+}
+
+/// what the server told us about a file we're streaming
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamMetadata {
+    pub final_url: String,
+    pub size: u64,
+    pub etag: Option<String>,
+    /// the (hex-encoded) md5 digest the file is checked against, if any
+    pub expected_md5: Option<String>,
+}
+
+impl DownloadStream {
+    pub fn new(cfg: Config) -> DownloadStream {
+        let reporter = cfg.reporter();
+        let verify = cfg.verify.clone();
+        let buffer_size = cfg.buffer_size;
+        let deadline = cfg
+            .timeouts
+            .total
+            .map(|total| Delay::new(Instant::now() + total));
+        let url = cfg.uri.to_string();
+        reporter.emit(&Event::FetchingMetadata { url: url.clone() });
+        DownloadStream {
+            url,
+            state: State::Metadata(Box::new(
+                MetadataDownloader::from_config(cfg)
+                    .into_future()
+                    .and_then(MetadataDownloader::fetch),
+            )),
+            reporter,
+            verify,
+            buffer_size,
+            deadline,
+            hasher: Md5::new(),
+            unread: Bytes::new(),
+            metadata: None,
+            pieces: vec![],
+            digests: vec![],
+        }
+    }
+
+    /// what the server told us about the file (once the stream has started)
+    pub fn metadata(&self) -> Option<&StreamMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// the pieces the file was fetched in (once the stream has ended), sorted by offset
+    pub fn pieces(&self) -> &[PieceReport] {
+        &self.pieces
+    }
+
+    /// the outcome of checking the file (once the stream has ended)
+    pub fn digests(&self) -> &[DigestReport] {
+        &self.digests
+    }
+
+    /// resolves with the stream once it has started (so that its `metadata` is known), before any of the file
+    /// has been read
+    pub fn started(self) -> impl Future<Item = DownloadStream, Error = DlError> {
+        let mut stream = Some(self);
+        future::poll_fn(move || {
+            try_ready!(stream
+                .as_mut()
+                .expect("polled after it started")
+                .poll_started());
+            Ok(Async::Ready(
+                stream.take().expect("polled after it started"),
+            ))
+        })
+    }
+
+    /// fetches the file's metadata and starts streaming the file, if that hasn't happened yet (failing once the
+    /// download runs out of time)
+    fn poll_started(&mut self) -> Poll<(), DlError> {
+        if let Some(ref mut deadline) = self.deadline {
+            if let Async::Ready(_) = deadline.poll().map_err(DlError::Timer)? {
+                return Err(DlError::DownloadTimeout);
+            }
+        }
+        if let State::Metadata(ref mut metadata) = self.state {
+            let file_downloader = try_ready!(metadata.poll());
+            self.start(file_downloader)?;
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// starts streaming the file `file_downloader` found (failing up front if we can't check it the way we've
+    /// been asked to)
+    fn start(&mut self, file_downloader: FileDownloader) -> Result<(), DlError> {
+        let expected_md5 = self.verify.expected_md5(file_downloader.etag.as_ref())?;
+        let metadata = StreamMetadata {
+            final_url: file_downloader.uri.to_string(),
+            size: file_downloader.file_size,
+            etag: file_downloader.etag.clone(),
+            expected_md5,
+        };
+        self.reporter.emit(&Event::Metadata {
+            url: self.url.clone(),
+            final_url: metadata.final_url.clone(),
+            size: metadata.size,
+            etag: metadata.etag.clone(),
+        });
+        self.reporter.emit(&Event::Downloading {
+            path: file_downloader.destination.to_string(),
+            size: metadata.size,
+        });
+        self.metadata = Some(metadata);
+        self.state = State::Streaming(file_downloader.fetch_ordered(self.buffer_size));
+        Ok(())
+    }
+
+    /// checks everything we've read against the expected digest (if there is one)
+    fn finish(&mut self) -> Result<(), DlError> {
+        if let State::Streaming(ref ordered) = std::mem::replace(&mut self.state, State::Done) {
+            self.pieces = ordered.pieces().to_vec();
+        }
+        self.deadline = None;
+        let expected = self
+            .metadata
+            .as_ref()
+            .and_then(|md| md.expected_md5.clone());
+        if let Some(expected) = expected {
+            self.reporter.emit(&Event::Verifying);
+            let hasher = std::mem::replace(&mut self.hasher, Md5::new());
+            let digest = checksum::md5_report(&expected, &hasher.result());
+            self.reporter.emit(&Event::Verified(digest.clone()));
+            self.digests.push(digest.clone());
+            if !digest.verified {
+                return Err(DlError::DigestMismatch(digest.expected, digest.actual));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream for DownloadStream {
+    type Item = Bytes;
+    type Error = DlError;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, DlError> {
+        if !self.unread.is_empty() {
+            return Ok(Async::Ready(Some(self.unread.split_off(0))));
+        }
+        try_ready!(self.poll_started());
+        let next = match self.state {
+            State::Streaming(ref mut ordered) => try_ready!(ordered.poll()),
+            _ => return Ok(Async::Ready(None)),
+        };
+        match next {
+            Some(chunk) => {
+                self.hasher.input(&chunk);
+                Ok(Async::Ready(Some(chunk)))
+            }
+            None => {
+                self.finish()?;
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+impl Read for DownloadStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread.is_empty() {
+            match self.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.unread = chunk,
+                Ok(Async::Ready(None)) => return Ok(0),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+        let n = min(buf.len(), self.unread.len());
+        buf[..n].copy_from_slice(&self.unread[..n]);
+        self.unread.advance(n);
+        Ok(n)
+    }
+}
+
+impl AsyncRead for DownloadStream {}
+
+#[cfg(test)]
+mod stream_tests {
+    use std::path::Path;
+
+    use futures::future;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::file::download_tests::{local_downloader, partial_content, serve, test_content};
+    use crate::file::MIN_PIECE_SIZE;
+    use crate::output::OutputFormat;
+
+    /// a stream of the file `file_downloader` points at (skipping the metadata request)
+    fn stream_of(file_downloader: FileDownloader, verify: Verify) -> DownloadStream {
+        DownloadStream {
+            url: file_downloader.uri.to_string(),
+            state: State::Metadata(Box::new(future::ok(file_downloader))),
+            reporter: Reporter::stderr(OutputFormat::Text),
+            verify,
+            buffer_size: 2 * MIN_PIECE_SIZE,
+            deadline: None,
+            hasher: Md5::new(),
+            unread: Bytes::new(),
+            metadata: None,
+            pieces: vec![],
+            digests: vec![],
+        }
+    }
+
+    #[test]
+    fn reading_a_download_in_order() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 8 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let digest = hex::encode(Md5::digest(&content[..]));
+        let c = content.clone();
+        let addr = serve(&mut rt, move |range| {
+            let (start, end) = range.unwrap();
+            partial_content(&c, start, end, if start == 0 { 10 } else { 0 })
+        });
+        let downloader = || FileDownloader {
+            parallelism: 4,
+            ..local_downloader(addr, Path::new("-"), file_size)
+        };
+
+        // as an `AsyncRead`...
+        let stream = stream_of(downloader(), Verify::Md5(digest.clone()));
+        let (stream, bytes) = rt
+            .block_on(tokio_io::io::read_to_end(stream, vec![]))
+            .unwrap();
+        assert_eq!(bytes, *content);
+        assert!(stream.digests()[0].verified);
+        assert_eq!(stream.metadata().unwrap().size, file_size);
+        assert_eq!(
+            stream.pieces().iter().map(|p| p.length).sum::<u64>(),
+            file_size
+        );
+
+        // ...or a `Stream`
+        let stream = stream_of(downloader(), Verify::EtagIfPresent);
+        let bytes = rt.block_on(stream.concat2()).unwrap();
+        assert_eq!(bytes.as_ref(), &content[..]);
+    }
+
+    #[test]
+    fn verifying_a_download_as_it_is_read() {
+        let mut rt = Runtime::new().unwrap();
+        let file_size = 2 * MIN_PIECE_SIZE;
+        let content = test_content(file_size);
+        let addr = serve(&mut rt, move |range| {
+            let (start, end) = range.unwrap();
+            partial_content(&content, start, end, 0)
+        });
+        let downloader = || local_downloader(addr, Path::new("-"), file_size);
+
+        // every byte is handed on, but the stream fails (rather than ends) if they don't match the digest
+        let mut read = 0;
+        let result = rt.block_on(
+            stream_of(downloader(), Verify::Md5("0".repeat(32))).for_each(move |chunk| {
+                read += chunk.len() as u64;
+                assert!(read <= file_size);
+                Ok(())
+            }),
+        );
+        assert_eq!(result.unwrap_err().code(), "digest_mismatch");
+
+        // and fails up front if there's nothing to check against
+        let result = rt.block_on(stream_of(downloader(), Verify::Etag).collect());
+        assert_eq!(result.unwrap_err().code(), "etag_absent");
+    }
+}
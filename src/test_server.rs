@@ -0,0 +1,886 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{future, try_ready, Async, Future, Poll, Stream};
+use hyper::header::HeaderValue;
+use hyper::service::service_fn;
+use hyper::{Body, Chunk, Method, Request, Response, Server, StatusCode};
+use native_tls::{Identity, TlsAcceptor};
+use tokio::runtime::Runtime;
+use tokio::timer::Delay;
+use tokio_tcp::TcpListener;
+
+use crate::https::{self, Network};
+use crate::timeout::Timeouts;
+use crate::transport::{self, FakeFile, RecordedRequest, SharedTransport};
+
+/// the certificate authority that signed the https test server's certificate (for `localhost` and `127.0.0.1`)
+pub const CA_BUNDLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data/tls/ca.pem");
+
+const IDENTITY: &[u8] = include_bytes!("../data/tls/localhost.p12");
+const IDENTITY_PASSWORD: &str = "dl";
+
+/// a web server on an ephemeral local port (over http or https), serving `FakeFile`s (generated content, with
+/// whichever of `Accept-Ranges`, `Content-Length`, `ETag` and `Digest` headers they ask for), for testing
+/// downloads end to end without a network. it speaks http/1.1 and http/2 (offering http/2 via alpn over https),
+/// and http/3 too if asked to, and runs on a thread of its own until dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    scheme: &'static str,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    connections: Arc<AtomicUsize>,
+    peers: Arc<Mutex<Vec<IpAddr>>>,
+    commands: Arc<Mutex<Vec<String>>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+/// the files a `TestServer` serves (at which paths), and how
+#[derive(Debug, Clone, Default)]
+pub struct TestServerBuilder {
+    files: HashMap<String, FakeFile>,
+    https: bool,
+    http1_only: bool,
+    faults: Vec<(Fault, Range<usize>)>,
+    bandwidth: Option<u64>,
+    latency: Option<Duration>,
+    #[cfg(feature = "http3")]
+    http3: bool,
+    ftp: bool,
+}
+
+/// something for a `TestServer` to get wrong, to check that `dl` recovers from it (or reports it correctly)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// drops the connection once this many bytes of the body have been sent
+    DropAfter(u64),
+    /// answers a range request with a `Content-Range` one byte off from the range asked for
+    WrongContentRange,
+    /// ignores `Range` headers, sending the whole file
+    IgnoreRange,
+    /// answers with this status (say `429` or `503`) and no body, asking to be retried after this many seconds
+    RetryAfter(StatusCode, u64),
+    /// waits this long before answering
+    Stall(Duration),
+    /// flips the bits of the byte at this offset of the file
+    FlipByte(u64),
+    /// serves a new version of the file (with every byte changed), with this etag
+    ChangeEtag(String),
+}
+
+/// what the server's connections share
+struct State {
+    files: HashMap<String, FakeFile>,
+    faults: Vec<(Fault, Range<usize>)>,
+    bandwidth: Option<u64>,
+    latency: Option<Duration>,
+    /// where (if anywhere) the server also speaks http/3, as an `Alt-Svc` header to send with every response
+    alt_svc: Option<HeaderValue>,
+    gets: AtomicUsize,
+    log: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl TestServerBuilder {
+    /// serves `file` at `path` (anything else is a `404`)
+    pub fn file(mut self, path: &str, file: FakeFile) -> Self {
+        self.files.insert(path.to_string(), file);
+        self
+    }
+
+    /// serves over https (with a certificate signed by `CA_BUNDLE`) rather than plain http
+    pub fn https(mut self) -> Self {
+        self.https = true;
+        self
+    }
+
+    /// speaks http/1.1 only (like plenty of servers still do), rather than offering http/2 too
+    pub fn http1_only(mut self) -> Self {
+        self.http1_only = true;
+        self
+    }
+
+    /// caps the bandwidth of every connection to the server at `bytes_per_sec` (shared between the responses
+    /// on it, when it multiplexes them over http/2)
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    /// also speaks http/3 (over https, so this implies `https`), on the same port over udp, advertising it to
+    /// clients with an `Alt-Svc` header
+    #[cfg(feature = "http3")]
+    pub fn http3(mut self) -> Self {
+        self.https = true;
+        self.http3 = true;
+        self
+    }
+
+    /// speaks ftp (or, with `https`, ftps: ftp over tls from the start) instead of http, with passive data
+    /// connections, `SIZE` and `REST`. only the files' content is served (their headers, and the server's faults,
+    /// bandwidth and latency, are http's alone)
+    pub fn ftp(mut self) -> Self {
+        self.ftp = true;
+        self
+    }
+
+    /// waits `latency` before answering every request (as if it had to cross the network)
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// injects `fault` into every `GET` the server answers (`HEAD`s are left alone)
+    pub fn fault(self, fault: Fault) -> Self {
+        self.fault_on(fault, 0..usize::MAX)
+    }
+
+    /// injects `fault` into the `GET`s numbered `gets` (counting from 0, in the order they arrive)
+    pub fn fault_on(mut self, fault: Fault, gets: Range<usize>) -> Self {
+        self.faults.push((fault, gets));
+        self
+    }
+
+    pub fn start(self) -> TestServer {
+        if self.ftp {
+            return ftp_server::start(self);
+        }
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let (bound, addr) = mpsc::channel();
+        let (https, http1_only) = (self.https, self.http1_only);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let peers = Arc::new(Mutex::new(vec![]));
+        let connected_from = peers.clone();
+        #[cfg(feature = "http3")]
+        let http3 = self.http3;
+        #[cfg(not(feature = "http3"))]
+        let http3 = false;
+        let log = requests.clone();
+
+        thread::spawn(move || {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+                .expect("Test server failed to bind");
+            let local = listener.local_addr().unwrap();
+            let mut rt = Runtime::new().expect("Test server runtime failed to start");
+            let state = Arc::new(State {
+                files: self.files,
+                faults: self.faults,
+                bandwidth: self.bandwidth,
+                latency: self.latency,
+                alt_svc: match http3 {
+                    true => {
+                        Some(HeaderValue::from_str(&format!("h3=\":{}\"", local.port())).unwrap())
+                    }
+                    false => None,
+                },
+                gets: AtomicUsize::new(0),
+                log,
+            });
+            // (stopped, with every connection to it, when dropped)
+            #[cfg(feature = "http3")]
+            let _http3 = match http3 {
+                true => Some(http3_server::start(
+                    local,
+                    state.clone(),
+                    rt.executor(),
+                    accepted.clone(),
+                )),
+                false => None,
+            };
+            bound.send(local).unwrap();
+            // (called once per connection)
+            let serve = move || {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let (state, link) = (state.clone(), Arc::new(Mutex::new(None)));
+                service_fn(move |req: Request<Body>| respond(&state, &link, &req))
+            };
+            let incoming = listener.incoming().inspect(move |tcp| {
+                if let Ok(peer) = tcp.peer_addr() {
+                    connected_from.lock().unwrap().push(peer.ip());
+                }
+            });
+            let server: Box<dyn Future<Item = (), Error = ()> + Send> = match https {
+                false => Box::new(
+                    Server::builder(incoming)
+                        .http1_only(http1_only)
+                        .serve(serve)
+                        .map_err(|err| panic!("Test server failed: {}", err)),
+                ),
+                true => {
+                    let identity = Identity::from_pkcs12(IDENTITY, IDENTITY_PASSWORD).unwrap();
+                    let alpn: &[&str] = match http1_only {
+                        true => &["http/1.1"],
+                        false => &["h2", "http/1.1"],
+                    };
+                    let tls = TlsAcceptor::builder(identity)
+                        .accept_alpn(alpn)
+                        .build()
+                        .unwrap();
+                    let tls = tokio_tls::TlsAcceptor::from(tls);
+                    // a client that gives up on the handshake shouldn't bring the whole server down
+                    let incoming = incoming
+                        .map(move |tcp| tls.accept(tcp).then(|tls| Ok(tls.ok())))
+                        .buffer_unordered(64)
+                        .filter_map(|tls| tls);
+                    Box::new(
+                        Server::builder(incoming)
+                            .http1_only(http1_only)
+                            .serve(serve)
+                            .map_err(|err| panic!("Test server failed: {}", err)),
+                    )
+                }
+            };
+            let _ = rt.block_on(server.select2(stopped));
+        });
+
+        TestServer {
+            addr: addr.recv().expect("Test server failed to start"),
+            scheme: if https { "https" } else { "http" },
+            requests,
+            connections,
+            peers,
+            commands: Arc::new(Mutex::new(vec![])),
+            shutdown: Some(shutdown),
+        }
+    }
+}
+
+impl TestServer {
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// the url of `path` on the server (at `localhost`, so that its certificate checks out)
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://localhost:{}{}", self.scheme, self.addr.port(), path)
+    }
+
+    /// a `--resolve` entry (see `dns::Override`) pointing `host`, on the server's port, at the server (whose
+    /// certificate is only good for `localhost`, though)
+    pub fn resolve(&self, host: &str) -> String {
+        format!("{}:{}:{}", host, self.addr.port(), self.addr.ip())
+    }
+
+    /// a pem file of the certificate authority to trust to reach the server over https
+    pub fn ca_bundle(&self) -> PathBuf {
+        PathBuf::from(CA_BUNDLE)
+    }
+
+    /// `dl`'s default transport, trusting the server, whether it speaks http, https or ftp(s)
+    pub fn transport(&self) -> SharedTransport {
+        let network = Network {
+            ca_bundle: Some(self.ca_bundle()),
+            ..Network::default()
+        };
+        https::transport_for(2, &Timeouts::default(), &network).expect("TLS initialization failed")
+    }
+
+    /// how many connections have been made to the server so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// the addresses the server's (tcp) connections came from, in the order they were made
+    pub fn peers(&self) -> Vec<IpAddr> {
+        self.peers.lock().expect("Peer log poisoned").clone()
+    }
+
+    /// every request the server has been sent so far
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().expect("Request log poisoned").clone()
+    }
+
+    /// every command an ftp server has been sent so far (on any of its connections), as it was sent
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().expect("Command log poisoned").clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+fn respond(
+    state: &State,
+    link: &Link,
+    req: &Request<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    state
+        .log
+        .lock()
+        .expect("Request log poisoned")
+        .push(RecordedRequest {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+        });
+    let file = match state.files.get(req.uri().path()) {
+        Some(file) => file,
+        None => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            return Box::new(future::ok(res));
+        }
+    };
+    let faults: Vec<&Fault> = match *req.method() {
+        Method::GET => {
+            let n = state.gets.fetch_add(1, Ordering::SeqCst);
+            state
+                .faults
+                .iter()
+                .filter(|(_, gets)| gets.contains(&n))
+                .map(|(fault, _)| fault)
+                .collect()
+        }
+        _ => vec![],
+    };
+    let mut res = faulty(file.clone(), &faults, req);
+    if let Some(ref alt_svc) = state.alt_svc {
+        res.headers_mut().insert("alt-svc", alt_svc.clone());
+    }
+    if let Some(bytes_per_sec) = state.bandwidth {
+        let (parts, body) = res.into_parts();
+        let body = Paced {
+            body,
+            bytes_per_sec: bytes_per_sec.max(1),
+            link: link.clone(),
+            pending: None,
+        };
+        res = Response::from_parts(parts, Body::wrap_stream(body));
+    }
+    let delay = faults
+        .iter()
+        .filter_map(|fault| match fault {
+            Fault::Stall(delay) => Some(*delay),
+            _ => None,
+        })
+        .chain(state.latency)
+        .sum::<Duration>();
+    match delay {
+        Duration::ZERO => Box::new(future::ok(res)),
+        delay => Box::new(Delay::new(Instant::now() + delay).then(|_| Ok(res))),
+    }
+}
+
+/// when a connection will next be free to send (if it has sent anything yet), at the bandwidth it's capped at
+type Link = Arc<Mutex<Option<Instant>>>;
+
+/// a body sent over a `link` capped at `bytes_per_sec`. each chunk is held until the time by which (at that rate)
+/// the link would have sent it, so that the timer's granularity doesn't add up over many small chunks
+struct Paced<S> {
+    body: S,
+    bytes_per_sec: u64,
+    link: Link,
+    pending: Option<(Chunk, Delay)>,
+}
+
+impl<S: Stream<Item = Chunk, Error = hyper::Error>> Stream for Paced<S> {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if let Some((_, ref mut due)) = self.pending {
+                // a timer that has gone away is no reason to hold on to the chunk
+                if let Ok(Async::NotReady) = due.poll() {
+                    return Ok(Async::NotReady);
+                }
+                return Ok(Async::Ready(self.pending.take().map(|(chunk, _)| chunk)));
+            }
+            let chunk = match try_ready!(self.body.poll()) {
+                Some(chunk) => chunk,
+                None => return Ok(Async::Ready(None)),
+            };
+            let sending = Duration::from_secs_f64(chunk.len() as f64 / self.bytes_per_sec as f64);
+            let due = {
+                let mut free = self.link.lock().expect("Link lock poisoned");
+                let now = Instant::now();
+                let due = free.filter(|free| *free > now).unwrap_or(now) + sending;
+                *free = Some(due);
+                due
+            };
+            self.pending = Some((chunk, Delay::new(due)));
+        }
+    }
+}
+
+/// `file`'s response to `req`, with `faults` (other than stalls) injected into it
+fn faulty(mut file: FakeFile, faults: &[&Fault], req: &Request<Body>) -> Response<Body> {
+    for fault in faults {
+        match fault {
+            Fault::IgnoreRange => file.ranges = false,
+            Fault::FlipByte(offset) => {
+                let mut content = file.content.to_vec();
+                if let Some(byte) = content.get_mut(*offset as usize) {
+                    *byte = !*byte;
+                }
+                file.content = Arc::new(content);
+            }
+            Fault::ChangeEtag(etag) => {
+                file.content = Arc::new(file.content.iter().map(|b| b.wrapping_add(1)).collect());
+                file.etag = Some(etag.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut res = file.respond(req);
+    for fault in faults {
+        match fault {
+            Fault::WrongContentRange => {
+                let shifted = res
+                    .headers()
+                    .get("content-range")
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.strip_prefix("bytes "))
+                    .and_then(|range| {
+                        let (range, len) = range.split_once('/')?;
+                        let (start, end) = range.split_once('-')?;
+                        let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+                        Some(format!("bytes {}-{}/{}", start + 1, end + 1, len))
+                    });
+                if let Some(shifted) = shifted {
+                    res.headers_mut()
+                        .insert("content-range", HeaderValue::from_str(&shifted).unwrap());
+                }
+            }
+            Fault::RetryAfter(status, secs) => {
+                res = Response::new(Body::empty());
+                *res.status_mut() = *status;
+                res.headers_mut()
+                    .insert("retry-after", HeaderValue::from(*secs));
+            }
+            Fault::DropAfter(bytes) => res = transport::shape(res, None, Some(*bytes)),
+            _ => {}
+        }
+    }
+    res
+}
+
+/// the server's http/3 side: a quic endpoint on a (tokio 1) runtime of its own, answering with the same `respond`
+/// as the http/1.1 and http/2 side (run on the server's tokio 0.1 runtime, whose timers shape and stall responses)
+#[cfg(feature = "http3")]
+mod http3_server {
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use bytes1::Bytes;
+    use futures::sync::{mpsc, oneshot};
+    use futures::{future, Future, Sink, Stream};
+    use futures03::compat::{Future01CompatExt, Stream01CompatExt};
+    use futures03::StreamExt;
+    use hyper::header::{HeaderName, HeaderValue};
+    use hyper::{Body, Method, Request};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio::runtime::TaskExecutor;
+
+    use super::{respond, Link, State};
+
+    /// `IDENTITY`'s certificate and key, as pem (which is what rustls reads)
+    const CERTIFICATE: &[u8] = include_bytes!("../data/tls/localhost.pem");
+    const KEY: &[u8] = include_bytes!("../data/tls/localhost.key");
+
+    type RequestStream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+    /// serves http/3 on udp `addr` (counting connections in `accepted`) until the runtime returned is dropped
+    pub fn start(
+        addr: SocketAddr,
+        state: Arc<State>,
+        executor: TaskExecutor,
+        accepted: Arc<AtomicUsize>,
+    ) -> tokio1::runtime::Runtime {
+        let runtime = tokio1::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("Test server http/3 runtime failed to start");
+        let endpoint = {
+            let _entered = runtime.enter();
+            quinn::Endpoint::server(config(), addr).expect("Test server failed to bind udp")
+        };
+        runtime.spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio1::spawn(serve(incoming, state.clone(), executor.clone()));
+            }
+        });
+        runtime
+    }
+
+    fn config() -> quinn::ServerConfig {
+        let certs = CertificateDer::pem_slice_iter(CERTIFICATE)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(KEY).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).unwrap();
+        quinn::ServerConfig::with_crypto(Arc::new(crypto))
+    }
+
+    async fn serve(incoming: quinn::Incoming, state: Arc<State>, executor: TaskExecutor) {
+        let conn = match incoming.await {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        let mut conn =
+            match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+        let link: Link = Arc::new(Mutex::new(None));
+        while let Ok(Some(resolver)) = conn.accept().await {
+            let (state, link, executor) = (state.clone(), link.clone(), executor.clone());
+            tokio1::spawn(async move {
+                if let Ok((req, stream)) = resolver.resolve_request().await {
+                    let _ = answer(req, stream, state, link, &executor).await;
+                }
+            });
+        }
+    }
+
+    /// answers `req` with the response (and body) made for it on `executor`
+    async fn answer(
+        req: http1::Request<()>,
+        mut stream: RequestStream,
+        state: Arc<State>,
+        link: Link,
+        executor: &TaskExecutor,
+    ) -> Result<(), h3::error::StreamError> {
+        let mut hyper_req = Request::new(Body::empty());
+        *hyper_req.method_mut() = Method::from_bytes(req.method().as_str().as_bytes()).unwrap();
+        *hyper_req.uri_mut() = req.uri().to_string().parse().unwrap();
+        for (name, value) in req.headers() {
+            hyper_req.headers_mut().append(
+                HeaderName::from_bytes(name.as_str().as_bytes()).unwrap(),
+                HeaderValue::from_bytes(value.as_bytes()).unwrap(),
+            );
+        }
+
+        let (sent, head) = oneshot::channel();
+        let (chunks, body) = mpsc::channel(16);
+        executor.spawn(
+            future::lazy(move || respond(&state, &link, &hyper_req))
+                .map_err(|_| ())
+                .and_then(move |res| {
+                    let (parts, body) = res.into_parts();
+                    let _ = sent.send(parts);
+                    chunks
+                        .sink_map_err(|_| ())
+                        .send_all(body.then(|chunk| Ok::<_, ()>(chunk.map_err(|_| ()))))
+                        .map(|_| ())
+                }),
+        );
+
+        let head = match head.compat().await {
+            Ok(head) => head,
+            Err(_) => return Ok(()),
+        };
+        let mut res = http1::Response::builder().status(head.status.as_u16());
+        for (name, value) in head.headers.iter() {
+            res = res.header(name.as_str(), value.as_bytes());
+        }
+        stream
+            .send_response(res.body(()).expect("Test server made an invalid response"))
+            .await?;
+        let mut body = body.compat();
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(Ok(chunk)) => stream.send_data(Bytes::copy_from_slice(&chunk)).await?,
+                // a body that fails (say, to drop the connection partway) resets the stream
+                _ => {
+                    stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                    return Ok(());
+                }
+            }
+        }
+        stream.finish().await
+    }
+}
+
+/// the server's ftp side (see `TestServerBuilder::ftp`): a thread accepting control connections, each served by a
+/// thread of its own (with blocking io, as there's no tokio 0.1 ftp server to build on), until the server's dropped
+mod ftp_server {
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use futures::sync::oneshot;
+    use futures::Future;
+    use native_tls::{Identity, TlsAcceptor};
+
+    use super::{TestServer, TestServerBuilder, IDENTITY, IDENTITY_PASSWORD};
+
+    /// a control or data connection (over tls, for ftps)
+    trait Channel: Read + Write + Send {}
+
+    impl<T: Read + Write + Send> Channel for T {}
+
+    struct Shared {
+        builder: TestServerBuilder,
+        tls: Option<TlsAcceptor>,
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    pub fn start(builder: TestServerBuilder) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test server failed to bind");
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let (connections, peers) = (Arc::new(AtomicUsize::new(0)), Arc::new(Mutex::new(vec![])));
+        let commands = Arc::new(Mutex::new(vec![]));
+        let tls = match builder.https {
+            true => {
+                let identity = Identity::from_pkcs12(IDENTITY, IDENTITY_PASSWORD).unwrap();
+                Some(TlsAcceptor::new(identity).unwrap())
+            }
+            false => None,
+        };
+        let https = builder.https;
+        let shared = Arc::new(Shared {
+            builder,
+            tls,
+            commands: commands.clone(),
+        });
+
+        // (woken from `accept` to stop, once the server's dropped, by a connection of its own)
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        thread::spawn(move || {
+            let _ = stopped.wait();
+            stopping.store(true, Ordering::SeqCst);
+            let _ = TcpStream::connect(addr);
+        });
+        let (accepted, connected_from) = (connections.clone(), peers.clone());
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let tcp = match tcp {
+                    Ok(tcp) => tcp,
+                    Err(_) => continue,
+                };
+                accepted.fetch_add(1, Ordering::SeqCst);
+                if let Ok(peer) = tcp.peer_addr() {
+                    connected_from.lock().unwrap().push(peer.ip());
+                }
+                let shared = shared.clone();
+                thread::spawn(move || serve(&shared, tcp));
+            }
+        });
+
+        TestServer {
+            addr,
+            scheme: if https { "ftps" } else { "ftp" },
+            requests: Arc::new(Mutex::new(vec![])),
+            connections,
+            peers,
+            commands,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    fn secure(shared: &Shared, tcp: TcpStream) -> io::Result<Box<dyn Channel>> {
+        match shared.tls {
+            None => Ok(Box::new(tcp)),
+            Some(ref tls) => tls
+                .accept(tcp)
+                .map(|tls| Box::new(tls) as Box<dyn Channel>)
+                .map_err(io::Error::other),
+        }
+    }
+
+    /// answers a control connection's commands until it's closed (or asks to `QUIT`)
+    fn serve(shared: &Shared, tcp: TcpStream) -> io::Result<()> {
+        let mut control = BufReader::new(secure(shared, tcp)?);
+        let send = |control: &mut BufReader<Box<dyn Channel>>, reply: &str| {
+            control
+                .get_mut()
+                .write_all(format!("{}\r\n", reply).as_bytes())
+        };
+        send(&mut control, "220-dl test server\r\n220 ready")?;
+        let (mut passive, mut rest, mut protected) = (None::<TcpListener>, 0, false);
+        loop {
+            let mut line = String::new();
+            if control.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            shared.commands.lock().unwrap().push(line.to_string());
+            let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+            let file = shared.builder.files.get(&format!("/{}", arg));
+            let reply = match verb {
+                "USER" => String::from("331 password, please"),
+                "PASS" => String::from("230 logged in"),
+                "TYPE" | "PBSZ" => String::from("200 ok"),
+                "PROT" => {
+                    protected = arg == "P";
+                    String::from("200 ok")
+                }
+                "SIZE" => match file {
+                    Some(file) => format!("213 {}", file.content.len()),
+                    None => String::from("550 no such file"),
+                },
+                // (every file was last modified at the same moment: 2015-10-21 07:28:00 utc)
+                "MDTM" => match file {
+                    Some(_) => String::from("213 20151021072800"),
+                    None => String::from("550 no such file"),
+                },
+                "EPSV" | "PASV" => {
+                    let listener = TcpListener::bind("127.0.0.1:0")?;
+                    let port = listener.local_addr()?.port();
+                    passive = Some(listener);
+                    match verb {
+                        "EPSV" => format!("229 Entering Extended Passive Mode (|||{}|)", port),
+                        _ => format!(
+                            "227 Entering Passive Mode (127,0,0,1,{},{})",
+                            port >> 8,
+                            port & 0xff
+                        ),
+                    }
+                }
+                "REST" => {
+                    rest = arg.parse::<usize>().unwrap_or(0);
+                    String::from("350 restarting")
+                }
+                "RETR" => match (file, passive.take()) {
+                    (None, _) => String::from("550 no such file"),
+                    (Some(_), None) => String::from("425 use PASV first"),
+                    (Some(file), Some(listener)) => {
+                        send(&mut control, "150 opening data connection")?;
+                        let from = rest.min(file.content.len());
+                        rest = 0;
+                        let (tcp, _) = listener.accept()?;
+                        let mut data = match protected {
+                            true => secure(shared, tcp)?,
+                            false => Box::new(tcp),
+                        };
+                        // (the client hangs up once it has the range it asked for)
+                        match data.write_all(&file.content[from..]) {
+                            Ok(()) => String::from("226 transfer complete"),
+                            Err(_) => String::from("426 transfer aborted"),
+                        }
+                    }
+                },
+                "QUIT" => {
+                    send(&mut control, "221 bye")?;
+                    return Ok(());
+                }
+                _ => String::from("502 not implemented"),
+            };
+            send(&mut control, &reply)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_server_tests {
+    use super::*;
+    use crate::error::DlError;
+    use crate::transport::Transport;
+
+    #[test]
+    fn serving_files_over_http_and_https() {
+        let file = FakeFile::generated(1000).etag("abc");
+        let mut rt = Runtime::new().unwrap();
+        for server in [
+            TestServer::builder().file("/file", file.clone()).start(),
+            TestServer::builder()
+                .file("/file", file.clone())
+                .https()
+                .start(),
+        ] {
+            let client = server.transport();
+            let get = |path: &str| {
+                let req = Request::get(server.url(path))
+                    .header("range", "bytes=10-19")
+                    .body(Body::empty())
+                    .unwrap();
+                client.request(req).and_then(|res| {
+                    let (status, etag) = (res.status(), res.headers()["etag"].clone());
+                    res.into_body()
+                        .concat2()
+                        .map_err(DlError::from)
+                        .map(move |body| (status, etag, body.to_vec()))
+                })
+            };
+
+            let (status, etag, body) = rt.block_on(get("/file")).unwrap();
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(etag, "\"abc\"");
+            assert_eq!(body, file.content[10..20].to_vec());
+            assert_eq!(
+                rt.block_on(
+                    client.request(
+                        Request::get(server.url("/nope"))
+                            .body(Body::empty())
+                            .unwrap()
+                    )
+                )
+                .unwrap()
+                .status(),
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(server.requests().len(), 2);
+        }
+    }
+
+    #[test]
+    fn shaping_bandwidth_and_latency() {
+        let file = FakeFile::generated(256 * 1024);
+        let server = TestServer::builder()
+            .file("/file", file.clone())
+            .bandwidth(1024 * 1024)
+            .latency(Duration::from_millis(100))
+            .start();
+        let client = server.transport();
+        let mut rt = Runtime::new().unwrap();
+
+        let started = Instant::now();
+        let head = Request::head(server.url("/file"))
+            .body(Body::empty())
+            .unwrap();
+        rt.block_on(client.request(head)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let started = Instant::now();
+        let get = Request::get(server.url("/file"))
+            .body(Body::empty())
+            .unwrap();
+        let body = rt
+            .block_on(
+                client
+                    .request(get)
+                    .and_then(|res| res.into_body().concat2().map_err(DlError::from)),
+            )
+            .unwrap();
+        // a quarter of a megabyte, at a megabyte a second (after the latency)
+        assert!(started.elapsed() >= Duration::from_millis(350));
+        assert_eq!(body.to_vec(), *file.content);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use tokio::timer::Delay;
+
+use crate::error::DlError;
+
+/// caps the combined throughput of every stream it throttles at `bytes_per_sec` (shared between all of a
+/// download's connections, so that `--rate-limit` limits the download as a whole)
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// the moment by which everything let through so far will have "used up" its share of the rate
+    next_free: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: std::cmp::max(bytes_per_sec, 1),
+            next_free: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// books `bytes` worth of time, returning the moment they may be let through
+    fn reserve(&self, bytes: u64, now: Instant) -> Instant {
+        let mut next_free = self.next_free.lock().expect("Rate limiter lock poisoned");
+        let start = std::cmp::max(*next_free, now);
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        start
+    }
+}
+
+/// holds back each chunk of `body` until `limiter` has room for it (or passes it straight through if there
+/// is no limiter)
+pub fn throttle<S: Stream>(body: S, limiter: Option<RateLimiter>) -> Throttled<S> {
+    Throttled {
+        inner: body,
+        limiter,
+        pending: None,
+    }
+}
+
+/// see `throttle`
+pub struct Throttled<S: Stream> {
+    inner: S,
+    limiter: Option<RateLimiter>,
+    pending: Option<(S::Item, Delay)>,
+}
+
+impl<S> Stream for Throttled<S>
+where
+    S: Stream<Error = DlError>,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = DlError;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, DlError> {
+        if self.pending.is_none() {
+            let chunk = match self.inner.poll()? {
+                Async::Ready(Some(chunk)) => chunk,
+                other => return Ok(other),
+            };
+            let limiter = match self.limiter {
+                None => return Ok(Async::Ready(Some(chunk))),
+                Some(ref limiter) => limiter,
+            };
+            let now = Instant::now();
+            let at = limiter.reserve(chunk.as_ref().len() as u64, now);
+            if at <= now {
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            self.pending = Some((chunk, Delay::new(at)));
+        }
+
+        if let Some((_, ref mut delay)) = self.pending {
+            if let Async::NotReady = delay.poll().map_err(DlError::Timer)? {
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(self.pending.take().map(|(chunk, _)| chunk)))
+    }
+}
+
+#[cfg(test)]
+mod throttle_tests {
+    use super::*;
+    use futures::stream;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn reserving_bandwidth() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(500, now), now);
+        assert_eq!(limiter.reserve(500, now), now + Duration::from_millis(500));
+        assert_eq!(limiter.reserve(1, now), now + Duration::from_secs(1));
+        // time spent idle isn't banked
+        let later = now + Duration::from_secs(5);
+        assert_eq!(limiter.reserve(1, later), later);
+    }
+
+    #[test]
+    fn throttling_streams() {
+        let mut rt = Runtime::new().unwrap();
+        let chunks = || stream::iter_ok::<_, DlError>(vec![vec![0u8; 100]; 5]);
+
+        let started = Instant::now();
+        let limiter = RateLimiter::new(2000);
+        // two streams sharing a limiter: 1000 bytes at 2000 bytes/sec
+        let both = throttle(chunks(), Some(limiter.clone()))
+            .collect()
+            .join(throttle(chunks(), Some(limiter)).collect());
+        let (a, b) = rt.block_on(both).unwrap();
+        assert_eq!(a.len() + b.len(), 10);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let started = Instant::now();
+        assert_eq!(
+            rt.block_on(throttle(chunks(), None).collect())
+                .unwrap()
+                .len(),
+            5
+        );
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
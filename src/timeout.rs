@@ -0,0 +1,336 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::future::Either;
+use futures::{Async, Future, Poll, Stream};
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::timer::{Delay, Timeout};
+
+use crate::error::DlError;
+
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_FIRST_BYTE_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_SPEED_TIME_SECS: u64 = 30;
+
+/// how long `dl` is willing to wait at each stage of a download (`None` means forever)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// for the tcp connection to be established
+    pub connect: Option<Duration>,
+    /// for the tls handshake (on top of however long `connect` allows)
+    pub tls_handshake: Option<Duration>,
+    /// between sending a request and receiving the response's headers
+    pub first_byte: Option<Duration>,
+    /// between two chunks of a response body
+    pub idle: Option<Duration>,
+    /// for the whole download (metadata, pieces and verification)
+    pub total: Option<Duration>,
+    /// abort any response that is slower than this for too long
+    pub min_speed: Option<MinSpeed>,
+}
+
+/// like curl's `--speed-limit`/`--speed-time`: a response averaging fewer than `bytes_per_sec` over
+/// `window` is aborted (and retried, like any other transient failure)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MinSpeed {
+    pub bytes_per_sec: u64,
+    pub window: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS)),
+            tls_handshake: Some(Duration::from_secs(DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS)),
+            first_byte: Some(Duration::from_secs(DEFAULT_FIRST_BYTE_TIMEOUT_SECS)),
+            idle: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)),
+            total: None,
+            min_speed: None,
+        }
+    }
+}
+
+/// fails `future` with `err` if it hasn't resolved within `limit`
+pub fn deadline<F>(
+    future: F,
+    limit: Option<Duration>,
+    err: DlError,
+) -> impl Future<Item = F::Item, Error = DlError>
+where
+    F: Future<Error = DlError>,
+{
+    match limit {
+        None => Either::A(future),
+        Some(limit) => Either::B(Timeout::new(future, limit).map_err(move |e| {
+            match (e.is_elapsed(), e.into_inner()) {
+                (_, Some(inner)) => inner,
+                (true, None) => err,
+                (false, None) => DlError::Io(io::Error::other("timer failed")),
+            }
+        })),
+    }
+}
+
+/// the stage of connecting to a server that took too long. our connectors wrap this in the `io::Error`
+/// they fail with, so that it survives the trip through hyper (see `From<hyper::Error> for DlError`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Elapsed {
+    Connect,
+    TlsHandshake,
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Elapsed::Connect => write!(f, "connect timed out"),
+            Elapsed::TlsHandshake => write!(f, "tls handshake timed out"),
+        }
+    }
+}
+
+impl Error for Elapsed {}
+
+/// a connector that gives up on `inner` (failing with `elapsed`) after `timeout`
+#[derive(Debug, Clone)]
+pub struct DeadlineConnector<C> {
+    inner: C,
+    timeout: Option<Duration>,
+    elapsed: Elapsed,
+}
+
+impl<C> DeadlineConnector<C> {
+    pub fn new(inner: C, timeout: Option<Duration>, elapsed: Elapsed) -> DeadlineConnector<C> {
+        DeadlineConnector {
+            inner,
+            timeout,
+            elapsed,
+        }
+    }
+}
+
+impl<C> Connect for DeadlineConnector<C>
+where
+    C: Connect<Error = io::Error>,
+    C::Future: 'static,
+{
+    type Transport = C::Transport;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (C::Transport, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let connecting = self.inner.connect(dst);
+        let elapsed = self.elapsed;
+        match self.timeout {
+            None => Box::new(connecting),
+            Some(timeout) => Box::new(Timeout::new(connecting, timeout).map_err(move |e| {
+                match (e.is_elapsed(), e.into_inner()) {
+                    (_, Some(inner)) => inner,
+                    (true, None) => io::Error::new(io::ErrorKind::TimedOut, elapsed),
+                    (false, None) => io::Error::other("timer failed"),
+                }
+            })),
+        }
+    }
+}
+
+/// wraps a response body, failing it with `IdleTimeout` if no chunk arrives for `timeouts.idle`, or with
+/// `TooSlow` if it averages less than `timeouts.min_speed` over a whole window
+pub fn watch<S>(body: S, timeouts: &Timeouts) -> Watched<S> {
+    let now = Instant::now();
+    Watched {
+        inner: body,
+        idle: timeouts.idle,
+        min_speed: timeouts.min_speed,
+        last_chunk: now,
+        window_start: now,
+        window_bytes: 0,
+        timer: None,
+    }
+}
+
+/// see `watch`
+pub struct Watched<S> {
+    inner: S,
+    idle: Option<Duration>,
+    min_speed: Option<MinSpeed>,
+    last_chunk: Instant,
+    window_start: Instant,
+    window_bytes: u64,
+    timer: Option<Delay>,
+}
+
+impl<S> Watched<S> {
+    /// fails if the window is over and too few bytes arrived in it (otherwise starts a new window)
+    fn check_speed(&mut self, now: Instant) -> Result<(), DlError> {
+        let min_speed = match self.min_speed {
+            Some(ref min_speed) if now >= self.window_start + min_speed.window => min_speed,
+            _ => return Ok(()),
+        };
+        let secs = (now - self.window_start).as_secs_f64();
+        let rate = (self.window_bytes as f64 / secs) as u64;
+        if rate < min_speed.bytes_per_sec {
+            return Err(DlError::TooSlow(rate));
+        }
+        self.window_start = now;
+        self.window_bytes = 0;
+        Ok(())
+    }
+
+    /// the next moment we need to wake up at to check on the body, if any
+    fn next_deadline(&self) -> Option<Instant> {
+        let idle = self.idle.map(|idle| self.last_chunk + idle);
+        let window = self.min_speed.map(|ms| self.window_start + ms.window);
+        match (idle, window) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+impl<S> Stream for Watched<S>
+where
+    S: Stream<Error = DlError>,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = DlError;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, DlError> {
+        match self.inner.poll()? {
+            Async::Ready(Some(chunk)) => {
+                let now = Instant::now();
+                self.last_chunk = now;
+                self.window_bytes += chunk.as_ref().len() as u64;
+                self.check_speed(now)?;
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => (),
+        }
+
+        loop {
+            let now = Instant::now();
+            if let Some(idle) = self.idle {
+                if now >= self.last_chunk + idle {
+                    return Err(DlError::IdleTimeout);
+                }
+            }
+            self.check_speed(now)?;
+
+            let deadline = match self.next_deadline() {
+                None => return Ok(Async::NotReady),
+                Some(deadline) => deadline,
+            };
+            let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+            if timer.deadline() != deadline {
+                timer.reset(deadline);
+            }
+            match timer.poll().map_err(DlError::Timer)? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(()) => continue,
+            }
+        }
+    }
+}
+
+/// parses a (possibly fractional, strictly positive) number of seconds
+pub fn parse_secs(secs: &str) -> Option<Duration> {
+    secs.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s > 0.0)
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+}
+
+#[cfg(test)]
+mod timeout_tests {
+    use super::*;
+    use futures::stream;
+    use tokio::runtime::Runtime;
+
+    fn trickle(
+        chunks: u64,
+        size: usize,
+        delay_millis: u64,
+    ) -> impl Stream<Item = Vec<u8>, Error = DlError> {
+        stream::iter_ok(0..chunks).and_then(move |_| {
+            Delay::new(Instant::now() + Duration::from_millis(delay_millis))
+                .map_err(DlError::Timer)
+                .map(move |_| vec![0; size])
+        })
+    }
+
+    fn timeouts(idle_millis: Option<u64>, min_speed: Option<MinSpeed>) -> Timeouts {
+        Timeouts {
+            idle: idle_millis.map(Duration::from_millis),
+            min_speed,
+            ..Timeouts::default()
+        }
+    }
+
+    #[test]
+    fn timing_out_idle_bodies() {
+        let mut rt = Runtime::new().unwrap();
+        let ok = watch(trickle(3, 10, 10), &timeouts(Some(200), None)).collect();
+        assert_eq!(rt.block_on(ok).unwrap().len(), 3);
+
+        let stalled = watch(trickle(3, 10, 200), &timeouts(Some(50), None)).collect();
+        assert_eq!(rt.block_on(stalled).unwrap_err().code(), "idle_timeout");
+    }
+
+    #[test]
+    fn aborting_slow_bodies() {
+        let mut rt = Runtime::new().unwrap();
+        let min_speed = MinSpeed {
+            bytes_per_sec: 10_000,
+            window: Duration::from_millis(100),
+        };
+
+        // ~100KB/sec
+        let fast = watch(trickle(20, 1000, 10), &timeouts(None, Some(min_speed))).collect();
+        assert_eq!(rt.block_on(fast).unwrap().len(), 20);
+
+        // ~1KB/sec
+        let slow = watch(trickle(20, 10, 10), &timeouts(None, Some(min_speed))).collect();
+        assert_eq!(rt.block_on(slow).unwrap_err().code(), "too_slow");
+
+        // nothing at all (and no idle timeout to catch it)
+        let stalled = watch(trickle(1, 10, 1000), &timeouts(None, Some(min_speed))).collect();
+        assert_eq!(rt.block_on(stalled).unwrap_err().code(), "too_slow");
+    }
+
+    #[test]
+    fn enforcing_deadlines() {
+        let mut rt = Runtime::new().unwrap();
+        let slow = trickle(1, 1, 200).collect();
+        let err = rt
+            .block_on(deadline(
+                slow,
+                Some(Duration::from_millis(50)),
+                DlError::DownloadTimeout,
+            ))
+            .unwrap_err();
+        assert_eq!(err.code(), "download_timeout");
+
+        let fast = trickle(1, 1, 10).collect();
+        assert!(rt
+            .block_on(deadline(
+                fast,
+                Some(Duration::from_millis(200)),
+                DlError::DownloadTimeout
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn parsing_seconds() {
+        assert_eq!(parse_secs("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_secs("0"), None);
+        assert_eq!(parse_secs("-1"), None);
+        assert_eq!(parse_secs("soon"), None);
+        assert_eq!(parse_secs("1e20"), None);
+    }
+}
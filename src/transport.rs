@@ -0,0 +1,525 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures::Future;
+use hyper::{Body, Request, Response};
+
+use crate::error::DlError;
+use crate::https::HttpsClient;
+
+/// what a `Transport` resolves a request with
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = DlError> + Send>;
+
+/// how requests reach servers: sends a request and resolves with the response as soon as its headers have
+/// arrived (its body streams in behind them). `dl` uses a hyper client unless told otherwise
+pub trait Transport: Send + Sync {
+    fn request(&self, req: Request<Body>) -> ResponseFuture;
+}
+
+impl Transport for HttpsClient {
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        Box::new(hyper::Client::request(self, req).map_err(DlError::from))
+    }
+}
+
+/// a `Transport` shared by every request of a download (two are equal if they are the same transport)
+#[derive(Clone)]
+pub struct SharedTransport(Arc<dyn Transport>);
+
+impl SharedTransport {
+    pub fn new<T: Transport + 'static>(transport: T) -> SharedTransport {
+        SharedTransport(Arc::new(transport))
+    }
+}
+
+impl Transport for SharedTransport {
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        self.0.request(req)
+    }
+}
+
+impl fmt::Debug for SharedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transport")
+    }
+}
+
+impl PartialEq for SharedTransport {
+    fn eq(&self, other: &SharedTransport) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// test doubles standing in for the network (built for dl's own tests, and for those of code built on dl with the
+/// `test-server` feature)
+#[cfg(any(test, feature = "test-server"))]
+mod fake {
+    use std::cmp::min;
+    use std::fmt;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use futures::{future, stream, try_ready, Async, Future, Poll, Stream};
+    use hyper::header::HeaderValue;
+    use hyper::{Body, Chunk, HeaderMap, Method, Request, Response, StatusCode, Uri};
+    use md5::{Digest, Md5};
+    use tokio::timer::Delay;
+
+    use super::{parse_range, ResponseFuture, Transport};
+    use crate::error::DlError;
+
+    /// an in-process stand-in for the network, which answers every request by running it through a script (and
+    /// remembers every request it was sent), so that tests can run offline and deterministically
+    #[derive(Clone)]
+    pub struct FakeTransport {
+        script: Arc<Script>,
+        requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    }
+
+    type Script = dyn Fn(&Request<Body>) -> Reply + Send + Sync;
+
+    /// a request a `FakeTransport` was sent
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct RecordedRequest {
+        pub method: Method,
+        pub uri: Uri,
+        pub headers: HeaderMap<HeaderValue>,
+    }
+
+    impl FakeTransport {
+        /// a transport that answers each request with whatever `script` replies to it
+        pub fn new<F>(script: F) -> FakeTransport
+        where
+            F: Fn(&Request<Body>) -> Reply + Send + Sync + 'static,
+        {
+            FakeTransport {
+                script: Arc::new(script),
+                requests: Arc::new(Mutex::new(vec![])),
+            }
+        }
+
+        /// a transport that serves `file` at every url
+        pub fn serving(file: FakeFile) -> FakeTransport {
+            FakeTransport::new(move |req| Reply::ok(file.respond(req)))
+        }
+
+        /// every request sent so far, in the order they were sent
+        pub fn requests(&self) -> Vec<RecordedRequest> {
+            self.requests.lock().expect("Request log poisoned").clone()
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn request(&self, req: Request<Body>) -> ResponseFuture {
+            self.requests
+                .lock()
+                .expect("Request log poisoned")
+                .push(RecordedRequest {
+                    method: req.method().clone(),
+                    uri: req.uri().clone(),
+                    headers: req.headers().clone(),
+                });
+            let Reply {
+                result,
+                delay,
+                chunk_delay,
+                fail_after,
+            } = (self.script)(&req);
+            let result = result.map(|res| shape(res, chunk_delay, fail_after));
+            match delay {
+                None => Box::new(future::result(result)),
+                Some(delay) => Box::new(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(DlError::Timer)
+                        .and_then(move |_| result),
+                ),
+            }
+        }
+    }
+
+    impl fmt::Debug for FakeTransport {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "FakeTransport")
+        }
+    }
+
+    /// how a `FakeTransport` answers a request: with a response (or an error), possibly after a delay, and with a
+    /// body that may trickle in or be cut off partway through
+    pub struct Reply {
+        result: Result<Response<Body>, DlError>,
+        delay: Option<Duration>,
+        chunk_delay: Option<Duration>,
+        fail_after: Option<u64>,
+    }
+
+    impl Reply {
+        pub fn ok(response: Response<Body>) -> Reply {
+            Reply {
+                result: Ok(response),
+                delay: None,
+                chunk_delay: None,
+                fail_after: None,
+            }
+        }
+
+        /// fails the request (as if the connection couldn't be made, say) rather than responding
+        pub fn fail(err: DlError) -> Reply {
+            Reply {
+                result: Err(err),
+                ..Reply::ok(Response::new(Body::empty()))
+            }
+        }
+
+        /// a response with nothing but a `status`
+        pub fn status(status: StatusCode) -> Reply {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = status;
+            Reply::ok(res)
+        }
+
+        /// holds the response (or error) back for `delay`
+        pub fn after(mut self, delay: Duration) -> Reply {
+            self.delay = Some(delay);
+            self
+        }
+
+        /// waits `delay` before handing on each chunk of the body
+        pub fn trickle(mut self, delay: Duration) -> Reply {
+            self.chunk_delay = Some(delay);
+            self
+        }
+
+        /// drops the connection once `bytes` bytes of the body have been sent
+        pub fn fail_after(mut self, bytes: u64) -> Reply {
+            self.fail_after = Some(bytes);
+            self
+        }
+    }
+
+    /// reshapes the body of `res` as a `Reply` asked for
+    pub(crate) fn shape(
+        res: Response<Body>,
+        chunk_delay: Option<Duration>,
+        fail_after: Option<u64>,
+    ) -> Response<Body> {
+        if chunk_delay.is_none() && fail_after.is_none() {
+            return res;
+        }
+        let (parts, body) = res.into_parts();
+        let body = body
+            .map_err(|err| Box::new(err) as BoxError)
+            .and_then(move |chunk| match chunk_delay {
+                None => future::Either::A(future::ok(chunk)),
+                Some(delay) => future::Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map(|_| chunk)
+                        .map_err(|err| Box::new(err) as BoxError),
+                ),
+            });
+        let body: Box<dyn Stream<Item = Chunk, Error = BoxError> + Send> = match fail_after {
+            None => Box::new(body),
+            Some(left) => Box::new(CutShort { body, left }),
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// a body that fails (as if the connection was reset) once `left` more bytes of it have been handed on
+    struct CutShort<S> {
+        body: S,
+        left: u64,
+    }
+
+    impl<S: Stream<Item = Chunk, Error = BoxError>> Stream for CutShort<S> {
+        type Item = Chunk;
+        type Error = BoxError;
+
+        fn poll(&mut self) -> Poll<Option<Chunk>, BoxError> {
+            if self.left == 0 {
+                let reset = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+                return Err(Box::new(reset));
+            }
+            Ok(match try_ready!(self.body.poll()) {
+                Some(chunk) => {
+                    let len = min(chunk.len() as u64, self.left);
+                    self.left -= len;
+                    Async::Ready(Some(Chunk::from(chunk.into_bytes().slice_to(len as usize))))
+                }
+                None => Async::Ready(None),
+            })
+        }
+    }
+
+    /// a file served the way a typical (range-supporting) web server would serve it: see `FakeFile::respond`
+    #[derive(Debug, Clone)]
+    pub struct FakeFile {
+        pub content: Arc<Vec<u8>>,
+        /// sent (quoted) in the `ETag` header
+        pub etag: Option<String>,
+        /// whether range requests are honored (and advertised with `Accept-Ranges: bytes`)
+        pub ranges: bool,
+        /// whether responses say how long they are (with `Content-Length`)
+        pub content_length: bool,
+        /// whether responses carry the file's md5 digest (as `Digest: md5=<base64>`)
+        pub digest: bool,
+        /// sent with every response
+        pub headers: HeaderMap<HeaderValue>,
+    }
+
+    /// how much of a `FakeFile` is sent at a time
+    pub const FAKE_CHUNK_SIZE: usize = 16 * 1024;
+
+    impl FakeFile {
+        pub fn new(content: Vec<u8>) -> FakeFile {
+            FakeFile {
+                content: Arc::new(content),
+                etag: None,
+                ranges: true,
+                content_length: true,
+                digest: false,
+                headers: HeaderMap::new(),
+            }
+        }
+
+        /// a file of `size` (arbitrary, but always the same) bytes
+        pub fn generated(size: u64) -> FakeFile {
+            FakeFile::new((0..size).map(|i| (i % 251) as u8).collect())
+        }
+
+        /// the file's md5 digest, hex-encoded (as `dl` expects it in an etag or `--md5`)
+        pub fn md5(&self) -> String {
+            hex::encode(Md5::digest(&self.content[..]))
+        }
+
+        pub fn etag(mut self, etag: &str) -> FakeFile {
+            self.etag = Some(etag.to_string());
+            self
+        }
+
+        /// ignores range requests (answering them with the whole file)
+        pub fn without_ranges(mut self) -> FakeFile {
+            self.ranges = false;
+            self
+        }
+
+        pub fn without_content_length(mut self) -> FakeFile {
+            self.content_length = false;
+            self
+        }
+
+        pub fn with_digest(mut self) -> FakeFile {
+            self.digest = true;
+            self
+        }
+
+        pub fn header(mut self, name: &'static str, value: &str) -> FakeFile {
+            self.headers.insert(
+                name,
+                HeaderValue::from_str(value).expect("Invalid header value"),
+            );
+            self
+        }
+
+        /// the response to `req`: the file's headers for a `HEAD`, a `206` with the requested bytes for a
+        /// (satisfiable) range request, and the whole file otherwise. conditions are honored as a web server would:
+        /// a failed `If-Match` is a `412`, and a failed `If-Range` gets the whole file
+        pub fn respond(&self, req: &Request<Body>) -> Response<Body> {
+            let len = self.content.len() as u64;
+            let etag = self.etag.as_ref().map(|etag| format!("\"{}\"", etag));
+            let header = |name: &str| req.headers().get(name).and_then(|val| val.to_str().ok());
+            let validators = [
+                etag.as_deref(),
+                self.headers
+                    .get("last-modified")
+                    .and_then(|val| val.to_str().ok()),
+            ];
+            let precondition_failed = header("if-match")
+                .is_some_and(|expected| expected != "*" && etag.as_deref() != Some(expected));
+            let range = header("range")
+                .filter(|_| self.ranges)
+                .filter(|_| header("if-range").is_none_or(|v| validators.contains(&Some(v))))
+                .and_then(parse_range);
+            let (status, start, end) = match range {
+                _ if precondition_failed => (StatusCode::PRECONDITION_FAILED, 0, 0),
+                None => (StatusCode::OK, 0, len),
+                Some((start, end)) if start <= end && end < len => {
+                    (StatusCode::PARTIAL_CONTENT, start, end + 1)
+                }
+                Some(_) => (StatusCode::RANGE_NOT_SATISFIABLE, 0, 0),
+            };
+
+            let mut res = match *req.method() {
+                Method::HEAD => Response::new(Body::empty()),
+                _ => {
+                    // copied out a chunk at a time, as it's sent (the file may be hundreds of megabytes)
+                    let content = self.content.clone();
+                    let chunks = (start..end).step_by(FAKE_CHUNK_SIZE).map(move |at| {
+                        let to = min(at + FAKE_CHUNK_SIZE as u64, end);
+                        Chunk::from(content[at as usize..to as usize].to_vec())
+                    });
+                    Response::new(Body::wrap_stream(stream::iter_ok::<_, hyper::Error>(
+                        chunks,
+                    )))
+                }
+            };
+            *res.status_mut() = status;
+            let headers = res.headers_mut();
+            headers.extend(self.headers.clone());
+            if self.content_length {
+                headers.insert("content-length", HeaderValue::from(end - start));
+            }
+            if self.digest {
+                let digest = format!("md5={}", base64(&Md5::digest(&self.content[..])));
+                headers.insert("digest", HeaderValue::from_str(&digest).unwrap());
+            }
+            if self.ranges {
+                headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+            }
+            if let Some(ref etag) = self.etag {
+                let etag = format!("\"{}\"", etag);
+                headers.insert("etag", HeaderValue::from_str(&etag).expect("Invalid etag"));
+            }
+            if status == StatusCode::PARTIAL_CONTENT {
+                let range = format!("bytes {}-{}/{}", start, end - 1, len);
+                headers.insert("content-range", HeaderValue::from_str(&range).unwrap());
+            }
+            res
+        }
+    }
+
+    /// encodes `bytes` as (padded) base64
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for group in bytes.chunks(3) {
+            let n = group
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                match i <= group.len() {
+                    true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                    false => encoded.push('='),
+                }
+            }
+        }
+        encoded
+    }
+}
+
+#[cfg(any(test, feature = "test-server"))]
+pub(crate) use self::fake::shape;
+#[cfg(any(test, feature = "test-server"))]
+pub use self::fake::{FakeFile, FakeTransport, RecordedRequest, Reply, FAKE_CHUNK_SIZE};
+
+/// parses a `Range` header value of the form `bytes=<first>-<last>`
+pub(crate) fn parse_range(header: &str) -> Option<(u64, u64)> {
+    let mut bounds = header.strip_prefix("bytes=")?.splitn(2, '-');
+    let first = bounds.next()?.trim().parse::<u64>().ok()?;
+    let last = bounds.next()?.trim().parse::<u64>().ok()?;
+    Some((first, last))
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use std::time::{Duration, Instant};
+
+    use futures::Stream;
+    use hyper::StatusCode;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn get(range: Option<&str>) -> Request<Body> {
+        let mut req = Request::get("https://foo.com/file");
+        if let Some(range) = range {
+            req.header("range", range);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    /// the status and body of the response `transport` gives `req`
+    fn send(
+        rt: &mut Runtime,
+        transport: &dyn Transport,
+        req: Request<Body>,
+    ) -> Result<(StatusCode, Vec<u8>), DlError> {
+        rt.block_on(transport.request(req).and_then(|res| {
+            let status = res.status();
+            res.into_body()
+                .concat2()
+                .map_err(DlError::from)
+                .map(move |body| (status, body.to_vec()))
+        }))
+    }
+
+    #[test]
+    fn serving_fake_files() {
+        let mut rt = Runtime::new().unwrap();
+        let file = FakeFile::new((0..100).collect()).etag("abc");
+        let transport = FakeTransport::serving(file.clone());
+
+        let head = file.respond(&Request::head("/").body(Body::empty()).unwrap());
+        assert_eq!(head.headers()["content-length"], "100");
+        assert_eq!(head.headers()["accept-ranges"], "bytes");
+        assert_eq!(head.headers()["etag"], "\"abc\"");
+
+        let (status, body) = send(&mut rt, &transport, get(Some("bytes=10-19"))).unwrap();
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, (10..20).collect::<Vec<u8>>());
+        let (status, _) = send(&mut rt, &transport, get(Some("bytes=100-109"))).unwrap();
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(transport.requests()[1].headers["range"], "bytes=100-109");
+
+        let described = FakeFile::new(b"foo".to_vec())
+            .with_digest()
+            .without_content_length()
+            .respond(&get(None));
+        assert_eq!(
+            described.headers()["digest"],
+            "md5=rL0Y20zC+Fzt72VPzMSk2A=="
+        );
+        assert!(described.headers().get("content-length").is_none());
+
+        let ignoring = FakeTransport::serving(file.without_ranges());
+        let (status, body) = send(&mut rt, &ignoring, get(Some("bytes=10-19"))).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.len(), 100);
+    }
+
+    #[test]
+    fn scripting_delays_and_failures() {
+        let mut rt = Runtime::new().unwrap();
+        let file = FakeFile::new(vec![7; 3 * FAKE_CHUNK_SIZE]);
+
+        let f = file.clone();
+        let slow = FakeTransport::new(move |req| {
+            Reply::ok(f.respond(req))
+                .after(Duration::from_millis(20))
+                .trickle(Duration::from_millis(10))
+        });
+        let started = Instant::now();
+        let (_, body) = send(&mut rt, &slow, get(None)).unwrap();
+        assert_eq!(body.len(), 3 * FAKE_CHUNK_SIZE);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let f = file.clone();
+        let dropped = FakeTransport::new(move |req| Reply::ok(f.respond(req)).fail_after(20_000));
+        let err = send(&mut rt, &dropped, get(None)).unwrap_err();
+        assert!(err.is_transient());
+
+        let refused = FakeTransport::new(|_| Reply::fail(DlError::ConnectTimeout));
+        let err = send(&mut rt, &refused, get(None)).unwrap_err();
+        assert_eq!(err.code(), "connect_timeout");
+        let (status, _) = send(
+            &mut rt,
+            &FakeTransport::new(|_| Reply::status(StatusCode::SERVICE_UNAVAILABLE)),
+            get(None),
+        )
+        .unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,178 @@
+//! end to end tests of how `dl` copes with servers that misbehave, against a `TestServer` injecting `Fault`s
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use dl::disk;
+use dl::error::DlError;
+use dl::output::Summary;
+use dl::test_server::{Fault, TestServer};
+use dl::timeout::Timeouts;
+use dl::transport::FakeFile;
+use dl::{Download, DownloadBuilder};
+use hyper::StatusCode;
+use tokio::runtime::Runtime;
+
+const FILE_SIZE: u64 = 256 * 1024;
+const PIECE_SIZE: u64 = 64 * 1024;
+
+/// a server (over https) with a file at `/file` whose etag is its md5 digest, and `faults` injected into it
+fn server_with(faults: Vec<(Fault, std::ops::Range<usize>)>) -> (TestServer, FakeFile) {
+    let file = FakeFile::generated(FILE_SIZE);
+    let file = file.clone().etag(&file.md5());
+    let server = faults
+        .into_iter()
+        .fold(
+            TestServer::builder().file("/file", file.clone()).https(),
+            |server, (fault, gets)| server.fault_on(fault, gets),
+        )
+        .start();
+    (server, file)
+}
+
+/// a download of `/file` from `server` to a file of its own (named for the test)
+fn download_from(server: &TestServer, name: &str) -> (DownloadBuilder, PathBuf) {
+    let path = PathBuf::from(format!("data/foo_faults_{}.bin", name));
+    let builder = Download::builder()
+        .url(server.url("/file"))
+        .path(&path)
+        .ca_bundle(server.ca_bundle())
+        .piece_size(PIECE_SIZE)
+        .retry_backoff(Duration::from_millis(10));
+    (builder, path)
+}
+
+fn run(builder: DownloadBuilder) -> Result<Summary, DlError> {
+    Runtime::new()
+        .unwrap()
+        .block_on(builder.build().unwrap().run())
+}
+
+fn retries(summary: &Summary) -> u32 {
+    summary.pieces.iter().map(|piece| piece.retries).sum()
+}
+
+/// checks that the download at `path` is exactly `file` (and cleans it up)
+fn assert_downloaded(path: &Path, file: &FakeFile) {
+    assert_eq!(std::fs::read(path).unwrap(), *file.content);
+    assert!(!disk::part_path(path).exists());
+    std::fs::remove_file(path).unwrap();
+}
+
+/// checks that a failed download left nothing behind at `path`
+fn assert_cleaned_up(path: &Path) {
+    assert!(!path.exists());
+    assert!(!disk::part_path(path).exists());
+}
+
+#[test]
+fn recovering_from_dropped_connections() {
+    let (server, file) = server_with(vec![(Fault::DropAfter(1000), 0..3)]);
+    let (download, path) = download_from(&server, "dropped");
+
+    let summary = run(download.max_retries(3)).unwrap();
+    assert!(retries(&summary) >= 1);
+    assert_downloaded(&path, &file);
+}
+
+#[test]
+fn recovering_from_wrong_content_ranges() {
+    let (server, file) = server_with(vec![(Fault::WrongContentRange, 0..2)]);
+    let (download, path) = download_from(&server, "wrong-range");
+
+    let summary = run(download.max_retries(3)).unwrap();
+    assert!(retries(&summary) >= 1);
+    assert_downloaded(&path, &file);
+}
+
+#[test]
+fn reporting_content_ranges_that_stay_wrong() {
+    let (server, _) = server_with(vec![(Fault::WrongContentRange, 0..usize::MAX)]);
+    let (download, path) = download_from(&server, "always-wrong-range");
+
+    match run(download.max_retries(1)) {
+        Err(DlError::ContentRangeMismatch) => (),
+        other => panic!("expected a content range mismatch, got {:?}", other),
+    }
+    assert_cleaned_up(&path);
+}
+
+#[test]
+fn recovering_from_ignored_ranges() {
+    let (server, file) = server_with(vec![(Fault::IgnoreRange, 0..usize::MAX)]);
+    let (download, path) = download_from(&server, "ignored-range");
+
+    run(download.max_retries(1)).unwrap();
+    assert_downloaded(&path, &file);
+}
+
+#[test]
+fn waiting_as_long_as_throttling_servers_ask() {
+    for status in [
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::SERVICE_UNAVAILABLE,
+    ] {
+        let (server, file) = server_with(vec![(Fault::RetryAfter(status, 1), 0..1)]);
+        let (download, path) = download_from(&server, &format!("throttled-{}", status.as_u16()));
+
+        let started = Instant::now();
+        let summary = run(download.max_retries(1)).unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(retries(&summary), 1);
+        assert_downloaded(&path, &file);
+    }
+}
+
+#[test]
+fn reporting_servers_that_stay_throttled() {
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+    let (server, _) = server_with(vec![(Fault::RetryAfter(status, 0), 0..usize::MAX)]);
+    let (download, path) = download_from(&server, "always-throttled");
+
+    match run(download.max_retries(1)) {
+        Err(DlError::RetryAfter(503, _)) => (),
+        other => panic!("expected to be asked to retry later, got {:?}", other),
+    }
+    assert_cleaned_up(&path);
+}
+
+#[test]
+fn recovering_from_stalled_responses() {
+    let (server, file) = server_with(vec![(Fault::Stall(Duration::from_secs(5)), 0..1)]);
+    let (download, path) = download_from(&server, "stalled");
+    let timeouts = Timeouts {
+        first_byte: Some(Duration::from_millis(500)),
+        ..Timeouts::default()
+    };
+
+    let started = Instant::now();
+    let summary = run(download.timeouts(timeouts).max_retries(1)).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(retries(&summary), 1);
+    assert_downloaded(&path, &file);
+}
+
+#[test]
+fn reporting_corrupted_files() {
+    let (server, file) = server_with(vec![(Fault::FlipByte(100_000), 0..usize::MAX)]);
+    let (download, path) = download_from(&server, "corrupted");
+
+    match run(download) {
+        Err(DlError::DigestMismatch(expected, _)) => assert_eq!(expected, file.md5()),
+        other => panic!("expected a digest mismatch, got {:?}", other),
+    }
+    assert_cleaned_up(&path);
+}
+
+#[test]
+fn reporting_files_that_change_mid_download() {
+    let fault = Fault::ChangeEtag("v2".to_string());
+    let (server, _) = server_with(vec![(fault, 2..usize::MAX)]);
+    let (download, path) = download_from(&server, "changed");
+
+    match run(download.max_retries(3)) {
+        Err(DlError::RemoteChanged) => (),
+        other => panic!("expected the remote file to have changed, got {:?}", other),
+    }
+    assert_cleaned_up(&path);
+}